@@ -0,0 +1,212 @@
+//! The architecture of a downloaded model, read from its `config.json` before converting it:
+//! a converter given an architecture it doesn't know fails late, or writes garbage. For the
+//! Llama family, whatever the repo, the arguments convert.py needs are inferred from it too.
+
+use crate::OutputFormat;
+use serde::Deserialize;
+use std::path::Path;
+
+/// Architectures of the Llama family, the only ones convert.py reads
+const LLAMA_ARCHITECTURES: &[&str] =
+    &["LlamaForCausalLM", "LLaMAForCausalLM", "MistralForCausalLM"];
+
+/// Model types of the Llama family, for configs listing no architectures
+const LLAMA_MODEL_TYPES: &[&str] = &["llama", "mistral"];
+
+/// The fields of `config.json` naming the architecture
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Architecture {
+    #[serde(default)]
+    pub model_type: Option<String>,
+    #[serde(default)]
+    pub architectures: Option<Vec<String>>,
+}
+
+impl Architecture {
+    /// Read the architecture of the model in `dir`. The original LLaMA checkpoints have no
+    /// `config.json`, only a `params.json`.
+    pub fn detect(dir: &Path) -> Result<Self, String> {
+        let config = dir.join("config.json");
+        if !config.is_file() && dir.join("params.json").is_file() {
+            return Ok(Architecture {
+                model_type: Some("llama".to_string()),
+                architectures: None,
+            });
+        }
+        let config = std::fs::read(&config).map_err(|err| format!("Reading config.json: {err}"))?;
+        serde_json::from_slice(&config).map_err(|err| format!("Invalid config.json: {err}"))
+    }
+
+    fn architectures(&self) -> &[String] {
+        self.architectures.as_deref().unwrap_or_default()
+    }
+
+    /// The architectures listed by the config, or else its model type
+    pub fn name(&self) -> String {
+        match (self.architectures(), &self.model_type) {
+            ([], Some(model_type)) => model_type.clone(),
+            ([], None) => "unknown".to_string(),
+            (architectures, _) => architectures.join(", "),
+        }
+    }
+
+    /// Whether the model is of the Llama family, `None` when the config names no architecture
+    pub fn is_llama_family(&self) -> Option<bool> {
+        match (self.architectures(), &self.model_type) {
+            ([], None) => None,
+            ([], Some(model_type)) => Some(LLAMA_MODEL_TYPES.contains(&model_type.as_str())),
+            (architectures, _) => Some(
+                architectures
+                    .iter()
+                    .any(|architecture| LLAMA_ARCHITECTURES.contains(&architecture.as_str())),
+            ),
+        }
+    }
+
+    /// Whether the converter of the format reads models of this architecture.
+    ///
+    /// convert.py only reads the Llama family, and a config naming no architecture can't be
+    /// told apart. convert-hf-to-gguf.py names each architecture it supports, which vary with
+    /// the llama.cpp ref, so they are looked up in its source. It is left to tell a config
+    /// naming no architecture at all.
+    pub fn is_supported(&self, format: OutputFormat, converter: &Path) -> bool {
+        match format {
+            OutputFormat::Ggml => self.is_llama_family() == Some(true),
+            OutputFormat::Gguf => {
+                // a missing converter is reported by the conversion itself
+                let Ok(source) = std::fs::read_to_string(converter) else {
+                    return true;
+                };
+                self.architectures().is_empty()
+                    || self
+                        .architectures()
+                        .iter()
+                        .any(|architecture| source.contains(&format!("\"{architecture}\"")))
+            }
+        }
+    }
+}
+
+/// The arguments of convert.py for the model in `dir`, the requested ones followed by the ones
+/// inferred from its files that the request didn't give.
+///
+/// convert.py reads a sentencepiece `tokenizer.model` unless told otherwise. Llama-family
+/// repos shipping only a Hugging Face `tokenizer.json`, or BPE `vocab.json` and `merges.txt`,
+/// need `--vocab-type`.
+pub fn converter_args(dir: &Path, requested: &[String]) -> Vec<String> {
+    let mut args = requested.to_vec();
+    let given = |flag: &str| {
+        requested
+            .iter()
+            .any(|arg| arg == flag || arg.starts_with(&format!("{flag}=")))
+    };
+    if !given("--vocab-type") && !dir.join("tokenizer.model").is_file() {
+        let vocab_type = match (
+            dir.join("tokenizer.json").is_file(),
+            dir.join("vocab.json").is_file() && dir.join("merges.txt").is_file(),
+        ) {
+            (true, _) => Some("hfft"),
+            (false, true) => Some("bpe"),
+            // no tokenizer at all, convert.py reports it
+            (false, false) => None,
+        };
+        if let Some(vocab_type) = vocab_type {
+            args.extend(["--vocab-type".to_string(), vocab_type.to_string()]);
+        }
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{llama_model, TestDir};
+
+    fn model(config: &str, files: &[&str]) -> TestDir {
+        let dir = TestDir::new();
+        std::fs::write(dir.join("config.json"), config).unwrap();
+        for file in files {
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn detects_a_llama_family_model() {
+        let dir = TestDir::new();
+        llama_model(dir.path());
+
+        let architecture = Architecture::detect(dir.path()).unwrap();
+
+        assert_eq!(architecture.name(), "LlamaForCausalLM");
+        assert_eq!(architecture.is_llama_family(), Some(true));
+        assert!(architecture.is_supported(OutputFormat::Ggml, Path::new("convert.py")));
+
+        let mistral = model(r#"{"model_type": "mistral"}"#, &[]);
+        let architecture = Architecture::detect(mistral.path()).unwrap();
+        assert_eq!(architecture.name(), "mistral");
+        assert_eq!(architecture.is_llama_family(), Some(true));
+
+        // the original checkpoints
+        let original = TestDir::new();
+        std::fs::write(original.join("params.json"), "{}").unwrap();
+        let architecture = Architecture::detect(original.path()).unwrap();
+        assert_eq!(architecture.is_llama_family(), Some(true));
+    }
+
+    #[test]
+    fn refuses_an_architecture_the_converter_doesnt_know() {
+        let dir = model(r#"{"architectures": ["FalconForCausalLM"]}"#, &[]);
+        let converter = dir.join("convert-hf-to-gguf.py");
+        std::fs::write(&converter, "@Model.register(\"LlamaForCausalLM\")\n").unwrap();
+
+        let architecture = Architecture::detect(dir.path()).unwrap();
+
+        assert_eq!(architecture.name(), "FalconForCausalLM");
+        assert_eq!(architecture.is_llama_family(), Some(false));
+        assert!(!architecture.is_supported(OutputFormat::Ggml, Path::new("convert.py")));
+        assert!(!architecture.is_supported(OutputFormat::Gguf, &converter));
+        // nothing to tell an unnamed architecture by, convert-hf-to-gguf.py is left to try
+        let unnamed = Architecture::default();
+        assert_eq!(unnamed.name(), "unknown");
+        assert_eq!(unnamed.is_llama_family(), None);
+        assert!(!unnamed.is_supported(OutputFormat::Ggml, Path::new("convert.py")));
+        assert!(unnamed.is_supported(OutputFormat::Gguf, &converter));
+    }
+
+    #[test]
+    fn fails_on_a_missing_or_invalid_config() {
+        let missing = TestDir::new();
+        let err = Architecture::detect(missing.path()).unwrap_err();
+        assert!(err.starts_with("Reading config.json: "), "{err}");
+
+        let invalid = model("{", &[]);
+        let err = Architecture::detect(invalid.path()).unwrap_err();
+        assert!(err.starts_with("Invalid config.json: "), "{err}");
+    }
+
+    #[test]
+    fn infers_the_vocab_type_from_the_tokenizer_files() {
+        let args = |files: &[&str], requested: &[&str]| {
+            let dir = model("{}", files);
+            let requested: Vec<String> = requested.iter().map(|arg| arg.to_string()).collect();
+            converter_args(dir.path(), &requested)
+        };
+
+        assert_eq!(
+            args(&["tokenizer.model", "tokenizer.json"], &[]),
+            [] as [&str; 0]
+        );
+        assert_eq!(args(&["tokenizer.json"], &[]), ["--vocab-type", "hfft"]);
+        assert_eq!(
+            args(&["vocab.json", "merges.txt"], &[]),
+            ["--vocab-type", "bpe"]
+        );
+        assert_eq!(args(&["vocab.json"], &["--pad-vocab"]), ["--pad-vocab"]);
+        // the request has the last word
+        assert_eq!(
+            args(&["tokenizer.json"], &["--vocab-type=spm"]),
+            ["--vocab-type=spm"]
+        );
+    }
+}
@@ -0,0 +1,68 @@
+//! Batches of conversions submitted together with `POST /batch`, each conversion still runs as
+//! a job of its own. Batches are only kept in memory, their jobs are persisted as usual.
+
+use crate::job::{JobId, JobStore};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+};
+
+/// Most conversions a single batch may hold
+pub const MAX_BATCH_JOBS: usize = 100;
+
+/// Batch ids are generated and rendered like job ids
+pub type BatchId = JobId;
+
+/// The jobs of each batch
+#[derive(Debug, Clone, Default)]
+pub struct BatchStore {
+    batches: Arc<Mutex<HashMap<BatchId, Vec<JobId>>>>,
+}
+
+impl BatchStore {
+    pub fn insert(&self, job_ids: Vec<JobId>) -> BatchId {
+        let batch_id = BatchId::new();
+        self.batches.lock().unwrap().insert(batch_id, job_ids);
+        batch_id
+    }
+
+    pub fn get(&self, batch_id: BatchId) -> Option<Vec<JobId>> {
+        self.batches.lock().unwrap().get(&batch_id).cloned()
+    }
+}
+
+/// Aggregate state of a batch, returned by `GET /batch/{id}`
+#[derive(Debug, Serialize)]
+pub struct BatchStatus {
+    pub batch_id: BatchId,
+    pub job_ids: Vec<JobId>,
+    /// Number of jobs in each state, states without jobs left out
+    pub counts: BTreeMap<String, usize>,
+    /// Every job reached a final state
+    pub finished: bool,
+}
+
+impl BatchStatus {
+    pub fn new(batch_id: BatchId, job_ids: Vec<JobId>, jobs: &JobStore) -> Self {
+        let mut counts = BTreeMap::new();
+        let mut finished = true;
+        for job_id in &job_ids {
+            // jobs past their time to live leave the store, a missing one is still counted rather
+            // than dropped
+            let state = jobs.get(*job_id).map(|job| job.state);
+            finished &= state.is_none_or(|state| state.is_finished());
+            let state = match state {
+                Some(state) => format!("{state:?}"),
+                None => "Unknown".to_string(),
+            };
+            *counts.entry(state).or_insert(0) += 1;
+        }
+        BatchStatus {
+            batch_id,
+            job_ids,
+            counts,
+            finished,
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! How llama.cpp gets built: the parallelism of `make` and the variables enabling its
+//! accelerated backends
+
+use crate::runner::CommandSpec;
+use std::path::{Path, PathBuf};
+
+/// The make variables of llama.cpp that may be set, anything else could inject arbitrary make
+/// arguments
+const ALLOWED_VARIABLES: &[&str] = &[
+    "LLAMA_CUBLAS",
+    "LLAMA_CUDA_F16",
+    "LLAMA_CUDA_NVCC",
+    "CUDA_DOCKER_ARCH",
+    "LLAMA_HIPBLAS",
+    "LLAMA_CLBLAST",
+    "LLAMA_METAL",
+    "LLAMA_NO_METAL",
+    "LLAMA_NO_ACCELERATE",
+    "LLAMA_OPENBLAS",
+    "LLAMA_BLIS",
+    "LLAMA_NO_K_QUANTS",
+    "LLAMA_FAST",
+    "LLAMA_DEBUG",
+];
+
+/// Options of the llama.cpp build, from `GGML_MAKE_JOBS` and `GGML_MAKE_FLAGS`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildOptions {
+    /// Passed as `-j<jobs>`, the number of CPUs by default
+    pub jobs: usize,
+    /// `NAME=value` variables, e.g. `LLAMA_CUBLAS=1`
+    pub flags: Vec<String>,
+}
+
+impl BuildOptions {
+    pub fn from_env() -> Result<Self, String> {
+        let jobs = match std::env::var("GGML_MAKE_JOBS") {
+            Ok(jobs) => match jobs.parse::<usize>() {
+                Ok(jobs) if jobs > 0 => jobs,
+                _ => {
+                    return Err(format!(
+                        "Invalid GGML_MAKE_JOBS '{jobs}', expected a positive number"
+                    ))
+                }
+            },
+            Err(_) => std::thread::available_parallelism()
+                .map(usize::from)
+                .unwrap_or(1),
+        };
+        let flags = match std::env::var("GGML_MAKE_FLAGS") {
+            Ok(flags) => flags
+                .split([' ', ','])
+                .filter(|flag| !flag.is_empty())
+                .map(validate_flag)
+                .collect::<Result<_, _>>()?,
+            Err(_) => Vec::new(),
+        };
+        Ok(BuildOptions { jobs, flags })
+    }
+
+    /// The `make` invocation building the checkout
+    pub fn make_command(&self, llama_cpp_dir: &Path) -> CommandSpec {
+        let make = CommandSpec::new("make", llama_cpp_dir).arg(format!("-j{}", self.jobs));
+        self.flags.iter().fold(make, |make, flag| make.arg(flag))
+    }
+}
+
+/// The quantize tool of a built checkout, the error lists every path searched
+pub fn find_quantizer(llama_cpp_dir: &Path) -> Result<PathBuf, String> {
+    find_tool(llama_cpp_dir, "quantize")
+}
+
+/// The tool generating text with a model of a built checkout, `main` until it was renamed
+/// `llama-cli`
+pub fn find_loader(llama_cpp_dir: &Path) -> Result<PathBuf, String> {
+    find_tool(llama_cpp_dir, "main")
+        .or_else(|main| find_tool(llama_cpp_dir, "cli").map_err(|cli| format!("{main}; {cli}")))
+}
+
+/// A tool of a built checkout, such as `quantize`, wherever builds of its age put it: the
+/// Makefile used to write `<name>` to the root of the checkout, the tools were then renamed
+/// `llama-<name>` and CMake builds go to `build/bin`
+pub fn find_tool(llama_cpp_dir: &Path, name: &str) -> Result<PathBuf, String> {
+    let candidates: Vec<PathBuf> = [
+        name.to_string(),
+        format!("llama-{name}"),
+        format!("build/bin/llama-{name}"),
+        format!("build/bin/{name}"),
+    ]
+    .iter()
+    .map(|candidate| llama_cpp_dir.join(candidate))
+    .collect();
+    match candidates.iter().find(|candidate| candidate.is_file()) {
+        Some(tool) => Ok(tool.clone()),
+        None => Err(format!(
+            "No {name} tool in the llama.cpp build, searched {}",
+            candidates
+                .iter()
+                .map(|candidate| candidate.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+/// Check that the flag sets an allowed variable to a plain value
+fn validate_flag(flag: &str) -> Result<String, String> {
+    let invalid = || {
+        format!(
+            "Invalid GGML_MAKE_FLAGS entry '{flag}', expected NAME=value with NAME one of {}",
+            ALLOWED_VARIABLES.join(", ")
+        )
+    };
+    let (name, value) = flag.split_once('=').ok_or_else(invalid)?;
+    let plain_value = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-' | '/'));
+    if !ALLOWED_VARIABLES.contains(&name) || !plain_value || value.starts_with('-') {
+        return Err(invalid());
+    }
+    Ok(flag.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{TestDir, ENV};
+
+    /// The build options with `GGML_MAKE_JOBS` and `GGML_MAKE_FLAGS` set
+    fn from_env_with(jobs: &str, flags: &str) -> Result<BuildOptions, String> {
+        let _env = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        std::env::set_var("GGML_MAKE_JOBS", jobs);
+        std::env::set_var("GGML_MAKE_FLAGS", flags);
+        let options = BuildOptions::from_env();
+        std::env::remove_var("GGML_MAKE_JOBS");
+        std::env::remove_var("GGML_MAKE_FLAGS");
+        options
+    }
+
+    #[test]
+    fn passes_the_jobs_and_the_flags_to_make() {
+        let options = from_env_with(
+            "6",
+            "LLAMA_CUBLAS=1, LLAMA_CUDA_NVCC=/usr/local/cuda/bin/nvcc",
+        )
+        .unwrap();
+
+        assert_eq!(
+            options.make_command(Path::new("/llama.cpp")).to_string(),
+            "make -j6 LLAMA_CUBLAS=1 LLAMA_CUDA_NVCC=/usr/local/cuda/bin/nvcc"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_jobs_and_flags() {
+        for jobs in ["0", "-1", "many"] {
+            assert!(from_env_with(jobs, "").is_err(), "{jobs}");
+        }
+        for flags in [
+            "CC=evil",
+            "LLAMA_CUBLAS",
+            "LLAMA_CUBLAS=",
+            "LLAMA_FAST=$(rm)",
+            "LLAMA_FAST=-k",
+        ] {
+            let err = from_env_with("1", flags).unwrap_err();
+
+            assert!(
+                err.starts_with("Invalid GGML_MAKE_FLAGS entry"),
+                "{flags}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn finds_a_tool_in_each_layout() {
+        for layout in [
+            "quantize",
+            "llama-quantize",
+            "build/bin/llama-quantize",
+            "build/bin/quantize",
+        ] {
+            let checkout = TestDir::new();
+            let tool = checkout.join(layout);
+            std::fs::create_dir_all(tool.parent().unwrap()).unwrap();
+            std::fs::write(&tool, "").unwrap();
+
+            assert_eq!(find_quantizer(checkout.path()), Ok(tool), "{layout}");
+        }
+    }
+
+    #[test]
+    fn lists_the_paths_searched_for_a_missing_tool() {
+        let checkout = TestDir::new();
+        // a directory of the name isn't the tool
+        std::fs::create_dir(checkout.join("quantize")).unwrap();
+
+        let err = find_quantizer(checkout.path()).unwrap_err();
+
+        assert!(
+            err.starts_with("No quantize tool in the llama.cpp build"),
+            "{err}"
+        );
+        assert!(
+            err.contains(
+                &checkout
+                    .join("build/bin/llama-quantize")
+                    .display()
+                    .to_string()
+            ),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn finds_the_loader_under_its_old_and_new_names() {
+        let checkout = TestDir::new();
+        std::fs::write(checkout.join("llama-cli"), "").unwrap();
+
+        assert_eq!(find_loader(checkout.path()), Ok(checkout.join("llama-cli")));
+        std::fs::write(checkout.join("main"), "").unwrap();
+        assert_eq!(find_loader(checkout.path()), Ok(checkout.join("main")));
+    }
+}
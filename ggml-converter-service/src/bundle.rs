@@ -0,0 +1,128 @@
+//! `GET /jobs/{id}/bundle`: the outputs of a job in a single tar archive, written as it is
+//! sent rather than to a file first.
+//!
+//! The tar format is the files one after the other, each behind a 512-byte header and padded
+//! to 512 bytes, so only the headers are built with the `tar` crate and the files are streamed
+//! in between, the size of the archive being known upfront.
+
+use crate::{cleanup::DownloadGuard, storage::StoredFile};
+use axum::body::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use std::time::SystemTime;
+use tokio::io::AsyncReadExt;
+
+const BLOCK: u64 = 512;
+
+/// A file of the archive, named as in the storage
+pub struct Entry {
+    pub name: String,
+    pub file: StoredFile,
+    /// Keeps the file from being cleaned up until it is sent
+    pub guard: DownloadGuard,
+}
+
+/// The archive of the files, with its size
+pub fn tar(
+    entries: Vec<Entry>,
+) -> std::io::Result<(u64, impl Stream<Item = std::io::Result<Bytes>>)> {
+    let mut parts = Vec::new();
+    // the two empty blocks ending an archive
+    let mut len = 2 * BLOCK;
+    for entry in entries {
+        let file = entry.file;
+        let headers = headers(&entry.name, file.len, file.modified)?;
+        let padding = (BLOCK - file.len % BLOCK) % BLOCK;
+        len += headers.len() as u64 + file.len + padding;
+
+        // read no further than the size in the header, should the file grow meanwhile
+        let guard = entry.guard;
+        let part = stream::once(async { Ok(Bytes::from(headers)) })
+            .chain(tokio_util::io::ReaderStream::new(
+                file.reader.take(file.len),
+            ))
+            .chain(stream::once(async move {
+                drop(guard);
+                Ok(Bytes::from(vec![0; padding as usize]))
+            }));
+        parts.push(part);
+    }
+    let end = stream::once(async { Ok(Bytes::from(vec![0; 2 * BLOCK as usize])) });
+    Ok((len, stream::iter(parts).flatten().chain(end)))
+}
+
+/// The header of a file, preceded by the entry holding its name when it is too long for
+/// the header
+fn headers(name: &str, len: u64, modified: Option<SystemTime>) -> std::io::Result<Vec<u8>> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(len);
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    if let Some(gnu) = header.as_gnu_mut() {
+        gnu.set_device_major(0);
+        gnu.set_device_minor(0);
+    }
+    if let Some(modified) = modified {
+        let mtime = modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        header.set_mtime(mtime.as_secs());
+    }
+    // nothing but the headers is written, the builder pads no data
+    let mut headers = tar::Builder::new(Vec::new());
+    headers.append_data(&mut header, name, std::io::empty())?;
+    Ok(headers.get_ref().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cleanup::ActiveDownloads, tests::TestDir};
+    use std::io::Read;
+
+    #[tokio::test]
+    async fn archives_the_files_one_after_the_other() {
+        let root = TestDir::new();
+        let long_name = format!("{}-q4_0.gguf", "a-long-model-name".repeat(8));
+        let files = [
+            ("tiny.gguf", "converted"),
+            (long_name.as_str(), "quantized"),
+        ];
+        let downloads = ActiveDownloads::default();
+        let mut entries = Vec::new();
+        for (name, content) in files {
+            std::fs::write(root.join(name), content).unwrap();
+            entries.push(Entry {
+                name: name.to_string(),
+                file: StoredFile::open(&root.join(name)).await.unwrap(),
+                guard: downloads.track(name),
+            });
+        }
+
+        let (len, stream) = tar(entries).unwrap();
+        let chunks: Vec<Bytes> = stream.map(Result::unwrap).collect().await;
+        let archive = chunks.concat();
+
+        assert_eq!(archive.len() as u64, len);
+        assert_eq!(len % BLOCK, 0);
+        // sent, the files can be cleaned up again
+        assert!(!downloads.is_active("tiny.gguf"));
+        let mut archive = ::tar::Archive::new(archive.as_slice());
+        let archived: Vec<(String, String)> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let name = entry.path().unwrap().display().to_string();
+                let mut content = String::new();
+                entry.read_to_string(&mut content).unwrap();
+                (name, content)
+            })
+            .collect();
+        assert_eq!(
+            archived,
+            files.map(|(name, content)| (name.to_string(), content.to_string()))
+        );
+    }
+}
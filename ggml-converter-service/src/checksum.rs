@@ -0,0 +1,111 @@
+//! SHA-256 digests of the converted files, kept next to them as `<file>.sha256`
+
+use crate::storage::Storage;
+use openssl::sha::Sha256;
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+};
+use tokio::io::AsyncReadExt;
+
+/// Where the digest of a file is kept
+pub fn checksum_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".sha256");
+    path.with_file_name(name)
+}
+
+/// Name the digest of a stored file is kept under
+pub fn checksum_name(name: &str) -> String {
+    format!("{name}.sha256")
+}
+
+/// Hex SHA-256 of the file, read in chunks so multi-GB models never sit in memory
+pub fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hex(hasher))
+}
+
+fn hex(hasher: Sha256) -> String {
+    hasher
+        .finish()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The digest of the file, from its `.sha256` when there is one, computed and saved otherwise
+pub async fn file_checksum(path: &Path) -> std::io::Result<String> {
+    let checksum_file = checksum_path(path);
+    if let Ok(checksum) = tokio::fs::read_to_string(&checksum_file).await {
+        return Ok(checksum.trim().to_string());
+    }
+
+    let file = path.to_path_buf();
+    let checksum = tokio::task::spawn_blocking(move || sha256_file(&file))
+        .await
+        .map_err(std::io::Error::other)??;
+    // written aside then moved, a reader never sees half a digest
+    let mut tmp = checksum_file.clone().into_os_string();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, &checksum).await?;
+    tokio::fs::rename(&tmp, &checksum_file).await?;
+    Ok(checksum)
+}
+
+/// The digest of a stored file, like `file_checksum` but through the storage
+pub async fn stored_checksum(storage: &dyn Storage, name: &str) -> std::io::Result<String> {
+    let checksum_name = checksum_name(name);
+    if let Ok(mut stored) = storage.get(&checksum_name).await {
+        let mut checksum = String::new();
+        stored.reader.read_to_string(&mut checksum).await?;
+        return Ok(checksum.trim().to_string());
+    }
+
+    let mut stored = storage.get(name).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let read = stored.reader.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    let checksum = hex(hasher);
+    storage
+        .put(&checksum_name, &mut checksum.as_bytes())
+        .await?;
+    Ok(checksum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TestDir;
+
+    const ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[tokio::test]
+    async fn hashes_a_file_and_keeps_its_digest_next_to_it() {
+        let dir = TestDir::new();
+        let file = dir.join("tiny-q4_0.gguf");
+        std::fs::write(&file, "abc").unwrap();
+
+        assert_eq!(sha256_file(&file).unwrap(), ABC);
+        assert_eq!(file_checksum(&file).await.unwrap(), ABC);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("tiny-q4_0.gguf.sha256")).unwrap(),
+            ABC
+        );
+    }
+}
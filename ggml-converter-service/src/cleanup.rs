@@ -0,0 +1,301 @@
+//! Periodic removal of old jobs: finished jobs past their time to live are dropped from the
+//! store along with their logs, and optionally their outputs, so neither grows forever.
+//!
+//! Outputs still being downloaded, or produced again by a job that is kept, are never deleted.
+
+use crate::{
+    checksum,
+    job::{now_secs, JobState, JobStore},
+    joblog,
+    storage::Storage,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+/// Default time a finished job is kept for
+pub const DEFAULT_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Default time between two cleanups
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// When and what to clean up
+#[derive(Debug, Clone, Copy)]
+pub struct Cleanup {
+    /// Time a job is kept for once finished
+    pub ttl: Duration,
+    pub interval: Duration,
+    /// Whether the outputs of the removed jobs are deleted too
+    pub delete_outputs: bool,
+}
+
+impl Cleanup {
+    /// Settings from `GGML_JOB_TTL_SECS`, `GGML_CLEANUP_INTERVAL_SECS` and
+    /// `GGML_CLEANUP_DELETE_OUTPUTS`, `None` when the time to live is 0, which disables the
+    /// cleanup
+    pub fn from_env() -> Option<Self> {
+        let secs = |var: &str| {
+            std::env::var(var)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .map(Duration::from_secs)
+        };
+        let ttl = secs("GGML_JOB_TTL_SECS").unwrap_or(DEFAULT_TTL);
+        if ttl.is_zero() {
+            return None;
+        }
+        Some(Cleanup {
+            ttl,
+            interval: secs("GGML_CLEANUP_INTERVAL_SECS")
+                .filter(|interval| !interval.is_zero())
+                .unwrap_or(DEFAULT_INTERVAL),
+            delete_outputs: std::env::var("GGML_CLEANUP_DELETE_OUTPUTS")
+                .is_ok_and(|value| value == "true"),
+        })
+    }
+
+    /// Clean up every `interval`, forever
+    pub async fn run(
+        self,
+        jobs: JobStore,
+        downloads: ActiveDownloads,
+        storage: Arc<dyn Storage>,
+        logs_dir: PathBuf,
+    ) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            self.tick(&jobs, &downloads, storage.as_ref(), &logs_dir)
+                .await;
+        }
+    }
+
+    /// Remove the finished jobs older than the time to live, with their logs and outputs.
+    /// Jobs with an output being downloaded are left for a later tick.
+    pub async fn tick(
+        &self,
+        jobs: &JobStore,
+        downloads: &ActiveDownloads,
+        storage: &dyn Storage,
+        logs_dir: &Path,
+    ) -> Reclaimed {
+        let cutoff = now_secs().saturating_sub(self.ttl.as_secs());
+        let removed = jobs.remove_expired(|job| {
+            job.updated_at < cutoff
+                && !job
+                    .model_info
+                    .output_filenames()
+                    .iter()
+                    .any(|filename| downloads.is_active(filename))
+        });
+        let mut reclaimed = Reclaimed {
+            jobs: removed.len(),
+            ..Reclaimed::default()
+        };
+        if removed.is_empty() {
+            return reclaimed;
+        }
+
+        for job in &removed {
+            let log = joblog::log_path(logs_dir, job.id);
+            let mut rotated = log.as_os_str().to_owned();
+            rotated.push(".1");
+            for path in [log, PathBuf::from(rotated)] {
+                reclaimed.bytes += remove_file(&path).await;
+            }
+        }
+
+        if self.delete_outputs {
+            // another run of the same conversion may have written the same files
+            let kept: HashSet<String> = jobs
+                .list(None, None)
+                .iter()
+                .flat_map(|job| job.model_info.output_filenames())
+                .collect();
+            let filenames: HashSet<String> = removed
+                .iter()
+                .filter(|job| job.state == JobState::Done)
+                .flat_map(|job| job.model_info.output_filenames())
+                .filter(|filename| !kept.contains(filename) && !downloads.is_active(filename))
+                .collect();
+            for filename in filenames {
+                let freed = delete_output(storage, &filename).await;
+                if freed > 0 {
+                    reclaimed.outputs += 1;
+                    reclaimed.bytes +=
+                        freed + delete_output(storage, &checksum::checksum_name(&filename)).await;
+                }
+            }
+        }
+
+        let ids: Vec<String> = removed.iter().map(|job| job.id.to_string()).collect();
+        info!(
+            "Removed the jobs {} finished over {:?} ago and {} outputs, freeing {} bytes",
+            ids.join(", "),
+            self.ttl,
+            reclaimed.outputs,
+            reclaimed.bytes
+        );
+        reclaimed
+    }
+}
+
+/// What a cleanup removed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Reclaimed {
+    pub jobs: usize,
+    pub outputs: usize,
+    /// Size of the logs and outputs deleted
+    pub bytes: u64,
+}
+
+/// Remove the file if it exists, returning its size
+async fn remove_file(path: &Path) -> u64 {
+    let len = match tokio::fs::metadata(path).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => return 0,
+    };
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => len,
+        Err(err) => {
+            warn!("Failed to remove {}: {err}", path.display());
+            0
+        }
+    }
+}
+
+/// Remove the output if it is stored, returning its size
+async fn delete_output(storage: &dyn Storage, name: &str) -> u64 {
+    match storage.delete(name).await {
+        Ok(freed) => freed.unwrap_or_default(),
+        Err(err) => {
+            warn!("Failed to remove the output {name}: {err}");
+            0
+        }
+    }
+}
+
+/// The outputs being sent by `GET /download`, with the number of streams of each.
+///
+/// Cloning is cheap, all clones share the same downloads.
+#[derive(Debug, Clone, Default)]
+pub struct ActiveDownloads {
+    files: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl ActiveDownloads {
+    /// Count the file as being downloaded until the guard is dropped
+    pub fn track(&self, filename: &str) -> DownloadGuard {
+        *self
+            .files
+            .lock()
+            .unwrap()
+            .entry(filename.to_string())
+            .or_default() += 1;
+        DownloadGuard {
+            downloads: self.clone(),
+            filename: filename.to_string(),
+        }
+    }
+
+    pub fn is_active(&self, filename: &str) -> bool {
+        self.files.lock().unwrap().contains_key(filename)
+    }
+}
+
+/// A download in progress, held by its response body
+#[derive(Debug)]
+pub struct DownloadGuard {
+    downloads: ActiveDownloads,
+    filename: String,
+}
+
+impl Drop for DownloadGuard {
+    fn drop(&mut self) {
+        let mut files = self.downloads.files.lock().unwrap();
+        if let Some(count) = files.get_mut(&self.filename) {
+            *count -= 1;
+            if *count == 0 {
+                files.remove(&self.filename);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        job::Job,
+        storage::LocalStorage,
+        tests::{model_info, TestDir},
+    };
+    use serde_json::json;
+
+    /// A job done `age` ago
+    fn done(jobs: &JobStore, name: &str, age: Duration) -> Job {
+        let mut job = Job::new(model_info(json!({"name": name, "quant_info": "Q4"})));
+        job.state = JobState::Done;
+        job.updated_at = now_secs() - age.as_secs();
+        jobs.insert_unless_running(job.clone()).unwrap();
+        job
+    }
+
+    #[tokio::test]
+    async fn removes_the_jobs_past_their_time_to_live() {
+        let root = TestDir::new();
+        let (outputs_dir, logs_dir) = (root.join("outputs"), root.join("logs"));
+        std::fs::create_dir_all(&outputs_dir).unwrap();
+        std::fs::create_dir_all(&logs_dir).unwrap();
+        let jobs = JobStore::default();
+        let downloads = ActiveDownloads::default();
+        let hour = Duration::from_secs(60 * 60);
+        let old = done(&jobs, "acme/expired", 2 * hour);
+        let recent = done(&jobs, "acme/recent", Duration::ZERO);
+        let downloaded = done(&jobs, "acme/downloaded", 2 * hour);
+        for job in [&old, &recent, &downloaded] {
+            std::fs::write(joblog::log_path(&logs_dir, job.id), "log").unwrap();
+            for filename in job.model_info.output_filenames() {
+                std::fs::write(outputs_dir.join(filename), "quantized").unwrap();
+            }
+        }
+        let _download = downloads.track(&downloaded.model_info.output_filenames()[0]);
+        let cleanup = Cleanup {
+            ttl: hour,
+            interval: hour,
+            delete_outputs: true,
+        };
+
+        let reclaimed = cleanup
+            .tick(
+                &jobs,
+                &downloads,
+                &LocalStorage::new(&outputs_dir),
+                &logs_dir,
+            )
+            .await;
+
+        assert_eq!(
+            reclaimed,
+            Reclaimed {
+                jobs: 1,
+                // the converted model and its quant
+                outputs: 2,
+                bytes: 3 + 2 * 9,
+            }
+        );
+        assert!(jobs.get(old.id).is_none());
+        assert!(!joblog::log_path(&logs_dir, old.id).exists());
+        for filename in old.model_info.output_filenames() {
+            assert!(!outputs_dir.join(filename).exists());
+        }
+        for job in [&recent, &downloaded] {
+            assert!(jobs.get(job.id).is_some());
+            assert!(joblog::log_path(&logs_dir, job.id).exists());
+        }
+    }
+}
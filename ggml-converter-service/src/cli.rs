@@ -0,0 +1,162 @@
+use std::path::PathBuf;
+
+pub const DEFAULT_HOST: &str = "0.0.0.0";
+pub const DEFAULT_PORT: u16 = 3000;
+
+pub const USAGE: &str = "\
+Usage: ggml-converter-service [OPTIONS]
+
+Options:
+      --host <HOST>                Address to bind to [env: GGML_HOST] [default: 0.0.0.0]
+      --port <PORT>                Port to listen on [env: GGML_PORT] [default: 3000]
+      --outputs-dir <DIR>          Where converted models are written [env: GGML_OUTPUTS_DIR]
+      --models-dir <DIR>           Where model repos are downloaded [env: GGML_MODELS_DIR]
+      --llama-cpp-dir <DIR>        Where llama.cpp is checked out [env: GGML_LLAMA_CPP_DIR]
+      --logs-dir <DIR>             Where the logs of the jobs are kept [env: GGML_LOGS_DIR]
+  -h, --help                       Print this help
+";
+
+/// Command line options. Flags take precedence over the matching environment variables.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Args {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub outputs_dir: Option<PathBuf>,
+    pub models_dir: Option<PathBuf>,
+    pub llama_cpp_dir: Option<PathBuf>,
+    pub logs_dir: Option<PathBuf>,
+    pub help: bool,
+}
+
+impl Args {
+    /// Parse the arguments, without the program name. Both `--flag value` and `--flag=value`
+    /// are accepted.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => {
+                    (flag.to_string(), Some(value.to_string()))
+                }
+                _ => (arg, None),
+            };
+            if flag == "-h" || flag == "--help" {
+                parsed.help = true;
+                continue;
+            }
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("Missing value for {flag}"))
+            };
+            match flag.as_str() {
+                "--host" => parsed.host = Some(value()?),
+                "--port" => {
+                    let port = value()?;
+                    let port = port.parse().map_err(|_| format!("Invalid port '{port}'"))?;
+                    parsed.port = Some(port);
+                }
+                "--outputs-dir" => parsed.outputs_dir = Some(value()?.into()),
+                "--models-dir" => parsed.models_dir = Some(value()?.into()),
+                "--llama-cpp-dir" => parsed.llama_cpp_dir = Some(value()?.into()),
+                "--logs-dir" => parsed.logs_dir = Some(value()?.into()),
+                _ => return Err(format!("Unknown argument '{flag}'\n\n{USAGE}")),
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// The address to bind to, from the flags, then `GGML_HOST`/`GGML_PORT`, then the defaults
+    pub fn bind_address(&self) -> Result<std::net::SocketAddr, String> {
+        let host = match &self.host {
+            Some(host) => host.clone(),
+            None => std::env::var("GGML_HOST").unwrap_or_else(|_| DEFAULT_HOST.to_string()),
+        };
+        let port = match self.port {
+            Some(port) => port,
+            None => match std::env::var("GGML_PORT") {
+                Ok(port) => port
+                    .parse()
+                    .map_err(|_| format!("Invalid GGML_PORT '{port}'"))?,
+                Err(_) => DEFAULT_PORT,
+            },
+        };
+        // brackets let IPv6 hosts through the `host:port` parsing
+        let addr = match host.contains(':') {
+            true => format!("[{host}]:{port}"),
+            false => format!("{host}:{port}"),
+        };
+        addr.parse()
+            .map_err(|_| format!("Invalid bind address '{addr}'"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_flags_with_spaced_and_inline_values() {
+        let args = parse(&[
+            "--host",
+            "127.0.0.1",
+            "--port=8080",
+            "--outputs-dir",
+            "/data/outputs",
+            "--models-dir=/data/models",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            args,
+            Args {
+                host: Some("127.0.0.1".to_string()),
+                port: Some(8080),
+                outputs_dir: Some("/data/outputs".into()),
+                models_dir: Some("/data/models".into()),
+                ..Args::default()
+            }
+        );
+        assert_eq!(
+            args.bind_address().unwrap(),
+            "127.0.0.1:8080".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn parses_help_and_no_flags() {
+        assert_eq!(parse(&[]).unwrap(), Args::default());
+        assert!(parse(&["-h"]).unwrap().help);
+        assert!(parse(&["--port", "80", "--help"]).unwrap().help);
+    }
+
+    #[test]
+    fn brackets_an_ipv6_host() {
+        let args = parse(&["--host", "::1", "--port", "3001"]).unwrap();
+
+        assert_eq!(args.bind_address().unwrap(), "[::1]:3001".parse().unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_flags() {
+        assert_eq!(
+            parse(&["--port", "http"]),
+            Err("Invalid port 'http'".to_string())
+        );
+        assert_eq!(
+            parse(&["--logs-dir"]),
+            Err("Missing value for --logs-dir".to_string())
+        );
+        let err = parse(&["--verbose"]).unwrap_err();
+        assert!(
+            err.starts_with("Unknown argument '--verbose'\n\nUsage:"),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,265 @@
+use crate::{
+    build::BuildOptions,
+    cli::Args,
+    converter::{self, ConverterEnv},
+    s3::S3Config,
+    signed_url::UrlSigner,
+    OutputFormat,
+};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Where the service keeps its files
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Converted models, served by `GET /download/{filename}`
+    pub outputs_dir: PathBuf,
+    /// Downloaded model repos
+    pub models_dir: PathBuf,
+    /// Holds one `llama.cpp-<ref>` checkout per llama.cpp ref
+    pub llama_cpp_dir: PathBuf,
+    /// The log of each job, served by `GET /jobs/{id}/log`
+    pub logs_dir: PathBuf,
+    /// The only directory local models are converted from, `GGML_LOCAL_MODELS_DIR`. Local
+    /// models are refused without one.
+    pub local_models_dir: Option<PathBuf>,
+    /// How the checkouts get built
+    pub build: BuildOptions,
+    /// Bucket the outputs are uploaded to, they are only served locally without one
+    pub s3: Option<S3Config>,
+    /// Signs the download urls, downloads are open to anyone without one
+    pub url_signer: Option<UrlSigner>,
+    /// Leave the unquantized model in the outputs directory once quantized, it is removed
+    /// unless `GGML_KEEP_INTERMEDIATE` is `true`
+    pub keep_intermediate: bool,
+    /// Name of the Ggml converter script in the checkouts, `GGML_CONVERTER_GGML`
+    pub ggml_converter: String,
+    /// Name of the Gguf converter script in the checkouts, `GGML_CONVERTER_GGUF`
+    pub gguf_converter: String,
+    /// Variables set for the converter scripts, from `GGML_CONVERT_ENV_<NAME>`
+    pub converter_env: ConverterEnv,
+    /// Where clients reach the service, `GGML_PUBLIC_BASE_URL` without its trailing slashes.
+    /// Download urls are relative to the service without one.
+    pub public_base_url: Option<String>,
+    /// Longest a job may run once it got a conversion slot, `GGML_JOB_DEADLINE_SECS`, 2 hours
+    /// by default. `None` when set to 0, jobs then run for as long as their stages do.
+    pub job_deadline: Option<Duration>,
+}
+
+impl Config {
+    /// Take the directories from the command line, or else from `GGML_OUTPUTS_DIR`,
+    /// `GGML_MODELS_DIR`, `GGML_LLAMA_CPP_DIR` and `GGML_LOGS_DIR`.
+    ///
+    /// Unset ones default to `outputs/`, `models/`, the checkouts themselves and `logs/` next
+    /// to the service's launch directory. Relative paths are resolved against the launch directory,
+    /// subprocesses run elsewhere and only ever see absolute paths.
+    pub fn load(args: &Args) -> Result<Self, String> {
+        let curr_dir = std::env::current_dir()
+            .map_err(|err| format!("Failed to read the current directory: {err}"))?;
+        let root_dir = curr_dir
+            .parent()
+            .ok_or("The service has no parent directory")?;
+        let dir =
+            |arg: &Option<PathBuf>, var: &str, default: &Path| match (arg, std::env::var_os(var)) {
+                (Some(dir), _) => curr_dir.join(dir),
+                (None, Some(dir)) if !dir.is_empty() => curr_dir.join(dir),
+                _ => default.to_path_buf(),
+            };
+        Ok(Config {
+            outputs_dir: dir(
+                &args.outputs_dir,
+                "GGML_OUTPUTS_DIR",
+                &root_dir.join("outputs"),
+            ),
+            models_dir: dir(
+                &args.models_dir,
+                "GGML_MODELS_DIR",
+                &root_dir.join("models"),
+            ),
+            llama_cpp_dir: dir(&args.llama_cpp_dir, "GGML_LLAMA_CPP_DIR", root_dir),
+            logs_dir: dir(&args.logs_dir, "GGML_LOGS_DIR", &root_dir.join("logs")),
+            local_models_dir: std::env::var_os("GGML_LOCAL_MODELS_DIR")
+                .filter(|dir| !dir.is_empty())
+                .map(|dir| curr_dir.join(dir)),
+            build: BuildOptions::from_env()?,
+            s3: S3Config::from_env()?,
+            url_signer: UrlSigner::from_env()?,
+            keep_intermediate: std::env::var("GGML_KEEP_INTERMEDIATE")
+                .is_ok_and(|value| value == "true"),
+            ggml_converter: converter::script_from_env(OutputFormat::Ggml)?,
+            gguf_converter: converter::script_from_env(OutputFormat::Gguf)?,
+            converter_env: ConverterEnv::from_env(),
+            public_base_url: public_base_url_from_env()?,
+            job_deadline: job_deadline_from_env(),
+        })
+    }
+
+    /// The url clients reach a path of the service at, `path` itself without a public base url
+    pub fn public_url(&self, path: &str) -> String {
+        match &self.public_base_url {
+            Some(base_url) => format!("{base_url}/{}", path.trim_start_matches('/')),
+            None => path.to_string(),
+        }
+    }
+
+    /// Name of the script converting to the format, for llama.cpp refs that renamed it
+    pub fn converter_script(&self, format: OutputFormat) -> &str {
+        match format {
+            OutputFormat::Ggml => &self.ggml_converter,
+            OutputFormat::Gguf => &self.gguf_converter,
+        }
+    }
+
+    /// Checkout of the given llama.cpp ref
+    pub fn llama_cpp_checkout(&self, llama_cpp_ref: &str) -> PathBuf {
+        self.llama_cpp_dir
+            .join(format!("llama.cpp-{llama_cpp_ref}"))
+    }
+}
+
+/// `GGML_JOB_DEADLINE_SECS`, `None` when set to 0
+fn job_deadline_from_env() -> Option<Duration> {
+    let secs = std::env::var("GGML_JOB_DEADLINE_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(2 * 60 * 60);
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// `GGML_PUBLIC_BASE_URL`, e.g. `https://models.example.com` or a path of it the service is
+/// proxied under, without its trailing slashes
+fn public_base_url_from_env() -> Result<Option<String>, String> {
+    let base_url = match std::env::var("GGML_PUBLIC_BASE_URL") {
+        Ok(base_url) if !base_url.is_empty() => base_url,
+        _ => return Ok(None),
+    };
+    let valid = reqwest::Url::parse(&base_url).is_ok_and(|url| {
+        matches!(url.scheme(), "http" | "https")
+            && url.has_host()
+            && url.query().is_none()
+            && url.fragment().is_none()
+    });
+    match valid {
+        true => Ok(Some(base_url.trim_end_matches('/').to_string())),
+        false => Err(format!(
+            "Invalid GGML_PUBLIC_BASE_URL '{base_url}', expected an http(s) url without query, e.g. https://models.example.com"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runner::mock::MockCommandRunner,
+        tests::{eventually, llama_cpp_checkout, local_model, serve, services, TestDir, ENV},
+    };
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+
+    const DIR_VARS: [&str; 5] = [
+        "GGML_OUTPUTS_DIR",
+        "GGML_MODELS_DIR",
+        "GGML_LLAMA_CPP_DIR",
+        "GGML_LOGS_DIR",
+        "GGML_LOCAL_MODELS_DIR",
+    ];
+
+    /// Load the configuration with the directories set in the environment
+    fn load_with(args: &Args, dirs: [&Path; 5]) -> Config {
+        let _env = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (var, dir) in DIR_VARS.into_iter().zip(dirs) {
+            std::env::set_var(var, dir);
+        }
+        let config = Config::load(args);
+        for var in DIR_VARS {
+            std::env::remove_var(var);
+        }
+        config.unwrap()
+    }
+
+    #[tokio::test]
+    async fn writes_the_outputs_to_the_configured_directory() {
+        let root = TestDir::new();
+        let dirs = ["out", "repos", "llama", "job-logs", "local"].map(|dir| root.join(dir));
+        let config = load_with(&Args::default(), dirs.each_ref().map(PathBuf::as_path));
+        assert_eq!(
+            [
+                &config.outputs_dir,
+                &config.models_dir,
+                &config.llama_cpp_dir,
+                &config.logs_dir,
+                config.local_models_dir.as_ref().unwrap(),
+            ],
+            dirs.each_ref()
+        );
+        llama_cpp_checkout(&config);
+        local_model(&config, "tiny");
+        let url = serve(services(config, Arc::new(MockCommandRunner::llama_cpp())));
+
+        let response = reqwest::Client::new()
+            .post(format!("{url}/ggml"))
+            .json(&json!({"name": {"local_path": "tiny"}, "quant_info": "Q4"}))
+            .send()
+            .await
+            .unwrap();
+        let created: Value = response.json().await.unwrap();
+        let job_id = created["job_id"].as_str().unwrap();
+        eventually("the output", || root.join("out/tiny-q4_0.gguf").is_file()).await;
+        let log = || root.join(format!("job-logs/{job_id}.log"));
+        eventually("the log", || log().is_file()).await;
+    }
+
+    #[test]
+    fn prefers_the_flags_to_the_environment() {
+        let root = TestDir::new();
+        let args = Args {
+            outputs_dir: Some(root.join("flag-out")),
+            ..Args::default()
+        };
+
+        let config = load_with(&args, [root.path(); 5]);
+
+        assert_eq!(config.outputs_dir, root.join("flag-out"));
+        assert_eq!(config.models_dir, root.path());
+    }
+
+    #[test]
+    fn joins_paths_to_the_public_base_url() {
+        let public_base_url = |value: &str| {
+            let _env = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            std::env::set_var("GGML_PUBLIC_BASE_URL", value);
+            let base_url = public_base_url_from_env();
+            std::env::remove_var("GGML_PUBLIC_BASE_URL");
+            base_url
+        };
+        let root = TestDir::new();
+        let mut config = crate::tests::config(root.path());
+
+        assert_eq!(
+            config.public_url("/download/tiny.gguf"),
+            "/download/tiny.gguf"
+        );
+        for base_url in [
+            "https://models.example.com/ggml",
+            "https://models.example.com/ggml//",
+        ] {
+            config.public_base_url = public_base_url(base_url).unwrap();
+            assert_eq!(
+                config.public_url("/download/tiny.gguf"),
+                "https://models.example.com/ggml/download/tiny.gguf",
+                "{base_url}"
+            );
+        }
+        assert_eq!(public_base_url(""), Ok(None));
+        for invalid in [
+            "models.example.com",
+            "ftp://models.example.com",
+            "https://x.com/?a=b",
+        ] {
+            assert!(public_base_url(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! The llama.cpp script converting a checkpoint, and the arguments a request may add to it.
+//!
+//! llama.cpp refs rename the scripts and grow flags for models needing special handling, so
+//! the script names are configurable and a few flags are passed through from the request.
+
+use crate::OutputFormat;
+
+/// Flags a request may pass to the converter, each with the values it takes, none for a
+/// plain switch. Only flags changing how the model is read or written, never where.
+const ALLOWED_ARGS: &[(&str, &[&str])] = &[
+    ("--vocab-type", &["spm", "bpe", "hfft"]),
+    ("--pad-vocab", &[]),
+    ("--outtype", &["f32", "f16", "bf16", "q8_0", "auto"]),
+];
+
+/// Prefix of the variables forwarded to the converter, `GGML_CONVERT_ENV_HF_HOME=/data/hf`
+/// sets `HF_HOME`
+const ENV_PREFIX: &str = "GGML_CONVERT_ENV_";
+
+/// Parts of variable names whose values stay out of the logs
+const SECRET_NAMES: &[&str] = &["TOKEN", "KEY", "SECRET", "PASSWORD", "CREDENTIAL"];
+
+/// Variables set for the converter on top of the service's environment, sorted by name.
+/// Printed as `NAME=value`, with the values of the ones looking like secrets redacted.
+#[derive(Clone, Default)]
+pub struct ConverterEnv(Vec<(String, String)>);
+
+impl ConverterEnv {
+    /// The `GGML_CONVERT_ENV_*` variables
+    pub fn from_env() -> Self {
+        let mut vars: Vec<(String, String)> = std::env::vars()
+            .filter_map(|(var, value)| {
+                let name = var.strip_prefix(ENV_PREFIX)?;
+                (!name.is_empty()).then(|| (name.to_string(), value))
+            })
+            .collect();
+        vars.sort();
+        ConverterEnv(vars)
+    }
+
+    pub fn vars(&self) -> &[(String, String)] {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl std::fmt::Display for ConverterEnv {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let vars: Vec<String> = self
+            .0
+            .iter()
+            .map(|(name, value)| {
+                let upper = name.to_ascii_uppercase();
+                match SECRET_NAMES.iter().any(|secret| upper.contains(secret)) {
+                    true => format!("{name}=<redacted>"),
+                    false => format!("{name}={value}"),
+                }
+            })
+            .collect();
+        write!(f, "{}", vars.join(" "))
+    }
+}
+
+impl std::fmt::Debug for ConverterEnv {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ConverterEnv({self})")
+    }
+}
+
+/// Name of the converter script of the format in the checkouts, read from
+/// `GGML_CONVERTER_GGML` or `GGML_CONVERTER_GGUF`
+pub fn script_from_env(format: OutputFormat) -> Result<String, String> {
+    let var = match format {
+        OutputFormat::Ggml => "GGML_CONVERTER_GGML",
+        OutputFormat::Gguf => "GGML_CONVERTER_GGUF",
+    };
+    match std::env::var(var) {
+        Ok(script) if !script.is_empty() => {
+            // joined to the checkout, it must stay a file of it
+            let valid = script != ".."
+                && script != "."
+                && !script.contains(['/', '\\'])
+                && !script.starts_with('-');
+            match valid {
+                true => Ok(script),
+                false => Err(format!(
+                    "Invalid {var} '{script}', expected the file name of a script in the llama.cpp checkout"
+                )),
+            }
+        }
+        _ => Ok(format.converter_script().to_string()),
+    }
+}
+
+/// Check the `converter_args` of a request: allowed flags only, each followed by one of its
+/// values when it takes one, `--flag value` or `--flag=value`
+pub fn validate_args(args: &[String]) -> Result<(), String> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        let Some((_, values)) = ALLOWED_ARGS.iter().find(|(allowed, _)| *allowed == flag) else {
+            let allowed: Vec<&str> = ALLOWED_ARGS.iter().map(|(flag, _)| *flag).collect();
+            return Err(format!(
+                "Unsupported converter argument '{arg}', expected one of {}",
+                allowed.join(", ")
+            ));
+        };
+        match (values.is_empty(), inline) {
+            (true, None) => {}
+            (true, Some(_)) => return Err(format!("{flag} takes no value")),
+            (false, value) => {
+                let value = value.or_else(|| args.next().map(String::as_str));
+                if !value.is_some_and(|value| values.contains(&value)) {
+                    return Err(format!(
+                        "{flag} expects one of {}, got '{}'",
+                        values.join(", "),
+                        value.unwrap_or_default()
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A run of the converter: the script of the format and the arguments of the request
+#[derive(Debug, Clone, Copy)]
+pub struct Converter<'a> {
+    pub format: OutputFormat,
+    /// File name of the script in the llama.cpp checkout
+    pub script: &'a str,
+    /// Passed to the script ahead of the model directory
+    pub args: &'a [String],
+    /// Set for the script on top of the service's environment
+    pub env: &'a ConverterEnv,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn accepts_the_allowed_args_in_both_forms() {
+        for allowed in [
+            args(&["--pad-vocab"]),
+            args(&["--vocab-type", "bpe", "--outtype=f16"]),
+            args(&["--vocab-type=hfft", "--pad-vocab", "--outtype", "auto"]),
+        ] {
+            assert_eq!(validate_args(&allowed), Ok(()), "{allowed:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_other_args_and_values() {
+        for (refused, expected) in [
+            (
+                args(&["--outfile", "/etc/passwd"]),
+                "Unsupported converter argument '--outfile'",
+            ),
+            (
+                args(&["--vocab-type", "wordpiece"]),
+                "--vocab-type expects one of",
+            ),
+            (args(&["--outtype"]), "--outtype expects one of"),
+            (args(&["--pad-vocab=1"]), "--pad-vocab takes no value"),
+        ] {
+            let err = validate_args(&refused).unwrap_err();
+            assert!(err.starts_with(expected), "{refused:?}: {err}");
+        }
+    }
+}
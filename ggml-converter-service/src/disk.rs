@@ -0,0 +1,168 @@
+use crate::error::AppError;
+use std::{os::unix::fs::MetadataExt, path::Path};
+
+/// Default free space left over once a conversion is done
+pub const DEFAULT_MIN_FREE_SPACE_MB: u64 = 1024;
+
+const MB: u64 = 1024 * 1024;
+
+/// Margin kept on top of the estimated needs, read from `GGML_MIN_FREE_SPACE_MB`
+pub fn min_free_space() -> u64 {
+    std::env::var("GGML_MIN_FREE_SPACE_MB")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_MIN_FREE_SPACE_MB)
+        * MB
+}
+
+/// Largest repo downloaded for a conversion, from `GGML_MAX_MODEL_BYTES`. Unset, any size is
+/// taken as long as it fits on disk.
+pub fn max_model_size() -> Option<u64> {
+    std::env::var("GGML_MAX_MODEL_BYTES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+}
+
+/// Fail when the repo is larger than `max_size`. A repo of unknown size is let through.
+pub fn check_model_size(
+    model_name: &str,
+    size: Option<u64>,
+    max_size: Option<u64>,
+) -> Result<(), AppError> {
+    match (size, max_size) {
+        (Some(size), Some(max_size)) if size > max_size => Err(AppError::PayloadTooLarge(format!(
+            "'{model_name}' is {size} bytes, more than the {max_size} bytes allowed (GGML_MAX_MODEL_BYTES)"
+        ))),
+        (None, Some(_)) => {
+            tracing::warn!("Unknown size for '{model_name}', skipping the model size check");
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Bytes available to the service on the filesystem holding `path`
+pub fn available_space(path: &Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a valid C string and `stat` a properly sized out parameter
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Whether both paths live on the same filesystem, so they share its free space
+pub fn same_filesystem(a: &Path, b: &Path) -> std::io::Result<bool> {
+    Ok(std::fs::metadata(a)?.dev() == std::fs::metadata(b)?.dev())
+}
+
+/// Fail unless `available` bytes cover `required` ones plus the `margin`
+pub fn check_space(
+    path: &Path,
+    required: u64,
+    available: u64,
+    margin: u64,
+) -> Result<(), AppError> {
+    if available >= required.saturating_add(margin) {
+        return Ok(());
+    }
+    Err(AppError::InsufficientStorage(format!(
+        "Not enough free space in {}: {} MB needed (including a {} MB margin), {} MB available",
+        path.display(),
+        required.saturating_add(margin) / MB,
+        margin / MB,
+        available / MB
+    )))
+}
+
+/// Size of the model weights among the files of a repo.
+///
+/// Repos often ship the same weights in several formats, the conversion only reads one of
+/// them, so the largest format is taken.
+pub fn weights_size<'a>(files: impl IntoIterator<Item = (&'a str, u64)>) -> u64 {
+    let (mut safetensors, mut pytorch) = (0, 0);
+    for (name, size) in files {
+        if name.ends_with(".safetensors") {
+            safetensors += size;
+        } else if name.ends_with(".bin") || name.ends_with(".pth") || name.ends_with(".pt") {
+            pytorch += size;
+        }
+    }
+    safetensors.max(pytorch)
+}
+
+/// Names and sizes of the files under `dir`, recursively
+pub fn list_files(dir: &Path) -> std::io::Result<Vec<(String, u64)>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            files.extend(list_files(&entry.path())?);
+        } else {
+            files.push((
+                entry.file_name().to_string_lossy().into_owned(),
+                metadata.len(),
+            ));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+
+    #[test]
+    fn accepts_a_conversion_leaving_the_margin_free() {
+        let path = Path::new("/data/models");
+
+        assert!(check_space(path, 10 * MB, 12 * MB, 2 * MB).is_ok());
+        assert!(check_space(path, 10 * MB, 100 * MB, 2 * MB).is_ok());
+    }
+
+    #[test]
+    fn rejects_a_conversion_eating_into_the_margin() {
+        let err = check_space(Path::new("/data/models"), 10 * MB, 12 * MB - 1, 2 * MB).unwrap_err();
+
+        assert_eq!(err.status_code(), StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(
+            err.to_string(),
+            "Not enough free space in /data/models: 12 MB needed (including a 2 MB margin), 11 MB available"
+        );
+    }
+
+    #[test]
+    fn counts_the_largest_format_of_the_weights() {
+        let files = [
+            ("model-00001-of-00002.safetensors", 4 * MB),
+            ("model-00002-of-00002.safetensors", 3 * MB),
+            ("pytorch_model.bin", 8 * MB),
+            ("tokenizer.json", MB),
+        ];
+
+        assert_eq!(weights_size(files), 8 * MB);
+        assert_eq!(weights_size(files[..2].iter().copied()), 7 * MB);
+    }
+
+    #[test]
+    fn takes_a_model_up_to_the_max_size() {
+        assert!(check_model_size("acme/tiny", Some(10 * MB), Some(10 * MB)).is_ok());
+        assert!(check_model_size("acme/tiny", Some(10 * MB), None).is_ok());
+        // the size couldn't be told
+        assert!(check_model_size("acme/tiny", None, Some(10 * MB)).is_ok());
+
+        let err = check_model_size("acme/tiny", Some(10 * MB + 1), Some(10 * MB)).unwrap_err();
+
+        assert_eq!(err.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            err.to_string(),
+            "'acme/tiny' is 10485761 bytes, more than the 10485760 bytes allowed (GGML_MAX_MODEL_BYTES)"
+        );
+    }
+}
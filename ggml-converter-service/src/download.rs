@@ -0,0 +1,892 @@
+use crate::job::{DownloadProgress, FileProgress, JobContext, JobStore};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+use tokio::io::AsyncWriteExt;
+
+/// Where model repos are fetched from
+pub const HF_ENDPOINT: &str = "https://huggingface.co";
+
+/// Revision of the repos downloaded without one
+pub const DEFAULT_REVISION: &str = "main";
+
+/// Longest revision taken, a branch or tag name rarely comes close
+const MAX_REVISION_LEN: usize = 255;
+
+/// Attempts for each file before giving up on the HTTP download
+const MAX_ATTEMPTS: u32 = 3;
+
+type DownloadError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Entry of the repo tree returned by the Hugging Face API
+#[derive(Debug, Deserialize)]
+struct TreeEntry {
+    #[serde(rename = "type")]
+    kind: String,
+    path: String,
+    #[serde(default)]
+    size: u64,
+}
+
+/// The repo id of a Hugging Face model url, `None` for any other host
+pub fn hf_repo(url: &str) -> Option<&str> {
+    url.strip_prefix(HF_ENDPOINT)?
+        .strip_prefix('/')
+        .map(|repo| repo.trim_end_matches('/').trim_end_matches(".git"))
+        .filter(|repo| !repo.is_empty())
+}
+
+fn client_request(
+    client: &reqwest::Client,
+    url: &str,
+    token: Option<&str>,
+) -> reqwest::RequestBuilder {
+    let request = client.get(url);
+    match token {
+        Some(token) => request.bearer_auth(token),
+        None => request,
+    }
+}
+
+/// Check a revision is a branch, tag or commit name that can't pass for an option of git or
+/// leave its place in a url: letters, digits, `.`, `_`, `-` and `/` between names
+pub fn validate_revision(revision: &str) -> Result<(), String> {
+    let valid = !revision.is_empty()
+        && revision.len() <= MAX_REVISION_LEN
+        && revision
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '/'))
+        && !revision.starts_with('-')
+        && revision
+            .split('/')
+            .all(|part| !part.is_empty() && !part.starts_with('.') && !part.ends_with(".lock"))
+        && !revision.contains("..");
+    match valid {
+        true => Ok(()),
+        false => Err(format!(
+            "Invalid revision '{revision}', expected a branch, tag or commit of the repo"
+        )),
+    }
+}
+
+/// The revision as a single segment of an API url, `refs/pr/1` being `refs%2Fpr%2F1`
+fn url_revision(revision: &str) -> String {
+    revision.replace('/', "%2F")
+}
+
+/// What git LFS leaves in place of a file whose content it didn't fetch
+const LFS_POINTER_PREFIX: &[u8] = b"version https://git-lfs.github.com/spec/";
+
+/// Check that a downloaded repo holds an actual model: a `config.json` (or the `params.json`
+/// of the original LLaMA checkpoints), every shard its index lists, and weights rather than
+/// git LFS pointers, which a clone without LFS checks out just as successfully
+pub fn verify_model_dir(dir: &Path) -> Result<(), String> {
+    if !dir.join("config.json").is_file() && !dir.join("params.json").is_file() {
+        return Err(format!("{} has no config.json", dir.display()));
+    }
+    let shards = shards(dir)?;
+    let missing: Vec<&str> = shards
+        .iter()
+        .filter(|shard| !is_fetched(&dir.join(shard)))
+        .map(String::as_str)
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "{} of the {} shards of the checkpoint are missing: {}",
+            missing.len(),
+            shards.len(),
+            missing.join(", ")
+        ));
+    }
+    let pointers = lfs_pointers(dir).map_err(|err| format!("Reading {}: {err}", dir.display()))?;
+    match pointers.first() {
+        None => Ok(()),
+        Some(pointer) => Err(format!(
+            "git LFS pointers were checked out instead of the files' content ({} of them, e.g. {})",
+            pointers.len(),
+            pointer.display()
+        )),
+    }
+}
+
+/// Extensions of weights saved with Python's pickle, which runs code when loaded
+const PICKLE_EXTENSIONS: &[&str] = &["bin", "pt", "pth", "pkl", "ckpt"];
+
+/// Check that the repo has safetensors weights for the converter to load, with
+/// `pickle_allowed` telling whether pickle weights next to them are fine: the converter to GGUF
+/// loads the safetensors ones whenever there are some, convert.py may pick the pickle ones
+pub fn verify_safetensors(dir: &Path, pickle_allowed: bool) -> Result<(), String> {
+    let mut safetensors = 0;
+    let mut pickles = Vec::new();
+    let entries =
+        std::fs::read_dir(dir).map_err(|err| format!("Reading {}: {err}", dir.display()))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("safetensors") => safetensors += 1,
+            Some(extension) if PICKLE_EXTENSIONS.contains(&extension) => {
+                pickles.push(entry.file_name().to_string_lossy().into_owned())
+            }
+            _ => {}
+        }
+    }
+    pickles.sort();
+    if safetensors == 0 {
+        return Err(format!(
+            "require_safetensors is set but the repo has no .safetensors weights, only pickle ones ({}) which aren't loaded for it",
+            pickles.join(", ")
+        ));
+    }
+    if !pickle_allowed && !pickles.is_empty() {
+        return Err(format!(
+            "require_safetensors is set and the repo also has pickle weights ({}) the GGML converter could load, convert to GGUF instead",
+            pickles.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// Index of the weights of a sharded checkpoint, `model.safetensors.index.json` or
+/// `pytorch_model.bin.index.json`
+#[derive(Debug, Deserialize)]
+struct ShardIndex {
+    /// Shard holding each tensor
+    weight_map: HashMap<String, String>,
+}
+
+/// Files of the shards listed by the indexes at the root of the repo, none for a checkpoint in
+/// a single file
+pub fn shards(dir: &Path) -> Result<BTreeSet<String>, String> {
+    let mut shards = BTreeSet::new();
+    let entries =
+        std::fs::read_dir(dir).map_err(|err| format!("Reading {}: {err}", dir.display()))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.to_string_lossy().ends_with(".index.json") {
+            continue;
+        }
+        let index = std::fs::read(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|index| {
+                serde_json::from_slice::<ShardIndex>(&index).map_err(|err| err.to_string())
+            })
+            .map_err(|err| format!("Invalid shard index {}: {err}", path.display()))?;
+        for shard in index.weight_map.into_values() {
+            // joined to the repo directory, it must stay inside it
+            let safe = Path::new(&shard)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+            if !safe {
+                return Err(format!("Unexpected shard '{shard}' in {}", path.display()));
+            }
+            shards.insert(shard);
+        }
+    }
+    Ok(shards)
+}
+
+/// The file is there with its content, not as a git LFS pointer
+fn is_fetched(path: &Path) -> bool {
+    use std::io::Read;
+
+    let mut head = Vec::with_capacity(LFS_POINTER_PREFIX.len());
+    std::fs::File::open(path)
+        .and_then(|file| {
+            file.take(LFS_POINTER_PREFIX.len() as u64)
+                .read_to_end(&mut head)
+        })
+        .is_ok_and(|_| head != LFS_POINTER_PREFIX)
+}
+
+/// Files under `dir` that are git LFS pointers, skipping the `.git` directory
+fn lfs_pointers(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    use std::io::Read;
+
+    let mut pointers = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            if entry.file_name() != ".git" {
+                pointers.extend(lfs_pointers(&entry.path())?);
+            }
+        // pointers are a few lines of text, there is no need to open the real weights
+        } else if metadata.len() < 1024 {
+            let mut head = Vec::with_capacity(LFS_POINTER_PREFIX.len());
+            std::fs::File::open(entry.path())?
+                .take(LFS_POINTER_PREFIX.len() as u64)
+                .read_to_end(&mut head)?;
+            if head == LFS_POINTER_PREFIX {
+                pointers.push(entry.path());
+            }
+        }
+    }
+    Ok(pointers)
+}
+
+/// Outcome of looking a repo up before downloading it
+#[derive(Debug)]
+pub enum RepoCheck {
+    /// The repo exists, with the total size of its files when the API gave it
+    Exists(Option<u64>),
+    /// The repo doesn't exist, or is private and the token can't see it
+    NotFound,
+    /// The lookup itself failed, the repo may well exist
+    Unknown(String),
+}
+
+/// The part of the Hugging Face API's model info giving the files of the repo
+#[derive(Debug, Deserialize)]
+struct RepoInfo {
+    /// Commit the revision points to
+    #[serde(default)]
+    sha: Option<String>,
+    #[serde(default)]
+    siblings: Vec<Sibling>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Sibling {
+    /// Only listed when the blobs are asked for
+    #[serde(default)]
+    size: Option<u64>,
+}
+
+/// Look the revision of the repo up on the Hugging Face API, which is much cheaper than a
+/// doomed download
+pub async fn check_repo(repo: &str, revision: &str, token: Option<&str>) -> RepoCheck {
+    check_repo_at(HF_ENDPOINT, repo, revision, token).await
+}
+
+/// `check_repo` against the API at `endpoint`
+async fn check_repo_at(
+    endpoint: &str,
+    repo: &str,
+    revision: &str,
+    token: Option<&str>,
+) -> RepoCheck {
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+    {
+        Ok(client) => client,
+        Err(err) => return RepoCheck::Unknown(err.to_string()),
+    };
+    let url = format!(
+        "{endpoint}/api/models/{repo}/revision/{}?blobs=true",
+        url_revision(revision)
+    );
+    match client_request(&client, &url, token).send().await {
+        Ok(response) if response.status().is_success() => {
+            let size = response.json::<RepoInfo>().await.ok().and_then(|info| {
+                info.siblings
+                    .iter()
+                    .map(|sibling| sibling.size)
+                    .sum::<Option<u64>>()
+            });
+            RepoCheck::Exists(size)
+        }
+        // Hugging Face answers 401 rather than 404 for repos it won't reveal
+        Ok(response)
+            if matches!(
+                response.status(),
+                reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::UNAUTHORIZED
+            ) =>
+        {
+            RepoCheck::NotFound
+        }
+        Ok(response) => RepoCheck::Unknown(format!("{url} answered {}", response.status())),
+        Err(err) => RepoCheck::Unknown(err.to_string()),
+    }
+}
+
+/// The commit the revision of the repo points to
+async fn commit_of(
+    client: &reqwest::Client,
+    repo: &str,
+    revision: &str,
+    token: Option<&str>,
+) -> Result<String, DownloadError> {
+    let url = format!(
+        "{HF_ENDPOINT}/api/models/{repo}/revision/{}",
+        url_revision(revision)
+    );
+    let info: RepoInfo = client_request(client, &url, token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    info.sha
+        .ok_or_else(|| format!("No commit for the revision '{revision}' of {repo}").into())
+}
+
+/// List the files of the revision of the repo, with their sizes
+async fn list_files(
+    client: &reqwest::Client,
+    repo: &str,
+    revision: &str,
+    token: Option<&str>,
+) -> Result<Vec<TreeEntry>, DownloadError> {
+    let url = format!(
+        "{HF_ENDPOINT}/api/models/{repo}/tree/{}?recursive=true",
+        url_revision(revision)
+    );
+    let entries: Vec<TreeEntry> = client_request(client, &url, token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let mut files = Vec::new();
+    for entry in entries.into_iter().filter(|entry| entry.kind == "file") {
+        // the paths end up joined to a local directory, they must stay inside it
+        let safe = Path::new(&entry.path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if !safe {
+            return Err(format!("Unexpected file path '{}' in {repo}", entry.path).into());
+        }
+        files.push(entry);
+    }
+    Ok(files)
+}
+
+/// Names and sizes of the files of the revision of the Hugging Face repo
+pub async fn repo_files(
+    repo: &str,
+    revision: &str,
+    token: Option<&str>,
+) -> Result<Vec<(String, u64)>, DownloadError> {
+    let files = list_files(&reqwest::Client::new(), repo, revision, token).await?;
+    Ok(files
+        .into_iter()
+        .map(|file| (file.path, file.size))
+        .collect())
+}
+
+/// File of a downloaded repo keeping the ETag of each of its files, by path
+const ETAGS_FILE: &str = ".etags.json";
+
+/// The ETags kept in the directory, none when it has no readable file of them
+fn read_etags(dir: &Path) -> HashMap<String, String> {
+    std::fs::read(dir.join(ETAGS_FILE))
+        .ok()
+        .and_then(|etags| serde_json::from_slice(&etags).ok())
+        .unwrap_or_default()
+}
+
+async fn write_etags(dir: &Path, etags: &HashMap<String, String>) -> std::io::Result<()> {
+    tokio::fs::write(dir.join(ETAGS_FILE), serde_json::to_vec(etags)?).await
+}
+
+/// The repo was downloaded over HTTP with the ETags of its files, so it can be checked for
+/// changes cheaply
+pub fn has_etags(dir: &Path) -> bool {
+    dir.join(ETAGS_FILE).is_file()
+}
+
+/// File of a repo downloaded over HTTP keeping the commit its files are from
+const COMMIT_FILE: &str = ".commit";
+
+/// The commit a repo downloaded over HTTP is at, `None` for other repos
+pub fn downloaded_commit(dir: &Path) -> Option<String> {
+    std::fs::read_to_string(dir.join(COMMIT_FILE))
+        .ok()
+        .map(|commit| commit.trim().to_string())
+        .filter(|commit| !commit.is_empty())
+}
+
+/// The `.partial` directory a repo is downloaded to before being renamed to `dir`
+fn partial_dir(dir: &Path) -> PathBuf {
+    let mut partial_dir = dir.as_os_str().to_os_string();
+    partial_dir.push(".partial");
+    PathBuf::from(partial_dir)
+}
+
+/// Download every file of the revision of the Hugging Face repo into `dir`, reporting progress
+/// on the job.
+///
+/// The files are first written to a sibling `.partial` directory, which is only renamed to
+/// `dir` once complete. Files already there with the expected size are kept, so an
+/// interrupted download resumes where it stopped.
+pub async fn download_repo(
+    repo: &str,
+    revision: &str,
+    dir: &Path,
+    token: Option<&str>,
+    jobs: &JobStore,
+    ctx: &JobContext,
+) -> Result<(), DownloadError> {
+    let client = reqwest::Client::new();
+    // the files are listed and fetched at the commit, should the branch move meanwhile
+    let commit = commit_of(&client, repo, revision, token).await?;
+    let files = list_files(&client, repo, &commit, token).await?;
+
+    let partial_dir = partial_dir(dir);
+    fetch_files(
+        &client,
+        &format!("{HF_ENDPOINT}/{repo}/resolve/{commit}"),
+        &commit,
+        &files,
+        &partial_dir,
+        token,
+        jobs,
+        ctx,
+    )
+    .await?;
+    tokio::fs::rename(&partial_dir, dir).await?;
+    Ok(())
+}
+
+/// Bring a repo downloaded by `download_repo` up to date with the hub: only the files whose
+/// ETag changed are downloaded again, the others are answered `304 Not Modified`.
+///
+/// The repo is left as is when the hub can't be reached. Should the update fail past that,
+/// `dir` is gone and its `.partial` directory is resumed by the next download.
+pub async fn update_repo(
+    repo: &str,
+    revision: &str,
+    dir: &Path,
+    token: Option<&str>,
+    jobs: &JobStore,
+    ctx: &JobContext,
+) -> Result<(), DownloadError> {
+    let client = reqwest::Client::new();
+    let commit = commit_of(&client, repo, revision, token).await?;
+    if downloaded_commit(dir).as_deref() == Some(commit.as_str()) {
+        ctx.events.log(format!("{repo} is still at {commit}"));
+        return Ok(());
+    }
+    let files = list_files(&client, repo, &commit, token).await?;
+
+    let partial_dir = partial_dir(dir);
+    tokio::fs::rename(dir, &partial_dir).await?;
+    fetch_files(
+        &client,
+        &format!("{HF_ENDPOINT}/{repo}/resolve/{commit}"),
+        &commit,
+        &files,
+        &partial_dir,
+        token,
+        jobs,
+        ctx,
+    )
+    .await?;
+    tokio::fs::rename(&partial_dir, dir).await?;
+    Ok(())
+}
+
+/// Download the files of the commit of the repo missing from `dir` or changed since, with the
+/// ETag of each, then record the commit. The files are under `files_url`,
+/// `<endpoint>/<repo>/resolve/<commit>`.
+#[allow(clippy::too_many_arguments)] // the download and the job it reports to
+async fn fetch_files(
+    client: &reqwest::Client,
+    files_url: &str,
+    commit: &str,
+    files: &[TreeEntry],
+    dir: &Path,
+    token: Option<&str>,
+    jobs: &JobStore,
+    ctx: &JobContext,
+) -> Result<(), DownloadError> {
+    tokio::fs::create_dir_all(dir).await?;
+    let mut etags = read_etags(dir);
+    // files removed from the repo since it was downloaded
+    let removed: Vec<String> = etags
+        .keys()
+        .filter(|path| !files.iter().any(|file| &file.path == *path))
+        .cloned()
+        .collect();
+    for path in removed {
+        etags.remove(&path);
+        match tokio::fs::remove_file(dir.join(&path)).await {
+            Ok(()) => ctx.events.log(format!("{path} was removed from the repo")),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    let progress = files
+        .iter()
+        .map(|file| FileProgress {
+            name: file.path.clone(),
+            downloaded: 0,
+            total: file.size,
+        })
+        .collect();
+    jobs.update_progress(ctx.id, |p| *p = DownloadProgress::new(progress));
+
+    for (index, file) in files.iter().enumerate() {
+        let path = dir.join(&file.path);
+        let complete = std::fs::metadata(&path).is_ok_and(|metadata| metadata.len() == file.size);
+        // without an ETag, a file of the expected size is taken as it is
+        let etag = match (complete, etags.get(&file.path)) {
+            (true, Some(etag)) => Some(etag.clone()),
+            (true, None) => {
+                ctx.events.log(format!("{} already downloaded", file.path));
+                jobs.update_progress(ctx.id, |p| p.set_downloaded(index, file.size));
+                continue;
+            }
+            (false, _) => None,
+        };
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let url = format!("{files_url}/{}", file.path);
+        let mut attempt = 1;
+        loop {
+            let fetched = download_file(
+                client,
+                &url,
+                token,
+                etag.as_deref(),
+                &path,
+                index,
+                jobs,
+                ctx,
+            )
+            .await;
+            if ctx.token.is_cancelled() {
+                return Err("Download cancelled".into());
+            }
+            // keep only the message, the boxed error can't be held across the sleep below
+            let err = match fetched {
+                Ok(Fetched::NotModified) => {
+                    ctx.events.log(format!("{} is unchanged", file.path));
+                    jobs.update_progress(ctx.id, |p| p.set_downloaded(index, file.size));
+                    break;
+                }
+                Ok(Fetched::Downloaded { size, etag }) if size == file.size => {
+                    match etag {
+                        Some(etag) => etags.insert(file.path.clone(), etag),
+                        None => etags.remove(&file.path),
+                    };
+                    write_etags(dir, &etags).await?;
+                    break;
+                }
+                Ok(Fetched::Downloaded { size, .. }) => {
+                    format!("{} is {size} bytes, {} were expected", file.path, file.size)
+                }
+                Err(err) => err.to_string(),
+            };
+            if attempt >= MAX_ATTEMPTS {
+                return Err(format!(
+                    "Downloading {} failed after {attempt} attempts: {err}",
+                    file.path
+                )
+                .into());
+            }
+            ctx.events.log(format!(
+                "({attempt}) Downloading {} failed: {err}",
+                file.path
+            ));
+            tokio::time::sleep(Duration::from_secs(2u64.pow(attempt))).await;
+            attempt += 1;
+        }
+    }
+    tokio::fs::write(dir.join(COMMIT_FILE), commit).await?;
+    Ok(())
+}
+
+/// Outcome of requesting a file
+enum Fetched {
+    /// The file was written, with its size and the ETag the server gave it
+    Downloaded { size: u64, etag: Option<String> },
+    /// The file still has the ETag it was requested with and was left as is
+    NotModified,
+}
+
+/// Stream a single file to `path`, unless the server answers that it still has `etag`
+#[allow(clippy::too_many_arguments)] // the request and the job it reports to
+async fn download_file(
+    client: &reqwest::Client,
+    url: &str,
+    token: Option<&str>,
+    etag: Option<&str>,
+    path: &Path,
+    index: usize,
+    jobs: &JobStore,
+    ctx: &JobContext,
+) -> Result<Fetched, DownloadError> {
+    let mut request = client_request(client, url, token);
+    if let Some(etag) = etag {
+        request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+    }
+    let response = request.send().await?;
+    if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+        return Ok(Fetched::NotModified);
+    }
+    let mut response = response.error_for_status()?;
+    let etag = response
+        .headers()
+        .get(reqwest::header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .map(str::to_string);
+    let mut out = tokio::fs::File::create(path).await?;
+    let mut downloaded = 0;
+    jobs.update_progress(ctx.id, |p| p.set_downloaded(index, 0));
+
+    loop {
+        let chunk = tokio::select! {
+            chunk = response.chunk() => chunk?,
+            _ = ctx.token.cancelled() => return Err("Download cancelled".into()),
+        };
+        let chunk = match chunk {
+            Some(chunk) => chunk,
+            None => break,
+        };
+        out.write_all(&chunk).await?;
+        downloaded += chunk.len() as u64;
+        jobs.update_progress(ctx.id, |p| p.set_downloaded(index, downloaded));
+    }
+    out.flush().await?;
+
+    Ok(Fetched::Downloaded {
+        size: downloaded,
+        etag,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::serve_router;
+    use axum::{
+        extract::Path as UrlPath,
+        http::{HeaderMap, StatusCode},
+        response::{Headers, IntoResponse, Response},
+        routing::get,
+        Json, Router,
+    };
+    use serde_json::json;
+
+    /// A Hugging Face API knowing `acme/tiny`, the gated `acme/gated` for the `hf_ok` token
+    /// only, and failing for `acme/broken`
+    fn hub() -> String {
+        async fn revision(
+            UrlPath((name, revision)): UrlPath<(String, String)>,
+            headers: HeaderMap,
+        ) -> Response {
+            let authorized = headers
+                .get("authorization")
+                .is_some_and(|value| value == "Bearer hf_ok");
+            let siblings = json!({"sha": "abc123", "siblings": [{"size": 10}, {"size": 32}]});
+            match (name.as_str(), revision.as_str()) {
+                ("tiny", "main") => Json(siblings).into_response(),
+                ("gated", "main") if authorized => Json(siblings).into_response(),
+                ("gated", _) => StatusCode::UNAUTHORIZED.into_response(),
+                ("broken", _) => StatusCode::BAD_GATEWAY.into_response(),
+                _ => StatusCode::NOT_FOUND.into_response(),
+            }
+        }
+        serve_router(
+            Router::new().route("/api/models/acme/:name/revision/:revision", get(revision)),
+        )
+    }
+
+    #[tokio::test]
+    async fn finds_a_repo_with_the_size_of_its_files() {
+        let hub = hub();
+
+        let check = check_repo_at(&hub, "acme/tiny", "main", None).await;
+
+        assert!(matches!(check, RepoCheck::Exists(Some(42))), "{check:?}");
+    }
+
+    #[tokio::test]
+    async fn doesnt_find_a_missing_repo_or_revision() {
+        let hub = hub();
+
+        for (repo, revision) in [("acme/missing", "main"), ("acme/tiny", "v2")] {
+            let check = check_repo_at(&hub, repo, revision, None).await;
+
+            assert!(
+                matches!(check, RepoCheck::NotFound),
+                "{repo}@{revision}: {check:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn sees_a_gated_repo_with_a_token_only() {
+        let hub = hub();
+
+        let without = check_repo_at(&hub, "acme/gated", "main", None).await;
+        let with = check_repo_at(&hub, "acme/gated", "main", Some("hf_ok")).await;
+
+        assert!(matches!(without, RepoCheck::NotFound), "{without:?}");
+        assert!(matches!(with, RepoCheck::Exists(_)), "{with:?}");
+    }
+
+    #[tokio::test]
+    async fn doesnt_know_when_the_lookup_fails() {
+        let hub = hub();
+
+        let check = check_repo_at(&hub, "acme/broken", "main", None).await;
+
+        assert!(
+            matches!(&check, RepoCheck::Unknown(err) if err.ends_with("answered 502 Bad Gateway")),
+            "{check:?}"
+        );
+    }
+
+    #[test]
+    fn finds_a_shard_missing_from_the_index() {
+        let dir = crate::tests::TestDir::new();
+        std::fs::write(dir.join("config.json"), "{}").unwrap();
+        std::fs::write(
+            dir.join("model.safetensors.index.json"),
+            json!({"weight_map": {
+                "embed_tokens.weight": "model-00001-of-00002.safetensors",
+                "lm_head.weight": "model-00002-of-00002.safetensors",
+            }})
+            .to_string(),
+        )
+        .unwrap();
+        std::fs::write(dir.join("model-00001-of-00002.safetensors"), "weights").unwrap();
+
+        let err = verify_model_dir(dir.path()).unwrap_err();
+
+        assert_eq!(
+            err,
+            "1 of the 2 shards of the checkpoint are missing: model-00002-of-00002.safetensors"
+        );
+        std::fs::write(dir.join("model-00002-of-00002.safetensors"), "weights").unwrap();
+        assert_eq!(verify_model_dir(dir.path()), Ok(()));
+    }
+
+    #[test]
+    fn requires_safetensors_weights() {
+        let safetensors = crate::tests::TestDir::new();
+        std::fs::write(safetensors.join("model.safetensors"), "weights").unwrap();
+        let pickle = crate::tests::TestDir::new();
+        std::fs::write(pickle.join("pytorch_model.bin"), "weights").unwrap();
+
+        assert_eq!(verify_safetensors(safetensors.path(), false), Ok(()));
+        let err = verify_safetensors(pickle.path(), true).unwrap_err();
+        assert!(
+            err.contains("no .safetensors weights, only pickle ones (pytorch_model.bin)"),
+            "{err}"
+        );
+        // pickle weights next to the safetensors ones only matter when they could be loaded
+        std::fs::write(safetensors.join("pytorch_model.bin"), "weights").unwrap();
+        assert_eq!(verify_safetensors(safetensors.path(), true), Ok(()));
+        assert!(verify_safetensors(safetensors.path(), false).is_err());
+    }
+
+    #[tokio::test]
+    async fn skips_the_files_the_hub_answers_304_for() {
+        use std::sync::{Arc, Mutex};
+
+        // each file with its content and ETag on the hub
+        let hub_files = [
+            ("config.json", "XX", "\"config-v1\""),
+            ("tokenizer.json", "[]", "\"tokenizer-v2\""),
+            ("model.safetensors", "weights", "\"weights-v1\""),
+        ];
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let hub = serve_router(Router::new().route(
+            "/acme/tiny/resolve/abc123/:file",
+            get({
+                let requests = requests.clone();
+                move |UrlPath(file): UrlPath<String>, headers: HeaderMap| async move {
+                    let sent = headers
+                        .get("if-none-match")
+                        .map(|etag| etag.to_str().unwrap().to_string());
+                    requests.lock().unwrap().push((file.clone(), sent.clone()));
+                    let (_, content, etag) = hub_files
+                        .into_iter()
+                        .find(|(name, ..)| *name == file)
+                        .unwrap();
+                    match sent.as_deref() == Some(etag) {
+                        true => StatusCode::NOT_MODIFIED.into_response(),
+                        false => (Headers([("etag", etag)]), content).into_response(),
+                    }
+                }
+            }),
+        ));
+        let dir = crate::tests::TestDir::new();
+        std::fs::write(dir.join("config.json"), "{}").unwrap();
+        std::fs::write(dir.join("tokenizer.json"), "{}").unwrap();
+        let etags = HashMap::from(
+            [
+                ("config.json", "\"config-v1\""),
+                ("tokenizer.json", "\"tokenizer-v1\""),
+            ]
+            .map(|(path, etag)| (path.to_string(), etag.to_string())),
+        );
+        write_etags(dir.path(), &etags).await.unwrap();
+        let files = hub_files.map(|(path, content, _)| TreeEntry {
+            kind: "file".to_string(),
+            path: path.to_string(),
+            size: content.len() as u64,
+        });
+
+        fetch_files(
+            &reqwest::Client::new(),
+            &format!("{hub}/acme/tiny/resolve/abc123"),
+            "abc123",
+            &files,
+            dir.path(),
+            None,
+            &JobStore::default(),
+            &crate::tests::job_context(),
+        )
+        .await
+        .unwrap();
+
+        let etag = |etag: &str| Some(etag.to_string());
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [
+                ("config.json".to_string(), etag("\"config-v1\"")),
+                ("tokenizer.json".to_string(), etag("\"tokenizer-v1\"")),
+                ("model.safetensors".to_string(), None),
+            ]
+        );
+        // unchanged on the hub, it wasn't written again
+        assert_eq!(
+            std::fs::read_to_string(dir.join("config.json")).unwrap(),
+            "{}"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("tokenizer.json")).unwrap(),
+            "[]"
+        );
+        assert_eq!(
+            read_etags(dir.path()),
+            hub_files
+                .map(|(path, _, etag)| (path.to_string(), etag.to_string()))
+                .into()
+        );
+        assert_eq!(downloaded_commit(dir.path()).as_deref(), Some("abc123"));
+    }
+
+    #[test]
+    fn validates_a_revision() {
+        for valid in ["main", "v1.0", "refs/pr/1", "a1b2c3d", "release_2-rc"] {
+            assert_eq!(validate_revision(valid), Ok(()), "{valid}");
+        }
+        for invalid in [
+            "",
+            "--upload-pack=x",
+            "../main",
+            "a..b",
+            "refs//pr",
+            "refs/.hidden",
+            "main.lock",
+            "main branch",
+            "main?x=1",
+        ] {
+            assert!(validate_revision(invalid).is_err(), "{invalid}");
+        }
+        assert_eq!(url_revision("refs/pr/1"), "refs%2Fpr%2F1");
+    }
+}
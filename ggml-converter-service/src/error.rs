@@ -0,0 +1,458 @@
+use axum::{
+    body,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{header, Request, StatusCode};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Keep at most this much of a failed subprocess' stderr
+const MAX_STDERR_BYTES: usize = 8 * 1024;
+
+/// A subprocess of the pipeline exited unsuccessfully
+#[derive(Debug)]
+pub struct SubprocessError {
+    pub stage: &'static str,
+    pub status: std::process::ExitStatus,
+    /// The end of the captured stderr, where the actual error usually is
+    pub stderr: String,
+}
+
+impl SubprocessError {
+    pub fn new(stage: &'static str, output: &std::process::Output) -> Self {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let mut start = stderr.len().saturating_sub(MAX_STDERR_BYTES);
+        while !stderr.is_char_boundary(start) {
+            start += 1;
+        }
+        SubprocessError {
+            stage,
+            status: output.status,
+            stderr: stderr[start..].to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for SubprocessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} failed ({})", self.stage, self.status)
+    }
+}
+
+impl std::error::Error for SubprocessError {}
+
+impl SubprocessError {
+    /// Whether the process was killed with SIGKILL. The pipeline only stops its subprocesses
+    /// by dropping them on a timeout or a cancellation, which don't get this far, so this is
+    /// the kernel killing a process of a machine out of memory.
+    pub fn was_killed(&self) -> bool {
+        use std::os::unix::process::ExitStatusExt;
+
+        self.status.signal() == Some(libc::SIGKILL)
+    }
+}
+
+/// The peak resident memory of the largest subprocess waited for so far, in bytes
+fn peak_subprocess_memory() -> Option<u64> {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: `usage` is a properly sized out parameter
+    if unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) } != 0 {
+        return None;
+    }
+    // in kilobytes on Linux
+    u64::try_from(usage.ru_maxrss)
+        .ok()
+        .filter(|&kb| kb > 0)
+        .map(|kb| kb * 1024)
+}
+
+/// Stable code of an error response, for clients to branch on rather than on the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    BadRequest,
+    /// A conversion request with problems, listed in `details.problems`
+    InvalidRequest,
+    Unauthorized,
+    Forbidden,
+    ModelNotFound,
+    JobNotFound,
+    BatchNotFound,
+    FileNotFound,
+    /// No such route
+    NotFound,
+    MethodNotAllowed,
+    Conflict,
+    /// The job is already over, `details.state` says how it ended
+    JobFinished,
+    Gone,
+    LengthRequired,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    /// Retry after `details.retry_after_secs`
+    RateLimited,
+    /// A subprocess of the pipeline failed, `details` has its stage, exit status and stderr
+    SubprocessFailed,
+    OutOfMemory,
+    Internal,
+    Unavailable,
+    TimedOut,
+    InsufficientStorage,
+}
+
+impl ErrorCode {
+    /// Every code, for the API docs
+    pub const ALL: &'static [ErrorCode] = &[
+        ErrorCode::BadRequest,
+        ErrorCode::InvalidRequest,
+        ErrorCode::Unauthorized,
+        ErrorCode::Forbidden,
+        ErrorCode::ModelNotFound,
+        ErrorCode::JobNotFound,
+        ErrorCode::BatchNotFound,
+        ErrorCode::FileNotFound,
+        ErrorCode::NotFound,
+        ErrorCode::MethodNotAllowed,
+        ErrorCode::Conflict,
+        ErrorCode::JobFinished,
+        ErrorCode::Gone,
+        ErrorCode::LengthRequired,
+        ErrorCode::PayloadTooLarge,
+        ErrorCode::UnsupportedMediaType,
+        ErrorCode::RangeNotSatisfiable,
+        ErrorCode::RateLimited,
+        ErrorCode::SubprocessFailed,
+        ErrorCode::OutOfMemory,
+        ErrorCode::Internal,
+        ErrorCode::Unavailable,
+        ErrorCode::TimedOut,
+        ErrorCode::InsufficientStorage,
+    ];
+
+    /// The code of an error response that didn't come with one, e.g. a rejected extractor
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => ErrorCode::Unauthorized,
+            StatusCode::FORBIDDEN => ErrorCode::Forbidden,
+            StatusCode::NOT_FOUND => ErrorCode::NotFound,
+            StatusCode::METHOD_NOT_ALLOWED => ErrorCode::MethodNotAllowed,
+            StatusCode::CONFLICT => ErrorCode::Conflict,
+            StatusCode::GONE => ErrorCode::Gone,
+            StatusCode::LENGTH_REQUIRED => ErrorCode::LengthRequired,
+            StatusCode::PAYLOAD_TOO_LARGE => ErrorCode::PayloadTooLarge,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => ErrorCode::UnsupportedMediaType,
+            StatusCode::RANGE_NOT_SATISFIABLE => ErrorCode::RangeNotSatisfiable,
+            StatusCode::TOO_MANY_REQUESTS => ErrorCode::RateLimited,
+            StatusCode::SERVICE_UNAVAILABLE => ErrorCode::Unavailable,
+            StatusCode::GATEWAY_TIMEOUT => ErrorCode::TimedOut,
+            StatusCode::INSUFFICIENT_STORAGE => ErrorCode::InsufficientStorage,
+            status if status.is_client_error() => ErrorCode::BadRequest,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+/// An error response, `{"code": ..., "message": ..., "details": {...}, "request_id": ...}`,
+/// `details` only when there are some and `request_id` within a request
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub code: ErrorCode,
+    pub message: String,
+    pub details: Option<Value>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: ErrorCode, message: impl Into<String>) -> Self {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    pub fn body(&self) -> Value {
+        let mut body = json!({ "code": self.code, "message": self.message });
+        if let Some(details) = &self.details {
+            body["details"] = details.clone();
+        }
+        if let Some(request_id) = crate::request_id::current() {
+            body["request_id"] = request_id.into();
+        }
+        body
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = body::boxed(body::Full::from(self.body().to_string()));
+        Response::builder()
+            .status(self.status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .unwrap()
+    }
+}
+
+/// Errors of the service, each maps to a status code and a JSON body
+#[derive(Debug)]
+pub enum AppError {
+    Io(std::io::Error),
+    Subprocess(SubprocessError),
+    ModelNotFound(String),
+    JobNotFound(String),
+    BatchNotFound(String),
+    Unauthorized(String),
+    Forbidden(String),
+    FileNotFound(String),
+    Conflict(String),
+    BadRequest(String),
+    LengthRequired(String),
+    PayloadTooLarge(String),
+    Gone(String),
+    TimedOut(String),
+    InsufficientStorage(String),
+    Unavailable(String),
+    /// A subprocess killed by the kernel for want of memory
+    OutOfMemory(String),
+    Internal(String),
+}
+
+impl AppError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::Io(_)
+            | AppError::Subprocess(_)
+            | AppError::OutOfMemory(_)
+            | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::ModelNotFound(_)
+            | AppError::JobNotFound(_)
+            | AppError::BatchNotFound(_)
+            | AppError::FileNotFound(_) => StatusCode::NOT_FOUND,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::LengthRequired(_) => StatusCode::LENGTH_REQUIRED,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Gone(_) => StatusCode::GONE,
+            AppError::TimedOut(_) => StatusCode::GATEWAY_TIMEOUT,
+            AppError::InsufficientStorage(_) => StatusCode::INSUFFICIENT_STORAGE,
+            AppError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            AppError::Io(_) | AppError::Internal(_) => ErrorCode::Internal,
+            AppError::Subprocess(_) => ErrorCode::SubprocessFailed,
+            AppError::OutOfMemory(_) => ErrorCode::OutOfMemory,
+            AppError::ModelNotFound(_) => ErrorCode::ModelNotFound,
+            AppError::JobNotFound(_) => ErrorCode::JobNotFound,
+            AppError::BatchNotFound(_) => ErrorCode::BatchNotFound,
+            AppError::FileNotFound(_) => ErrorCode::FileNotFound,
+            AppError::BadRequest(_) => ErrorCode::BadRequest,
+            AppError::Conflict(_) => ErrorCode::Conflict,
+            AppError::LengthRequired(_) => ErrorCode::LengthRequired,
+            AppError::PayloadTooLarge(_) => ErrorCode::PayloadTooLarge,
+            AppError::Unauthorized(_) => ErrorCode::Unauthorized,
+            AppError::Forbidden(_) => ErrorCode::Forbidden,
+            AppError::Gone(_) => ErrorCode::Gone,
+            AppError::TimedOut(_) => ErrorCode::TimedOut,
+            AppError::InsufficientStorage(_) => ErrorCode::InsufficientStorage,
+            AppError::Unavailable(_) => ErrorCode::Unavailable,
+        }
+    }
+
+    /// The captured stderr, for subprocess failures
+    pub fn stderr(&self) -> Option<String> {
+        match self {
+            AppError::Subprocess(err) => Some(err.stderr.clone()),
+            _ => None,
+        }
+    }
+
+    /// What the message leaves out, for subprocess failures
+    fn details(&self) -> Option<Value> {
+        match self {
+            AppError::Subprocess(err) => Some(json!({
+                "stage": err.stage,
+                "exit_status": err.status.to_string(),
+                "stderr": err.stderr,
+            })),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::Io(err) => write!(f, "I/O error: {err}"),
+            AppError::Subprocess(err) => write!(f, "{err}"),
+            AppError::ModelNotFound(name) => write!(f, "Model '{name}' not found"),
+            AppError::JobNotFound(id) => write!(f, "Job '{id}' not found"),
+            AppError::BatchNotFound(id) => write!(f, "Batch '{id}' not found"),
+            AppError::FileNotFound(name) => write!(f, "File '{name}' not found"),
+            AppError::BadRequest(msg)
+            | AppError::Conflict(msg)
+            | AppError::LengthRequired(msg)
+            | AppError::PayloadTooLarge(msg)
+            | AppError::Unauthorized(msg)
+            | AppError::Forbidden(msg)
+            | AppError::Gone(msg)
+            | AppError::TimedOut(msg)
+            | AppError::InsufficientStorage(msg)
+            | AppError::Unavailable(msg)
+            | AppError::OutOfMemory(msg)
+            | AppError::Internal(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<std::io::Error> for AppError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::TimedOut => AppError::TimedOut(err.to_string()),
+            _ => AppError::Io(err),
+        }
+    }
+}
+
+impl From<SubprocessError> for AppError {
+    fn from(err: SubprocessError) -> Self {
+        if !err.was_killed() {
+            return AppError::Subprocess(err);
+        }
+        let peak = match peak_subprocess_memory() {
+            Some(bytes) => format!(
+                " The largest subprocess peaked at {} MB of memory.",
+                bytes / (1024 * 1024)
+            ),
+            None => String::new(),
+        };
+        AppError::OutOfMemory(format!(
+            "{} was killed (SIGKILL), most likely by the kernel running out of memory. Give the service more RAM or convert a smaller model.{peak}",
+            err.stage
+        ))
+    }
+}
+
+impl From<Box<dyn std::error::Error>> for AppError {
+    fn from(err: Box<dyn std::error::Error>) -> Self {
+        let err = match err.downcast::<AppError>() {
+            Ok(err) => return *err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<SubprocessError>() {
+            Ok(err) => return AppError::from(*err),
+            Err(err) => err,
+        };
+        match err.downcast::<std::io::Error>() {
+            Ok(err) => AppError::from(*err),
+            Err(err) => AppError::Internal(err.to_string()),
+        }
+    }
+}
+
+impl From<AppError> for ApiError {
+    fn from(err: AppError) -> Self {
+        ApiError {
+            status: err.status_code(),
+            code: err.code(),
+            message: err.to_string(),
+            details: err.details(),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        ApiError::from(self).into_response()
+    }
+}
+
+/// Give the error responses not built here, e.g. those of rejected extractors or unknown
+/// routes, the JSON shape of the others, their text becoming the message
+pub async fn standardize<B>(req: Request<B>, next: Next<B>) -> Response {
+    let response = next.run(req).await;
+    let status = response.status();
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    if !(status.is_client_error() || status.is_server_error()) || is_json {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let message = match hyper::body::to_bytes(body).await {
+        Ok(text) if !text.trim_ascii().is_empty() => {
+            String::from_utf8_lossy(text.trim_ascii()).into_owned()
+        }
+        _ => status.canonical_reason().unwrap_or("Error").to_string(),
+    };
+    let error = ApiError::new(status, ErrorCode::from_status(status), message);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(
+        parts,
+        body::boxed(body::Full::from(error.body().to_string())),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::mock::exited;
+
+    #[test]
+    fn answers_a_failed_subprocess_with_its_stderr() {
+        let output = exited(1, "Traceback (most recent call last):\nKeyError: 'lm_head'");
+        let err = AppError::from(SubprocessError::new("Conversion", &output));
+
+        let err = ApiError::from(err);
+
+        assert_eq!(err.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            err.body(),
+            json!({
+                "code": "SUBPROCESS_FAILED",
+                "message": "Conversion failed (exit status: 1)",
+                "details": {
+                    "stage": "Conversion",
+                    "exit_status": "exit status: 1",
+                    "stderr": "Traceback (most recent call last):\nKeyError: 'lm_head'",
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_the_end_of_a_long_stderr() {
+        // a multi-byte character straddles the cut
+        let stderr = format!(
+            "{}é{}KeyError: 'lm_head'",
+            "a".repeat(10_000),
+            "b".repeat(8_172)
+        );
+        let err = SubprocessError::new("Conversion", &exited(1, &stderr));
+
+        assert!(err.stderr.len() <= MAX_STDERR_BYTES);
+        assert!(err.stderr.starts_with('b'));
+        assert!(err.stderr.ends_with("KeyError: 'lm_head'"));
+    }
+}
@@ -0,0 +1,44 @@
+use crate::error::AppError;
+use async_trait::async_trait;
+use axum::{
+    body::{Bytes, HttpBody},
+    extract::{FromRequest, RequestParts},
+    BoxError,
+};
+use serde::de::DeserializeOwned;
+
+/// Like axum's `Json`, but a body that can't be deserialized is a `400` whose JSON error says
+/// what is wrong with it, rather than a terse `422`
+#[derive(Debug)]
+pub struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    B: HttpBody + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let body = Bytes::from_request(req).await.map_err(|err| {
+            AppError::BadRequest(format!("Failed to read the request body: {err}"))
+        })?;
+        if body.is_empty() {
+            return Err(AppError::BadRequest(
+                "The request body is empty, a JSON object is expected".to_string(),
+            ));
+        }
+        serde_json::from_slice(&body).map(JsonBody).map_err(|err| {
+            let reason = match err.classify() {
+                serde_json::error::Category::Syntax | serde_json::error::Category::Eof => {
+                    "The request body is not valid JSON"
+                }
+                _ => "Invalid request body",
+            };
+            AppError::BadRequest(format!("{reason}: {err}"))
+        })
+    }
+}
@@ -0,0 +1,93 @@
+//! Offloading the importance matrix computation to a GPU. The quantize tool of llama.cpp has
+//! no GPU support, only `imatrix` evaluates the model and gains from it.
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// The `gpu` of a conversion request
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GpuOffload {
+    /// Layers of the model evaluated on the GPU, `--n-gpu-layers`
+    pub layers: u32,
+    /// Device holding the layers that aren't split across GPUs, `--main-gpu`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub main_gpu: Option<u32>,
+}
+
+impl GpuOffload {
+    pub fn validate(&self) -> Result<(), String> {
+        match self.layers {
+            0 => Err("gpu.layers must be positive".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Arguments of the llama.cpp tools for this offload
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec!["--n-gpu-layers".to_string(), self.layers.to_string()];
+        if let Some(main_gpu) = self.main_gpu {
+            args.extend(["--main-gpu".to_string(), main_gpu.to_string()]);
+        }
+        args
+    }
+}
+
+/// Whether the tool was built with a GPU backend. llama.cpp only lists `--n-gpu-layers` in
+/// its usage then, a CPU build would ignore the flag.
+pub async fn supports_offload(tool: &Path) -> bool {
+    let output = match tokio::process::Command::new(tool)
+        .arg("--help")
+        .output()
+        .await
+    {
+        Ok(output) => output,
+        Err(_) => return false,
+    };
+    // the usage goes to stdout or stderr depending on the version
+    [&output.stdout, &output.stderr]
+        .iter()
+        .any(|usage| String::from_utf8_lossy(usage).contains("--n-gpu-layers"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{script, TestDir};
+
+    #[test]
+    fn passes_the_layers_and_the_main_gpu() {
+        let gpu = GpuOffload {
+            layers: 35,
+            main_gpu: None,
+        };
+        assert_eq!(gpu.args(), ["--n-gpu-layers", "35"]);
+
+        let gpu = GpuOffload {
+            main_gpu: Some(1),
+            ..gpu
+        };
+        assert_eq!(gpu.args(), ["--n-gpu-layers", "35", "--main-gpu", "1"]);
+        assert!(GpuOffload {
+            layers: 0,
+            main_gpu: None
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn tells_a_gpu_build_from_its_usage() {
+        let dir = TestDir::new();
+        let gpu = dir.join("gpu-imatrix");
+        script(
+            &gpu,
+            "echo '  -ngl N, --n-gpu-layers N  number of layers to store in VRAM' >&2",
+        );
+        let cpu = dir.join("cpu-imatrix");
+        script(&cpu, "echo '  -t N, --threads N  number of threads'");
+
+        assert!(supports_offload(&gpu).await);
+        assert!(!supports_offload(&cpu).await);
+        assert!(!supports_offload(&dir.join("missing")).await);
+    }
+}
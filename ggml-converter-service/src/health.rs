@@ -0,0 +1,103 @@
+use crate::config::Config;
+use axum::{extract::Extension, http::StatusCode, response::Json};
+use serde_json::{json, Value};
+use std::path::Path;
+
+/// Programs the pipeline runs
+pub const REQUIRED_TOOLS: [&str; 3] = ["git", "python3", "make"];
+
+/// Liveness: the service is up and answering
+pub async fn health() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+/// Readiness: everything a conversion needs is in place
+pub async fn ready(Extension(config): Extension<Config>) -> (StatusCode, Json<Value>) {
+    readiness(&config, is_on_path)
+}
+
+/// The readiness of the service, the tools looked up with the checker
+fn readiness(config: &Config, is_available: impl Fn(&str) -> bool) -> (StatusCode, Json<Value>) {
+    let mut missing = missing_tools(is_available);
+    for dir in [&config.outputs_dir, &config.models_dir] {
+        if !is_writable(dir) {
+            missing.push(format!("writable {}", dir.display()));
+        }
+    }
+
+    match missing.is_empty() {
+        true => (StatusCode::OK, Json(json!({ "status": "ok" }))),
+        false => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "status": "unavailable", "missing": missing })),
+        ),
+    }
+}
+
+/// The required tools the checker can't find
+pub fn missing_tools(is_available: impl Fn(&str) -> bool) -> Vec<String> {
+    REQUIRED_TOOLS
+        .into_iter()
+        .filter(|tool| !is_available(tool))
+        .map(String::from)
+        .collect()
+}
+
+/// Whether an executable file with that name is in one of the `PATH` directories
+pub fn is_on_path(name: &str) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path).any(|dir| {
+        std::fs::metadata(dir.join(name))
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    })
+}
+
+/// Whether files can be created in the directory, creating it if needed
+fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(".ready-probe");
+    let writable = std::fs::create_dir_all(dir).is_ok() && std::fs::write(&probe, b"").is_ok();
+    let _ = std::fs::remove_file(&probe);
+    writable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{config, TestDir};
+
+    #[test]
+    fn reports_the_tools_the_checker_cant_find() {
+        assert_eq!(missing_tools(|tool| tool != "make"), ["make"]);
+        assert_eq!(missing_tools(|_| false), ["git", "python3", "make"]);
+        assert!(missing_tools(|_| true).is_empty());
+    }
+
+    #[test]
+    fn is_unavailable_with_a_tool_missing() {
+        let root = TestDir::new();
+        let config = config(root.path());
+
+        let (status, Json(body)) = readiness(&config, |tool| tool != "python3");
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            body,
+            json!({ "status": "unavailable", "missing": ["python3"] })
+        );
+        let (status, Json(body)) = readiness(&config, |_| true);
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "status": "ok" }));
+    }
+
+    #[test]
+    fn finds_a_directory_under_a_file_unwritable() {
+        let root = TestDir::new();
+        std::fs::write(root.join("file"), "").unwrap();
+
+        assert!(is_writable(&root.join("outputs")));
+        assert!(!root.join("outputs/.ready-probe").exists());
+        assert!(!is_writable(&root.join("file/outputs")));
+    }
+}
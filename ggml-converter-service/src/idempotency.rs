@@ -0,0 +1,121 @@
+//! The `Idempotency-Key` of conversion requests: a client retrying a request it doesn't know
+//! the outcome of sends the same key, and gets the job created the first time back rather than
+//! a second job, whatever the parameters.
+
+use crate::job::JobId;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+pub const HEADER: &str = "idempotency-key";
+
+/// Default time a key is remembered for
+pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Longest key taken
+const MAX_KEY_LEN: usize = 255;
+
+/// The job created for each key, forgotten once the key expires.
+///
+/// Cloning is cheap, all clones share the same keys.
+#[derive(Debug, Clone)]
+pub struct IdempotencyKeys {
+    ttl: Duration,
+    jobs: Arc<Mutex<HashMap<String, (Instant, JobId)>>>,
+}
+
+impl IdempotencyKeys {
+    pub fn new(ttl: Duration) -> Self {
+        IdempotencyKeys {
+            ttl,
+            jobs: Arc::default(),
+        }
+    }
+
+    /// Time to live from `GGML_IDEMPOTENCY_TTL_SECS`, a day by default
+    pub fn from_env() -> Self {
+        let ttl = std::env::var("GGML_IDEMPOTENCY_TTL_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TTL);
+        IdempotencyKeys::new(ttl)
+    }
+
+    /// The job created for the key, if it hasn't expired
+    pub fn get(&self, key: &str) -> Option<JobId> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.forget_expired(&mut jobs);
+        jobs.get(key).map(|(_, job_id)| *job_id)
+    }
+
+    /// The job created for the key, or else the one `create` creates, remembered for the key.
+    /// Requests with the same key arriving together get a single job.
+    pub fn get_or_create<E>(
+        &self,
+        key: &str,
+        create: impl FnOnce() -> Result<JobId, E>,
+    ) -> Result<JobId, E> {
+        let mut jobs = self.jobs.lock().unwrap();
+        self.forget_expired(&mut jobs);
+        if let Some((_, job_id)) = jobs.get(key) {
+            return Ok(*job_id);
+        }
+        let job_id = create()?;
+        jobs.insert(key.to_string(), (Instant::now(), job_id));
+        Ok(job_id)
+    }
+
+    fn forget_expired(&self, jobs: &mut HashMap<String, (Instant, JobId)>) {
+        jobs.retain(|_, (created_at, _)| created_at.elapsed() < self.ttl);
+    }
+}
+
+/// Check a key is printable ASCII of at most 255 characters, like the UUIDs clients send
+pub fn validate_key(key: &str) -> Result<(), String> {
+    let valid =
+        !key.is_empty() && key.len() <= MAX_KEY_LEN && key.chars().all(|c| c.is_ascii_graphic());
+    match valid {
+        true => Ok(()),
+        false => Err(format!(
+            "Invalid Idempotency-Key, expected 1 to {MAX_KEY_LEN} printable ASCII characters"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn creates_a_job_once_per_key_until_it_expires() {
+        let keys = IdempotencyKeys::new(Duration::from_secs(60));
+        let job_id = JobId::new();
+
+        assert_eq!(
+            keys.get_or_create("key", || Ok::<_, ()>(job_id)),
+            Ok(job_id)
+        );
+        assert_eq!(
+            keys.get_or_create("key", || -> Result<JobId, ()> { panic!("created twice") }),
+            Ok(job_id)
+        );
+        assert_eq!(keys.get("key"), Some(job_id));
+
+        let expired = IdempotencyKeys::new(Duration::ZERO);
+        expired
+            .get_or_create("key", || Ok::<_, ()>(job_id))
+            .unwrap();
+        assert_eq!(expired.get("key"), None);
+    }
+
+    #[test]
+    fn validates_the_key() {
+        assert!(validate_key("4f1d2c3e-retry").is_ok());
+        for invalid in ["", "with space", "caf\u{e9}", &"k".repeat(256)] {
+            assert!(validate_key(invalid).is_err(), "{invalid}");
+        }
+    }
+}
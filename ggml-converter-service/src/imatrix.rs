@@ -0,0 +1,110 @@
+//! Importance matrices, computed by llama.cpp's `imatrix` tool over calibration text so the
+//! k-quants keep the weights that matter most precise.
+//!
+//! The calibration datasets and the matrices are kept under `<models dir>/imatrix/`, a matrix
+//! is computed once per model, llama.cpp ref and dataset.
+
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+
+/// The `imatrix` of a conversion request
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ImatrixConfig {
+    /// Calibration text: an http(s) url, or the name of a file of the imatrix directory
+    pub dataset: String,
+    /// Process only this many chunks of the dataset, all of them when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<u32>,
+}
+
+impl ImatrixConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.chunks == Some(0) {
+            return Err("imatrix.chunks must be positive".to_string());
+        }
+        if let Some(url) = self.url() {
+            return match url.has_host() {
+                true => Ok(()),
+                false => Err(format!("Invalid imatrix.dataset url '{}'", self.dataset)),
+            };
+        }
+        // only files set aside for calibration, not any file the service can read
+        let plain = !self.dataset.is_empty()
+            && !self.dataset.contains("..")
+            && !self.dataset.contains(['/', '\\']);
+        match plain {
+            true => Ok(()),
+            false => Err(format!(
+                "Invalid imatrix.dataset '{}', expected an http(s) url or the name of a file of the imatrix directory",
+                self.dataset
+            )),
+        }
+    }
+
+    fn url(&self) -> Option<reqwest::Url> {
+        reqwest::Url::parse(&self.dataset)
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https"))
+    }
+
+    /// Where the matrix of the model computed with this dataset is cached
+    pub fn matrix_path(&self, dir: &Path, repo_name: &str, llama_cpp_ref: &str) -> PathBuf {
+        let key = format!("{}\n{:?}", self.dataset, self.chunks);
+        dir.join(format!(
+            "{repo_name}-{llama_cpp_ref}-{}.imatrix",
+            short_hash(&key)
+        ))
+    }
+
+    /// The calibration text on disk, downloaded into `dir` the first time it is needed
+    pub async fn dataset_path(&self, dir: &Path) -> Result<PathBuf, String> {
+        let Some(url) = self.url() else {
+            let path = dir.join(&self.dataset);
+            return match path.is_file() {
+                true => Ok(path),
+                false => Err(format!("Calibration dataset {} not found", path.display())),
+            };
+        };
+
+        let path = dir.join(format!("dataset-{}.txt", short_hash(&self.dataset)));
+        if path.is_file() {
+            return Ok(path);
+        }
+        let download = |err: String| format!("Downloading the calibration dataset {url}: {err}");
+        let mut response = reqwest::get(url.clone())
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|err| download(err.to_string()))?;
+        // written aside then moved, an interrupted download is never taken for the dataset
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = tokio::fs::File::create(&tmp)
+            .await
+            .map_err(|err| download(err.to_string()))?;
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .map_err(|err| download(err.to_string()))?
+        {
+            file.write_all(&chunk)
+                .await
+                .map_err(|err| download(err.to_string()))?;
+        }
+        file.flush()
+            .await
+            .map_err(|err| download(err.to_string()))?;
+        tokio::fs::rename(&tmp, &path)
+            .await
+            .map_err(|err| download(err.to_string()))?;
+        Ok(path)
+    }
+}
+
+/// A few hex digits of the SHA-256 of the value, enough to tell datasets apart in file names
+fn short_hash(value: &str) -> String {
+    openssl::sha::sha256(value.as_bytes())[..6]
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
@@ -0,0 +1,1190 @@
+use crate::{
+    persistence::{JobPersistence, JobRecord},
+    ModelInfo, OutputFormat, QuantInfo,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    fs::File,
+    hash::{BuildHasher, Hasher},
+    io::Read,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+/// Identifier of a conversion job, rendered as a random (version 4) UUID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct JobId(u128);
+
+impl JobId {
+    pub fn new() -> Self {
+        let mut bytes = random_bytes();
+
+        // version 4, variant RFC 4122
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+        JobId(u128::from_be_bytes(bytes))
+    }
+}
+
+/// Reads 16 bytes from the OS random source. Where `/dev/urandom` can't be
+/// read, the bytes are hashed from the clock and a counter instead: those are
+/// unique within the process but not unpredictable.
+fn random_bytes() -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    if File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .is_ok()
+    {
+        return bytes;
+    }
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    // std seeds the `RandomState` keys once per thread and then increments
+    // them, so they only keep the two halves apart
+    for chunk in bytes.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        hasher.write_u64(count);
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+    bytes
+}
+
+impl Default for JobId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Display for JobId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hex = format!("{:032x}", self.0);
+        write!(
+            f,
+            "{}-{}-{}-{}-{}",
+            &hex[0..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..32]
+        )
+    }
+}
+
+impl std::str::FromStr for JobId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.replace('-', "");
+        if hex.len() != 32 || s.len() != 36 {
+            return Err(format!("Invalid job id '{s}'"));
+        }
+        u128::from_str_radix(&hex, 16)
+            .map(JobId)
+            .map_err(|_| format!("Invalid job id '{s}'"))
+    }
+}
+
+impl Serialize for JobId {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for JobId {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum JobState {
+    Queued,
+    Downloading,
+    Converting,
+    Quantizing,
+    Done,
+    Failed,
+    Cancelled,
+    /// Stopped by the service shutting down
+    Interrupted,
+}
+
+impl JobState {
+    /// Whether the job has reached a state it will never leave
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            JobState::Done | JobState::Failed | JobState::Cancelled | JobState::Interrupted
+        )
+    }
+}
+
+/// Seconds since the unix epoch
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: JobId,
+    /// Order the jobs were created in, the queued ones start in that order
+    pub seq: u64,
+    pub model_info: ModelInfo,
+    pub state: JobState,
+    pub started_at: u64,
+    pub updated_at: u64,
+    pub error: Option<String>,
+    /// Tail of the stderr of the subprocess that made the job fail
+    pub stderr: Option<String>,
+    pub download_urls: Vec<String>,
+    /// Progress of the model download, when it goes over HTTP
+    pub progress: Option<DownloadProgress>,
+    /// Percentage of the tensors converted, when the converter prints its progress
+    pub progress_pct: Option<u8>,
+    /// Architecture of the model, read from its config once downloaded
+    pub architecture: Option<String>,
+    /// Commit of the repo the model was downloaded from, once downloaded
+    pub commit: Option<String>,
+    /// Time spent in each stage, once done
+    pub timings: Option<Timings>,
+    /// Total size of the outputs, once done
+    pub size_bytes: Option<u64>,
+    /// Outcome of each target, once done. Some may have failed unless `fail_fast` was set.
+    pub quant_statuses: Vec<QuantStatus>,
+    /// Time the job fails at if it is still running, set once it gets a conversion slot
+    pub deadline_at: Option<u64>,
+    pub cancel_token: CancellationToken,
+    pub events: JobEvents,
+}
+
+impl Job {
+    /// Create a new job in the `Queued` state
+    pub fn new(model_info: ModelInfo) -> Self {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+
+        let now = now_secs();
+        Job {
+            id: JobId::new(),
+            seq: SEQ.fetch_add(1, Ordering::Relaxed),
+            model_info,
+            state: JobState::Queued,
+            started_at: now,
+            updated_at: now,
+            error: None,
+            stderr: None,
+            download_urls: Vec::new(),
+            progress: None,
+            progress_pct: None,
+            architecture: None,
+            commit: None,
+            timings: None,
+            size_bytes: None,
+            quant_statuses: Vec::new(),
+            deadline_at: None,
+            cancel_token: CancellationToken::new(),
+            events: JobEvents::default(),
+        }
+    }
+
+    /// Handle given to the pipeline running this job
+    pub fn context(&self) -> JobContext {
+        JobContext {
+            id: self.id,
+            token: self.cancel_token.clone(),
+            events: self.events.clone(),
+        }
+    }
+}
+
+/// Bytes downloaded so far for each file of the model repo
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DownloadProgress {
+    pub downloaded: u64,
+    pub total: u64,
+    pub files: Vec<FileProgress>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FileProgress {
+    pub name: String,
+    pub downloaded: u64,
+    pub total: u64,
+}
+
+impl DownloadProgress {
+    pub fn new(files: Vec<FileProgress>) -> Self {
+        DownloadProgress {
+            downloaded: files.iter().map(|file| file.downloaded).sum(),
+            total: files.iter().map(|file| file.total).sum(),
+            files,
+        }
+    }
+
+    /// Set how much of the `index`th file is on disk
+    pub fn set_downloaded(&mut self, index: usize, downloaded: u64) {
+        if let Some(file) = self.files.get_mut(index) {
+            self.downloaded = self.downloaded - file.downloaded + downloaded;
+            file.downloaded = downloaded;
+        }
+    }
+}
+
+/// Seconds spent in each stage of a conversion. Stages that didn't run, e.g. when the outputs
+/// were reused, are at zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub struct Timings {
+    pub download_secs: f64,
+    pub build_secs: f64,
+    pub convert_secs: f64,
+    /// Including the computation of the importance matrix
+    pub quantize_secs: f64,
+    /// Loading the outputs in llama.cpp, for requests asking to `verify` them
+    #[serde(default)]
+    pub verify_secs: f64,
+    /// From the moment the job got a conversion slot
+    pub total_secs: f64,
+}
+
+/// What the pipeline of a job needs to report progress and observe cancellation
+#[derive(Debug, Clone)]
+pub struct JobContext {
+    pub id: JobId,
+    pub token: CancellationToken,
+    pub events: JobEvents,
+}
+
+/// An entry of the live log of a job
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobEvent {
+    /// A line printed by one of the subprocesses
+    Log(String),
+    /// The job finished, carries the download urls
+    Done(Vec<String>),
+    /// The job failed or was cancelled, carries the reason
+    Error(String),
+}
+
+impl JobEvent {
+    pub fn is_terminal(&self) -> bool {
+        !matches!(self, JobEvent::Log(_))
+    }
+}
+
+/// Keep at most this many events around for clients connecting late
+const MAX_BUFFERED_EVENTS: usize = 10_000;
+
+/// Broadcast channel of the events of a job, with a replay buffer
+#[derive(Debug, Clone)]
+pub struct JobEvents {
+    sender: broadcast::Sender<JobEvent>,
+    history: Arc<Mutex<VecDeque<JobEvent>>>,
+}
+
+impl Default for JobEvents {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(1024);
+        JobEvents {
+            sender,
+            history: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+}
+
+impl JobEvents {
+    pub fn publish(&self, event: JobEvent) {
+        let mut history = self.history.lock().unwrap();
+        if history.back().is_some_and(JobEvent::is_terminal) {
+            return;
+        }
+        if history.len() == MAX_BUFFERED_EVENTS {
+            history.pop_front();
+        }
+        history.push_back(event.clone());
+        // nobody listening is fine, the history keeps the event
+        let _ = self.sender.send(event);
+    }
+
+    pub fn log(&self, line: impl Into<String>) {
+        self.publish(JobEvent::Log(line.into()))
+    }
+
+    /// Return the events published so far along with a receiver for the following ones
+    pub fn subscribe(&self) -> (Vec<JobEvent>, broadcast::Receiver<JobEvent>) {
+        // holding the history lock guarantees no event slips between the two
+        let history = self.history.lock().unwrap();
+        (history.iter().cloned().collect(), self.sender.subscribe())
+    }
+}
+
+/// An output of a conversion, labeled with its format and quant
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Output {
+    pub format: OutputFormat,
+    pub quant: QuantInfo,
+    pub download_url: String,
+    /// The converted model as is, for `NoQuant`
+    #[serde(default)]
+    pub unquantized: bool,
+}
+
+/// Whether a target of a conversion produced its output, `Done` or `Failed`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QuantStatus {
+    pub format: OutputFormat,
+    pub quant: QuantInfo,
+    pub state: JobState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl QuantStatus {
+    /// A `Done` status for each target of the conversion
+    pub fn all_done(model_info: &ModelInfo) -> Vec<QuantStatus> {
+        model_info
+            .targets()
+            .into_iter()
+            .map(|(format, quant)| QuantStatus {
+                format,
+                quant,
+                state: JobState::Done,
+                error: None,
+            })
+            .collect()
+    }
+
+    pub fn is_for(&self, format: OutputFormat, quant: &QuantInfo) -> bool {
+        self.format == format && &self.quant == quant
+    }
+}
+
+/// The state of a target of the job, the job's own when it has no status of its own
+pub fn target_state(
+    state: JobState,
+    quant_statuses: &[QuantStatus],
+    format: OutputFormat,
+    quant: &QuantInfo,
+) -> JobState {
+    quant_statuses
+        .iter()
+        .find(|status| status.is_for(format, quant))
+        .map_or(state, |status| status.state)
+}
+
+/// The download urls labeled with the targets they were made for, the failed targets aside.
+/// Empty when they don't match up.
+pub fn outputs(
+    model_info: &ModelInfo,
+    quant_statuses: &[QuantStatus],
+    download_urls: &[String],
+) -> Vec<Output> {
+    let targets: Vec<(OutputFormat, QuantInfo)> = model_info
+        .targets()
+        .into_iter()
+        .filter(|(format, quant)| {
+            target_state(JobState::Done, quant_statuses, *format, quant) == JobState::Done
+        })
+        .collect();
+    if targets.len() != download_urls.len() {
+        return Vec::new();
+    }
+    targets
+        .into_iter()
+        .zip(download_urls)
+        .map(|((format, quant), download_url)| Output {
+            format,
+            unquantized: !quant.is_quantized(),
+            quant,
+            download_url: download_url.clone(),
+        })
+        .collect()
+}
+
+/// Public view of a job, returned by `GET /jobs/{id}`
+#[derive(Debug, Serialize)]
+pub struct JobStatus {
+    pub model: String,
+    pub quant: String,
+    pub state: JobState,
+    pub started_at: u64,
+    pub updated_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+    /// Set when a single quantization was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub download_url: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub download_urls: Vec<String>,
+    /// The download urls with their format and quant
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<Output>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<DownloadProgress>,
+    /// Unset while the progress of the conversion is unknown
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress_pct: Option<u8>,
+    /// Architecture of the model, unset until it is downloaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub architecture: Option<String>,
+    /// Revision of the repo requested, its default branch when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// Commit the revision resolved to, unset until the model is downloaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// Set once the job is done
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timings: Option<Timings>,
+    /// Total size of the outputs, in bytes, set once the job is done
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_bytes: Option<u64>,
+    /// Outcome of each target, set once the job is done
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub quant_statuses: Vec<QuantStatus>,
+    /// Time the job fails at if still running, set once it got a conversion slot
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deadline_at: Option<u64>,
+    /// Seconds left before the deadline, while the job runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remaining_secs: Option<u64>,
+    /// Queued jobs ahead of this one, `0` when it is the next to run. Unset once it left the
+    /// queue.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_position: Option<u32>,
+}
+
+impl From<&Job> for JobStatus {
+    fn from(job: &Job) -> Self {
+        JobStatus {
+            model: job.model_info.name.to_string(),
+            quant: job.model_info.quant_label(),
+            state: job.state,
+            started_at: job.started_at,
+            updated_at: job.updated_at,
+            error: job.error.clone(),
+            stderr: job.stderr.clone(),
+            download_url: match job.download_urls.as_slice() {
+                [download_url] => Some(download_url.clone()),
+                _ => None,
+            },
+            download_urls: job.download_urls.clone(),
+            outputs: outputs(&job.model_info, &job.quant_statuses, &job.download_urls),
+            progress: job.progress.clone(),
+            progress_pct: job.progress_pct,
+            architecture: job.architecture.clone(),
+            revision: job.model_info.revision.clone(),
+            commit: job.commit.clone(),
+            timings: job.timings,
+            size_bytes: job.size_bytes,
+            quant_statuses: job.quant_statuses.clone(),
+            deadline_at: job.deadline_at,
+            remaining_secs: job
+                .deadline_at
+                .filter(|_| !job.state.is_finished())
+                .map(|deadline_at| deadline_at.saturating_sub(now_secs())),
+            queue_position: None,
+        }
+    }
+}
+
+impl JobStatus {
+    /// The state on a single line, for scripts, e.g. `Converting 42%` or `Queued, 2 ahead`
+    pub fn line(&self) -> String {
+        let state = format!("{:?}", self.state);
+        match self.state {
+            JobState::Queued => match self.queue_position {
+                Some(ahead) if ahead > 0 => format!("{state}, {ahead} ahead"),
+                _ => state,
+            },
+            JobState::Downloading => match &self.progress {
+                Some(progress) if progress.total > 0 => {
+                    format!("{state} {}%", progress.downloaded * 100 / progress.total)
+                }
+                _ => state,
+            },
+            JobState::Converting | JobState::Quantizing => match self.progress_pct {
+                Some(progress_pct) => format!("{state} {progress_pct}%"),
+                None => state,
+            },
+            JobState::Done => format!("{state} {}", self.download_urls.join(" ")),
+            JobState::Failed | JobState::Cancelled | JobState::Interrupted => match &self.error {
+                Some(error) => format!("{state}: {error}"),
+                None => state,
+            },
+        }
+    }
+}
+
+/// Entry of the `GET /jobs` listing.
+///
+/// Only the output file name is exposed, never the full path on the server.
+#[derive(Debug, Serialize)]
+pub struct JobSummary {
+    pub id: JobId,
+    pub model: String,
+    pub quant: String,
+    pub state: JobState,
+    pub started_at: u64,
+    pub updated_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub output_files: Vec<String>,
+}
+
+impl From<&Job> for JobSummary {
+    fn from(job: &Job) -> Self {
+        JobSummary {
+            id: job.id,
+            model: job.model_info.name.to_string(),
+            quant: job.model_info.quant_label(),
+            state: job.state,
+            started_at: job.started_at,
+            updated_at: job.updated_at,
+            error: job.error.clone(),
+            output_files: job
+                .download_urls
+                .iter()
+                .filter_map(|url| {
+                    // signed and presigned urls carry their signature in the query
+                    let path = url.split('?').next().unwrap_or_default();
+                    std::path::Path::new(path)
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Shared registry of the conversion jobs known to the service.
+///
+/// Cloning is cheap, all clones refer to the same jobs.
+#[derive(Debug, Clone, Default)]
+pub struct JobStore {
+    jobs: Arc<Mutex<HashMap<JobId, Job>>>,
+    persistence: Option<Arc<Persisted>>,
+    /// Jobs kept at most, the least recently updated finished ones are evicted beyond it
+    max_jobs: Option<usize>,
+}
+
+/// The persistence of a store, written to outside of the jobs lock
+#[derive(Debug)]
+struct Persisted {
+    backend: Box<dyn JobPersistence>,
+    /// Snapshot of the jobs taken under the lock and not written yet, always the latest one
+    pending: Mutex<Option<Vec<JobRecord>>>,
+    /// Held while writing, so a slow write never overtakes a later one
+    writing: Mutex<()>,
+}
+
+impl Persisted {
+    /// Write the pending snapshot, if another write didn't take it already
+    fn write(&self) {
+        let _writing = self.writing.lock().unwrap();
+        let pending = self.pending.lock().unwrap().take();
+        if let Some(records) = pending {
+            if let Err(err) = self.backend.save(&records) {
+                tracing::error!("Failed to persist jobs: {err}");
+            }
+        }
+    }
+}
+
+/// Default number of jobs kept at most
+pub const DEFAULT_MAX_JOBS: usize = 10_000;
+
+/// Why a job wasn't added to the store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NotInserted {
+    /// The same conversion is still running, its id is to be used instead
+    Running(JobId),
+    /// Another unfinished job writes a file the conversion would write too
+    OutputTaken { job_id: JobId, filename: String },
+}
+
+fn running(jobs: &HashMap<JobId, Job>, model_info: &ModelInfo) -> Option<JobId> {
+    let key = model_info.conversion_key();
+    jobs.values()
+        .find(|job| !job.state.is_finished() && job.model_info.conversion_key() == key)
+        .map(|job| job.id)
+}
+
+/// A quantized output of the conversion that another unfinished conversion writes too, e.g.
+/// under a name given with `output_name`, or for another llama.cpp ref of the same model
+fn taken_output(jobs: &HashMap<JobId, Job>, model_info: &ModelInfo) -> Option<(JobId, String)> {
+    let key = model_info.conversion_key();
+    let filenames = model_info.quantized_filenames();
+    jobs.values()
+        .filter(|job| !job.state.is_finished() && job.model_info.conversion_key() != key)
+        .find_map(|job| {
+            job.model_info
+                .quantized_filenames()
+                .into_iter()
+                .find(|filename| filenames.contains(filename))
+                .map(|filename| (job.id, filename))
+        })
+}
+
+/// Signal the job's pipeline to stop and mark it `Cancelled`
+fn cancel(job: &mut Job) {
+    job.cancel_token.cancel();
+    job.state = JobState::Cancelled;
+    job.updated_at = now_secs();
+    job.events
+        .publish(JobEvent::Error("Job cancelled".to_string()));
+}
+
+/// Remove the least recently updated finished jobs until at most `max_jobs` remain, returning
+/// their ids. Unfinished jobs are never evicted, the store may stay above the limit with them.
+fn evict(jobs: &mut HashMap<JobId, Job>, max_jobs: usize) -> Vec<JobId> {
+    let excess = jobs.len().saturating_sub(max_jobs);
+    if excess == 0 {
+        return Vec::new();
+    }
+    let mut finished: Vec<&Job> = jobs
+        .values()
+        .filter(|job| job.state.is_finished())
+        .collect();
+    finished.sort_by_key(|job| (job.updated_at, job.seq));
+    let evicted: Vec<JobId> = finished.iter().take(excess).map(|job| job.id).collect();
+    for id in &evicted {
+        jobs.remove(id);
+    }
+    evicted
+}
+
+/// Queued jobs created before this one, if it is queued itself
+fn queue_position(jobs: &HashMap<JobId, Job>, job: &Job) -> Option<u32> {
+    if job.state != JobState::Queued {
+        return None;
+    }
+    let ahead = jobs
+        .values()
+        .filter(|other| other.state == JobState::Queued && other.seq < job.seq)
+        .count();
+    Some(ahead as u32)
+}
+
+impl JobStore {
+    /// Create a store backed by the given persistence, reloading the jobs it holds.
+    ///
+    /// Jobs that were still running when the service stopped are marked `Failed`.
+    pub fn persistent(
+        persistence: impl JobPersistence + 'static,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let jobs = persistence
+            .load()?
+            .into_iter()
+            .map(|record| {
+                let job = record.restore();
+                (job.id, job)
+            })
+            .collect();
+        let store = JobStore {
+            jobs: Arc::new(Mutex::new(jobs)),
+            persistence: Some(Arc::new(Persisted {
+                backend: Box::new(persistence),
+                pending: Mutex::new(None),
+                writing: Mutex::new(()),
+            })),
+            max_jobs: None,
+        };
+        store.save(&store.jobs.lock().unwrap());
+        Ok(store)
+    }
+
+    /// Keep at most `max_jobs` jobs, all of them if `None`, evicting the excess right away
+    pub fn with_max_jobs(mut self, max_jobs: Option<usize>) -> Self {
+        self.max_jobs = max_jobs;
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(max_jobs) = max_jobs {
+            if !evict(&mut jobs, max_jobs).is_empty() {
+                self.save(&jobs);
+            }
+        }
+        drop(jobs);
+        self
+    }
+
+    pub fn max_jobs(&self) -> Option<usize> {
+        self.max_jobs
+    }
+
+    /// Number of jobs in the store, finished ones included
+    pub fn count(&self) -> usize {
+        self.jobs.lock().unwrap().len()
+    }
+
+    /// Write the jobs through to the persistence, if any.
+    ///
+    /// Only the snapshot is taken under the lock, the write happens on the blocking pool when
+    /// there is a runtime and right away otherwise.
+    fn save(&self, jobs: &HashMap<JobId, Job>) {
+        if let Some(persisted) = &self.persistence {
+            let records = jobs.values().map(JobRecord::from).collect();
+            *persisted.pending.lock().unwrap() = Some(records);
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    let persisted = persisted.clone();
+                    runtime.spawn_blocking(move || persisted.write());
+                }
+                Err(_) => persisted.write(),
+            }
+        }
+    }
+
+    /// Wait for the latest snapshot to be written, writes still queued on the blocking pool
+    /// are dropped with the runtime
+    pub async fn flush(&self) {
+        if let Some(persisted) = &self.persistence {
+            let persisted = persisted.clone();
+            if let Err(err) = tokio::task::spawn_blocking(move || persisted.write()).await {
+                tracing::error!("Failed to persist jobs: {err}");
+            }
+        }
+    }
+
+    /// Add a job to the store unless an identical conversion is still in flight, or another
+    /// unfinished job writes an output of the same name.
+    ///
+    /// Looking for the duplicate and inserting happen under the same lock, so two identical
+    /// requests can't both start a pipeline. Finished jobs never match, a later request runs
+    /// again.
+    pub fn insert_unless_running(&self, job: Job) -> Result<JobId, NotInserted> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(running) = running(&jobs, &job.model_info) {
+            return Err(NotInserted::Running(running));
+        }
+        if let Some((job_id, filename)) = taken_output(&jobs, &job.model_info) {
+            return Err(NotInserted::OutputTaken { job_id, filename });
+        }
+        let id = job.id;
+        jobs.insert(id, job);
+        if let Some(max_jobs) = self.max_jobs {
+            let evicted = evict(&mut jobs, max_jobs);
+            if !evicted.is_empty() {
+                let ids: Vec<String> = evicted.iter().map(ToString::to_string).collect();
+                tracing::debug!("Evicted the jobs {} over {max_jobs} jobs", ids.join(", "));
+            }
+        }
+        self.save(&jobs);
+        Ok(id)
+    }
+
+    /// The unfinished job of the same conversion, if any
+    pub fn running(&self, model_info: &ModelInfo) -> Option<JobId> {
+        running(&self.jobs.lock().unwrap(), model_info)
+    }
+
+    /// An output of the conversion that another unfinished conversion already writes, and the
+    /// job writing it
+    pub fn taken_output(&self, model_info: &ModelInfo) -> Option<(JobId, String)> {
+        taken_output(&self.jobs.lock().unwrap(), model_info)
+    }
+
+    pub fn get(&self, id: JobId) -> Option<Job> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Public view of the job, with its place in the queue
+    pub fn status(&self, id: JobId) -> Option<JobStatus> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(&id)?;
+        let mut status = JobStatus::from(job);
+        status.queue_position = queue_position(&jobs, job);
+        Some(status)
+    }
+
+    /// Jobs in the given state (all of them if `None`), most recently started first
+    pub fn list(&self, state: Option<JobState>, limit: Option<usize>) -> Vec<Job> {
+        let mut jobs: Vec<Job> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| state.is_none_or(|state| job.state == state))
+            .cloned()
+            .collect();
+        jobs.sort_by_key(|job| std::cmp::Reverse(job.started_at));
+        jobs.truncate(limit.unwrap_or(usize::MAX));
+        jobs
+    }
+
+    pub fn update_state(&self, id: JobId, state: JobState) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&id) {
+            // a cancelled or interrupted pipeline may still be wrapping up its stage
+            if job.state.is_finished() {
+                return;
+            }
+            job.state = state;
+            job.updated_at = now_secs();
+            self.save(&jobs);
+        }
+    }
+
+    /// Update the download progress of the job.
+    ///
+    /// Progress changes too often to be persisted, it only lives in memory.
+    pub fn update_progress(&self, id: JobId, update: impl FnOnce(&mut DownloadProgress)) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&id) {
+            update(job.progress.get_or_insert_with(DownloadProgress::default));
+            job.updated_at = now_secs();
+        }
+    }
+
+    /// Update the conversion progress of the job, in memory only like the download progress
+    pub fn set_progress_pct(&self, id: JobId, progress_pct: u8) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&id) {
+            if job.progress_pct != Some(progress_pct) {
+                job.progress_pct = Some(progress_pct);
+                job.updated_at = now_secs();
+            }
+        }
+    }
+
+    /// Record the architecture detected for the model of the job
+    pub fn set_architecture(&self, id: JobId, architecture: String) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&id) {
+            job.architecture = Some(architecture);
+            job.updated_at = now_secs();
+            self.save(&jobs);
+        }
+    }
+
+    pub fn set_commit(&self, id: JobId, commit: String) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&id) {
+            job.commit = Some(commit);
+            job.updated_at = now_secs();
+            self.save(&jobs);
+        }
+    }
+
+    /// Record the time the job fails at if it is still running
+    pub fn set_deadline(&self, id: JobId, deadline_at: u64) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&id) {
+            job.deadline_at = Some(deadline_at);
+        }
+    }
+
+    /// Record the failure reason and mark the job `Failed`
+    pub fn set_error(&self, id: JobId, error: String, stderr: Option<String>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&id) {
+            if job.state.is_finished() {
+                return;
+            }
+            job.state = JobState::Failed;
+            job.updated_at = now_secs();
+            job.events.publish(JobEvent::Error(error.clone()));
+            job.error = Some(error);
+            job.stderr = stderr;
+            self.save(&jobs);
+        }
+    }
+
+    /// Record the download urls, the timings, the size of the outputs and the outcome of each
+    /// target, and mark the job `Done`
+    pub fn finish(
+        &self,
+        id: JobId,
+        download_urls: Vec<String>,
+        timings: Timings,
+        size_bytes: u64,
+        quant_statuses: Vec<QuantStatus>,
+    ) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&id) {
+            if job.state.is_finished() {
+                return;
+            }
+            job.state = JobState::Done;
+            job.updated_at = now_secs();
+            job.events.publish(JobEvent::Done(download_urls.clone()));
+            job.download_urls = download_urls;
+            job.timings = Some(timings);
+            job.size_bytes = Some(size_bytes);
+            job.quant_statuses = quant_statuses;
+            self.save(&jobs);
+        }
+    }
+
+    /// Signal the job's pipeline to stop and mark it `Cancelled`
+    pub fn cancel(&self, id: JobId) -> CancelOutcome {
+        let mut jobs = self.jobs.lock().unwrap();
+        let outcome = match jobs.get_mut(&id) {
+            None => CancelOutcome::NotFound,
+            Some(job) if job.state.is_finished() => CancelOutcome::AlreadyFinished(job.state),
+            Some(job) => {
+                cancel(job);
+                CancelOutcome::Cancelled
+            }
+        };
+        if outcome == CancelOutcome::Cancelled {
+            self.save(&jobs);
+        }
+        outcome
+    }
+
+    /// Cancel the unfinished jobs matching the filter, returning their ids
+    pub fn cancel_all(&self, filter: impl Fn(&Job) -> bool) -> Vec<JobId> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut cancelled = Vec::new();
+        for job in jobs.values_mut() {
+            if job.state.is_finished() || !filter(job) {
+                continue;
+            }
+            cancel(job);
+            cancelled.push(job.id);
+        }
+        if !cancelled.is_empty() {
+            self.save(&jobs);
+        }
+        cancelled
+    }
+
+    /// Signal the pipelines of the unfinished jobs matching the filter to stop and mark them
+    /// `Interrupted`, returning their ids
+    pub fn interrupt(&self, filter: impl Fn(&Job) -> bool) -> Vec<JobId> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut interrupted = Vec::new();
+        for job in jobs.values_mut() {
+            if job.state.is_finished() || !filter(job) {
+                continue;
+            }
+            job.cancel_token.cancel();
+            job.state = JobState::Interrupted;
+            job.updated_at = now_secs();
+            let error = "Job interrupted by the service shutting down".to_string();
+            job.events.publish(JobEvent::Error(error.clone()));
+            job.error = Some(error);
+            interrupted.push(job.id);
+        }
+        if !interrupted.is_empty() {
+            self.save(&jobs);
+        }
+        interrupted
+    }
+
+    /// Remove the finished jobs matching the filter, returning them
+    pub fn remove_expired(&self, filter: impl Fn(&Job) -> bool) -> Vec<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let expired: Vec<JobId> = jobs
+            .values()
+            .filter(|job| job.state.is_finished() && filter(job))
+            .map(|job| job.id)
+            .collect();
+        let removed: Vec<Job> = expired.iter().filter_map(|id| jobs.remove(id)).collect();
+        if !removed.is_empty() {
+            self.save(&jobs);
+        }
+        removed
+    }
+}
+
+/// Result of [`JobStore::cancel`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelOutcome {
+    Cancelled,
+    AlreadyFinished(JobState),
+    NotFound,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::model_info;
+    use serde_json::json;
+    use std::{sync::atomic::AtomicBool, time::Duration};
+
+    fn job(model: &str) -> Job {
+        Job::new(model_info(json!({"name": model, "quant_info": "Q4"})))
+    }
+
+    #[test]
+    fn concurrent_updates_of_different_jobs_all_land() {
+        let jobs = JobStore::default();
+        let ids: Vec<JobId> = (0..8)
+            .map(|i| {
+                jobs.insert_unless_running(job(&format!("owner/model-{i}")))
+                    .unwrap()
+            })
+            .collect();
+
+        std::thread::scope(|scope| {
+            for &id in &ids {
+                let jobs = jobs.clone();
+                scope.spawn(move || {
+                    for state in [
+                        JobState::Downloading,
+                        JobState::Converting,
+                        JobState::Quantizing,
+                    ] {
+                        for _ in 0..100 {
+                            jobs.update_state(id, state);
+                        }
+                    }
+                });
+            }
+        });
+
+        for id in ids {
+            assert_eq!(jobs.get(id).unwrap().state, JobState::Quantizing);
+        }
+    }
+
+    #[test]
+    fn concurrent_updates_never_revive_a_cancelled_job() {
+        let jobs = JobStore::default();
+        let id = jobs.insert_unless_running(job("owner/model")).unwrap();
+
+        std::thread::scope(|scope| {
+            for state in [JobState::Converting, JobState::Quantizing] {
+                let jobs = jobs.clone();
+                scope.spawn(move || {
+                    for _ in 0..1000 {
+                        jobs.update_state(id, state);
+                    }
+                });
+            }
+            let jobs = jobs.clone();
+            scope.spawn(move || {
+                std::thread::yield_now();
+                assert_eq!(jobs.cancel(id), CancelOutcome::Cancelled);
+            });
+        });
+
+        let job = jobs.get(id).unwrap();
+        assert_eq!(job.state, JobState::Cancelled);
+        assert!(job.cancel_token.is_cancelled());
+    }
+
+    #[test]
+    fn moves_the_queued_jobs_up_as_the_first_one_starts() {
+        let jobs = JobStore::default();
+        let ids: Vec<JobId> = (0..3)
+            .map(|i| {
+                jobs.insert_unless_running(job(&format!("owner/queued-{i}")))
+                    .unwrap()
+            })
+            .collect();
+        let positions = || -> Vec<Option<u32>> {
+            ids.iter()
+                .map(|&id| jobs.status(id).unwrap().queue_position)
+                .collect()
+        };
+
+        assert_eq!(positions(), [Some(0), Some(1), Some(2)]);
+        jobs.update_state(ids[0], JobState::Downloading);
+        assert_eq!(positions(), [None, Some(0), Some(1)]);
+        jobs.cancel(ids[1]);
+        assert_eq!(positions(), [None, None, Some(0)]);
+    }
+
+    #[test]
+    fn evicts_the_oldest_finished_jobs_beyond_the_cap() {
+        let jobs = JobStore::default().with_max_jobs(Some(5));
+        let active: Vec<JobId> = (0..2)
+            .map(|i| {
+                jobs.insert_unless_running(job(&format!("owner/active-{i}")))
+                    .unwrap()
+            })
+            .collect();
+        jobs.update_state(active[0], JobState::Converting);
+        // done 4, 3, 2 then 1 minute ago
+        let finished: Vec<JobId> = (0..4)
+            .map(|i| {
+                let mut job = job(&format!("owner/finished-{i}"));
+                job.state = JobState::Done;
+                job.updated_at = now_secs() - 60 * (4 - i);
+                jobs.insert_unless_running(job).unwrap()
+            })
+            .collect();
+
+        assert_eq!(jobs.count(), 5);
+        assert!(jobs.get(finished[0]).is_none());
+        for id in active.iter().chain(&finished[1..]) {
+            assert!(jobs.get(*id).is_some());
+        }
+
+        // the active ones are kept whatever the cap
+        let jobs = jobs.with_max_jobs(Some(1));
+        assert_eq!(jobs.count(), 2);
+        for id in &active {
+            assert!(jobs.get(*id).is_some());
+        }
+    }
+
+    /// Persistence whose writes wait for the gate to open, recording the number of jobs written
+    #[derive(Debug, Default)]
+    struct GatedPersistence {
+        open: Arc<AtomicBool>,
+        written: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl JobPersistence for GatedPersistence {
+        fn load(&self) -> Result<Vec<JobRecord>, Box<dyn std::error::Error>> {
+            Ok(Vec::new())
+        }
+
+        fn save(&self, records: &[JobRecord]) -> Result<(), Box<dyn std::error::Error>> {
+            while !self.open.load(Ordering::SeqCst) {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            self.written.lock().unwrap().push(records.len());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_the_jobs_outside_of_the_lock() {
+        let persistence = GatedPersistence::default();
+        let open = persistence.open.clone();
+        let written = persistence.written.clone();
+        let jobs = JobStore::persistent(persistence).unwrap();
+
+        // the writes are stuck, the store still answers
+        let store = jobs.clone();
+        let inserting = tokio::task::spawn_blocking(move || {
+            let first = store.insert_unless_running(job("owner/first")).unwrap();
+            store.insert_unless_running(job("owner/second")).unwrap();
+            store.get(first)
+        });
+        let inserted = tokio::time::timeout(Duration::from_secs(5), inserting)
+            .await
+            .expect("the store is blocked on the write")
+            .unwrap();
+        assert!(inserted.is_some());
+        assert_eq!(jobs.count(), 2);
+
+        open.store(true, Ordering::SeqCst);
+        jobs.flush().await;
+        assert_eq!(written.lock().unwrap().last(), Some(&2));
+    }
+}
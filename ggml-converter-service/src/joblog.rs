@@ -0,0 +1,104 @@
+//! The log of each job, `<logs dir>/<job id>.log`, kept once the job is over for
+//! `GET /jobs/{id}/log`. It holds what the live events carry: the lines printed by the
+//! subprocesses and the pipeline, then the outcome of the job.
+
+use crate::job::{JobEvent, JobEvents, JobId};
+use std::path::{Path, PathBuf};
+use tokio::{io::AsyncWriteExt, sync::broadcast};
+use tracing::warn;
+
+/// Default size a log grows to before it is rotated, in MB
+pub const DEFAULT_MAX_LOG_MB: u64 = 16;
+
+const MB: u64 = 1024 * 1024;
+
+/// Size a log grows to before it is rotated, read from `GGML_MAX_LOG_MB`
+pub fn max_log_size() -> u64 {
+    std::env::var("GGML_MAX_LOG_MB")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_MAX_LOG_MB)
+        * MB
+}
+
+pub fn log_path(logs_dir: &Path, job_id: JobId) -> PathBuf {
+    logs_dir.join(format!("{job_id}.log"))
+}
+
+/// Write the events of the job to its log until the job is over.
+///
+/// A log reaching `max_size` is moved to `<job id>.log.1`, replacing the one moved there
+/// before, and a new one is begun. A job's logs take at most twice `max_size`, and the end of
+/// the log, where failures are, is always kept.
+pub async fn record(events: JobEvents, path: PathBuf, max_size: u64) {
+    if let Err(err) = write_events(events, &path, max_size).await {
+        warn!("Failed to write the log {}: {err}", path.display());
+    }
+}
+
+async fn write_events(events: JobEvents, path: &Path, max_size: u64) -> std::io::Result<()> {
+    // subscribed first, the lines printed while the file is being created aren't missed
+    let (history, mut receiver) = events.subscribe();
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let mut log = Log {
+        file: tokio::fs::File::create(path).await?,
+        path,
+        size: 0,
+        max_size,
+    };
+    for event in history {
+        log.write(&line(&event)).await?;
+        if event.is_terminal() {
+            return Ok(());
+        }
+    }
+    loop {
+        match receiver.recv().await {
+            Ok(event) => {
+                log.write(&line(&event)).await?;
+                if event.is_terminal() {
+                    return Ok(());
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                log.write(&format!("[{skipped} lines skipped]")).await?
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+fn line(event: &JobEvent) -> String {
+    match event {
+        JobEvent::Log(line) => line.clone(),
+        JobEvent::Done(download_urls) => format!("Done: {}", download_urls.join(", ")),
+        JobEvent::Error(error) => format!("Error: {error}"),
+    }
+}
+
+struct Log<'a> {
+    file: tokio::fs::File,
+    path: &'a Path,
+    size: u64,
+    max_size: u64,
+}
+
+impl Log<'_> {
+    async fn write(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            let mut rotated = self.path.as_os_str().to_owned();
+            rotated.push(".1");
+            tokio::fs::rename(self.path, &rotated).await?;
+            self.file = tokio::fs::File::create(self.path).await?;
+            self.size = 0;
+        }
+        self.file.write_all(format!("{line}\n").as_bytes()).await?;
+        // readable by `GET /jobs/{id}/log` while the job runs
+        self.file.flush().await?;
+        self.size += len;
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+//! Models converted from a directory already on disk rather than downloaded, for deployments
+//! without access to Hugging Face.
+//!
+//! Only directories under `GGML_LOCAL_MODELS_DIR` are read: a request must not get the
+//! converter to open any file of the host. Local models are refused when it is unset.
+
+use crate::error::AppError;
+use std::path::{Path, PathBuf};
+
+/// The directory of a local model: `path`, absolute or relative to `root`, with its links
+/// resolved. It must be a directory inside `root`.
+pub fn resolve(root: Option<&Path>, path: &Path) -> Result<PathBuf, AppError> {
+    let root = root.ok_or_else(|| {
+        AppError::Forbidden(
+            "Converting local models is disabled, GGML_LOCAL_MODELS_DIR is not set".to_string(),
+        )
+    })?;
+    let root = root.canonicalize().map_err(|err| {
+        AppError::Internal(format!(
+            "Reading the local models directory {}: {err}",
+            root.display()
+        ))
+    })?;
+    // resolved before the check, `..` or a link can't lead out of the root
+    let dir = root
+        .join(path)
+        .canonicalize()
+        .map_err(|err| match err.kind() {
+            std::io::ErrorKind::NotFound => {
+                AppError::BadRequest(format!("Local model '{}' not found", path.display()))
+            }
+            _ => AppError::BadRequest(format!("Reading '{}': {err}", path.display())),
+        })?;
+    if !dir.starts_with(&root) || dir == root {
+        return Err(AppError::Forbidden(format!(
+            "Local model '{}' is not inside the local models directory",
+            path.display()
+        )));
+    }
+    if !dir.is_dir() {
+        return Err(AppError::BadRequest(format!(
+            "Local model '{}' is not a directory",
+            path.display()
+        )));
+    }
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TestDir;
+
+    #[test]
+    fn resolves_a_model_inside_the_root() {
+        let root = TestDir::new();
+        std::fs::create_dir_all(root.join("models/tiny")).unwrap();
+        let models = root.join("models");
+
+        let dir = resolve(Some(&models), Path::new("tiny")).unwrap();
+
+        assert_eq!(dir, models.join("tiny").canonicalize().unwrap());
+    }
+
+    #[test]
+    fn refuses_a_path_outside_the_root() {
+        let root = TestDir::new();
+        std::fs::create_dir_all(root.join("models/tiny")).unwrap();
+        std::fs::create_dir_all(root.join("secrets")).unwrap();
+        std::fs::write(root.join("models/file"), "").unwrap();
+        let models = root.join("models");
+
+        for path in ["../secrets", ".", "tiny/../.."] {
+            assert!(
+                matches!(
+                    resolve(Some(&models), Path::new(path)),
+                    Err(AppError::Forbidden(_))
+                ),
+                "{path}"
+            );
+        }
+        assert!(matches!(
+            resolve(Some(&models), Path::new("missing")),
+            Err(AppError::BadRequest(message)) if message.contains("not found")
+        ));
+        assert!(matches!(
+            resolve(Some(&models), Path::new("file")),
+            Err(AppError::BadRequest(message)) if message.contains("not a directory")
+        ));
+        assert!(matches!(
+            resolve(None, Path::new("tiny")),
+            Err(AppError::Forbidden(_))
+        ));
+    }
+}
@@ -0,0 +1,220 @@
+//! A small `tracing` subscriber writing one line per event to stderr.
+//!
+//! The filter follows the usual `RUST_LOG` syntax, a comma separated list of `level` and
+//! `target=level` directives, e.g. `info,ggml_converter_service=debug`.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::Write,
+    io::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Level, Metadata, Subscriber,
+};
+
+/// Level used when `RUST_LOG` is unset or doesn't match a target
+const DEFAULT_LEVEL: Level = Level::INFO;
+
+/// Install the subscriber for the whole process, filtered by `RUST_LOG`
+pub fn init() {
+    let filter = Filter::parse(&std::env::var("RUST_LOG").unwrap_or_default());
+    let subscriber = LineSubscriber {
+        filter,
+        next_id: AtomicU64::new(1),
+        spans: Mutex::new(HashMap::new()),
+    };
+    if tracing::subscriber::set_global_default(subscriber).is_err() {
+        eprintln!("A tracing subscriber was already installed");
+    }
+}
+
+#[derive(Debug)]
+struct Filter {
+    default: Level,
+    /// `(target prefix, level)`, longest prefixes first
+    targets: Vec<(String, Level)>,
+}
+
+impl Filter {
+    fn parse(spec: &str) -> Self {
+        let mut filter = Filter {
+            default: DEFAULT_LEVEL,
+            targets: Vec::new(),
+        };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    if let Ok(level) = level.parse() {
+                        filter.targets.push((target.to_string(), level));
+                    }
+                }
+                None => match directive.parse() {
+                    Ok(level) => filter.default = level,
+                    // a bare target enables everything it logs
+                    Err(_) => filter.targets.push((directive.to_string(), Level::TRACE)),
+                },
+            }
+        }
+        filter
+            .targets
+            .sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        filter
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        let level = self
+            .targets
+            .iter()
+            .find(|(target, _)| metadata.target().starts_with(target.as_str()))
+            .map_or(self.default, |(_, level)| *level);
+        *metadata.level() <= level
+    }
+}
+
+struct SpanData {
+    name: &'static str,
+    fields: String,
+    refs: usize,
+}
+
+struct LineSubscriber {
+    filter: Filter,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+}
+
+thread_local! {
+    /// Spans entered on this thread, innermost last
+    static CURRENT: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Renders fields as ` key=value`, keeping the message apart
+#[derive(Default)]
+struct FieldWriter {
+    message: String,
+    fields: String,
+}
+
+impl Visit for FieldWriter {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            let _ = write!(self.fields, " {}={}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+impl Subscriber for LineSubscriber {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn new_span(&self, attrs: &span::Attributes<'_>) -> span::Id {
+        let mut writer = FieldWriter::default();
+        attrs.record(&mut writer);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.spans.lock().unwrap().insert(
+            id,
+            SpanData {
+                name: attrs.metadata().name(),
+                fields: writer.fields,
+                refs: 1,
+            },
+        );
+        span::Id::from_u64(id)
+    }
+
+    fn record(&self, span: &span::Id, values: &span::Record<'_>) {
+        let mut writer = FieldWriter::default();
+        values.record(&mut writer);
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            data.fields.push_str(&writer.fields);
+        }
+    }
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut writer = FieldWriter::default();
+        event.record(&mut writer);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut line = format!(
+            "{}.{:03} {:>5} ",
+            now.as_secs(),
+            now.subsec_millis(),
+            event.metadata().level()
+        );
+        {
+            let spans = self.spans.lock().unwrap();
+            CURRENT.with(|current| {
+                for id in current.borrow().iter() {
+                    if let Some(data) = spans.get(id) {
+                        let _ = write!(line, "{}{{{}}}:", data.name, data.fields.trim_start());
+                    }
+                }
+            });
+        }
+        let _ = write!(
+            line,
+            " {}: {}{}",
+            event.metadata().target(),
+            writer.message,
+            writer.fields
+        );
+        let _ = writeln!(std::io::stderr().lock(), "{line}");
+    }
+
+    fn enter(&self, span: &span::Id) {
+        CURRENT.with(|current| current.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &span::Id) {
+        CURRENT.with(|current| {
+            let mut current = current.borrow_mut();
+            if let Some(position) = current.iter().rposition(|id| *id == span.into_u64()) {
+                current.remove(position);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &span::Id) -> span::Id {
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            data.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: span::Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let closed = match spans.get_mut(&span.into_u64()) {
+            Some(data) => {
+                data.refs -= 1;
+                data.refs == 0
+            }
+            None => false,
+        };
+        if closed {
+            spans.remove(&span.into_u64());
+        }
+        closed
+    }
+}
@@ -1,20 +1,110 @@
+mod architecture;
+mod batch;
+mod build;
+mod bundle;
+mod checksum;
+mod cleanup;
+mod cli;
+mod config;
+mod converter;
+mod disk;
+mod download;
+mod error;
+mod extract;
+mod gpu;
+mod health;
+mod idempotency;
+mod imatrix;
+mod job;
+mod joblog;
+mod local;
+mod logging;
+mod metrics;
+mod naming;
+mod openapi;
+mod persistence;
+mod quants;
+mod queue;
+mod rate_limit;
+mod request_id;
+mod runner;
+mod s3;
+mod selftest;
+mod shutdown;
+mod signed_url;
+mod stats;
+mod storage;
+#[cfg(test)]
+mod tests;
+mod ui;
+mod upload;
+mod webhook;
+mod ws;
+
 use axum::{
     body::{self, Body},
-    extract::Query,
+    extract::{BodyStream, Extension, Path, Query},
+    handler::Handler,
     http::header::{HeaderMap, HeaderName, HeaderValue},
-    response::{Headers, Html, IntoResponse, Json, Response},
-    routing::{get, post},
+    middleware,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Headers, Html, IntoResponse, Json, Response,
+    },
+    routing::{delete, get, post},
     Router,
 };
+use futures_util::{stream, Stream, StreamExt};
 use http::{StatusCode, Uri};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::process::Command;
-use std::{collections::HashMap, sync::Mutex, time::Instant};
+use std::{collections::HashMap, convert::Infallible, sync::Arc, sync::Mutex, time::Instant};
+use tokio::sync::{broadcast, mpsc};
+use tower_http::{
+    cors::{self, CorsLayer},
+    trace::TraceLayer,
+};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
 use once_cell::sync::Lazy;
 
-static MODELS: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| {
+use architecture::Architecture;
+use batch::{BatchId, BatchStatus, BatchStore, MAX_BATCH_JOBS};
+use cleanup::{ActiveDownloads, Cleanup, DownloadGuard};
+use config::Config;
+use converter::Converter;
+use error::{ApiError, AppError, ErrorCode, SubprocessError};
+use extract::JsonBody;
+use idempotency::IdempotencyKeys;
+use persistence::JsonFilePersistence;
+use queue::{ConversionQueue, OutputConflict};
+use runner::{CommandRunner, CommandSpec, ProcessRunner, Stage, Timeouts};
+use s3::S3Config;
+use selftest::{SelfTestCache, SelfTestReport};
+use shutdown::Shutdown;
+use signed_url::DownloadToken;
+use storage::{LocalStorage, Storage, StoredFile};
+
+use job::{
+    CancelOutcome, Job, JobContext, JobEvent, JobId, JobState, JobStatus, JobStore, JobSummary,
+    NotInserted, Timings,
+};
+
+static MODELS: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| Mutex::new(builtin_models()));
+
+/// Where the model gets downloaded from, repos missing from the registry come straight from
+/// Hugging Face
+fn model_url(model_name: &str) -> String {
+    MODELS
+        .lock()
+        .unwrap()
+        .get(model_name)
+        .cloned()
+        .unwrap_or_else(|| format!("{}/{}", download::HF_ENDPOINT, model_name))
+}
+
+/// Models known out of the box, used when no models file is provided
+fn builtin_models() -> HashMap<String, String> {
     let mut map = HashMap::new();
     map.insert(
         String::from("meta-llama/Llama-2-7b-hf"),
@@ -24,8 +114,20 @@ static MODELS: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| {
         String::from("meta-llama/Llama-2-7b-chat-hf"),
         String::from("https://huggingface.co/meta-llama/Llama-2-7b-chat-hf"),
     );
-    Mutex::new(map)
-});
+    map
+}
+
+/// Read a models file mapping repo ids to urls, `None` if the file doesn't exist
+fn load_models_file(path: &std::path::Path) -> Result<Option<HashMap<String, String>>, String> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content = std::fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read the models file {:?}: {err}", path))?;
+    serde_json::from_str(&content)
+        .map(Some)
+        .map_err(|err| format!("Failed to parse the models file {:?}: {err}", path))
+}
 
 // We've already seen returning &'static str
 async fn plain_text() -> &'static str {
@@ -108,30 +210,234 @@ async fn response() -> Response<Body> {
 //eg: query?a=1&b=1.0&c=xxx
 async fn query(Query(params): Query<HashMap<String, String>>) -> String {
     for (key, value) in &params {
-        println!("key:{},value:{}", key, value);
+        debug!("key:{},value:{}", key, value);
     }
     format!("{:?}", params)
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 struct ModelInfo {
     name: ModelType,
-    quant_info: QuantInfo,
+    /// The quants of `format`, unless `targets` are given instead
+    #[serde(default, skip_serializing_if = "QuantTargets::is_empty")]
+    quant_info: QuantTargets,
+    #[serde(default)]
+    format: OutputFormat,
+    /// Quants of several formats, converting the model once per format, in place of
+    /// `quant_info` and `format`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    targets: Vec<Target>,
+    /// llama.cpp tag or commit to convert with, `CODE_BASE` when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    llama_cpp_ref: Option<String>,
+    /// Branch, tag or commit of the repo to convert, its `main` branch when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    revision: Option<String>,
+    /// Hugging Face token for gated repos, `HF_TOKEN` when unset. Never written back out.
+    #[serde(default, skip_serializing)]
+    hf_token: Option<HfToken>,
+    /// Notified with a POST once the conversion is over, whatever its outcome
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    callback_url: Option<String>,
+    /// Only convert from safetensors weights, never load pickle ones from an untrusted repo
+    #[serde(default)]
+    require_safetensors: bool,
+    /// Quantize with an importance matrix computed over this calibration dataset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    imatrix: Option<imatrix::ImatrixConfig>,
+    /// Name of the quantized outputs instead of the one derived from the repo, sanitized and
+    /// given the extension of the format
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output_name: Option<String>,
+    /// Offload the importance matrix computation to a GPU, needs `imatrix` and a GPU build
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gpu: Option<gpu::GpuOffload>,
+    /// Extra arguments of the converter script, from a short allowlist like `--pad-vocab`.
+    /// convert.py also gets the ones inferred from the model, see `architecture::converter_args`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    converter_args: Vec<String>,
+    /// Load each output quantized by the job in llama.cpp, failing the targets that don't
+    /// load. Takes the time and memory of loading the model.
+    #[serde(default)]
+    verify: bool,
+    /// Fail the job on the first target that fails to quantize or verify, rather than going on
+    /// with the others and reporting each outcome in `quant_statuses`
+    #[serde(default)]
+    fail_fast: bool,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// See `ModelInfo::conversion_key`
+type ConversionKey = (
+    String,
+    Option<String>,
+    Vec<String>,
+    String,
+    Option<imatrix::ImatrixConfig>,
+    Option<String>,
+    Vec<String>,
+);
+
+impl ModelInfo {
+    /// What identifies the outputs of the conversion: the repo and its revision, the quants of
+    /// each format, the llama.cpp ref, the importance matrix, the output name and the converter
+    /// arguments. Two requests with the same key would write the same files.
+    fn conversion_key(&self) -> ConversionKey {
+        let mut targets: Vec<String> = self
+            .targets()
+            .iter()
+            .map(|(format, quant)| format!("{format}-{quant}"))
+            .collect();
+        targets.sort();
+        targets.dedup();
+        (
+            self.name.to_string(),
+            self.revision.clone(),
+            targets,
+            self.llama_cpp_ref
+                .clone()
+                .unwrap_or_else(|| CODE_BASE.to_string()),
+            self.imatrix.clone(),
+            self.output_name.clone(),
+            self.converter_args.clone(),
+        )
+    }
+
+    /// The format and quant of each output, in the order of the request
+    fn targets(&self) -> Vec<(OutputFormat, QuantInfo)> {
+        match self.targets.is_empty() {
+            true => self
+                .quant_info
+                .quants()
+                .into_iter()
+                .map(|quant| (self.format, quant))
+                .collect(),
+            false => self
+                .targets
+                .iter()
+                .map(|target| (target.format, target.quant.clone()))
+                .collect(),
+        }
+    }
+
+    /// The formats the model is converted to, each once
+    fn formats(&self) -> Vec<OutputFormat> {
+        let mut formats: Vec<OutputFormat> = Vec::new();
+        for (format, _) in self.targets() {
+            if !formats.contains(&format) {
+                formats.push(format);
+            }
+        }
+        formats
+    }
+
+    /// The quants, comma separated, prefixed with their format when `targets` are given
+    fn quant_label(&self) -> String {
+        match self.targets.is_empty() {
+            true => self.quant_info.to_string(),
+            false => {
+                let targets: Vec<String> = self
+                    .targets
+                    .iter()
+                    .map(|target| format!("{}-{}", target.format, target.quant))
+                    .collect();
+                targets.join(",")
+            }
+        }
+    }
+
+    /// File the quantization is written to, named after `output_name` when set. `NoQuant`'s is
+    /// the converted model itself, which keeps its own name.
+    fn quantized_filename(
+        &self,
+        format: OutputFormat,
+        quant: &QuantInfo,
+    ) -> Result<String, String> {
+        let repo_id = self.name.to_string();
+        match &self.output_name {
+            _ if !quant.is_quantized() => naming::ggml_filename(&repo_id, format),
+            Some(output_name) => {
+                naming::custom_filename(output_name, quant, self.targets().len() > 1, format)
+            }
+            None => naming::quantized_filename(&repo_id, quant, format),
+        }
+    }
+
+    /// Files the quantizations are written to, in the order of `targets()`
+    fn quantized_filenames(&self) -> Vec<String> {
+        self.targets()
+            .iter()
+            .filter_map(|(format, quant)| self.quantized_filename(*format, quant).ok())
+            .collect()
+    }
+
+    /// Files the conversion writes to the outputs directory, the converted models included,
+    /// each once
+    fn output_filenames(&self) -> Vec<String> {
+        let repo_id = self.name.to_string();
+        let mut filenames: Vec<String> = Vec::new();
+        for filename in self
+            .formats()
+            .into_iter()
+            .filter_map(|format| naming::ggml_filename(&repo_id, format).ok())
+            .chain(self.quantized_filenames())
+        {
+            if !filenames.contains(&filename) {
+                filenames.push(filename);
+            }
+        }
+        filenames
+    }
+
+    /// The revision of the repo to download
+    fn revision(&self) -> &str {
+        self.revision
+            .as_deref()
+            .unwrap_or(download::DEFAULT_REVISION)
+    }
+
+    /// Directory of the models directory the repo is downloaded to, one per revision
+    fn model_dir_name(&self) -> Result<String, String> {
+        naming::model_dir_name(&self.name.to_string(), self.revision.as_deref())
+    }
+
+    /// The token to download the model with, if any
+    fn hf_token(&self) -> Option<String> {
+        match &self.hf_token {
+            Some(HfToken(token)) => Some(token.clone()),
+            None => std::env::var("HF_TOKEN").ok(),
+        }
+        .filter(|token| !token.is_empty())
+    }
+}
+
+/// A secret that never shows up in the logs
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+struct HfToken(String);
+impl std::fmt::Debug for HfToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HfToken(<redacted>)")
+    }
+}
+
+/// Either one of the well-known models, any Hugging Face repo given as `owner/name`, or a
+/// directory under `GGML_LOCAL_MODELS_DIR` given as `{"local_path": "<dir>"}`.
+///
+/// Serialized as the variant name for the well-known models, as the repo id for the other
+/// repos and as `{"local_path": "<dir>"}` for local models.
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum ModelType {
     Llama2_7b,
     Llama2Chat7b,
     Llama2Chinese7b,
+    Repo(String),
+    /// Converted from the directory, never downloaded. Its outputs are named after the
+    /// directory.
+    Local(std::path::PathBuf),
 }
 impl From<ModelType> for String {
     fn from(model_type: ModelType) -> Self {
-        match model_type {
-            ModelType::Llama2_7b => "meta-llama/Llama-2-7b-hf".to_string(),
-            ModelType::Llama2Chat7b => "meta-llama/Llama-2-7b-chat-hf".to_string(),
-            ModelType::Llama2Chinese7b => "LinkSoul/Chinese-Llama-2-7b".to_string(),
-        }
+        model_type.to_string()
     }
 }
 impl std::fmt::Display for ModelType {
@@ -140,17 +446,114 @@ impl std::fmt::Display for ModelType {
             ModelType::Llama2_7b => "meta-llama/Llama-2-7b-hf",
             ModelType::Llama2Chat7b => "meta-llama/Llama-2-7b-chat-hf",
             ModelType::Llama2Chinese7b => "LinkSoul/Chinese-Llama-2-7b",
+            ModelType::Repo(repo_id) => repo_id,
+            ModelType::Local(path) => return write!(f, "{}", path.display()),
         };
         write!(f, "{}", model_type)
     }
 }
+impl Serialize for ModelType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ModelType::Llama2_7b => serializer.serialize_str("Llama2_7b"),
+            ModelType::Llama2Chat7b => serializer.serialize_str("Llama2Chat7b"),
+            ModelType::Llama2Chinese7b => serializer.serialize_str("Llama2Chinese7b"),
+            ModelType::Repo(repo_id) => serializer.serialize_str(repo_id),
+            ModelType::Local(path) => {
+                use serde::ser::SerializeMap;
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("local_path", path)?;
+                map.end()
+            }
+        }
+    }
+}
+impl<'de> Deserialize<'de> for ModelType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Name {
+            Name(String),
+            Local { local_path: std::path::PathBuf },
+        }
+        let name = Name::deserialize(deserializer).map_err(|_| {
+            serde::de::Error::custom(
+                "invalid name: expected a model name or {\"local_path\": \"<dir>\"}",
+            )
+        })?;
+        Ok(match name {
+            Name::Name(name) => ModelType::from_name(name),
+            Name::Local { local_path } => ModelType::Local(local_path),
+        })
+    }
+}
+impl ModelType {
+    /// One of the built-in models by its variant name, any other name as a repo id
+    fn from_name(name: String) -> Self {
+        match name.as_str() {
+            "Llama2_7b" => ModelType::Llama2_7b,
+            "Llama2Chat7b" => ModelType::Llama2Chat7b,
+            "Llama2Chinese7b" => ModelType::Llama2Chinese7b,
+            _ => ModelType::Repo(name),
+        }
+    }
 
-#[derive(Debug, Deserialize, Serialize)]
+    /// The directory of a local model, `None` for the ones downloaded
+    fn local_path(&self) -> Option<&std::path::Path> {
+        match self {
+            ModelType::Local(path) => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Check that a free-form repo id has the `owner/name` shape, and that a local path names a
+    /// directory the outputs can be named after
+    fn validate(&self) -> Result<(), String> {
+        if let ModelType::Local(path) = self {
+            return match path.file_name() {
+                Some(_) => Ok(()),
+                None => Err(format!(
+                    "Invalid local_path '{}': expected the directory of a model",
+                    path.display()
+                )),
+            };
+        }
+        let ModelType::Repo(repo_id) = self else {
+            return Ok(());
+        };
+
+        let valid_segment = |segment: &str| {
+            !segment.is_empty()
+                && !segment.starts_with(['.', '-'])
+                && !segment.contains("..")
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        };
+        match repo_id.split_once('/') {
+            Some((owner, name)) if valid_segment(owner) && valid_segment(name) => Ok(()),
+            _ => Err(format!(
+                "Invalid model name '{repo_id}': expected one of Llama2_7b, Llama2Chat7b, Llama2Chinese7b or a Hugging Face repo id like 'owner/name'"
+            )),
+        }
+    }
+}
+
+// variant names follow llama.cpp's quant type names
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 enum QuantInfo {
     Q4,
     Q8,
     F16,
     F32,
+    Q2_K,
+    Q3_K_M,
+    Q4_K_M,
+    Q5_K_M,
+    Q6_K,
+    /// No quantization, the converted model is the output
+    NoQuant,
 }
 impl std::fmt::Display for QuantInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -159,297 +562,3293 @@ impl std::fmt::Display for QuantInfo {
             QuantInfo::Q8 => "q8_0",
             QuantInfo::F16 => "f16",
             QuantInfo::F32 => "f32",
+            QuantInfo::Q2_K => "q2_K",
+            QuantInfo::Q3_K_M => "q3_K_M",
+            QuantInfo::Q4_K_M => "q4_K_M",
+            QuantInfo::Q5_K_M => "q5_K_M",
+            QuantInfo::Q6_K => "q6_K",
+            QuantInfo::NoQuant => "unquantized",
         };
         write!(f, "{}", quant_info)
     }
 }
+impl QuantInfo {
+    /// The quantizations, `NoQuant` left out as llama.cpp's `quantize` has no such type
+    const ALL: [QuantInfo; 9] = [
+        QuantInfo::Q4,
+        QuantInfo::Q8,
+        QuantInfo::F16,
+        QuantInfo::F32,
+        QuantInfo::Q2_K,
+        QuantInfo::Q3_K_M,
+        QuantInfo::Q4_K_M,
+        QuantInfo::Q5_K_M,
+        QuantInfo::Q6_K,
+    ];
 
-#[derive(Debug, Deserialize, Serialize)]
-struct ConversionResult {
-    download_url: String,
-}
+    /// Approximate bits each weight takes once quantized, to estimate the output size
+    fn bits_per_weight(&self) -> f64 {
+        match self {
+            QuantInfo::Q4 => 4.5,
+            QuantInfo::Q8 => 8.5,
+            QuantInfo::F16 => 16.0,
+            QuantInfo::F32 => 32.0,
+            QuantInfo::Q2_K => 2.6,
+            QuantInfo::Q3_K_M => 3.9,
+            QuantInfo::Q4_K_M => 4.9,
+            QuantInfo::Q5_K_M => 5.7,
+            QuantInfo::Q6_K => 6.6,
+            // converted as f16 by default
+            QuantInfo::NoQuant => 16.0,
+        }
+    }
 
-// json request
-async fn json_request(Json(model_info): Json<ModelInfo>) -> Json<ConversionResult> {
-    println!("{:?}", &model_info);
+    fn is_quantized(&self) -> bool {
+        *self != QuantInfo::NoQuant
+    }
+}
+/// Accepts both our variant names (`Q4`) and llama.cpp's quant names (`q4_0`)
+impl std::str::FromStr for QuantInfo {
+    type Err = String;
 
-    // download and build llama.cpp
-    let llama_cpp_dir = download_and_build_llama_cpp().await.unwrap();
-    dbg!(&llama_cpp_dir);
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let quants = QuantInfo::ALL.into_iter().chain([QuantInfo::NoQuant]);
+        quants
+            .clone()
+            .find(|quant| format!("{:?}", quant) == s || quant.to_string() == s)
+            .ok_or_else(|| {
+                let names: Vec<String> = quants
+                    .map(|quant| format!("{:?} ({})", quant, quant))
+                    .collect();
+                format!("Unknown quant '{s}': expected one of {}", names.join(", "))
+            })
+    }
+}
+impl<'de> Deserialize<'de> for QuantInfo {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
 
-    // download llama2 models
-    let model_repo_dir = download_llama2_models(&model_info).await.unwrap();
-    dbg!(&model_repo_dir);
+/// File format produced by the conversion
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+enum OutputFormat {
+    /// Legacy ggml `.bin` files, produced by `convert.py`
+    Ggml,
+    /// GGUF files, produced by `convert-hf-to-gguf.py`
+    #[default]
+    Gguf,
+}
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let format = match self {
+            OutputFormat::Ggml => "ggml",
+            OutputFormat::Gguf => "gguf",
+        };
+        write!(f, "{}", format)
+    }
+}
+impl<'de> Deserialize<'de> for OutputFormat {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)
+            .map_err(|err| serde::de::Error::custom(format!("invalid format: {err}")))?;
+        match s.as_str() {
+            "Ggml" => Ok(OutputFormat::Ggml),
+            "Gguf" => Ok(OutputFormat::Gguf),
+            _ => Err(serde::de::Error::custom(format!(
+                "invalid format: unknown format '{s}', expected one of Ggml, Gguf"
+            ))),
+        }
+    }
+}
+impl OutputFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Ggml => "bin",
+            OutputFormat::Gguf => "gguf",
+        }
+    }
 
-    // convert the target model to ggml
-    let curr_dir = std::env::current_dir().unwrap();
-    let root_dir = curr_dir.parent().unwrap();
-    let outputs_dir = root_dir.join("outputs");
-    if !outputs_dir.exists() {
-        std::fs::create_dir(outputs_dir.as_path()).unwrap();
-    }
-    let out_filename = format!(
-        "{}-ggml.{}",
-        model_info
-            .name
-            .to_string()
-            .split('/')
-            .collect::<Vec<&str>>()[1],
-        "bin"
-    );
-    let outfile = outputs_dir.join(out_filename.as_str());
-    convert_to_ggml(
-        llama_cpp_dir.as_path(),
-        model_repo_dir.as_path(),
-        outfile.as_path(),
-    )
-    .await
-    .unwrap();
-
-    // quantize the ggml model
-    let quantized_filename = format!(
-        "{}-ggml-{}.{}",
-        model_info
-            .name
-            .to_string()
-            .split('/')
-            .collect::<Vec<&str>>()[1],
-        model_info.quant_info,
-        "bin"
-    );
-    let quantized_outfile = outputs_dir.join(quantized_filename.as_str());
-    quantize_ggml(
-        llama_cpp_dir.as_path(),
-        outfile.as_path(),
-        model_info.quant_info,
-        quantized_outfile.as_path(),
-    )
-    .await
-    .unwrap();
+    /// The llama.cpp script converting a Hugging Face checkpoint to this format
+    fn converter_script(&self) -> &'static str {
+        match self {
+            OutputFormat::Ggml => "convert.py",
+            OutputFormat::Gguf => "convert-hf-to-gguf.py",
+        }
+    }
+}
 
-    println!("Done.");
+/// One quantization, or several sharing a single ggml conversion
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+enum QuantTargets {
+    One(QuantInfo),
+    Many(Vec<QuantInfo>),
+}
+/// None, for requests giving `targets` instead
+impl Default for QuantTargets {
+    fn default() -> Self {
+        QuantTargets::Many(Vec::new())
+    }
+}
+impl QuantTargets {
+    fn quants(&self) -> Vec<QuantInfo> {
+        match self {
+            QuantTargets::One(quant) => vec![quant.clone()],
+            QuantTargets::Many(quants) => quants.clone(),
+        }
+    }
 
-    let res = ConversionResult {
-        download_url: quantized_outfile.to_str().unwrap().to_string(),
-    };
+    fn is_empty(&self) -> bool {
+        self.quants().is_empty()
+    }
+}
 
-    Json(res)
+/// A quantization in a given format, for requests converting to several formats
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+struct Target {
+    format: OutputFormat,
+    quant: QuantInfo,
+}
+// by hand rather than untagged, which would hide which quant is wrong behind "data did not
+// match any variant"
+impl<'de> Deserialize<'de> for QuantTargets {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let invalid =
+            |reason: String| serde::de::Error::custom(format!("invalid quant_info: {reason}"));
+        match Value::deserialize(deserializer)? {
+            // converting only, as when asking for `NoQuant`
+            Value::Null => Ok(QuantTargets::One(QuantInfo::NoQuant)),
+            Value::String(quant) => quant.parse().map(QuantTargets::One).map_err(invalid),
+            Value::Array(quants) if quants.is_empty() => {
+                Err(invalid("at least one quant is required".to_string()))
+            }
+            Value::Array(quants) => quants
+                .into_iter()
+                .map(|quant| match quant {
+                    Value::String(quant) => quant.parse(),
+                    other => Err(format!("expected a quant name, found {other}")),
+                })
+                .collect::<Result<_, _>>()
+                .map(QuantTargets::Many)
+                .map_err(invalid),
+            other => Err(invalid(format!(
+                "expected a quant name or a list of them, found {other}"
+            ))),
+        }
+    }
+}
+impl std::fmt::Display for QuantTargets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let quants: Vec<String> = self.quants().iter().map(QuantInfo::to_string).collect();
+        write!(f, "{}", quants.join(","))
+    }
 }
 
-// From https://github.com/ggerganov/llama.cpp/tags
-const CODE_BASE: &str = "d2a4366";
+#[derive(Debug, Deserialize, Serialize)]
+struct ConversionResult {
+    /// The requested quantizations, comma separated
+    quant: String,
+    /// Set when a single quantization was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    download_url: Option<String>,
+    download_urls: Vec<String>,
+    /// The download urls with their format and quant
+    outputs: Vec<job::Output>,
+    /// SHA-256 of the output, set when a single quantization was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    /// SHA-256 of each output, in the order of `download_urls`
+    sha256s: Vec<String>,
+    /// Total size of the outputs, in bytes
+    size_bytes: u64,
+    /// Size of the conversion the outputs were quantized from, unset when they were reused
+    #[serde(skip_serializing_if = "Option::is_none")]
+    base_ggml_size_bytes: Option<u64>,
+    /// Seconds spent in each stage
+    timings: Timings,
+    /// Outcome of each target, the outputs only being those of the `Done` ones
+    quant_statuses: Vec<job::QuantStatus>,
+    /// Set when no output is quantized, only `NoQuant` having been requested
+    #[serde(default)]
+    unquantized: bool,
+}
+impl ConversionResult {
+    fn new(
+        model_info: &ModelInfo,
+        quant_statuses: Vec<job::QuantStatus>,
+        download_urls: Vec<String>,
+        sha256s: Vec<String>,
+        size_bytes: u64,
+        base_ggml_size_bytes: Option<u64>,
+        timings: Timings,
+    ) -> Self {
+        ConversionResult {
+            quant: model_info.quant_label(),
+            download_url: match download_urls.as_slice() {
+                [download_url] => Some(download_url.clone()),
+                _ => None,
+            },
+            outputs: job::outputs(model_info, &quant_statuses, &download_urls),
+            download_urls,
+            sha256: match sha256s.as_slice() {
+                [sha256] => Some(sha256.clone()),
+                _ => None,
+            },
+            sha256s,
+            size_bytes,
+            base_ggml_size_bytes,
+            timings,
+            quant_statuses,
+            unquantized: model_info
+                .targets()
+                .iter()
+                .all(|(_, quant)| !quant.is_quantized()),
+        }
+    }
+}
 
-async fn download_and_build_llama_cpp() -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
-    let curr_dir = std::env::current_dir()?;
-    let llama_cpp_dir = curr_dir.parent().unwrap().join("llama.cpp");
+#[derive(Debug, Deserialize)]
+struct ModelRegistration {
+    name: String,
+    url: String,
+}
 
-    // download
-    if !llama_cpp_dir.exists() {
-        let url = format!(
-            "https://github.com/ggerganov/llama.cpp/archive/refs/tags/master-{CODE_BASE}.tar.gz"
-        );
+// register a model repo at runtime
+async fn register_model(
+    JsonBody(registration): JsonBody<ModelRegistration>,
+) -> Result<(StatusCode, Json<Value>), AppError> {
+    ModelType::Repo(registration.name.clone())
+        .validate()
+        .map_err(AppError::BadRequest)?;
+    match reqwest::Url::parse(&registration.url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => {}
+        _ => {
+            return Err(AppError::BadRequest(format!(
+                "Invalid url '{}'",
+                registration.url
+            )))
+        }
+    }
 
-        let status = Command::new("wget").arg(&url).status()?;
-        println!("status: {:?}", status);
+    let mut models = MODELS.lock().unwrap();
+    if models.contains_key(&registration.name) {
+        return Err(AppError::Conflict(format!(
+            "Model '{}' is already registered",
+            registration.name
+        )));
+    }
+    models.insert(registration.name.clone(), registration.url.clone());
 
-        let status = Command::new("tar")
-            .arg("-zxvf")
-            .arg("master-d2a4366.tar.gz")
-            .status();
-        println!("status: {:?}", status);
+    Ok((
+        StatusCode::CREATED,
+        Json(json!({ "name": registration.name, "url": registration.url })),
+    ))
+}
 
-        let status = Command::new("rm")
-            .arg("-rf")
-            .arg(format!("master-{CODE_BASE}.tar.gz").as_str())
-            .status();
-        println!("status: {:?}", status);
+// list the registered model repos
+async fn list_models() -> Json<HashMap<String, String>> {
+    Json(MODELS.lock().unwrap().clone())
+}
 
-        let status = Command::new("mv")
-            .arg(format!("llama.cpp-master-{CODE_BASE}").as_str())
-            .arg("llama.cpp")
-            .status();
-        println!("status: {:?}", status);
+// unregister a model repo, the name is percent-encoded: /models/meta-llama%2FLlama-2-7b-hf
+async fn delete_model(
+    Extension(jobs): Extension<JobStore>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, AppError> {
+    let Some(url) = MODELS.lock().unwrap().remove(&name) else {
+        return Err(AppError::ModelNotFound(name));
+    };
 
-        if !std::path::Path::new("llama.cpp").exists() {
-            panic!("Not found llama.cpp directory");
-        }
-    } else {
-        println!("llama.cpp directory already exists");
+    let in_flight: Vec<JobId> = jobs
+        .list(None, None)
+        .iter()
+        .filter(|job| !job.state.is_finished() && job.model_info.name.to_string() == name)
+        .map(|job| job.id)
+        .collect();
+    if !in_flight.is_empty() {
+        warn!("Deleted model '{name}' while jobs {in_flight:?} are still using it");
     }
 
-    // build
-    let quantizer = llama_cpp_dir.join("quantize");
-    if quantizer.exists() && quantizer.is_file() {
-        println!("Already build llama.cpp");
-    } else {
-        std::env::set_current_dir(llama_cpp_dir.as_path())?;
+    Ok(Json(json!({ "name": name, "url": url })))
+}
 
-        // build llama.cpp
-        let status = Command::new("make").arg("-j").status();
-        println!("status: {:?}", status);
+/// An output of a previous conversion, found in the outputs directory
+#[derive(Debug, Serialize)]
+struct ConvertedQuant {
+    quant: String,
+    format: OutputFormat,
+    filename: String,
+    size_bytes: u64,
+    /// Only when already computed, listing the outputs mustn't hash gigabytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
 
-        // check if the build process is successful
-        let status = Command::new("./quantize").arg("--help").status()?;
-        println!("status: {:?}", status);
+// outputs already converted from a model, the name is percent-encoded like for DELETE
+async fn list_quants(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Path(name): Path<String>,
+) -> Result<Json<Vec<ConvertedQuant>>, AppError> {
+    let model = ModelType::from_name(name);
+    model.validate().map_err(AppError::BadRequest)?;
+    let repo_id = model.to_string();
 
-        std::env::set_current_dir(curr_dir.as_path())?;
+    // the exact names a conversion would write, a prefix would also match `<name>-chat`
+    let stored: HashMap<String, u64> = storage
+        .list()
+        .await?
+        .into_iter()
+        .map(|file| (file.name, file.len))
+        .collect();
+    let mut quants = Vec::new();
+    for format in [OutputFormat::Gguf, OutputFormat::Ggml] {
+        // a converted model kept after its quantizations is there unquantized
+        for quant in QuantInfo::ALL.into_iter().chain([QuantInfo::NoQuant]) {
+            let filename = naming::quantized_filename(&repo_id, &quant, format)
+                .map_err(AppError::BadRequest)?;
+            let size_bytes = match stored.get(&filename) {
+                Some(&len) if len > 0 => len,
+                _ => continue,
+            };
+            let sha256 = match stored.contains_key(&checksum::checksum_name(&filename)) {
+                true => checksum::stored_checksum(storage.as_ref(), &filename)
+                    .await
+                    .ok(),
+                false => None,
+            };
+            quants.push(ConvertedQuant {
+                quant: quant.to_string(),
+                format,
+                filename,
+                size_bytes,
+                sha256,
+            });
+        }
     }
+    Ok(Json(quants))
+}
 
-    Ok(llama_cpp_dir)
+#[derive(Debug, Deserialize)]
+struct ConversionParams {
+    /// Rebuild the output even if a previous run already produced it
+    #[serde(default)]
+    force: bool,
 }
 
-async fn download_llama2_models(
-    model_info: &ModelInfo,
-) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
-    let mut success = false;
-    let mut retries = 0;
+#[derive(Debug, Serialize)]
+struct JobCreated {
+    job_id: JobId,
+}
 
-    let curr_dir = std::env::current_dir()?;
-    let models_dir = curr_dir.parent().unwrap().join("models");
-    if !models_dir.exists() {
-        std::fs::create_dir(models_dir.as_path())?;
+/// Reject a request for a repo that doesn't exist, or that is larger than
+/// `GGML_MAX_MODEL_BYTES`, before any job starts on it.
+///
+/// Models already downloaded and repos outside Hugging Face are not checked, and neither is
+/// anything when the lookup fails, a network hiccup must not turn away a valid repo. A local
+/// model must be a directory under `GGML_LOCAL_MODELS_DIR`.
+async fn check_requested_repo(config: &Config, model_info: &ModelInfo) -> Result<(), AppError> {
+    if let Some(path) = model_info.name.local_path() {
+        return local::resolve(config.local_models_dir.as_deref(), path).map(|_| ());
     }
-
-    let model_repo_dir = models_dir.join(
-        model_info
-            .name
-            .to_string()
-            .split('/')
-            .collect::<Vec<&str>>()[1],
-    );
+    let model_name = model_info.name.to_string();
+    let model_repo_dir = config
+        .models_dir
+        .join(model_info.model_dir_name().map_err(AppError::BadRequest)?);
     if model_repo_dir.exists() {
-        println!("Model '{}' already exists", model_info.name);
-    } else {
-        let locked = MODELS.lock().unwrap();
-        let url = locked
-            .get(model_info.name.to_string().as_str())
-            .ok_or(format!(
-                "Failed to get the url of the model '{}'",
-                model_info.name.to_string()
-            ))?;
-
-        println!("Downloading from {url}...");
-
-        while !success && retries < 3 {
-            println!("({retries}) Git clone llama2 models...");
+        return Ok(());
+    }
+    let url = model_url(&model_name);
+    let Some(repo) = download::hf_repo(&url) else {
+        return Ok(());
+    };
+    let revision = model_info.revision();
+    match download::check_repo(repo, revision, model_info.hf_token().as_deref()).await {
+        download::RepoCheck::Exists(size) => {
+            disk::check_model_size(&model_name, size, disk::max_model_size())
+        }
+        download::RepoCheck::NotFound => Err(AppError::ModelNotFound(match &model_info.revision {
+            Some(revision) => format!("{model_name}@{revision}"),
+            None => model_name,
+        })),
+        download::RepoCheck::Unknown(err) => {
+            warn!("Could not check that '{model_name}' exists, going ahead: {err}");
+            Ok(())
+        }
+    }
+}
 
-            let output = Command::new("git").arg("clone").arg(url).output();
+/// Check a conversion request before any job is created for it
+fn validate_model_info(model_info: &ModelInfo) -> Result<(), AppError> {
+    match model_info_problems(model_info).into_iter().next() {
+        Some(problem) => Err(AppError::BadRequest(problem)),
+        None => Ok(()),
+    }
+}
 
-            match output {
-                Ok(output) if output.status.success() => {
-                    success = true;
-                    println!("Git clone succeeded!");
-                }
-                _ => {
-                    retries += 1;
-                    println!("output: {:?}", output);
-                    println!("Git clone failed, retry again...");
-                }
-            }
+/// Everything wrong with a conversion request, found without touching the disk or the network
+fn model_info_problems(model_info: &ModelInfo) -> Vec<String> {
+    let mut problems = Vec::new();
+    problems.extend(model_info.name.validate().err());
+    if let Some(llama_cpp_ref) = &model_info.llama_cpp_ref {
+        problems.extend(validate_llama_cpp_ref(llama_cpp_ref).err());
+    }
+    match (
+        model_info.quant_info.is_empty(),
+        model_info.targets.is_empty(),
+    ) {
+        (true, true) => problems.push("At least one quant_info or target is required".to_string()),
+        (false, false) => problems.push(
+            "Give either quant_info or targets, targets name the quant of each format".to_string(),
+        ),
+        _ => {}
+    }
+    for (index, target) in model_info.targets.iter().enumerate() {
+        if model_info.targets[..index].contains(target) {
+            problems.push(format!(
+                "The target {} {} is requested twice",
+                target.format, target.quant
+            ));
         }
-
-        if !success {
-            println!("Git clone failed after 3 retries.");
+    }
+    if let Some(revision) = &model_info.revision {
+        problems.extend(download::validate_revision(revision).err());
+        if model_info.name.local_path().is_some() {
+            problems.push("revision only applies to repos, not to local models".to_string());
         }
     }
-
-    Ok(model_repo_dir)
+    if let Some(callback_url) = &model_info.callback_url {
+        problems.extend(webhook::validate_url(callback_url).err());
+    }
+    if let Some(output_name) = &model_info.output_name {
+        problems.extend(naming::sanitize_output_name(output_name).err());
+    }
+    problems.extend(converter::validate_args(&model_info.converter_args).err());
+    if let Some(gpu) = &model_info.gpu {
+        problems.extend(gpu.validate().err());
+        // quantizing has no GPU support in llama.cpp
+        if model_info.imatrix.is_none() {
+            problems.push(
+                "gpu only applies to the imatrix computation, quantization runs on the CPU"
+                    .to_string(),
+            );
+        }
+    }
+    if let Some(imatrix) = &model_info.imatrix {
+        problems.extend(imatrix.validate().err());
+        if model_info.formats() != [OutputFormat::Gguf] {
+            problems.push("imatrix is only supported for the Gguf format".to_string());
+        }
+        if !model_info
+            .targets()
+            .iter()
+            .any(|(_, quant)| quant.is_quantized())
+        {
+            problems.push("imatrix only applies to quantizations, not to NoQuant".to_string());
+        }
+    }
+    // an invalid name or output name has already been reported, the file names come from them
+    if problems.is_empty() {
+        for (format, quant) in model_info.targets() {
+            problems.extend(model_info.quantized_filename(format, &quant).err());
+        }
+    }
+    problems
 }
 
-async fn convert_to_ggml(
-    llama_cpp_dir: &std::path::Path,
-    model_repo_dir: &std::path::Path,
-    outfile: &std::path::Path,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let converter = llama_cpp_dir.join("convert.py");
-    println!("converter: {:?}", converter.as_path());
+fn ensure_accepting(shutdown: &Shutdown) -> Result<(), AppError> {
+    match shutdown.is_shutting_down() {
+        true => Err(AppError::Unavailable(
+            "The service is shutting down, no new conversions are accepted".to_string(),
+        )),
+        false => Ok(()),
+    }
+}
 
-    println!("out_file: {:?}", outfile);
-    if outfile.exists() {
-        std::fs::remove_file(outfile)?;
+// check a conversion request without starting it: the request as the service would run it, or
+// everything wrong with it
+async fn validate_request(
+    model_info: Result<JsonBody<ModelInfo>, AppError>,
+) -> Result<Json<Value>, ApiError> {
+    let invalid = |problems: Vec<String>| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            ErrorCode::InvalidRequest,
+            "Invalid conversion request",
+        )
+        .with_details(json!({ "problems": problems }))
+    };
+    let model_info = match model_info {
+        Ok(JsonBody(model_info)) => model_info,
+        Err(err) => return Err(invalid(vec![err.to_string()])),
+    };
+    let problems = model_info_problems(&model_info);
+    if !problems.is_empty() {
+        return Err(invalid(problems));
     }
 
-    if converter.exists() && converter.is_file() {
-        println!(
-            "================ Start to convert {} to ggml...",
-            model_repo_dir.file_name().unwrap().to_str().unwrap()
-        );
+    let repo_id = model_info.name.to_string();
+    let converted_files: Vec<String> = model_info
+        .formats()
+        .into_iter()
+        .filter_map(|format| naming::ggml_filename(&repo_id, format).ok())
+        .collect();
+    let resolved = json!({
+        // local models aren't downloaded
+        "model_url": model_info.name.local_path().is_none().then(|| model_url(&repo_id)),
+        "llama_cpp_ref": model_info.llama_cpp_ref.as_deref().unwrap_or(CODE_BASE),
+        // set for a single format
+        "converted_file": match converted_files.as_slice() {
+            [converted_file] => Some(converted_file.clone()),
+            _ => None,
+        },
+        "converted_files": converted_files,
+        "output_files": model_info.quantized_filenames(),
+        "model_info": model_info,
+    });
+    Ok(Json(resolved))
+}
 
-        let start = Instant::now();
-        let output = Command::new("python3")
-            .arg(converter)
-            .arg(model_repo_dir)
-            .arg("--outfile")
-            .arg(outfile)
-            .output()?;
-        let elapsed = Instant::now() - start;
+// json request: the conversion runs in the background, the caller gets a job id to poll. A
+// request with the `Idempotency-Key` of an earlier one gets the job of the earlier one back.
+//eg: ggml?force=true
+#[allow(clippy::too_many_arguments)] // one per extractor
+async fn json_request(
+    Extension(jobs): Extension<JobStore>,
+    Extension(queue): Extension<ConversionQueue>,
+    Extension(runner): Extension<Arc<dyn CommandRunner>>,
+    Extension(config): Extension<Config>,
+    Extension(shutdown): Extension<Shutdown>,
+    Extension(keys): Extension<IdempotencyKeys>,
+    headers: HeaderMap,
+    Query(params): Query<ConversionParams>,
+    JsonBody(model_info): JsonBody<ModelInfo>,
+) -> Result<(StatusCode, Json<JobCreated>), AppError> {
+    debug!("{:?}", &model_info);
 
-        match output.status.success() {
-            true => println!("The conversion took {:?} seconds.", elapsed.as_secs()),
-            false => println!("Conversion failed!"),
+    let key = match headers.get(idempotency::HEADER) {
+        Some(key) => {
+            let key = key.to_str().unwrap_or_default();
+            idempotency::validate_key(key).map_err(AppError::BadRequest)?;
+            Some(key)
         }
-    } else {
-        panic!("Not found converter.py");
+        None => None,
+    };
+    if let Some(job_id) = key.and_then(|key| keys.get(key)) {
+        info!("Same Idempotency-Key as job {job_id}");
+        return Ok((StatusCode::ACCEPTED, Json(JobCreated { job_id })));
     }
 
-    Ok(())
-}
-
-/// Quantize the ggml model
-async fn quantize_ggml(
-    llama_cpp_dir: &std::path::Path,
-    model: &std::path::Path,
-    quant_info: QuantInfo,
-    outfile: &std::path::Path,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let quantizer = llama_cpp_dir.join("quantize");
-    println!("quantizer: {:?}", quantizer.as_path());
+    ensure_accepting(&shutdown)?;
+    validate_model_info(&model_info)?;
+    check_requested_repo(&config, &model_info).await?;
 
-    if outfile.exists() {
-        std::fs::remove_file(outfile)?;
-    }
+    let start = || {
+        start_conversion(
+            jobs,
+            queue,
+            runner,
+            config,
+            &shutdown,
+            model_info,
+            params.force,
+        )
+    };
+    // checked again along with the creation, a request with the key may have come meanwhile
+    let job_id = match key {
+        Some(key) => keys.get_or_create(key, start)?,
+        None => start()?,
+    };
+    Ok((StatusCode::ACCEPTED, Json(JobCreated { job_id })))
+}
 
-    // quantize
-    if quantizer.exists() && quantizer.is_file() {
-        println!(
-            "============== Start to quantize {} ...",
-            model.file_name().unwrap().to_str().unwrap()
-        );
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    jobs: Vec<ModelInfo>,
+}
 
-        let start = Instant::now();
-        let output = Command::new(quantizer.as_os_str())
-            .arg(model)
-            .arg(outfile)
-            .arg(quant_info.to_string())
-            .output()?;
-        let elapsed = Instant::now() - start;
+#[derive(Debug, Serialize)]
+struct BatchCreated {
+    batch_id: BatchId,
+    job_ids: Vec<JobId>,
+}
 
-        match output.status.success() {
-            true => println!("The quantization took {:?} seconds.", elapsed.as_secs()),
-            false => println!("Quantization failed!"),
+// start several conversions at once, each as its own job queued like any other
+#[allow(clippy::too_many_arguments)] // one per extractor
+async fn batch_request(
+    Extension(jobs): Extension<JobStore>,
+    Extension(batches): Extension<BatchStore>,
+    Extension(queue): Extension<ConversionQueue>,
+    Extension(runner): Extension<Arc<dyn CommandRunner>>,
+    Extension(config): Extension<Config>,
+    Extension(shutdown): Extension<Shutdown>,
+    Query(params): Query<ConversionParams>,
+    JsonBody(batch): JsonBody<BatchRequest>,
+) -> Result<(StatusCode, Json<BatchCreated>), AppError> {
+    ensure_accepting(&shutdown)?;
+    if batch.jobs.is_empty() || batch.jobs.len() > MAX_BATCH_JOBS {
+        return Err(AppError::BadRequest(format!(
+            "A batch holds between 1 and {MAX_BATCH_JOBS} jobs, got {}",
+            batch.jobs.len()
+        )));
+    }
+    // all or nothing, a batch is never left half started by an invalid entry
+    let mut filenames: HashMap<String, usize> = HashMap::new();
+    for (index, model_info) in batch.jobs.iter().enumerate() {
+        let in_batch = |err: AppError| match err {
+            AppError::BadRequest(msg) => AppError::BadRequest(format!("jobs[{index}]: {msg}")),
+            AppError::Conflict(msg) => AppError::Conflict(format!("jobs[{index}]: {msg}")),
+            err => err,
+        };
+        validate_model_info(model_info).map_err(in_batch)?;
+        ensure_output_free(&jobs, &queue, &config, model_info).map_err(in_batch)?;
+        // identical entries join the same job, others must not share a file, not even the
+        // converted model unless they may wait for each other
+        let key = model_info.conversion_key();
+        let entry_filenames = match queue.on_output_conflict() {
+            OutputConflict::Reject => model_info.output_filenames(),
+            OutputConflict::Wait => model_info.quantized_filenames(),
+        };
+        for filename in entry_filenames {
+            if let Some(&other) = filenames.get(&filename) {
+                if batch.jobs[other].conversion_key() != key {
+                    return Err(in_batch(AppError::Conflict(format!(
+                        "'{filename}' is written by jobs[{other}] too"
+                    ))));
+                }
+            }
+            filenames.insert(filename, index);
         }
-
-        // remove the original ggml model
-        std::fs::remove_file(model)?;
-    } else {
-        panic!("Not found quantizer");
+        check_requested_repo(&config, model_info)
+            .await
+            .map_err(in_batch)?;
     }
 
-    Ok(())
+    // a job started meanwhile could still take an output, the entries before it keep running
+    let job_ids: Vec<JobId> = batch
+        .jobs
+        .into_iter()
+        .map(|model_info| {
+            start_conversion(
+                jobs.clone(),
+                queue.clone(),
+                runner.clone(),
+                config.clone(),
+                &shutdown,
+                model_info,
+                params.force,
+            )
+        })
+        .collect::<Result<_, _>>()?;
+    let batch_id = batches.insert(job_ids.clone());
+    info!("Batch {batch_id} started with {} jobs", job_ids.len());
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(BatchCreated { batch_id, job_ids }),
+    ))
 }
 
-#[derive(Serialize)]
-struct Blog {
-    title: String,
-    author: String,
-    summary: String,
+// counts of the jobs of a batch per state
+async fn batch_status(
+    Extension(jobs): Extension<JobStore>,
+    Extension(batches): Extension<BatchStore>,
+    Path(id): Path<String>,
+) -> Result<Json<BatchStatus>, AppError> {
+    let batch_id: BatchId = id
+        .parse()
+        .map_err(|_| AppError::BadRequest(format!("Invalid batch id '{id}'")))?;
+    let job_ids = batches
+        .get(batch_id)
+        .ok_or_else(|| AppError::BatchNotFound(id.clone()))?;
+    Ok(Json(BatchStatus::new(batch_id, job_ids, &jobs)))
 }
 
-async fn blog_struct() -> Json<Blog> {
-    let blog = Blog {
-        title: "axum笔记(2)-response".to_string(),
-        author: "菩提树下的杨过".to_string(),
-        summary: "response各种示例".to_string(),
+/// 409 when another unfinished conversion writes one of the outputs of this one, or, unless
+/// conversions wait for each other, when another one holds any of its files
+fn ensure_output_free(
+    jobs: &JobStore,
+    queue: &ConversionQueue,
+    config: &Config,
+    model_info: &ModelInfo,
+) -> Result<(), AppError> {
+    if let Some((job_id, filename)) = jobs.taken_output(model_info) {
+        return Err(output_taken(job_id, &filename));
+    }
+    let held = match queue.on_output_conflict() {
+        OutputConflict::Reject => queue.held_output(&output_paths(config, model_info)),
+        OutputConflict::Wait => None,
+    };
+    match held {
+        Some(_) if jobs.running(model_info).is_some() => Ok(()),
+        Some(held) => Err(output_held(&held)),
+        None => Ok(()),
+    }
+}
+
+/// Where the conversion writes, the converted model included
+fn output_paths(config: &Config, model_info: &ModelInfo) -> Vec<std::path::PathBuf> {
+    model_info
+        .output_filenames()
+        .iter()
+        .map(|filename| config.outputs_dir.join(filename))
+        .collect()
+}
+
+fn output_held(path: &std::path::Path) -> AppError {
+    AppError::Conflict(format!(
+        "'{}' is already being written by another conversion",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    ))
+}
+
+fn output_taken(job_id: JobId, filename: &str) -> AppError {
+    AppError::Conflict(format!(
+        "'{filename}' is already being written by job {job_id}"
+    ))
+}
+
+/// Create the job of the conversion and run it in the background once the queue lets it. The
+/// same conversion already running is joined instead, its id returned.
+fn start_conversion(
+    jobs: JobStore,
+    queue: ConversionQueue,
+    runner: Arc<dyn CommandRunner>,
+    config: Config,
+    shutdown: &Shutdown,
+    model_info: ModelInfo,
+    force: bool,
+) -> Result<JobId, AppError> {
+    let outputs = output_paths(&config, &model_info);
+    // held from now on, the request is refused rather than waiting
+    let outputs_guard = match queue.on_output_conflict() {
+        OutputConflict::Reject => match queue.try_lock_outputs(outputs.clone()) {
+            Ok(guard) => Some(guard),
+            // joining the conversion writing them is fine
+            Err(_) if jobs.running(&model_info).is_some() => None,
+            Err(held) => return Err(output_held(&held)),
+        },
+        OutputConflict::Wait => None,
+    };
+
+    let job = Job::new(model_info.clone());
+    let ctx = job.context();
+    // the same conversion already running would race this one on its output files
+    let job_id = match jobs.insert_unless_running(job) {
+        Ok(job_id) => job_id,
+        Err(NotInserted::Running(job_id)) => {
+            info!("Same conversion as job {job_id}, which is still running");
+            return Ok(job_id);
+        }
+        Err(NotInserted::OutputTaken { job_id, filename }) => {
+            return Err(output_taken(job_id, &filename));
+        }
+    };
+    tokio::spawn(joblog::record(
+        ctx.events.clone(),
+        joblog::log_path(&config.logs_dir, job_id),
+        joblog::max_log_size(),
+    ));
+
+    let model = model_info.name.to_string();
+    let model_label = metrics::model_label(&model).to_string();
+    let quants: Vec<String> = model_info
+        .targets()
+        .iter()
+        .map(|(_, quant)| quant.to_string())
+        .collect();
+    // a done job may have failed targets, counted as failed conversions
+    let count_outcomes = {
+        let model_label = model_label.clone();
+        move |quant_statuses: &[job::QuantStatus]| {
+            for status in quant_statuses {
+                let name = match status.state {
+                    JobState::Done => "ggml_conversions_succeeded_total",
+                    _ => "ggml_conversions_failed_total",
+                };
+                let quant = status.quant.to_string();
+                metrics::increment_counter(name, &[("model", &model_label), ("quant", &quant)]);
+            }
+        }
+    };
+    let count_jobs = move |name| {
+        for quant in &quants {
+            metrics::increment_counter(name, &[("model", &model_label), ("quant", quant)]);
+        }
+    };
+    count_jobs("ggml_conversions_started_total");
+
+    // every event of the pipeline carries the job id, the model and the request starting it,
+    // the spawned task no longer being in the request's span
+    let span = info_span!(
+        "job",
+        id = %job_id,
+        model = %model_info.name,
+        request_id = tracing::field::Empty
+    );
+    if let Some(request_id) = request_id::current() {
+        span.record("request_id", request_id.as_str());
+    }
+
+    // a panic inside the pipeline surfaces as a `JoinError`, so the job never silently dies
+    let pipeline_jobs = jobs.clone();
+    // held until the job is over, the shutdown waits for it
+    let running = shutdown.track();
+    let callback_url = model_info.callback_url.clone();
+    let task = tokio::spawn(
+        async move {
+            // the job stays `Queued` until its output files and a slot free up
+            let _outputs = match outputs_guard {
+                Some(guard) => guard,
+                None => queue
+                    .lock_outputs(outputs, &ctx)
+                    .await
+                    .ok_or(PipelineError::Cancelled)?,
+            };
+            let _permit = queue.acquire(&ctx).await.ok_or(PipelineError::Cancelled)?;
+            let deadline = config.job_deadline;
+            if let Some(deadline) = deadline {
+                pipeline_jobs.set_deadline(job_id, job::now_secs() + deadline.as_secs());
+            }
+            let deadline_passed = async {
+                match deadline {
+                    Some(deadline) => {
+                        tokio::time::sleep(deadline).await;
+                        deadline
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            let token = ctx.token.clone();
+            let conversion = run_conversion(
+                pipeline_jobs,
+                &config,
+                &queue,
+                runner.as_ref(),
+                ctx,
+                model_info,
+                force,
+            );
+            tokio::pin!(conversion);
+            tokio::select! {
+                result = &mut conversion => result,
+                deadline = deadline_passed => {
+                    // the pipeline stops at the cancellation like for a cancelled job,
+                    // removing what it left half written
+                    token.cancel();
+                    let _ = conversion.await;
+                    Err(PipelineError::DeadlineExceeded(deadline))
+                }
+            }
+        }
+        .instrument(span.clone()),
+    );
+    tokio::spawn(
+        async move {
+            match task.await {
+                Ok(Ok(res)) => {
+                    info!("Job finished");
+                    count_outcomes(&res.quant_statuses);
+                    jobs.finish(
+                        job_id,
+                        res.download_urls,
+                        res.timings,
+                        res.size_bytes,
+                        res.quant_statuses,
+                    )
+                }
+                Ok(Err(PipelineError::Cancelled)) => {
+                    info!("Job cancelled");
+                    count_jobs("ggml_conversions_failed_total");
+                }
+                Ok(Err(PipelineError::DeadlineExceeded(deadline))) => {
+                    error!("Job failed: deadline of {deadline:?} exceeded");
+                    count_jobs("ggml_conversions_failed_total");
+                    jobs.set_error(
+                        job_id,
+                        format!(
+                            "DeadlineExceeded: the job was still running after {}s, see GGML_JOB_DEADLINE_SECS",
+                            deadline.as_secs()
+                        ),
+                        None,
+                    );
+                }
+                Ok(Err(PipelineError::Failed(err))) => {
+                    error!("Job failed: {err}");
+                    count_jobs("ggml_conversions_failed_total");
+                    jobs.set_error(job_id, err.to_string(), err.stderr());
+                }
+                Err(err) => {
+                    error!("Job failed: {err}");
+                    count_jobs("ggml_conversions_failed_total");
+                    jobs.set_error(job_id, err.to_string(), None);
+                }
+            }
+            if let (Some(url), Some(job)) = (callback_url, jobs.get(job_id)) {
+                webhook::notify(&url, &webhook::Callback::from(&job)).await;
+            }
+            drop(running);
+        }
+        .instrument(span),
+    );
+
+    Ok(job_id)
+}
+
+#[derive(Debug, Deserialize)]
+struct ListJobsParams {
+    state: Option<JobState>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct JobList {
+    /// Number of jobs waiting for a free conversion slot
+    queue_depth: usize,
+    max_concurrent: usize,
+    /// Jobs in the store, whatever the filter
+    job_count: usize,
+    /// Jobs kept at most, finished ones being evicted beyond it. Unset without a limit.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_jobs: Option<usize>,
+    jobs: Vec<JobSummary>,
+}
+
+/// First line printed by `<program> --version`, if it runs at all
+async fn tool_version(program: &str) -> Option<String> {
+    let output = tokio::process::Command::new(program)
+        .arg("--version")
+        .output()
+        .await
+        .ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout.lines().next().map(|line| line.trim().to_string())
+}
+
+/// Commit checked out in a llama.cpp directory
+async fn llama_cpp_head(dir: &std::path::Path) -> Option<String> {
+    let output = tokio::process::Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .current_dir(dir)
+        .output()
+        .await
+        .ok()?;
+    match output.status.success() {
+        true => Some(String::from_utf8_lossy(&output.stdout).trim().to_string()),
+        false => None,
+    }
+}
+
+/// What produced the outputs of this service, for support tickets
+async fn version(Extension(config): Extension<Config>) -> Json<Value> {
+    let checkout = config.llama_cpp_checkout(CODE_BASE);
+    let llama_cpp_head = match checkout.exists() {
+        true => llama_cpp_head(&checkout).await,
+        false => None,
+    };
+    Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "llama_cpp": {
+            "code_base": CODE_BASE,
+            "head": llama_cpp_head,
+        },
+        "python3": tool_version("python3").await,
+        "make": tool_version("make").await,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct QuantsParams {
+    /// llama.cpp tag or commit whose build is asked about, `CODE_BASE` when unset
+    llama_cpp_ref: Option<String>,
+}
+
+// quant types the quantize tool of a build accepts, the ones conversions take among them. A ref
+// not built yet gets the quants of this service.
+//eg: quants?llama_cpp_ref=b1696
+async fn supported_quants(
+    Extension(config): Extension<Config>,
+    Extension(cache): Extension<quants::QuantsCache>,
+    Query(params): Query<QuantsParams>,
+) -> Result<Json<Value>, AppError> {
+    let llama_cpp_ref = params.llama_cpp_ref.as_deref().unwrap_or(CODE_BASE);
+    validate_llama_cpp_ref(llama_cpp_ref).map_err(AppError::BadRequest)?;
+    let built = match build::find_quantizer(&config.llama_cpp_checkout(llama_cpp_ref)) {
+        Ok(quantizer) => cache.get(&quantizer).await,
+        Err(_) => None,
+    };
+    let (source, quants) = match built {
+        Some(quants) => ("build", quants),
+        None => (
+            "service",
+            QuantInfo::ALL.iter().map(ToString::to_string).collect(),
+        ),
+    };
+    let accepted: Vec<&String> = quants
+        .iter()
+        .filter(|quant| {
+            QuantInfo::ALL
+                .iter()
+                .any(|known| known.to_string().eq_ignore_ascii_case(quant))
+        })
+        .collect();
+    Ok(Json(json!({
+        "llama_cpp_ref": llama_cpp_ref,
+        "source": source,
+        "quants": quants,
+        "accepted": accepted,
+    })))
+}
+
+/// Prometheus scrape endpoint
+async fn metrics(Extension(jobs): Extension<JobStore>) -> impl IntoResponse {
+    let queue_depth = jobs.list(Some(JobState::Queued), None).len();
+    (
+        Headers([(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")]),
+        metrics::render(queue_depth),
+    )
+}
+
+// totals of the conversions in the job store
+//eg: stats
+async fn stats(Extension(jobs): Extension<JobStore>) -> Json<stats::Stats> {
+    Json(stats::Stats::new(&jobs.list(None, None)))
+}
+
+//eg: jobs?state=Converting&limit=50
+async fn list_jobs(
+    Extension(jobs): Extension<JobStore>,
+    Extension(queue): Extension<ConversionQueue>,
+    Query(params): Query<ListJobsParams>,
+) -> Json<JobList> {
+    let queue_depth = jobs.list(Some(JobState::Queued), None).len();
+    Json(JobList {
+        queue_depth,
+        max_concurrent: queue.max_concurrent(),
+        job_count: jobs.count(),
+        max_jobs: jobs.max_jobs(),
+        jobs: jobs
+            .list(params.state, params.limit)
+            .iter()
+            .map(JobSummary::from)
+            .collect(),
+    })
+}
+
+// job status, as JSON or, for `Accept: text/plain`, a single line
+//eg: curl -H 'Accept: text/plain' http://localhost:3000/jobs/<job id>
+async fn job_status(
+    Extension(jobs): Extension<JobStore>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let status = id
+        .parse::<JobId>()
+        .ok()
+        .and_then(|id| jobs.status(id))
+        .ok_or_else(|| AppError::JobNotFound(id.clone()))?;
+    Ok(match prefers_plain_text(&headers) {
+        true => format!("{}\n", status.line()).into_response(),
+        false => Json(status).into_response(),
+    })
+}
+
+/// Whether the `Accept` header ranks `text/plain` above `application/json`. Each type takes
+/// the quality of the most specific range matching it, JSON winning ties and a missing header.
+fn prefers_plain_text(headers: &HeaderMap) -> bool {
+    let ranges: Vec<(&str, f32)> = headers
+        .get_all(http::header::ACCEPT)
+        .iter()
+        .filter_map(|accept| accept.to_str().ok())
+        .flat_map(|accept| accept.split(','))
+        .map(|range| {
+            let mut params = range.split(';');
+            let media_type = params.next().unwrap_or_default().trim();
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|quality| quality.parse().ok())
+                .unwrap_or(1.0);
+            (media_type, quality)
+        })
+        .collect();
+    let quality = |media_type: &str| {
+        let (kind, _) = media_type.split_once('/').unwrap_or_default();
+        let wildcard = format!("{kind}/*");
+        [media_type, wildcard.as_str(), "*/*"]
+            .iter()
+            .find_map(|candidate| {
+                ranges
+                    .iter()
+                    .find(|(range, _)| range.eq_ignore_ascii_case(candidate))
+                    .map(|(_, quality)| *quality)
+            })
+            .unwrap_or(0.0)
+    };
+    quality("text/plain") > quality("application/json")
+}
+
+// cancel job
+async fn cancel_job(
+    Extension(jobs): Extension<JobStore>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    match id.parse::<JobId>().map(|job_id| jobs.cancel(job_id)) {
+        Ok(CancelOutcome::Cancelled) => {
+            Ok(Json(json!({ "job_id": id, "state": JobState::Cancelled })))
+        }
+        Ok(CancelOutcome::AlreadyFinished(state)) => Err(ApiError::new(
+            StatusCode::CONFLICT,
+            ErrorCode::JobFinished,
+            format!("Job '{id}' already finished as {state:?}"),
+        )
+        .with_details(json!({ "state": state }))),
+        Ok(CancelOutcome::NotFound) | Err(_) => Err(AppError::JobNotFound(id).into()),
+    }
+}
+
+// the log of a job as written so far, also once the job is over
+async fn job_log(
+    Extension(config): Extension<Config>,
+    Extension(jobs): Extension<JobStore>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let Ok(job_id) = id.parse::<JobId>() else {
+        return Err(AppError::JobNotFound(id));
+    };
+    let file = match StoredFile::open(&joblog::log_path(&config.logs_dir, job_id)).await {
+        Ok(file) => file,
+        // jobs of a previous run keep their logs, they may no longer be in the store
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return match jobs.get(job_id) {
+                Some(_) => Ok(ApiError::new(
+                    StatusCode::NOT_FOUND,
+                    ErrorCode::FileNotFound,
+                    format!("Job '{id}' has no log yet"),
+                )
+                .into_response()),
+                None => Err(AppError::JobNotFound(id)),
+            };
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let response =
+        Response::builder().header(http::header::CONTENT_TYPE, "text/plain; charset=utf-8");
+    serve_file(file, &headers, response, None).await
+}
+
+// all the outputs of a finished job in a tar archive
+//eg: curl -OJ http://localhost:3000/jobs/<job id>/bundle
+async fn job_bundle(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(jobs): Extension<JobStore>,
+    Extension(downloads): Extension<ActiveDownloads>,
+    Path(id): Path<String>,
+) -> Result<Response, AppError> {
+    let job = id
+        .parse::<JobId>()
+        .ok()
+        .and_then(|job_id| jobs.get(job_id))
+        .ok_or_else(|| AppError::JobNotFound(id.clone()))?;
+    if job.state != JobState::Done {
+        return Err(AppError::Conflict(format!(
+            "Job '{id}' is {:?}, only the outputs of a Done job are bundled",
+            job.state
+        )));
+    }
+
+    // the outputs still stored, uploaded ones may have been removed
+    let mut entries: Vec<bundle::Entry> = Vec::new();
+    for filename in job.model_info.output_filenames() {
+        // tracked first, the cleanup may remove it otherwise before it is open
+        let guard = downloads.track(&filename);
+        match storage.get(&filename).await {
+            Ok(file) => entries.push(bundle::Entry {
+                name: filename,
+                file,
+                guard,
+            }),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    if entries.is_empty() {
+        return Err(AppError::Gone(format!(
+            "The outputs of job '{id}' are no longer stored"
+        )));
+    }
+
+    let name = match &job.model_info.output_name {
+        Some(output_name) => naming::sanitize_output_name(output_name),
+        None => naming::repo_name(&job.model_info.name.to_string()).map(str::to_string),
+    }
+    .map_err(AppError::Internal)?;
+    let (len, archive) = bundle::tar(entries)?;
+    Ok(Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/x-tar")
+        .header(
+            http::header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{name}.tar\""),
+        )
+        .header(http::header::CONTENT_LENGTH, len)
+        .body(body::boxed(body::StreamBody::new(archive)))
+        .unwrap())
+}
+
+// live job logs as server-sent events, replaying what was already printed
+async fn job_events(
+    Extension(jobs): Extension<JobStore>,
+    Path(id): Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    let job = id
+        .parse::<JobId>()
+        .ok()
+        .and_then(|id| jobs.get(id))
+        .ok_or_else(|| AppError::JobNotFound(id.clone()))?;
+
+    let (history, receiver) = job.events.subscribe();
+    let finished = history.last().is_some_and(JobEvent::is_terminal);
+    let replay = stream::iter(history);
+    let live = stream::unfold(
+        (receiver, finished),
+        |(mut receiver, finished)| async move {
+            if finished {
+                return None;
+            }
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => {
+                        let finished = event.is_terminal();
+                        return Some((event, (receiver, finished)));
+                    }
+                    // a slow client misses some lines but keeps following the job
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        },
+    );
+
+    let events = replay.chain(live).map(|event| {
+        Ok(match event {
+            JobEvent::Log(line) => Event::default().event("log").data(line),
+            JobEvent::Done(download_urls) => Event::default()
+                .event("done")
+                .data(download_urls.join("\n")),
+            JobEvent::Error(error) => Event::default().event("error").data(error),
+        })
+    });
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+// the same events as `/jobs/{id}/events` over a WebSocket, the client may send `cancel` to
+// cancel the job
+async fn job_socket(
+    Extension(jobs): Extension<JobStore>,
+    Path(id): Path<String>,
+    mut req: http::Request<Body>,
+) -> Response {
+    let Some(job_id) = id
+        .parse::<JobId>()
+        .ok()
+        .filter(|job_id| jobs.get(*job_id).is_some())
+    else {
+        return AppError::JobNotFound(id).into_response();
+    };
+    let (response, upgrade) = match ws::handshake(&mut req) {
+        Ok(handshake) => handshake,
+        Err(err) => return err.into_response(),
+    };
+    tokio::spawn(async move {
+        let socket = match upgrade.await {
+            Ok(socket) => socket,
+            Err(err) => {
+                warn!("WebSocket upgrade for job {job_id} failed: {err}");
+                return;
+            }
+        };
+        if let Err(err) = follow_job(jobs, job_id, socket).await {
+            debug!("WebSocket of job {job_id} closed: {err}");
+        }
+    });
+    response
+}
+
+/// Message of the socket for an event, named like the server-sent one
+fn socket_message(event: &JobEvent) -> String {
+    match event {
+        JobEvent::Log(line) => json!({ "event": "log", "data": line }),
+        JobEvent::Done(download_urls) => json!({ "event": "done", "data": download_urls }),
+        JobEvent::Error(error) => json!({ "event": "error", "data": error }),
+    }
+    .to_string()
+}
+
+/// Send the state of the job, unless it is the one sent last
+async fn send_state<W: tokio::io::AsyncWrite + Unpin>(
+    writer: &mut ws::Writer<W>,
+    status: JobStatus,
+    sent: &mut Option<Value>,
+) -> std::io::Result<()> {
+    let state = json!({ "event": "state", "data": status });
+    if sent.as_ref() == Some(&state) {
+        return Ok(());
+    }
+    writer.send_text(&state.to_string()).await?;
+    *sent = Some(state);
+    Ok(())
+}
+
+/// Replay the state and the events of the job over the socket, then push them live until the
+/// job is over or the client leaves
+async fn follow_job(
+    jobs: JobStore,
+    job_id: JobId,
+    socket: hyper::upgrade::Upgraded,
+) -> std::io::Result<()> {
+    let (mut reader, mut writer) = ws::split(socket);
+    let Some(job) = jobs.get(job_id) else {
+        return writer.close(ws::CLOSE_NORMAL).await;
+    };
+    let mut sent_state = None;
+    if let Some(status) = jobs.status(job_id) {
+        send_state(&mut writer, status, &mut sent_state).await?;
+    }
+    let (history, mut receiver) = job.events.subscribe();
+    let mut finished = history.last().is_some_and(JobEvent::is_terminal);
+    for event in &history {
+        writer.send_text(&socket_message(event)).await?;
+    }
+
+    // reading a frame can't be interrupted by the events, the reader runs on its own
+    let (incoming_sender, mut incoming) = mpsc::channel(8);
+    let reading = tokio::spawn(async move {
+        loop {
+            let message = reader.recv().await;
+            let over = !matches!(
+                message,
+                Ok(ws::Message::Text(_) | ws::Message::Binary(_) | ws::Message::Ping(_))
+            );
+            if incoming_sender.send(message).await.is_err() || over {
+                break;
+            }
+        }
+    });
+    // the state changes without an event, e.g. the stage or the download progress
+    let mut refresh = tokio::time::interval(std::time::Duration::from_secs(1));
+    let mut client_left = false;
+    while !finished && !client_left {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) => {
+                    finished = event.is_terminal();
+                    writer.send_text(&socket_message(&event)).await?;
+                }
+                // a slow client misses some lines but keeps following the job
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => finished = true,
+            },
+            message = incoming.recv() => match message {
+                Some(Ok(ws::Message::Text(text))) if is_cancel(&text) => {
+                    info!("Job {job_id} cancelled over its WebSocket");
+                    // the cancellation is published as the final event of the job
+                    jobs.cancel(job_id);
+                }
+                Some(Ok(ws::Message::Ping(payload))) => writer.pong(&payload).await?,
+                Some(Ok(ws::Message::Text(_) | ws::Message::Binary(_))) => {}
+                Some(Ok(ws::Message::Close)) | None => client_left = true,
+                Some(Err(err)) => {
+                    reading.abort();
+                    writer.close(ws::CLOSE_PROTOCOL_ERROR).await?;
+                    return Err(err);
+                }
+            },
+            _ = refresh.tick() => {}
+        }
+        if let Some(status) = jobs.status(job_id) {
+            send_state(&mut writer, status, &mut sent_state).await?;
+        }
+    }
+
+    writer.close(ws::CLOSE_NORMAL).await?;
+    // the client answers with its own close frame before the connection is dropped
+    if !client_left {
+        let answer = async {
+            while let Some(Ok(message)) = incoming.recv().await {
+                if message == ws::Message::Close {
+                    break;
+                }
+            }
+        };
+        let _ = tokio::time::timeout(std::time::Duration::from_secs(5), answer).await;
+    }
+    reading.abort();
+    Ok(())
+}
+
+/// `cancel`, as plain text or `{"type": "cancel"}`
+fn is_cancel(text: &str) -> bool {
+    text.trim() == "cancel"
+        || serde_json::from_str::<Value>(text).is_ok_and(|message| message["type"] == "cancel")
+}
+
+/// URLs the converted files can be fetched from, signed when downloads require it, and
+/// absolute when the service has a public base url
+fn download_urls(config: &Config, paths: &[std::path::PathBuf]) -> Vec<String> {
+    paths
+        .iter()
+        .map(|path| {
+            let filename = path.file_name().unwrap_or_default().to_string_lossy();
+            let path = match &config.url_signer {
+                Some(signer) => signer.sign(&filename),
+                None => format!("/download/{}", filename),
+            };
+            config.public_url(&path)
+        })
+        .collect()
+}
+
+/// Parse a `Range` header against a file of `len` bytes into the inclusive byte range to send.
+///
+/// `Ok(None)` means the whole file should be sent, `Err(())` that the range can't be satisfied.
+fn parse_range(range: &str, len: u64) -> Result<Option<(u64, u64)>, ()> {
+    let spec = range.trim().strip_prefix("bytes=").ok_or(())?;
+    // several ranges would need a multipart body, sending the whole file is allowed instead
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = spec.split_once('-').ok_or(())?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", "") => return Err(()),
+        // the last `suffix` bytes
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().map_err(|_| ())?;
+            if suffix == 0 || len == 0 {
+                return Err(());
+            }
+            (len.saturating_sub(suffix), len - 1)
+        }
+        (start, "") => (start.parse().map_err(|_| ())?, len.saturating_sub(1)),
+        (start, end) => {
+            let start: u64 = start.parse().map_err(|_| ())?;
+            let end: u64 = end.parse().map_err(|_| ())?;
+            (start, end.min(len.saturating_sub(1)))
+        }
+    };
+    if start >= len || start > end {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+/// Only plain file names, nothing that could escape the outputs directory
+fn is_plain_filename(filename: &str) -> bool {
+    !(filename.is_empty()
+        || filename.contains("..")
+        || filename.contains('/')
+        || filename.contains('\\')
+        || std::path::Path::new(filename).is_absolute())
+}
+
+// remove an output and its digest to reclaim disk space
+async fn delete_output(
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(jobs): Extension<JobStore>,
+    Path(filename): Path<String>,
+) -> Result<Json<Value>, AppError> {
+    if !is_plain_filename(&filename) {
+        return Err(AppError::BadRequest(format!(
+            "Invalid file name '{}'",
+            filename
+        )));
+    }
+    if !storage.exists(&filename).await? {
+        return Err(AppError::FileNotFound(filename));
+    }
+    let writers: Vec<String> = jobs
+        .list(None, None)
+        .iter()
+        .filter(|job| {
+            !job.state.is_finished() && job.model_info.output_filenames().contains(&filename)
+        })
+        .map(|job| job.id.to_string())
+        .collect();
+    if !writers.is_empty() {
+        return Err(AppError::Conflict(format!(
+            "'{filename}' is being written by jobs {}",
+            writers.join(", ")
+        )));
+    }
+
+    let mut freed_bytes = 0;
+    for name in [filename.clone(), checksum::checksum_name(&filename)] {
+        freed_bytes += storage.delete(&name).await?.unwrap_or_default();
+    }
+    info!("Deleted output {:?}, freeing {freed_bytes} bytes", filename);
+    Ok(Json(
+        json!({ "filename": filename, "freed_bytes": freed_bytes }),
+    ))
+}
+
+// cancel every unfinished job of a model, the name is percent-encoded like for DELETE /models
+//eg: curl -X POST http://localhost:3000/models/openlm-research%2Fopen_llama_3b/cancel
+async fn cancel_model_jobs(
+    Extension(jobs): Extension<JobStore>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, AppError> {
+    let model = ModelType::from_name(name);
+    model.validate().map_err(AppError::BadRequest)?;
+    let repo_id = model.to_string();
+    let cancelled = jobs.cancel_all(|job| job.model_info.name.to_string() == repo_id);
+    if !cancelled.is_empty() {
+        let ids: Vec<String> = cancelled.iter().map(ToString::to_string).collect();
+        info!("Cancelled the jobs {} of '{repo_id}'", ids.join(", "));
+    }
+    Ok(Json(json!({ "name": repo_id, "cancelled": cancelled })))
+}
+
+// remove the downloaded repo of a model, the name is percent-encoded like for DELETE /models
+async fn delete_model_cache(
+    Extension(config): Extension<Config>,
+    Extension(jobs): Extension<JobStore>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, AppError> {
+    let model = ModelType::from_name(name);
+    model.validate().map_err(AppError::BadRequest)?;
+    let repo_id = model.to_string();
+    let repo_name = naming::repo_name(&repo_id).map_err(AppError::BadRequest)?;
+    let model_repo_dir = config.models_dir.join(repo_name);
+
+    let users: Vec<String> = jobs
+        .list(None, None)
+        .iter()
+        .filter(|job| !job.state.is_finished() && job.model_info.name.to_string() == repo_id)
+        .map(|job| job.id.to_string())
+        .collect();
+    if !users.is_empty() {
+        return Err(AppError::Conflict(format!(
+            "'{repo_id}' is used by jobs {}",
+            users.join(", ")
+        )));
+    }
+
+    // with what an interrupted HTTP download left behind, and the downloads of other revisions
+    let mut partial_dir = model_repo_dir.clone().into_os_string();
+    partial_dir.push(".partial");
+    let revision_prefix = format!("{repo_name}@");
+    let revision_dirs = std::fs::read_dir(&config.models_dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with(&revision_prefix)
+        })
+        .map(|entry| entry.path());
+    let dirs: Vec<std::path::PathBuf> = [model_repo_dir, partial_dir.into()]
+        .into_iter()
+        .chain(revision_dirs)
+        .filter(|dir| dir.is_dir())
+        .collect();
+    if dirs.is_empty() {
+        return Err(AppError::FileNotFound(repo_id));
+    }
+    let freed_bytes = tokio::task::spawn_blocking(move || {
+        let mut freed_bytes = 0;
+        for dir in dirs {
+            freed_bytes += disk::list_files(&dir)?
+                .iter()
+                .map(|(_, size)| size)
+                .sum::<u64>();
+            std::fs::remove_dir_all(&dir)?;
+        }
+        Ok::<_, std::io::Error>(freed_bytes)
+    })
+    .await
+    .map_err(|err| AppError::Internal(err.to_string()))??;
+    info!("Deleted the download of '{repo_id}', freeing {freed_bytes} bytes");
+    Ok(Json(json!({ "name": repo_id, "freed_bytes": freed_bytes })))
+}
+
+// Receive a tarball of a model's directory, conversions of the repo then use it instead of
+// downloading it. With a `Content-Range`, each request carries a part of the archive.
+async fn upload_model(
+    Extension(config): Extension<Config>,
+    Extension(uploads): Extension<upload::Uploads>,
+    Path(repo_id): Path<String>,
+    headers: HeaderMap,
+    mut body: BodyStream,
+) -> Result<Response, AppError> {
+    use tokio::io::AsyncWriteExt;
+
+    // the wildcard keeps the slash it follows
+    let model = ModelType::from_name(repo_id.trim_start_matches('/').to_string());
+    model.validate().map_err(AppError::BadRequest)?;
+    let repo_id = model.to_string();
+    let repo_name = naming::repo_name(&repo_id)
+        .map_err(AppError::BadRequest)?
+        .to_string();
+    let model_repo_dir = config.models_dir.join(&repo_name);
+    if model_repo_dir.exists() {
+        return Err(AppError::Conflict(format!(
+            "'{repo_id}' is already in the models directory, delete it with DELETE /models/{{name}}/cache first"
+        )));
+    }
+
+    let length = headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .ok_or_else(|| {
+            AppError::LengthRequired(
+                "The size of the archive must be given as Content-Length".to_string(),
+            )
+        })?;
+    let range = headers
+        .get(http::header::CONTENT_RANGE)
+        .map(|value| {
+            value
+                .to_str()
+                .map_err(|_| "Invalid Content-Range".to_string())
+                .and_then(upload::ContentRange::parse)
+        })
+        .transpose()
+        .map_err(AppError::BadRequest)?;
+    let (start, total) = match range {
+        Some(range) if range.len() != length => {
+            return Err(AppError::BadRequest(format!(
+                "Content-Range covers {} bytes but Content-Length is {length}",
+                range.len()
+            )))
+        }
+        Some(range) => (range.start, range.total),
+        None => (0, length),
+    };
+    let max_size = upload::max_upload_size();
+    if total > max_size {
+        return Err(AppError::PayloadTooLarge(format!(
+            "The archive is {total} bytes, more than the {max_size} bytes allowed (GGML_MAX_UPLOAD_MB)"
+        )));
+    }
+
+    let _upload = uploads
+        .begin(&repo_name)
+        .ok_or_else(|| AppError::Conflict(format!("'{repo_id}' is already being uploaded")))?;
+    let uploads_dir = upload::uploads_dir(&config.models_dir);
+    tokio::fs::create_dir_all(&uploads_dir).await?;
+    let archive = uploads_dir.join(format!("{repo_name}.tar"));
+    let received = match start {
+        0 => 0,
+        _ => tokio::fs::metadata(&archive)
+            .await
+            .map(|metadata| metadata.len())
+            .unwrap_or(0),
+    };
+    if start != received {
+        return Ok(ApiError::new(
+            StatusCode::CONFLICT,
+            ErrorCode::Conflict,
+            format!("Expected the part starting at byte {received}"),
+        )
+        .with_details(json!({ "received": received }))
+        .into_response());
+    }
+    // the rest of the archive, then its files once extracted
+    let available = disk::available_space(&uploads_dir)?;
+    disk::check_space(
+        &config.models_dir,
+        (total - start).saturating_add(total),
+        available,
+        disk::min_free_space(),
+    )?;
+
+    // streamed to disk as it arrives, what was received is kept if the connection drops
+    let mut file = match start {
+        0 => tokio::fs::File::create(&archive).await?,
+        _ => {
+            tokio::fs::OpenOptions::new()
+                .append(true)
+                .open(&archive)
+                .await?
+        }
+    };
+    let mut written = 0u64;
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                file.flush().await?;
+                return Err(AppError::BadRequest(format!(
+                    "The upload was interrupted after {} bytes: {err}",
+                    start + written
+                )));
+            }
+        };
+        written += chunk.len() as u64;
+        if written > length {
+            return Err(AppError::BadRequest(
+                "The body is longer than its Content-Length".to_string(),
+            ));
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    drop(file);
+    if start + written < total {
+        info!(
+            "Received {} of the {total} bytes of the archive of '{repo_id}'",
+            start + written
+        );
+        let payload = json!({ "name": repo_id, "received": start + written, "total": total });
+        return Ok((StatusCode::ACCEPTED, Json(payload)).into_response());
+    }
+
+    let dest = uploads_dir.join(&repo_name);
+    let extracted = tokio::task::spawn_blocking(move || {
+        if dest.exists() {
+            std::fs::remove_dir_all(&dest).map_err(|err| err.to_string())?;
+        }
+        let extracted = upload::extract(&archive, &dest, max_size)
+            .and_then(|()| upload::model_root(&dest).map_err(|err| err.to_string()))
+            .and_then(|root| upload::verify(&root).map(|()| root))
+            .and_then(|root| {
+                // a download of the repo may have finished meanwhile
+                if model_repo_dir.exists() {
+                    return Err(format!(
+                        "'{}' is already in the models directory",
+                        model_repo_dir.display()
+                    ));
+                }
+                std::fs::rename(&root, &model_repo_dir).map_err(|err| err.to_string())?;
+                disk::list_files(&model_repo_dir).map_err(|err| err.to_string())
+            });
+        // a rejected archive won't get any better by resuming its upload
+        let _ = std::fs::remove_dir_all(&dest);
+        let _ = std::fs::remove_file(&archive);
+        extracted
+    })
+    .await
+    .map_err(|err| AppError::Internal(err.to_string()))?
+    .map_err(AppError::BadRequest)?;
+
+    let size: u64 = extracted.iter().map(|(_, size)| size).sum();
+    info!(
+        "Uploaded '{repo_id}': {} files, {size} bytes",
+        extracted.len()
+    );
+    let payload = json!({ "name": repo_id, "files": extracted.len(), "size": size });
+    Ok((StatusCode::CREATED, Json(payload)).into_response())
+}
+
+/// Stream a converted file from the storage, honoring a single `Range`
+async fn download(
+    Extension(config): Extension<Config>,
+    Extension(storage): Extension<Arc<dyn Storage>>,
+    Extension(downloads): Extension<ActiveDownloads>,
+    Path(filename): Path<String>,
+    Query(download_token): Query<DownloadToken>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    if !is_plain_filename(&filename) {
+        return Err(AppError::BadRequest(format!(
+            "Invalid file name '{}'",
+            filename
+        )));
+    }
+    if let Some(signer) = &config.url_signer {
+        signer.verify(&filename, &download_token)?;
+    }
+
+    // the digest of an output, computed on the spot for outputs older than the digests
+    if let Some(output) = filename.strip_suffix(".sha256") {
+        if output.is_empty() || output.ends_with(".sha256") || !storage.exists(output).await? {
+            return Err(AppError::FileNotFound(filename));
+        }
+        let checksum = checksum::stored_checksum(storage.as_ref(), output).await?;
+        return Ok((
+            Headers([(http::header::CONTENT_TYPE, "text/plain; charset=utf-8")]),
+            format!("{checksum}\n"),
+        )
+            .into_response());
+    }
+
+    let file = match storage.get(&filename).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err(AppError::FileNotFound(filename))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let response = Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/octet-stream")
+        .header(
+            http::header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        );
+    // the cleanup leaves the output alone until it is sent
+    let guard = downloads.track(&filename);
+    serve_file(file, &headers, response, Some(guard)).await
+}
+
+/// Send the file, or the single `Range` of it the request asks for
+async fn serve_file(
+    file: StoredFile,
+    headers: &HeaderMap,
+    response: http::response::Builder,
+    guard: Option<DownloadGuard>,
+) -> Result<Response, AppError> {
+    let (len, mut reader) = (file.len, file.reader);
+
+    let range = match headers
+        .get(http::header::RANGE)
+        .and_then(|range| range.to_str().ok())
+    {
+        Some(range) => match parse_range(range, len) {
+            Ok(range) => range,
+            Err(()) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(http::header::CONTENT_RANGE, format!("bytes */{}", len))
+                    .body(body::boxed(body::Empty::new()))
+                    .unwrap())
+            }
+        },
+        None => None,
+    };
+
+    let response = response.header(http::header::ACCEPT_RANGES, "bytes");
+    let response = match range {
+        Some((start, end)) => {
+            use tokio::io::{AsyncReadExt, AsyncSeekExt};
+            reader.seek(std::io::SeekFrom::Start(start)).await?;
+            let stream =
+                tokio_util::io::ReaderStream::new(reader.take(end - start + 1)).map(move |chunk| {
+                    let _guard = &guard;
+                    chunk
+                });
+            response
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    http::header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end, len),
+                )
+                .header(http::header::CONTENT_LENGTH, end - start + 1)
+                .body(body::boxed(body::StreamBody::new(stream)))
+        }
+        None => {
+            let stream = tokio_util::io::ReaderStream::new(reader).map(move |chunk| {
+                let _guard = &guard;
+                chunk
+            });
+            response
+                .status(StatusCode::OK)
+                .header(http::header::CONTENT_LENGTH, len)
+                .body(body::boxed(body::StreamBody::new(stream)))
+        }
+    };
+    Ok(response.unwrap())
+}
+
+/// Why the pipeline stopped before producing its outputs
+#[derive(Debug)]
+enum PipelineError {
+    Cancelled,
+    /// Still running when its deadline passed, and cancelled
+    DeadlineExceeded(std::time::Duration),
+    Failed(AppError),
+}
+impl<E: Into<AppError>> From<E> for PipelineError {
+    fn from(err: E) -> Self {
+        PipelineError::Failed(err.into())
+    }
+}
+
+/// Remove files left behind by an interrupted stage
+fn remove_partial_outputs(paths: &[&std::path::Path]) {
+    for path in paths {
+        if path.exists() {
+            match std::fs::remove_file(path) {
+                Ok(()) => info!("Removed partial output {:?}", path),
+                Err(err) => warn!("Failed to remove partial output {:?}: {err}", path),
+            }
+        }
+    }
+}
+
+/// Path a stage writes to before the result is moved into place
+fn partial_path(path: &std::path::Path) -> std::path::PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// A previous run left a complete output at this path
+fn is_cached(path: &std::path::Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.len() > 0)
+}
+
+/// Fail fast when the model, its conversion and the quantized outputs wouldn't fit on disk
+async fn ensure_disk_space(
+    config: &Config,
+    model_info: &ModelInfo,
+    pending: &[(OutputFormat, QuantInfo, std::path::PathBuf)],
+) -> Result<(), AppError> {
+    let models_dir = config.models_dir.as_path();
+    let outputs_dir = config.outputs_dir.as_path();
+    std::fs::create_dir_all(models_dir)?;
+    let model_name = model_info.name.to_string();
+    let model_repo_dir = match model_info.name.local_path() {
+        Some(path) => local::resolve(config.local_models_dir.as_deref(), path)?,
+        None => models_dir.join(model_info.model_dir_name().map_err(AppError::BadRequest)?),
+    };
+
+    // (size of the download, size of the weights)
+    let (download_size, weights_size) = if model_repo_dir.exists() {
+        (
+            0,
+            disk::weights_size(
+                disk::list_files(&model_repo_dir)?
+                    .iter()
+                    .map(|(name, size)| (name.as_str(), *size)),
+            ),
+        )
+    } else {
+        let url = model_url(&model_name);
+        let files = match download::hf_repo(&url) {
+            Some(repo) => download::repo_files(
+                repo,
+                model_info.revision(),
+                model_info.hf_token().as_deref(),
+            )
+            .await
+            .ok(),
+            None => None,
+        };
+        match files {
+            Some(files) => (
+                files.iter().map(|(_, size)| size).sum(),
+                disk::weights_size(files.iter().map(|(name, size)| (name.as_str(), *size))),
+            ),
+            None => {
+                warn!("Unknown size for '{model_name}', skipping the disk space check");
+                return Ok(());
+            }
+        }
+    };
+
+    // each conversion is written as f16, each quantization shrinks it further
+    let mut formats: Vec<OutputFormat> = Vec::new();
+    for (format, _, _) in pending {
+        if !formats.contains(format) {
+            formats.push(*format);
+        }
+    }
+    let outputs_size = weights_size * formats.len() as u64
+        + pending
+            .iter()
+            // a NoQuant output is the conversion, already counted
+            .filter(|(_, quant_info, _)| quant_info.is_quantized())
+            .map(|(_, quant_info, _)| {
+                (weights_size as f64 * quant_info.bits_per_weight() / 16.0) as u64
+            })
+            .sum::<u64>();
+
+    let margin = disk::min_free_space();
+    if disk::same_filesystem(models_dir, outputs_dir)? {
+        let available = disk::available_space(outputs_dir)?;
+        disk::check_space(outputs_dir, download_size + outputs_size, available, margin)
+    } else {
+        disk::check_space(
+            models_dir,
+            download_size,
+            disk::available_space(models_dir)?,
+            margin,
+        )?;
+        disk::check_space(
+            outputs_dir,
+            outputs_size,
+            disk::available_space(outputs_dir)?,
+            margin,
+        )
+    }
+}
+
+/// Run the whole conversion pipeline for the given model, reporting each stage to the job store
+async fn run_conversion(
+    jobs: JobStore,
+    config: &Config,
+    queue: &ConversionQueue,
+    runner: &dyn CommandRunner,
+    ctx: JobContext,
+    model_info: ModelInfo,
+    force: bool,
+) -> Result<ConversionResult, PipelineError> {
+    let job_id = ctx.id;
+    let start = Instant::now();
+    let mut timings = Timings::default();
+
+    let outputs_dir = config.outputs_dir.as_path();
+    if !outputs_dir.exists() {
+        std::fs::create_dir_all(outputs_dir)?;
+    }
+    let repo_id = model_info.name.to_string();
+    let quantized_outfiles = model_info
+        .targets()
+        .into_iter()
+        .map(|(format, quant_info)| {
+            let quantized_filename = model_info
+                .quantized_filename(format, &quant_info)
+                .map_err(AppError::BadRequest)?;
+            Ok((format, quant_info, outputs_dir.join(quantized_filename)))
+        })
+        .collect::<Result<Vec<(OutputFormat, QuantInfo, std::path::PathBuf)>, AppError>>()?;
+    let outfiles: Vec<std::path::PathBuf> = quantized_outfiles
+        .iter()
+        .map(|(_, _, quantized_outfile)| quantized_outfile.clone())
+        .collect();
+
+    // a quantized file only appears once complete, so an existing one is safe to reuse. Not
+    // with an importance matrix, converter arguments or a revision, the file is named the same
+    // whether it was made with them. Nor a converted model, written in place by the converter.
+    let pending: Vec<(OutputFormat, QuantInfo, std::path::PathBuf)> = quantized_outfiles
+        .into_iter()
+        .filter(|(_, quant_info, quantized_outfile)| {
+            force
+                || !quant_info.is_quantized()
+                || model_info.imatrix.is_some()
+                || !model_info.converter_args.is_empty()
+                || model_info.revision.is_some()
+                || !is_cached(quantized_outfile)
+        })
+        .collect();
+    if pending.is_empty() {
+        info!("Reusing the existing outputs {:?}", outfiles);
+        let sha256s = checksums(&outfiles).await?;
+        let download_urls = match &config.s3 {
+            Some(s3) => upload_outputs(s3, &outfiles, &[]).await?,
+            None => download_urls(config, &outfiles),
+        };
+        return Ok(ConversionResult::new(
+            &model_info,
+            job::QuantStatus::all_done(&model_info),
+            download_urls,
+            sha256s,
+            total_size(&outfiles)?,
+            None,
+            Timings {
+                total_secs: start.elapsed().as_secs_f64(),
+                ..timings
+            },
+        ));
+    }
+
+    ensure_disk_space(config, &model_info, &pending).await?;
+    let fresh: Vec<std::path::PathBuf> = pending
+        .iter()
+        .map(|(_, _, quantized_outfile)| quantized_outfile.clone())
+        .collect();
+
+    jobs.update_state(job_id, JobState::Downloading);
+
+    // download and build llama.cpp
+    let llama_cpp_ref = model_info.llama_cpp_ref.as_deref().unwrap_or(CODE_BASE);
+    let stage = Instant::now();
+    let llama_cpp_dir = download_and_build_llama_cpp(config, llama_cpp_ref, runner, &ctx)
+        .instrument(info_span!("build_llama_cpp", llama_cpp_ref))
+        .await;
+    timings.build_secs = stage.elapsed().as_secs_f64();
+    if ctx.token.is_cancelled() {
+        return Err(PipelineError::Cancelled);
+    }
+    let llama_cpp_dir = llama_cpp_dir?;
+    debug!("llama.cpp directory: {:?}", llama_cpp_dir);
+
+    // another conversion of the model would clone into the same directory, held until the
+    // model is converted
+    let model_guard = queue
+        .lock_model(
+            naming::repo_name(&repo_id).map_err(AppError::BadRequest)?,
+            &ctx,
+        )
+        .await
+        .ok_or(PipelineError::Cancelled)?;
+
+    // download llama2 models
+    let stage = Instant::now();
+    let model_repo_dir = download_llama2_models(config, &model_info, runner, &jobs, &ctx)
+        .instrument(info_span!("download_model"))
+        .await;
+    timings.download_secs = stage.elapsed().as_secs_f64();
+    if ctx.token.is_cancelled() {
+        return Err(PipelineError::Cancelled);
+    }
+    let model_repo_dir = model_repo_dir?;
+    debug!("model directory: {:?}", model_repo_dir);
+    if let Some(commit) = model_commit(runner, &model_repo_dir, &ctx).await {
+        info!("Converting {repo_id} at {commit}");
+        jobs.set_commit(job_id, commit);
+    }
+
+    // caught before converting, the converter would fail late or write garbage
+    let architecture = Architecture::detect(&model_repo_dir).map_err(AppError::BadRequest)?;
+    jobs.set_architecture(job_id, architecture.name());
+    let ggml_args = architecture::converter_args(&model_repo_dir, &model_info.converter_args);
+    if ggml_args.len() > model_info.converter_args.len() {
+        let inferred = ggml_args[model_info.converter_args.len()..].join(" ");
+        info!("Inferred the converter arguments {inferred} from the model's files");
+        ctx.events.log(format!(
+            "Inferred the converter arguments {inferred} from the model's files"
+        ));
+    }
+
+    // the model is converted once to each format some pending quant is in
+    let conversions = model_info
+        .formats()
+        .into_iter()
+        .filter(|format| pending.iter().any(|(pending, _, _)| pending == format))
+        .map(|format| {
+            let converter = Converter {
+                format,
+                script: config.converter_script(format),
+                args: match format {
+                    OutputFormat::Ggml => &ggml_args,
+                    OutputFormat::Gguf => &model_info.converter_args,
+                },
+                env: &config.converter_env,
+            };
+            let outfile = naming::ggml_filename(&repo_id, format).map_err(AppError::BadRequest)?;
+            Ok((converter, outputs_dir.join(outfile)))
+        })
+        .collect::<Result<Vec<(Converter, std::path::PathBuf)>, AppError>>()?;
+    let intermediates: Vec<&std::path::Path> = conversions
+        .iter()
+        .map(|(_, outfile)| outfile.as_path())
+        .collect();
+
+    if model_info.require_safetensors {
+        let pickle_allowed = conversions
+            .iter()
+            .all(|(converter, _)| converter.format == OutputFormat::Gguf);
+        download::verify_safetensors(&model_repo_dir, pickle_allowed)
+            .map_err(AppError::BadRequest)?;
+    }
+
+    for (converter, _) in &conversions {
+        if architecture.is_supported(converter.format, &llama_cpp_dir.join(converter.script)) {
+            continue;
+        }
+        let error = match (converter.format, architecture.is_llama_family()) {
+            (OutputFormat::Ggml, None) => format!(
+                "The model's config.json has neither `architectures` nor `model_type`, so it \
+                 can't be told to be of the Llama family, the only one {} converts. Try the Gguf \
+                 format.",
+                converter.script
+            ),
+            (OutputFormat::Ggml, Some(_)) => format!(
+                "The model's architecture ({}) isn't of the Llama family, the only one {} \
+                 converts. Try the Gguf format.",
+                architecture.name(),
+                converter.script
+            ),
+            (OutputFormat::Gguf, _) => format!(
+                "The model's architecture ({}) isn't supported by {} of llama.cpp {llama_cpp_ref}",
+                architecture.name(),
+                converter.script
+            ),
+        };
+        return Err(AppError::BadRequest(error).into());
+    }
+
+    // convert the target model to ggml
+    jobs.update_state(job_id, JobState::Converting);
+    let stage = Instant::now();
+    for (converter, outfile) in &conversions {
+        let converted = convert_to_ggml(
+            runner,
+            llama_cpp_dir.as_path(),
+            model_repo_dir.as_path(),
+            *converter,
+            outfile.as_path(),
+            &jobs,
+            &ctx,
+        )
+        .instrument(info_span!("convert", format = %converter.format))
+        .await;
+        if ctx.token.is_cancelled() {
+            remove_partial_outputs(&intermediates);
+            return Err(PipelineError::Cancelled);
+        }
+        converted?;
+    }
+    drop(model_guard);
+    timings.convert_secs = stage.elapsed().as_secs_f64();
+    let base_ggml_size_bytes = intermediates
+        .iter()
+        .map(|outfile| std::fs::metadata(outfile).map(|metadata| metadata.len()))
+        .sum::<std::io::Result<u64>>()?;
+
+    // quantize the ggml model once per requested quant, reusing the conversion
+    if pending
+        .iter()
+        .any(|(_, quant_info, _)| quant_info.is_quantized())
+    {
+        jobs.update_state(job_id, JobState::Quantizing);
+    }
+    let stage = Instant::now();
+    let imatrix = match &model_info.imatrix {
+        // only taken with the Gguf format alone
+        Some(imatrix) => {
+            let computed = compute_imatrix(
+                config,
+                runner,
+                llama_cpp_dir.as_path(),
+                &model_info,
+                imatrix,
+                intermediates[0],
+                &ctx,
+            )
+            .instrument(info_span!("imatrix"))
+            .await;
+            if ctx.token.is_cancelled() {
+                remove_partial_outputs(&intermediates);
+                return Err(PipelineError::Cancelled);
+            }
+            Some(computed?)
+        }
+        None => None,
+    };
+    // unless failing fast, a target that fails is reported and the others still run
+    let mut quant_statuses = job::QuantStatus::all_done(&model_info);
+    let mut failed: Vec<(std::path::PathBuf, AppError)> = Vec::new();
+    for (format, quant_info, quantized_outfile) in pending {
+        let outfile = conversions
+            .iter()
+            .find(|(converter, _)| converter.format == format)
+            .map(|(_, outfile)| outfile.as_path())
+            .expect("every pending format is converted");
+        let quantized = match quant_info.is_quantized() {
+            // the conversion is the output as is
+            false => Ok(()),
+            true => quantize_ggml(
+                runner,
+                llama_cpp_dir.as_path(),
+                outfile,
+                quant_info.clone(),
+                imatrix.as_deref(),
+                quantized_outfile.as_path(),
+                &ctx,
+            )
+            .instrument(info_span!("quantize", %format, quant = %quant_info))
+            .await
+            .map_err(AppError::from),
+        };
+        if ctx.token.is_cancelled() {
+            let partial = partial_path(&quantized_outfile);
+            remove_partial_outputs(&[intermediates.as_slice(), &[partial.as_path()]].concat());
+            return Err(PipelineError::Cancelled);
+        }
+        let done = match quantized {
+            Ok(()) if model_info.verify => {
+                let verify = Instant::now();
+                let verified = verify_output(
+                    runner,
+                    llama_cpp_dir.as_path(),
+                    quantized_outfile.as_path(),
+                    &ctx,
+                )
+                .instrument(info_span!("verify", %format, quant = %quant_info))
+                .await
+                .map_err(AppError::from);
+                timings.verify_secs += verify.elapsed().as_secs_f64();
+                if ctx.token.is_cancelled() {
+                    remove_partial_outputs(&intermediates);
+                    return Err(PipelineError::Cancelled);
+                }
+                verified
+            }
+            quantized => quantized,
+        };
+
+        if let Err(err) = done {
+            if model_info.fail_fast {
+                if !config.keep_intermediate {
+                    remove_partial_outputs(&intermediates);
+                }
+                return Err(err.into());
+            }
+            error!("{format} {quant_info} failed, going on with the other targets: {err}");
+            ctx.events.log(format!(
+                "{format} {quant_info} failed, going on with the other targets: {err}"
+            ));
+            if let Some(status) = quant_statuses
+                .iter_mut()
+                .find(|status| status.is_for(format, &quant_info))
+            {
+                status.state = JobState::Failed;
+                status.error = Some(err.to_string());
+            }
+            failed.push((quantized_outfile, err));
+        }
+    }
+    // nothing to report but the failure when no target is left
+    if failed.len() == outfiles.len() {
+        if !config.keep_intermediate {
+            remove_partial_outputs(&intermediates);
+        }
+        let (_, err) = failed.remove(0);
+        return Err(err.into());
+    }
+    let outfiles: Vec<std::path::PathBuf> = outfiles
+        .into_iter()
+        .filter(|outfile| !failed.iter().any(|(failed, _)| failed == outfile))
+        .collect();
+    timings.quantize_secs = stage.elapsed().as_secs_f64() - timings.verify_secs;
+
+    // the original ggml models are only the input of the quantizations, all done by now, unless
+    // they are outputs themselves
+    for outfile in &intermediates {
+        if outfiles.iter().any(|output| output == outfile) {
+            continue;
+        }
+        if config.keep_intermediate {
+            info!("Keeping the intermediate model {}", outfile.display());
+        } else {
+            let size = std::fs::metadata(outfile)?.len();
+            std::fs::remove_file(outfile)?;
+            info!(
+                "Removed the intermediate model {}, reclaiming {size} bytes",
+                outfile.display()
+            );
+        }
+    }
+
+    let sha256s = checksums(&outfiles).await?;
+    let download_urls = match &config.s3 {
+        Some(s3) => upload_outputs(s3, &outfiles, &fresh).await?,
+        // signed last, a signed url is only valid for so long after the conversion
+        None => download_urls(config, &outfiles),
+    };
+
+    timings.total_secs = start.elapsed().as_secs_f64();
+    info!(
+        "Done in {:.1}s: download {:.1}s, build {:.1}s, convert {:.1}s, quantize {:.1}s, verify {:.1}s",
+        timings.total_secs,
+        timings.download_secs,
+        timings.build_secs,
+        timings.convert_secs,
+        timings.quantize_secs,
+        timings.verify_secs
+    );
+
+    Ok(ConversionResult::new(
+        &model_info,
+        quant_statuses,
+        download_urls,
+        sha256s,
+        total_size(&outfiles)?,
+        Some(base_ggml_size_bytes),
+        timings,
+    ))
+}
+
+/// Upload the outputs to the bucket and return their download urls there. The fresh outputs
+/// are always uploaded, the others only when the bucket doesn't have them yet.
+async fn upload_outputs(
+    s3: &S3Config,
+    outfiles: &[std::path::PathBuf],
+    fresh: &[std::path::PathBuf],
+) -> Result<Vec<String>, AppError> {
+    let mut download_urls = Vec::with_capacity(outfiles.len());
+    for outfile in outfiles {
+        let filename = outfile.file_name().unwrap_or_default().to_string_lossy();
+        let key = s3.object_key(&filename);
+        let (s3, outfile, is_fresh) = (s3.clone(), outfile.clone(), fresh.contains(outfile));
+        // the S3 client blocks, uploads of several GB must not hold up the runtime
+        let upload = tokio::task::spawn_blocking(move || {
+            if is_fresh || !s3.exists(&key)? {
+                let start = Instant::now();
+                s3.upload(&outfile, &key)?;
+                info!(
+                    "Uploaded {key} to {} in {:?} seconds",
+                    s3.bucket,
+                    start.elapsed().as_secs()
+                );
+            }
+            Ok::<_, String>(s3.download_url(&key))
+        });
+        let download_url = upload
+            .await
+            .map_err(|err| AppError::Internal(err.to_string()))?
+            .map_err(|err| AppError::Internal(format!("Uploading {filename} failed: {err}")))?;
+        download_urls.push(download_url);
+    }
+    Ok(download_urls)
+}
+
+/// Sum of the sizes of the files, in bytes
+fn total_size(paths: &[std::path::PathBuf]) -> std::io::Result<u64> {
+    paths
+        .iter()
+        .map(|path| std::fs::metadata(path).map(|metadata| metadata.len()))
+        .sum()
+}
+
+/// SHA-256 of each output file
+async fn checksums(outfiles: &[std::path::PathBuf]) -> std::io::Result<Vec<String>> {
+    let mut sha256s = Vec::with_capacity(outfiles.len());
+    for outfile in outfiles {
+        sha256s.push(checksum::file_checksum(outfile).await?);
+    }
+    Ok(sha256s)
+}
+
+// From https://github.com/ggerganov/llama.cpp/tags
+const CODE_BASE: &str = "d2a4366";
+
+const LLAMA_CPP_REPO: &str = "https://github.com/ggerganov/llama.cpp";
+
+/// Check a llama.cpp ref only contains characters allowed in a tag or commit hash. It is
+/// passed to git, where a leading `-` would read as an option, and names the checkout
+/// directory, where `..` would escape it.
+fn validate_llama_cpp_ref(llama_cpp_ref: &str) -> Result<(), String> {
+    let valid = !llama_cpp_ref.is_empty()
+        && llama_cpp_ref.len() <= 64
+        && !llama_cpp_ref.starts_with(['.', '-'])
+        && !llama_cpp_ref.contains("..")
+        && llama_cpp_ref
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    match valid {
+        true => Ok(()),
+        false => Err(format!("Invalid llama_cpp_ref '{llama_cpp_ref}'")),
+    }
+}
+
+/// Held while llama.cpp is cloned and built, so concurrent jobs never build the same checkout
+/// twice at once
+static BUILD_LOCK: Lazy<tokio::sync::Mutex<()>> = Lazy::new(|| tokio::sync::Mutex::new(()));
+
+// convert a tiny model through the real pipeline, 503 unless every stage passes
+async fn selftest(
+    Extension(cache): Extension<SelfTestCache>,
+    Extension(config): Extension<Config>,
+    Extension(runner): Extension<Arc<dyn CommandRunner>>,
+) -> (StatusCode, Json<SelfTestReport>) {
+    let report = cache
+        .get_or_run(|| run_selftest(&config, runner.as_ref()))
+        .await;
+    match report.passed {
+        true => (StatusCode::OK, Json(report)),
+        false => (StatusCode::SERVICE_UNAVAILABLE, Json(report)),
+    }
+}
+
+/// Build the default llama.cpp ref, then convert and quantize the tiny model with it the way a
+/// job would. Nothing of it is left behind.
+async fn run_selftest(config: &Config, runner: &dyn CommandRunner) -> SelfTestReport {
+    let ctx = JobContext {
+        id: JobId::new(),
+        token: tokio_util::sync::CancellationToken::new(),
+        events: job::JobEvents::default(),
+    };
+    // names no Hugging Face repo can have, no job writes them
+    let model_dir = config.models_dir.join(".selftest");
+    let converted = config.outputs_dir.join(".selftest.gguf");
+    let quantized = config.outputs_dir.join(".selftest-q4_0.gguf");
+
+    let mut stages = selftest::Stages::default();
+    // the other stages are skipped without a build, the path is never used then
+    let llama_cpp_dir = stages
+        .run(
+            "build",
+            download_and_build_llama_cpp(config, CODE_BASE, runner, &ctx),
+        )
+        .await
+        .unwrap_or_default();
+    stages
+        .run("convert", async {
+            selftest::write_fixture(&model_dir)?;
+            std::fs::create_dir_all(&config.outputs_dir)?;
+            let converter = Converter {
+                format: OutputFormat::Gguf,
+                script: config.converter_script(OutputFormat::Gguf),
+                args: &[],
+                env: &config.converter_env,
+            };
+            if !llama_cpp_dir.join(converter.script).is_file() {
+                return Err(format!("llama.cpp '{CODE_BASE}' has no {}", converter.script).into());
+            }
+            convert_to_ggml(
+                runner,
+                &llama_cpp_dir,
+                &model_dir,
+                converter,
+                &converted,
+                &JobStore::default(),
+                &ctx,
+            )
+            .await
+        })
+        .await;
+    stages
+        .run(
+            "quantize",
+            quantize_ggml(
+                runner,
+                &llama_cpp_dir,
+                &converted,
+                QuantInfo::Q4,
+                None,
+                &quantized,
+                &ctx,
+            ),
+        )
+        .await;
+
+    let _ = std::fs::remove_dir_all(&model_dir);
+    remove_partial_outputs(&[converted.as_path(), quantized.as_path()]);
+    let report = stages.report();
+    match report.passed {
+        true => info!("Self-test passed"),
+        false => warn!("Self-test failed: {:?}", report.stages),
+    }
+    report
+}
+
+/// Download and build the given llama.cpp ref, each ref gets its own `llama.cpp-<ref>` directory
+async fn download_and_build_llama_cpp(
+    config: &Config,
+    llama_cpp_ref: &str,
+    runner: &dyn CommandRunner,
+    ctx: &JobContext,
+) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+    validate_llama_cpp_ref(llama_cpp_ref)?;
+
+    let llama_cpp_dir = config.llama_cpp_checkout(llama_cpp_ref);
+
+    let _build = tokio::select! {
+        guard = BUILD_LOCK.lock() => guard,
+        _ = ctx.token.cancelled() => return Err("Build cancelled".into()),
+    };
+
+    // download
+    if !llama_cpp_dir.exists() {
+        std::fs::create_dir_all(&config.llama_cpp_dir)?;
+        let clone = CommandSpec::new("git", config.llama_cpp_dir.as_path())
+            .arg("clone")
+            .arg(LLAMA_CPP_REPO)
+            .arg(llama_cpp_dir.as_path());
+        let output = runner.run(clone, Stage::Clone, ctx).await?;
+        debug!("git clone status: {:?}", output.status);
+        if !output.status.success() {
+            return Err(format!("Failed to clone {LLAMA_CPP_REPO}").into());
+        }
+
+        let checkout = CommandSpec::new("git", llama_cpp_dir.as_path())
+            .arg("checkout")
+            .arg(llama_cpp_ref);
+        let output = runner.run(checkout, Stage::Clone, ctx).await?;
+        debug!("git checkout status: {:?}", output.status);
+        if !output.status.success() {
+            // don't leave a checkout of the wrong ref behind for the next run to pick up
+            std::fs::remove_dir_all(llama_cpp_dir.as_path())?;
+            return Err(format!("Failed to check out llama.cpp '{llama_cpp_ref}'").into());
+        }
+    } else {
+        info!("{:?} directory already exists", llama_cpp_dir);
+    }
+
+    // build
+    if let Ok(quantizer) = build::find_quantizer(&llama_cpp_dir) {
+        info!("Already build llama.cpp, found {:?}", quantizer);
+    } else {
+        // build llama.cpp
+        let make = config.build.make_command(&llama_cpp_dir);
+        info!(
+            "Building llama.cpp with `make {}`",
+            make.args
+                .iter()
+                .map(|arg| arg.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+        let output = runner.run(make, Stage::Build, ctx).await;
+        if ctx.token.is_cancelled() {
+            return Err("Build cancelled".into());
+        }
+        info!("make status: {:?}", output?.status);
+
+        // check if the build process is successful
+        let quantizer = build::find_quantizer(&llama_cpp_dir)?;
+        let check = CommandSpec::new(quantizer.as_os_str(), llama_cpp_dir.as_path()).arg("--help");
+        let output = runner.run(check, Stage::Build, ctx).await?;
+        info!("quantize --help status: {:?}", output.status);
+    }
+
+    Ok(llama_cpp_dir)
+}
+
+/// Fetch the model repo, over HTTP file by file for Hugging Face repos, with `git clone`
+/// for other hosts or when the HTTP download fails. Local models are taken where they are.
+async fn download_llama2_models(
+    config: &Config,
+    model_info: &ModelInfo,
+    runner: &dyn CommandRunner,
+    jobs: &JobStore,
+    ctx: &JobContext,
+) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+    let mut success = false;
+    let mut retries = 0;
+    let max_retries = clone_retries();
+    let mut last_output = String::new();
+
+    if let Some(path) = model_info.name.local_path() {
+        let model_dir = local::resolve(config.local_models_dir.as_deref(), path)?;
+        info!("Converting the local model {}", model_dir.display());
+        return Ok(model_dir);
+    }
+
+    let models_dir = config.models_dir.as_path();
+    if !models_dir.exists() {
+        std::fs::create_dir_all(models_dir)?;
+    }
+
+    let model_name = model_info.name.to_string();
+    let model_repo_dir = models_dir.join(model_info.model_dir_name()?);
+    // a repo downloaded over HTTP is checked for changes, which costs a single request when
+    // the revision hasn't moved
+    if let Some(repo) = download::hf_repo(&model_url(&model_name)) {
+        if download::has_etags(&model_repo_dir) {
+            let hf_token = model_info.hf_token();
+            let updated = download::update_repo(
+                repo,
+                model_info.revision(),
+                &model_repo_dir,
+                hf_token.as_deref(),
+                jobs,
+                ctx,
+            )
+            .await;
+            match updated {
+                Ok(()) => info!("Model '{}' is up to date", model_info.name),
+                Err(_) if ctx.token.is_cancelled() => return Err("Download cancelled".into()),
+                Err(err) if model_repo_dir.exists() => {
+                    warn!(
+                        "Checking '{}' for changes failed: {err}, using it as downloaded",
+                        model_info.name
+                    );
+                    ctx.events.log(format!(
+                        "Checking for changes failed: {err}, using the model as downloaded"
+                    ));
+                }
+                Err(err) => {
+                    warn!(
+                        "Updating '{}' failed: {err}, downloading it again",
+                        model_info.name
+                    );
+                    ctx.events.log(format!(
+                        "Updating the model failed: {err}, downloading it again"
+                    ));
+                }
+            }
+        }
+    }
+    if model_repo_dir.exists() {
+        info!("Model '{}' already exists", model_info.name);
+    } else {
+        let url = model_url(&model_info.name.to_string());
+
+        info!("Downloading from {url}...");
+
+        let hf_token = model_info.hf_token();
+        let start = Instant::now();
+        if let Some(repo) = download::hf_repo(&url) {
+            let downloaded = download::download_repo(
+                repo,
+                model_info.revision(),
+                &model_repo_dir,
+                hf_token.as_deref(),
+                jobs,
+                ctx,
+            )
+            .await;
+            match downloaded {
+                Ok(()) => {
+                    // every file of the repo was fetched, a git clone wouldn't find the shards
+                    // its index lists but it lacks
+                    if let Err(err) = download::verify_model_dir(&model_repo_dir) {
+                        std::fs::remove_dir_all(&model_repo_dir)?;
+                        return Err(
+                            format!("Downloading '{}' failed: {err}", model_info.name).into()
+                        );
+                    }
+                    success = true;
+                    info!("HTTP download succeeded!");
+                    metrics::observe_seconds(
+                        "ggml_download_duration_seconds",
+                        &[("method", "http")],
+                        start.elapsed().as_secs_f64(),
+                    );
+                }
+                Err(_) if ctx.token.is_cancelled() => return Err("Download cancelled".into()),
+                Err(err) => {
+                    warn!("HTTP download failed: {err}, falling back to git clone");
+                    ctx.events.log(format!(
+                        "HTTP download failed: {err}, falling back to git clone"
+                    ));
+                }
+            }
+        }
+
+        while !success && retries < max_retries {
+            if retries > 0 {
+                let delay = clone_backoff(retries);
+                info!(
+                    "({retries}/{max_retries}) Retrying git clone in {:.1}s...",
+                    delay.as_secs_f64()
+                );
+                ctx.events
+                    .log(format!("Retrying git clone in {:.1}s", delay.as_secs_f64()));
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = ctx.token.cancelled() => return Err("Git clone cancelled".into()),
+                }
+            }
+
+            // passed through the environment rather than the url, so it never appears in
+            // the arguments, the logs or the remote saved in the clone
+            let authenticate = |command: CommandSpec| match hf_token
+                .as_deref()
+                .filter(|_| download::hf_repo(&url).is_some())
+            {
+                Some(token) => with_git_token(command, token),
+                None => command,
+            };
+
+            // a clone interrupted on the previous attempt is resumed rather than started over
+            let mut output = None;
+            if retries > 0 && model_repo_dir.join(".git").is_dir() {
+                info!("({retries}) Resuming the interrupted git clone...");
+                ctx.events.log("Resuming the interrupted git clone");
+                output = resume_clone(runner, &model_repo_dir, &authenticate, ctx).await;
+                if ctx.token.is_cancelled() {
+                    return Err("Git clone cancelled".into());
+                }
+                if output.is_none() {
+                    warn!("The interrupted git clone can't be resumed, cloning again");
+                    ctx.events
+                        .log("The interrupted git clone can't be resumed, cloning again");
+                }
+            }
+            let output = match output {
+                Some(output) => output,
+                None => {
+                    // a failed clone can leave a directory behind, which makes the next one fail
+                    if model_repo_dir.exists() {
+                        std::fs::remove_dir_all(&model_repo_dir)?;
+                    }
+                    info!("({retries}) Git clone llama2 models...");
+                    let clone = CommandSpec::new("git", models_dir).arg("clone");
+                    // the revision is checked out in place of the default branch below
+                    let clone = match &model_info.revision {
+                        Some(_) => clone.arg("--no-checkout"),
+                        None => clone,
+                    };
+                    let clone = clone.arg(&url).arg(model_repo_dir.as_path());
+                    runner.run(authenticate(clone), Stage::Clone, ctx).await
+                }
+            };
+            let output = match (output, &model_info.revision) {
+                (Ok(output), Some(revision)) if output.status.success() => {
+                    info!("({retries}) Checking out {revision}...");
+                    // the revision was validated, it can't pass for an option
+                    let checkout = CommandSpec::new("git", model_repo_dir.as_path())
+                        .arg("checkout")
+                        .arg(revision)
+                        .arg("--");
+                    runner.run(authenticate(checkout), Stage::Clone, ctx).await
+                }
+                (output, _) => output,
+            };
+            if ctx.token.is_cancelled() {
+                return Err("Git clone cancelled".into());
+            }
+
+            match output {
+                Ok(output) if output.status.success() => {
+                    // retrying won't fetch what the clone didn't, LFS is missing or the repo
+                    // isn't a model
+                    if let Err(err) = download::verify_model_dir(&model_repo_dir) {
+                        std::fs::remove_dir_all(&model_repo_dir)?;
+                        return Err(
+                            format!("Downloading '{}' failed: {err}", model_info.name).into()
+                        );
+                    }
+                    success = true;
+                    info!("Git clone succeeded!");
+                    metrics::observe_seconds(
+                        "ggml_download_duration_seconds",
+                        &[("method", "git")],
+                        start.elapsed().as_secs_f64(),
+                    );
+                }
+                // retrying won't help without valid credentials
+                Ok(output) if is_auth_failure(&output.stderr) => {
+                    let reason = match hf_token {
+                        Some(_) => "the Hugging Face token was rejected",
+                        None => "the repo needs a Hugging Face token (`hf_token` or HF_TOKEN)",
+                    };
+                    return Err(Box::new(AppError::Unauthorized(format!(
+                        "Downloading '{}' failed: {reason}",
+                        model_info.name
+                    ))));
+                }
+                _ => {
+                    retries += 1;
+                    warn!("Git clone failed, retry again... output: {:?}", output);
+                    last_output = match output {
+                        Ok(output) => String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                        Err(err) => err.to_string(),
+                    };
+                }
+            }
+        }
+
+        if !success {
+            error!("Git clone failed after {max_retries} attempts.");
+            return Err(format!(
+                "Downloading '{}' failed after {max_retries} git clone attempts: {last_output}",
+                model_info.name
+            )
+            .into());
+        }
+    }
+
+    Ok(model_repo_dir)
+}
+
+/// Commit of the repo the model was downloaded from: recorded by the HTTP download, or the
+/// HEAD of a clone. `None` for models that came without history.
+async fn model_commit(
+    runner: &dyn CommandRunner,
+    model_repo_dir: &std::path::Path,
+    ctx: &JobContext,
+) -> Option<String> {
+    if let Some(commit) = download::downloaded_commit(model_repo_dir) {
+        return Some(commit);
+    }
+    if !model_repo_dir.join(".git").is_dir() {
+        return None;
+    }
+    let head = CommandSpec::new("git", model_repo_dir)
+        .arg("rev-parse")
+        .arg("HEAD");
+    match runner.run(head, Stage::Clone, ctx).await {
+        Ok(output) if output.status.success() => {
+            Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        }
+        _ => None,
+    }
+}
+
+/// The git command with an `Authorization` header for the Hugging Face token, passed through
+/// git's config environment
+fn with_git_token(command: CommandSpec, token: &str) -> CommandSpec {
+    command
+        .env("GIT_CONFIG_COUNT", "1")
+        .env("GIT_CONFIG_KEY_0", "http.extraHeader")
+        .env(
+            "GIT_CONFIG_VALUE_0",
+            format!("Authorization: Bearer {token}"),
+        )
+}
+
+/// Finish a clone interrupted on an earlier attempt instead of starting over: fetch what it
+/// lacks, complete its checkout, then pull its LFS files. Gives the output of the step that
+/// failed or of the last one, and `None` when the clone is past resuming, it never got as far
+/// as a commit.
+async fn resume_clone(
+    runner: &dyn CommandRunner,
+    repo_dir: &std::path::Path,
+    authenticate: &(dyn Fn(CommandSpec) -> CommandSpec + Sync),
+    ctx: &JobContext,
+) -> Option<std::io::Result<std::process::Output>> {
+    let git = |args: &[&str]| {
+        args.iter()
+            .fold(CommandSpec::new("git", repo_dir), |command, arg| {
+                command.arg(arg)
+            })
+    };
+    let head = git(&["rev-parse", "--verify", "--quiet", "HEAD"]);
+    let mut last = match runner.run(head, Stage::Clone, ctx).await {
+        Ok(output) if output.status.success() => output,
+        _ => return None,
+    };
+    let steps = [
+        authenticate(git(&["fetch", "origin"])),
+        // the LFS files are left as pointers, the pull replaces them
+        git(&["reset", "--hard", "HEAD"]).env("GIT_LFS_SKIP_SMUDGE", "1"),
+        authenticate(git(&["lfs", "pull"])),
+    ];
+    for step in steps {
+        match runner.run(step, Stage::Clone, ctx).await {
+            Ok(output) if output.status.success() => last = output,
+            failed => return Some(failed),
+        }
+    }
+    Some(Ok(last))
+}
+
+/// Number of `git clone` attempts, from `GGML_CLONE_RETRIES`, 3 by default
+fn clone_retries() -> u32 {
+    std::env::var("GGML_CLONE_RETRIES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|&retries| retries > 0)
+        .unwrap_or(3)
+}
+
+/// Delay before the given retry of `git clone`: `GGML_CLONE_BACKOFF_SECS` (2 by default),
+/// doubled on each retry up to 5 minutes, plus up to 50% of jitter so the jobs failing
+/// together don't all retry together
+fn clone_backoff(retry: u32) -> std::time::Duration {
+    let base = std::env::var("GGML_CLONE_BACKOFF_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(2.0_f64);
+    let delay = (base * 2f64.powi(retry.saturating_sub(1) as i32)).min(300.0);
+    // the clock's sub-second digits are random enough to spread the retries out
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos())
+        .unwrap_or_default();
+    let jitter = delay * 0.5 * (nanos as f64 / 1e9);
+    std::time::Duration::from_secs_f64((delay + jitter).max(0.0))
+}
+
+/// Whether git failed because the remote refused the credentials or asked for some
+fn is_auth_failure(stderr: &[u8]) -> bool {
+    let stderr = String::from_utf8_lossy(stderr);
+    [
+        "Authentication failed",
+        "could not read Username",
+        "401",
+        "403",
+    ]
+    .iter()
+    .any(|pattern| stderr.contains(pattern))
+}
+
+async fn convert_to_ggml(
+    runner: &dyn CommandRunner,
+    llama_cpp_dir: &std::path::Path,
+    model_repo_dir: &std::path::Path,
+    converter: Converter<'_>,
+    outfile: &std::path::Path,
+    jobs: &JobStore,
+    ctx: &JobContext,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let format = converter.format;
+    let script = llama_cpp_dir.join(converter.script);
+    debug!("converter: {:?}", script.as_path());
+
+    debug!("out_file: {:?}", outfile);
+    if outfile.exists() {
+        std::fs::remove_file(outfile)?;
+    }
+
+    if script.exists() && script.is_file() {
+        // the repo may predate the checks of the download, convert.py fails cryptically on
+        // a missing shard
+        download::verify_model_dir(model_repo_dir)?;
+        let shards = download::shards(model_repo_dir)?;
+        if !shards.is_empty() {
+            info!("Sharded checkpoint, in {} files", shards.len());
+        }
+
+        info!(
+            "Start to convert {} to {}...",
+            model_repo_dir
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy(),
+            format
+        );
+
+        let start = Instant::now();
+        let convert = converter
+            .args
+            .iter()
+            .fold(
+                CommandSpec::new("python3", llama_cpp_dir).arg(script),
+                |convert, arg| convert.arg(arg),
+            )
+            .arg(model_repo_dir)
+            .arg("--outfile")
+            .arg(outfile);
+        let convert = converter
+            .env
+            .vars()
+            .iter()
+            .fold(convert, |convert, (name, value)| convert.env(name, value));
+        info!("Running {convert}");
+        ctx.events.log(format!("Running {convert}"));
+        if !converter.env.is_empty() {
+            info!("With {}", converter.env);
+            ctx.events.log(format!("With {}", converter.env));
+        }
+        // the converter's lines reach the job's events as they are printed, the progress is
+        // read from there
+        let (_, mut events) = ctx.events.subscribe();
+        let track_progress = async {
+            loop {
+                match events.recv().await {
+                    Ok(JobEvent::Log(line)) => {
+                        if let Some(progress_pct) = convert_progress(&line) {
+                            jobs.set_progress_pct(ctx.id, progress_pct);
+                        }
+                    }
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+            std::future::pending::<()>().await
+        };
+        let output = tokio::select! {
+            output = runner.run(convert, Stage::Convert, ctx) => output?,
+            _ = track_progress => unreachable!("tracking the progress never completes"),
+        };
+        let elapsed = Instant::now() - start;
+
+        match output.status.success() {
+            true => {
+                info!("The conversion took {:?} seconds.", elapsed.as_secs());
+                metrics::observe_seconds(
+                    "ggml_stage_duration_seconds",
+                    &[("stage", "convert"), ("format", &format.to_string())],
+                    elapsed.as_secs_f64(),
+                );
+            }
+            false => {
+                error!("Conversion failed!");
+                return Err(SubprocessError::new("Conversion", &output).into());
+            }
+        }
+    } else {
+        return Err(format!("The converter {} is missing", script.display()).into());
+    }
+
+    Ok(())
+}
+
+/// Percentage of the tensors written, from a progress line of convert.py such as
+/// `[ 12/291] Writing tensor layers.1.attention.wk.weight | size 4096 x 4096 | type F16`.
+///
+/// `None` for any other line, the converter to GGUF doesn't count its tensors.
+fn convert_progress(line: &str) -> Option<u8> {
+    let counts = line.trim_start().strip_prefix('[')?;
+    let (counts, _) = counts.split_once(']')?;
+    let (done, total) = counts.split_once('/')?;
+    let done: u64 = done.trim().parse().ok()?;
+    let total: u64 = total.trim().parse().ok()?;
+    if total == 0 || done > total {
+        return None;
+    }
+    Some((done * 100 / total) as u8)
+}
+
+/// Compute the importance matrix of the converted model over the calibration dataset, or reuse
+/// the one a previous conversion computed
+async fn compute_imatrix(
+    config: &Config,
+    runner: &dyn CommandRunner,
+    llama_cpp_dir: &std::path::Path,
+    model_info: &ModelInfo,
+    imatrix: &imatrix::ImatrixConfig,
+    model: &std::path::Path,
+    ctx: &JobContext,
+) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+    let llama_cpp_ref = model_info.llama_cpp_ref.as_deref().unwrap_or(CODE_BASE);
+    let imatrix_dir = config.models_dir.join("imatrix");
+    std::fs::create_dir_all(&imatrix_dir)?;
+    let matrix = imatrix.matrix_path(&imatrix_dir, &model_info.model_dir_name()?, llama_cpp_ref);
+    if is_cached(&matrix) {
+        info!("Reusing the importance matrix {:?}", matrix);
+        return Ok(matrix);
+    }
+
+    let tool = build::find_tool(llama_cpp_dir, "imatrix").map_err(|err| {
+        format!("llama.cpp '{llama_cpp_ref}' can't compute importance matrices: {err}")
+    })?;
+    if model_info.gpu.is_some() && !gpu::supports_offload(&tool).await {
+        return Err(format!(
+            "llama.cpp '{llama_cpp_ref}' was built without GPU support, build it with a GPU backend through GGML_MAKE_FLAGS to use gpu, e.g. LLAMA_CUBLAS=1"
+        )
+        .into());
+    }
+    let dataset = imatrix.dataset_path(&imatrix_dir).await?;
+    info!("Computing the importance matrix over {:?}...", dataset);
+
+    let start = Instant::now();
+    let tmp_matrix = partial_path(&matrix);
+    let mut command = CommandSpec::new(tool.as_os_str(), llama_cpp_dir)
+        .arg("-m")
+        .arg(model)
+        .arg("-f")
+        .arg(&dataset)
+        .arg("-o")
+        .arg(&tmp_matrix);
+    if let Some(chunks) = imatrix.chunks {
+        command = command.arg("--chunks").arg(chunks.to_string());
+    }
+    if let Some(gpu) = &model_info.gpu {
+        info!("Offloading {} layers to the GPU", gpu.layers);
+        command = gpu.args().into_iter().fold(command, CommandSpec::arg);
+    }
+    match runner.run(command, Stage::Imatrix, ctx).await {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => {
+            error!("Computing the importance matrix failed!");
+            remove_partial_outputs(&[tmp_matrix.as_path()]);
+            return Err(SubprocessError::new("Importance matrix", &output).into());
+        }
+        Err(err) => {
+            remove_partial_outputs(&[tmp_matrix.as_path()]);
+            return Err(err.into());
+        }
+    }
+    std::fs::rename(&tmp_matrix, &matrix)?;
+    info!(
+        "The importance matrix took {:?} seconds.",
+        start.elapsed().as_secs()
+    );
+    Ok(matrix)
+}
+
+/// Quantize the ggml model
+async fn quantize_ggml(
+    runner: &dyn CommandRunner,
+    llama_cpp_dir: &std::path::Path,
+    model: &std::path::Path,
+    quant_info: QuantInfo,
+    imatrix: Option<&std::path::Path>,
+    outfile: &std::path::Path,
+    ctx: &JobContext,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let quantizer = build::find_quantizer(llama_cpp_dir)?;
+    debug!("quantizer: {:?}", quantizer.as_path());
+
+    // quantize into a temporary file, `outfile` only shows up once it is complete
+    let tmp_outfile = partial_path(outfile);
+    if tmp_outfile.exists() {
+        std::fs::remove_file(&tmp_outfile)?;
+    }
+
+    // quantize
+    info!(
+        "Start to quantize {} ...",
+        model.file_name().unwrap_or_default().to_string_lossy()
+    );
+
+    let start = Instant::now();
+    let mut quantize = CommandSpec::new(quantizer.as_os_str(), llama_cpp_dir);
+    if let Some(imatrix) = imatrix {
+        quantize = quantize.arg("--imatrix").arg(imatrix);
+    }
+    let quantize = quantize
+        .arg(model)
+        .arg(&tmp_outfile)
+        .arg(quant_info.to_string());
+    let output = runner.run(quantize, Stage::Quantize, ctx).await?;
+    let elapsed = Instant::now() - start;
+
+    match output.status.success() {
+        true => {
+            info!("The quantization took {:?} seconds.", elapsed.as_secs());
+            metrics::observe_seconds(
+                "ggml_stage_duration_seconds",
+                &[("stage", "quantize"), ("quant", &quant_info.to_string())],
+                elapsed.as_secs_f64(),
+            );
+            // the digest of a previous output no longer applies
+            let checksum_file = checksum::checksum_path(outfile);
+            if checksum_file.exists() {
+                std::fs::remove_file(&checksum_file)?;
+            }
+            std::fs::rename(&tmp_outfile, outfile)?;
+        }
+        false => {
+            error!("Quantization failed!");
+            if tmp_outfile.exists() {
+                std::fs::remove_file(&tmp_outfile)?;
+            }
+            return Err(SubprocessError::new("Quantization", &output).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Load the quantized model in llama.cpp and generate a token: a quantization that completes
+/// can still write a file llama.cpp refuses. A file that doesn't load is removed, later
+/// conversions would reuse it otherwise.
+async fn verify_output(
+    runner: &dyn CommandRunner,
+    llama_cpp_dir: &std::path::Path,
+    model: &std::path::Path,
+    ctx: &JobContext,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let loader = build::find_loader(llama_cpp_dir)
+        .map_err(|err| format!("The outputs can't be verified: {err}"))?;
+    info!(
+        "Loading {} to verify it...",
+        model.file_name().unwrap_or_default().to_string_lossy()
+    );
+
+    let start = Instant::now();
+    let load = CommandSpec::new(loader.as_os_str(), llama_cpp_dir)
+        .arg("--model")
+        .arg(model)
+        .arg("-n")
+        .arg("1")
+        .arg("-p")
+        .arg("hi");
+    let output = runner.run(load, Stage::Verify, ctx).await?;
+    let elapsed = Instant::now() - start;
+
+    match output.status.success() {
+        true => {
+            info!("The verification took {:?} seconds.", elapsed.as_secs());
+            metrics::observe_seconds(
+                "ggml_stage_duration_seconds",
+                &[("stage", "verify")],
+                elapsed.as_secs_f64(),
+            );
+            Ok(())
+        }
+        false => {
+            error!("Loading the quantized model failed!");
+            remove_partial_outputs(&[model]);
+            Err(SubprocessError::new("Loading the quantized model", &output).into())
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Blog {
+    title: String,
+    author: String,
+    summary: String,
+}
+
+async fn blog_struct() -> Json<Blog> {
+    let blog = Blog {
+        title: "axum笔记(2)-response".to_string(),
+        author: "菩提树下的杨过".to_string(),
+        summary: "response各种示例".to_string(),
     };
     Json(blog)
 }
@@ -475,11 +3874,12 @@ struct CustomError {
 
 impl IntoResponse for CustomError {
     fn into_response(self) -> Response {
-        let body = body::boxed(body::Full::from(self.msg));
-        Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(body)
-            .unwrap()
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::Internal,
+            self.msg,
+        )
+        .into_response()
     }
 }
 
@@ -489,12 +3889,94 @@ async fn custom_error() -> Result<&'static str, CustomError> {
     })
 }
 
-#[tokio::main]
-async fn main() {
-    println!("Service started on port 3000");
+/// CORS policy for browser frontends.
+///
+/// Any origin is allowed unless `GGML_CORS_ORIGINS` lists the allowed ones, comma separated.
+fn cors_layer() -> CorsLayer {
+    let origins: Vec<HeaderValue> = std::env::var("GGML_CORS_ORIGINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .filter_map(|origin| match HeaderValue::from_str(origin) {
+            Ok(origin) => Some(origin),
+            Err(_) => {
+                warn!("Ignoring the invalid CORS origin '{origin}'");
+                None
+            }
+        })
+        .collect();
+
+    if origins.is_empty() {
+        return CorsLayer::new()
+            .allow_origin(cors::Any)
+            .allow_methods(cors::Any)
+            .allow_headers(cors::Any);
+    }
+    info!("Allowing cross-origin requests from {:?}", origins);
+    CorsLayer::new()
+        .allow_origin(cors::Origin::list(origins))
+        .allow_methods(vec![
+            http::Method::GET,
+            http::Method::POST,
+            http::Method::DELETE,
+        ])
+        .allow_headers(vec![
+            http::header::CONTENT_TYPE,
+            http::header::RANGE,
+            HeaderName::from_static(request_id::HEADER),
+        ])
+        .expose_headers(vec![
+            http::header::CONTENT_DISPOSITION,
+            http::header::CONTENT_RANGE,
+            http::header::ACCEPT_RANGES,
+            HeaderName::from_static(request_id::HEADER),
+        ])
+}
+
+/// The job store, persisted to the file named by `GGML_JOBS_FILE` when set, keeping at most
+/// `GGML_MAX_JOBS` jobs, 10000 by default and 0 for no limit
+fn job_store() -> Result<JobStore, String> {
+    let store = match std::env::var("GGML_JOBS_FILE") {
+        Ok(path) => {
+            info!("Persisting jobs to {path}");
+            JobStore::persistent(JsonFilePersistence::new(&path)).map_err(|err| {
+                format!(
+                    "Failed to load the persisted jobs from {path}: {err}, fix or move the file \
+                     aside to start without them"
+                )
+            })?
+        }
+        Err(_) => JobStore::default(),
+    };
+    let max_jobs = std::env::var("GGML_MAX_JOBS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(job::DEFAULT_MAX_JOBS);
+    Ok(store.with_max_jobs((max_jobs > 0).then_some(max_jobs)))
+}
+
+/// What the handlers share, each given to them as an extension
+#[derive(Clone)]
+struct Services {
+    jobs: JobStore,
+    queue: ConversionQueue,
+    config: Config,
+    runner: Arc<dyn CommandRunner>,
+    storage: Arc<dyn Storage>,
+    shutdown: Shutdown,
+    downloads: ActiveDownloads,
+    /// Shared by `/ggml` and `/batch`, a batch counting as a single request
+    rate_limiter: rate_limit::RateLimiter,
+    idempotency_keys: IdempotencyKeys,
+}
 
-    // our router
-    let app = Router::new()
+/// The routes of the service with their middleware
+fn router(services: Services) -> Router {
+    let rate_limiter = services.rate_limiter;
+    let batch_rate_limiter = rate_limiter.clone();
+    Router::new()
+        .route("/", get(ui::index))
         .route("/plain_text", get(plain_text))
         .route("/plain_text_string", get(plain_text_string))
         .route("/bytes", get(bytes))
@@ -512,11 +3994,200 @@ async fn main() {
         .route("/blog_cn", get(blog_struct_cn))
         .route("/custom_error", get(custom_error))
         .route("/query", get(query))
-        .route("/ggml", post(json_request));
+        // only conversions are limited, everything else is cheap to answer
+        .route(
+            "/ggml",
+            post(json_request.layer(middleware::from_fn(move |req, next| {
+                rate_limit::limit(req, next, rate_limiter.clone())
+            }))),
+        )
+        // a batch counts as a single request
+        .route(
+            "/batch",
+            post(batch_request.layer(middleware::from_fn(move |req, next| {
+                rate_limit::limit(req, next, batch_rate_limiter.clone())
+            }))),
+        )
+        .route("/batch/:id", get(batch_status))
+        .route("/validate", post(validate_request))
+        .route("/models", get(list_models).post(register_model))
+        .route("/models/:name", delete(delete_model))
+        .route("/models/:name/quants", get(list_quants))
+        .route("/models/:name/cache", delete(delete_model_cache))
+        .route("/models/:name/cancel", post(cancel_model_jobs))
+        .route("/upload/*repo_id", post(upload_model))
+        .route("/outputs/:filename", delete(delete_output))
+        .route("/health", get(health::health))
+        .route("/metrics", get(metrics))
+        .route("/stats", get(stats))
+        .route("/version", get(version))
+        .route("/quants", get(supported_quants))
+        .route("/ready", get(health::ready))
+        .route("/selftest", post(selftest))
+        .route("/jobs", get(list_jobs))
+        .route("/jobs/:id", get(job_status).delete(cancel_job))
+        .route("/jobs/:id/events", get(job_events))
+        .route("/jobs/:id/log", get(job_log))
+        .route("/jobs/:id/bundle", get(job_bundle))
+        .route("/jobs/:id/ws", get(job_socket))
+        .route("/download/:filename", get(download))
+        .route("/api-docs/openapi.json", get(openapi::openapi_json))
+        .route("/swagger-ui", get(openapi::swagger_ui))
+        .layer(Extension(services.jobs))
+        .layer(Extension(BatchStore::default()))
+        .layer(Extension(SelfTestCache::default()))
+        .layer(Extension(upload::Uploads::default()))
+        .layer(Extension(quants::QuantsCache::default()))
+        .layer(Extension(services.downloads))
+        .layer(Extension(services.idempotency_keys))
+        .layer(Extension(services.shutdown))
+        .layer(Extension(services.queue))
+        .layer(Extension(services.config))
+        .layer(Extension(services.runner))
+        .layer(Extension(services.storage))
+        // rejected extractors and unknown routes answer with the same JSON as the handlers
+        .layer(middleware::from_fn(error::standardize))
+        .layer(cors_layer())
+        .layer(TraceLayer::new_for_http())
+        // outermost, the logs of the layers above carry the request id too
+        .layer(middleware::from_fn(request_id::propagate))
+}
 
-    // run it with hyper on localhost:3000
-    axum::Server::bind(&"0.0.0.0:3000".parse().unwrap())
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+#[tokio::main]
+async fn main() {
+    // log level from RUST_LOG, `info` by default
+    logging::init();
+
+    let args = match cli::Args::parse(std::env::args().skip(1)) {
+        Ok(args) if args.help => {
+            print!("{}", cli::USAGE);
+            return;
+        }
+        Ok(args) => args,
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(2);
+        }
+    };
+    let (config, addr) =
+        match Config::load(&args).and_then(|config| Ok((config, args.bind_address()?))) {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                error!("{err}");
+                std::process::exit(1);
+            }
+        };
+    info!("{:?}", config);
+    let queue = match ConversionQueue::from_env() {
+        Ok(queue) => queue,
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    };
+    let timeouts = match Timeouts::from_env() {
+        Ok(timeouts) => timeouts,
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    // seed the model registry, path from GGML_MODELS_FILE or ./models.json
+    let models_file =
+        std::env::var("GGML_MODELS_FILE").unwrap_or_else(|_| String::from("./models.json"));
+    match load_models_file(std::path::Path::new(&models_file)) {
+        Ok(Some(models)) => {
+            info!("Loaded {} models from {models_file}", models.len());
+            *MODELS.lock().unwrap() = models;
+        }
+        Ok(None) => info!("No models file at {models_file}, using the built-in models"),
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    }
+
+    info!("Service listening on {addr}");
+
+    let jobs = match job_store() {
+        Ok(jobs) => jobs,
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    };
+    let shutdown = Shutdown::default();
+    let downloads = ActiveDownloads::default();
+    let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(config.outputs_dir.clone()));
+
+    // drop the jobs past their time to live, GGML_JOB_TTL_SECS=0 keeps them all
+    match Cleanup::from_env() {
+        Some(cleanup) => {
+            info!("Removing the jobs finished over {:?} ago", cleanup.ttl);
+            tokio::spawn(cleanup.run(
+                jobs.clone(),
+                downloads.clone(),
+                storage.clone(),
+                config.logs_dir.clone(),
+            ));
+        }
+        None => info!("Keeping finished jobs forever"),
+    }
+
+    let app = router(Services {
+        jobs: jobs.clone(),
+        queue,
+        config,
+        runner: Arc::new(ProcessRunner::new(timeouts)),
+        storage,
+        shutdown: shutdown.clone(),
+        downloads,
+        rate_limiter: rate_limit::RateLimiter::from_env(),
+        idempotency_keys: IdempotencyKeys::from_env(),
+    });
+
+    // run it with hyper on localhost:3000, it stops accepting connections once the shutdown
+    // begins. Event streams can stay open for hours, so the shutdown doesn't wait for it.
+    let mut server = tokio::spawn(
+        axum::Server::bind(&addr)
+            .serve(app.into_make_service_with_connect_info::<std::net::SocketAddr, _>())
+            .with_graceful_shutdown(shutdown.clone().begun()),
+    );
+    tokio::select! {
+        served = &mut server => {
+            error!("Server stopped: {served:?}");
+            std::process::exit(1);
+        }
+        _ = shutdown::signal() => {}
+    }
+    shutdown.begin();
+
+    // nothing is lost by dropping jobs that haven't started
+    let ids = |ids: Vec<JobId>| {
+        ids.iter()
+            .map(ToString::to_string)
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+    let queued = jobs.interrupt(|job| job.state == JobState::Queued);
+    if !queued.is_empty() {
+        info!("Interrupted the queued jobs {}", ids(queued));
+    }
+
+    let timeout = shutdown::timeout();
+    info!("Shutting down, waiting up to {timeout:?} for the running jobs");
+    if !shutdown.drain(timeout).await {
+        let interrupted = jobs.interrupt(|_| true);
+        warn!(
+            "Interrupted the jobs {}, which were still running",
+            ids(interrupted)
+        );
+        // the pipelines remove their partial outputs as they stop
+        if !shutdown.drain(std::time::Duration::from_secs(10)).await {
+            warn!("Some jobs are still stopping, their partial outputs may remain");
+        }
+    }
+    jobs.flush().await;
+    info!("Shut down");
 }
@@ -0,0 +1,165 @@
+//! In-process counters and histograms, rendered in the Prometheus text format by `GET /metrics`
+
+use once_cell::sync::Lazy;
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+
+/// Upper bounds of the duration histograms, in seconds. Conversions take minutes to hours.
+const BUCKETS: [f64; 9] = [
+    10.0, 30.0, 60.0, 300.0, 600.0, 1800.0, 3600.0, 7200.0, 14400.0,
+];
+
+/// `(name, help, type)` of each metric, in the order they are rendered
+const DESCRIPTIONS: [(&str, &str, &str); 6] = [
+    (
+        "ggml_conversions_started_total",
+        "Conversions requested, per model and quant",
+        "counter",
+    ),
+    (
+        "ggml_conversions_succeeded_total",
+        "Conversions that produced their output, per model and quant",
+        "counter",
+    ),
+    (
+        "ggml_conversions_failed_total",
+        "Conversions that failed or were cancelled, per model and quant",
+        "counter",
+    ),
+    (
+        "ggml_stage_duration_seconds",
+        "Time spent converting and quantizing",
+        "histogram",
+    ),
+    (
+        "ggml_download_duration_seconds",
+        "Time spent downloading a model, per download method",
+        "histogram",
+    ),
+    (
+        "ggml_queue_depth",
+        "Jobs waiting for a free conversion slot",
+        "gauge",
+    ),
+];
+
+/// Label pairs of a series, kept sorted so each series has a single key
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    counters: BTreeMap<(&'static str, Labels), u64>,
+    histograms: BTreeMap<(&'static str, Labels), Histogram>,
+}
+
+static REGISTRY: Lazy<Mutex<Registry>> = Lazy::new(|| Mutex::new(Registry::default()));
+
+fn labels(pairs: &[(&'static str, &str)]) -> Labels {
+    let mut labels: Labels = pairs
+        .iter()
+        .map(|(name, value)| (*name, value.to_string()))
+        .collect();
+    labels.sort();
+    labels
+}
+
+pub fn increment_counter(name: &'static str, pairs: &[(&'static str, &str)]) {
+    let mut registry = REGISTRY.lock().unwrap();
+    *registry.counters.entry((name, labels(pairs))).or_default() += 1;
+}
+
+pub fn observe_seconds(name: &'static str, pairs: &[(&'static str, &str)], seconds: f64) {
+    let mut registry = REGISTRY.lock().unwrap();
+    let histogram = registry
+        .histograms
+        .entry((name, labels(pairs)))
+        .or_default();
+    for (bucket, bound) in histogram.buckets.iter_mut().zip(BUCKETS) {
+        if seconds <= bound {
+            *bucket += 1;
+        }
+    }
+    histogram.count += 1;
+    histogram.sum += seconds;
+}
+
+/// Label value for a model. Only the registered models get their own series, any other
+/// repo is reported as `other` so arbitrary requests can't grow the number of series.
+pub fn model_label(model: &str) -> &str {
+    match crate::MODELS.lock().unwrap().contains_key(model) {
+        true => model,
+        false => "other",
+    }
+}
+
+fn render_labels(labels: &[(&'static str, String)], extra: Option<(&str, &str)>) -> String {
+    let pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .chain(extra)
+        .map(|(name, value)| {
+            format!(
+                "{}=\"{}\"",
+                name,
+                value.replace('\\', "\\\\").replace('"', "\\\"")
+            )
+        })
+        .collect();
+    match pairs.is_empty() {
+        true => String::new(),
+        false => format!("{{{}}}", pairs.join(",")),
+    }
+}
+
+/// Render every metric in the Prometheus text exposition format
+pub fn render(queue_depth: usize) -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+    for (name, help, kind) in DESCRIPTIONS {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        match kind {
+            "counter" => {
+                for ((_, labels), value) in
+                    registry.counters.iter().filter(|((n, _), _)| *n == name)
+                {
+                    let _ = writeln!(out, "{name}{} {value}", render_labels(labels, None));
+                }
+            }
+            "histogram" => {
+                for ((_, labels), histogram) in
+                    registry.histograms.iter().filter(|((n, _), _)| *n == name)
+                {
+                    for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                        let le = bound.to_string();
+                        let _ = writeln!(
+                            out,
+                            "{name}_bucket{} {count}",
+                            render_labels(labels, Some(("le", &le)))
+                        );
+                    }
+                    let _ = writeln!(
+                        out,
+                        "{name}_bucket{} {}",
+                        render_labels(labels, Some(("le", "+Inf"))),
+                        histogram.count
+                    );
+                    let labels = render_labels(labels, None);
+                    let _ = writeln!(out, "{name}_sum{labels} {}", histogram.sum);
+                    let _ = writeln!(out, "{name}_count{labels} {}", histogram.count);
+                }
+            }
+            // the queue depth is the only gauge
+            _ => {
+                let _ = writeln!(out, "{name} {queue_depth}");
+            }
+        }
+    }
+    out
+}
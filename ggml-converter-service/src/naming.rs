@@ -0,0 +1,184 @@
+//! Names of the files and directories derived from a model's repo id
+
+use crate::{OutputFormat, QuantInfo};
+
+/// The repo part of an `owner/name` repo id, which the downloaded model and the outputs are
+/// named after. A bare `name` is taken as is.
+pub fn repo_name(repo_id: &str) -> Result<&str, String> {
+    let name = repo_id
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+    // joined to the models and outputs directories, it must stay a plain file name
+    if name.is_empty() || name == "." || name == ".." || name.contains('\\') {
+        return Err(format!("No model name in repo id '{repo_id}'"));
+    }
+    Ok(name)
+}
+
+/// Directory of the models directory the repo is downloaded to: named after the repo for its
+/// default branch, and `name@revision` for another revision, its slashes written `%2F`
+pub fn model_dir_name(repo_id: &str, revision: Option<&str>) -> Result<String, String> {
+    let name = repo_name(repo_id)?;
+    Ok(match revision {
+        Some(revision) => format!("{name}@{}", revision.replace('/', "%2F")),
+        None => name.to_string(),
+    })
+}
+
+/// File the converted, not yet quantized model is written to
+pub fn ggml_filename(repo_id: &str, format: OutputFormat) -> Result<String, String> {
+    let name = repo_name(repo_id)?;
+    Ok(match format {
+        OutputFormat::Ggml => format!("{}-ggml.{}", name, format.extension()),
+        OutputFormat::Gguf => format!("{}.{}", name, format.extension()),
+    })
+}
+
+/// File a quantization of the converted model is written to
+pub fn quantized_filename(
+    repo_id: &str,
+    quant: &QuantInfo,
+    format: OutputFormat,
+) -> Result<String, String> {
+    let name = repo_name(repo_id)?;
+    Ok(match format {
+        OutputFormat::Ggml => format!("{}-ggml-{}.{}", name, quant, format.extension()),
+        OutputFormat::Gguf => format!("{}-{}.{}", name, quant, format.extension()),
+    })
+}
+
+/// File a quantization is written to when the request names its outputs. Several quants
+/// can't share the name, each gets its quant appended then.
+pub fn custom_filename(
+    output_name: &str,
+    quant: &QuantInfo,
+    several_quants: bool,
+    format: OutputFormat,
+) -> Result<String, String> {
+    let stem = sanitize_output_name(output_name)?;
+    Ok(match several_quants {
+        true => format!("{}-{}.{}", stem, quant, format.extension()),
+        false => format!("{}.{}", stem, format.extension()),
+    })
+}
+
+/// The `output_name` of a request reduced to a plain file name stem: no directory, only
+/// ASCII letters, digits, `.`, `_` and `-`, and no extension of its own as the format's one
+/// gets added, e.g. `../evil.bin` is `evil`.
+pub fn sanitize_output_name(output_name: &str) -> Result<String, String> {
+    let name: String = output_name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect();
+    let known_extensions = [
+        OutputFormat::Ggml.extension(),
+        OutputFormat::Gguf.extension(),
+    ];
+    let stem = match name.rsplit_once('.') {
+        Some((stem, extension))
+            if known_extensions
+                .iter()
+                .any(|known| extension.eq_ignore_ascii_case(known)) =>
+        {
+            stem
+        }
+        _ => name.as_str(),
+    };
+    // no hidden files, nothing that looks like an option to the tools given the path
+    let stem = stem.trim_start_matches(['.', '-']);
+    if stem.is_empty() {
+        return Err(format!(
+            "Invalid output_name '{output_name}', no file name left in it"
+        ));
+    }
+    Ok(stem.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_the_model_after_the_repo() {
+        assert_eq!(repo_name("name"), Ok("name"));
+        assert_eq!(repo_name("a/b/c"), Ok("c"));
+        // a trailing slash is dropped, not taken as an empty name
+        assert_eq!(repo_name("owner/"), Ok("owner"));
+        assert_eq!(repo_name("owner/name/"), Ok("name"));
+        for repo_id in ["..", "owner/..", "a\\b", "/", ""] {
+            assert!(repo_name(repo_id).is_err(), "{repo_id}");
+        }
+        assert_eq!(
+            model_dir_name("owner/name", Some("refs/pr/1")),
+            Ok("name@refs%2Fpr%2F1".to_string())
+        );
+    }
+
+    #[test]
+    fn names_the_conversion_and_its_quants() {
+        assert_eq!(
+            ggml_filename("owner/name", OutputFormat::Gguf),
+            Ok("name.gguf".to_string())
+        );
+        assert_eq!(
+            ggml_filename("a/b/c", OutputFormat::Ggml),
+            Ok("c-ggml.bin".to_string())
+        );
+        assert_eq!(
+            quantized_filename("owner/name", &QuantInfo::Q4, OutputFormat::Gguf),
+            Ok("name-q4_0.gguf".to_string())
+        );
+        assert_eq!(
+            quantized_filename("name", &QuantInfo::Q8, OutputFormat::Ggml),
+            Ok("name-ggml-q8_0.bin".to_string())
+        );
+        assert_eq!(
+            ggml_filename("owner/", OutputFormat::Gguf),
+            Ok("owner.gguf".to_string())
+        );
+        assert!(ggml_filename("a\\b", OutputFormat::Gguf).is_err());
+        assert!(quantized_filename("..", &QuantInfo::Q4, OutputFormat::Gguf).is_err());
+    }
+
+    #[test]
+    fn names_the_outputs_after_the_requested_name() {
+        assert_eq!(
+            custom_filename("mine", &QuantInfo::Q4, false, OutputFormat::Gguf),
+            Ok("mine.gguf".to_string())
+        );
+        assert_eq!(
+            custom_filename("mine", &QuantInfo::Q4, true, OutputFormat::Gguf),
+            Ok("mine-q4_0.gguf".to_string())
+        );
+        assert_eq!(
+            custom_filename("../evil.bin", &QuantInfo::Q8, false, OutputFormat::Ggml),
+            Ok("evil.bin".to_string())
+        );
+    }
+
+    #[test]
+    fn reduces_the_output_name_to_a_plain_stem() {
+        assert_eq!(sanitize_output_name("name"), Ok("name".to_string()));
+        assert_eq!(sanitize_output_name("a/b/c"), Ok("c".to_string()));
+        assert_eq!(sanitize_output_name("a\\b"), Ok("b".to_string()));
+        assert_eq!(sanitize_output_name("../evil.bin"), Ok("evil".to_string()));
+        assert_eq!(
+            sanitize_output_name("my model.GGUF"),
+            Ok("my_model".to_string())
+        );
+        assert_eq!(sanitize_output_name("-rf"), Ok("rf".to_string()));
+        for output_name in ["owner/", "..", ".gguf", ""] {
+            assert!(sanitize_output_name(output_name).is_err(), "{output_name}");
+        }
+    }
+}
@@ -0,0 +1,1159 @@
+//! OpenAPI description of the conversion API, served at `GET /api-docs/openapi.json` and
+//! browsable with Swagger UI at `GET /swagger-ui`
+
+use crate::{error::ErrorCode, job::JobState, ModelType, OutputFormat, QuantInfo};
+use axum::response::{Html, Json};
+use serde_json::{json, Value};
+
+pub async fn openapi_json() -> Json<Value> {
+    Json(spec())
+}
+
+pub async fn swagger_ui() -> Html<&'static str> {
+    Html(
+        r##"<!DOCTYPE html>
+<html>
+<head>
+  <title>ggml-converter-service API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>
+    SwaggerUIBundle({ url: "/api-docs/openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>"##,
+    )
+}
+
+/// `$ref` to a schema of the document
+fn schema(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{name}") })
+}
+
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": schema } },
+    })
+}
+
+fn error_response(description: &str) -> Value {
+    json_response(description, schema("Error"))
+}
+
+fn job_id_parameter() -> Value {
+    json!({
+        "name": "id",
+        "in": "path",
+        "required": true,
+        "schema": { "type": "string", "format": "uuid" },
+    })
+}
+
+/// The whole document
+pub fn spec() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "ggml-converter-service",
+            "description": "Converts Hugging Face models to ggml/GGUF and quantizes them with llama.cpp. Every response has an `X-Request-Id` header, the one of the request when it is up to 128 printable ASCII characters, a random UUID otherwise, also found in the logs of the request and of the jobs it started.",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths(),
+        "components": { "schemas": schemas() },
+    })
+}
+
+fn paths() -> Value {
+    // added apart, the paths would exceed the recursion limit of `json!`
+    let stats = json!({
+        "get": {
+            "summary": "Totals of the conversions in the job store",
+            "description": "Jobs removed GGML_JOB_TTL_SECS after they end, or evicted beyond GGML_MAX_JOBS, no longer count.",
+            "responses": {
+                "200": json_response("The totals", schema("Stats")),
+            },
+        },
+    });
+
+    let mut paths = json!({
+        "/ggml": {
+            "post": {
+                "summary": "Start a conversion",
+                "description": "The conversion runs in the background, poll `/jobs/{id}` or follow `/jobs/{id}/events` for its outcome.",
+                "parameters": [
+                    {
+                        "name": "force",
+                        "in": "query",
+                        "description": "Rebuild the outputs even if a previous run already produced them",
+                        "schema": { "type": "boolean", "default": false },
+                    },
+                    {
+                        "name": "Idempotency-Key",
+                        "in": "header",
+                        "description": "Retries sending the key of an earlier request get its job back instead of a new one, whatever their parameters. Keys are remembered for GGML_IDEMPOTENCY_TTL_SECS, a day by default.",
+                        "schema": { "type": "string", "maxLength": 255 },
+                    },
+                ],
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": schema("ModelInfo") } },
+                },
+                "responses": {
+                    "202": json_response("The job was queued, or the job of an earlier request with the same Idempotency-Key", schema("JobCreated")),
+                    "400": error_response("Invalid request"),
+                    "404": error_response("The model repo doesn't exist"),
+                    "409": error_response("Another job is writing an output of the same name"),
+                    "413": error_response("The model repo exceeds GGML_MAX_MODEL_BYTES"),
+                    "429": error_response("Too many conversions requested, see `Retry-After`"),
+                    "503": error_response("The service is shutting down"),
+                },
+            },
+        },
+        "/batch": {
+            "post": {
+                "summary": "Start several conversions",
+                "description": "Each conversion runs as a job of its own, queued like the ones of `/ggml`. Nothing is started unless every entry is valid.",
+                "parameters": [{
+                    "name": "force",
+                    "in": "query",
+                    "description": "Rebuild the outputs even if a previous run already produced them",
+                    "schema": { "type": "boolean", "default": false },
+                }],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "required": ["jobs"],
+                                "properties": {
+                                    "jobs": {
+                                        "type": "array",
+                                        "minItems": 1,
+                                        "maxItems": crate::batch::MAX_BATCH_JOBS,
+                                        "items": schema("ModelInfo"),
+                                    },
+                                },
+                            },
+                        },
+                    },
+                },
+                "responses": {
+                    "202": json_response("The jobs were queued", schema("BatchCreated")),
+                    "400": error_response("Invalid request, the message names the invalid entry"),
+                    "404": error_response("A model repo doesn't exist"),
+                    "409": error_response("Another job or entry writes an output of the same name"),
+                    "413": error_response("A model repo exceeds GGML_MAX_MODEL_BYTES"),
+                    "429": error_response("Too many conversions requested, see `Retry-After`"),
+                    "503": error_response("The service is shutting down"),
+                },
+            },
+        },
+        "/validate": {
+            "post": {
+                "summary": "Check a conversion request without starting it",
+                "description": "Runs the checks of `POST /ggml` that need neither the disk nor the network: whether the repo exists or fits isn't known until the request is made.",
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": schema("ModelInfo") } },
+                },
+                "responses": {
+                    "200": json_response(
+                        "The request as the service would run it",
+                        json!({
+                            "type": "object",
+                            "properties": {
+                                "model_info": schema("ModelInfo"),
+                                "model_url": { "type": "string" },
+                                "llama_cpp_ref": { "type": "string" },
+                                "converted_file": {
+                                    "type": "string",
+                                    "description": "Set when the request converts to a single format",
+                                },
+                                "converted_files": { "type": "array", "items": { "type": "string" } },
+                                "output_files": { "type": "array", "items": { "type": "string" } },
+                            },
+                        }),
+                    ),
+                    "400": error_response(
+                        "INVALID_REQUEST, everything wrong with the request in `details.problems`",
+                    ),
+                },
+            },
+        },
+        "/batch/{id}": {
+            "get": {
+                "summary": "Aggregate state of the jobs of a batch",
+                "parameters": [job_id_parameter()],
+                "responses": {
+                    "200": json_response("The batch", schema("BatchStatus")),
+                    "400": error_response("Invalid batch id"),
+                    "404": error_response("Unknown batch"),
+                },
+            },
+        },
+        "/jobs": {
+            "get": {
+                "summary": "List the jobs, most recently started first",
+                "parameters": [
+                    {
+                        "name": "state",
+                        "in": "query",
+                        "schema": schema("JobState"),
+                    },
+                    {
+                        "name": "limit",
+                        "in": "query",
+                        "schema": { "type": "integer", "minimum": 0 },
+                    },
+                ],
+                "responses": {
+                    "200": json_response("The jobs", schema("JobList")),
+                },
+            },
+        },
+        "/jobs/{id}": {
+            "get": {
+                "summary": "Status of a job",
+                "description": "Finished jobs are removed GGML_JOB_TTL_SECS after they end, a week by default. With `Accept: text/plain` the state is a single line, e.g. `Converting 42%`.",
+                "parameters": [job_id_parameter()],
+                "responses": {
+                    "200": {
+                        "description": "The job",
+                        "content": {
+                            "application/json": { "schema": schema("JobStatus") },
+                            "text/plain": { "schema": { "type": "string" } },
+                        },
+                    },
+                    "400": error_response("Invalid job id"),
+                    "404": error_response("Unknown job, or removed since it finished"),
+                },
+            },
+            "delete": {
+                "summary": "Cancel a job",
+                "parameters": [job_id_parameter()],
+                "responses": {
+                    "200": json_response(
+                        "The job was cancelled",
+                        json!({
+                            "type": "object",
+                            "properties": {
+                                "job_id": { "type": "string", "format": "uuid" },
+                                "state": schema("JobState"),
+                            },
+                        }),
+                    ),
+                    "404": error_response("Unknown job"),
+                    "409": error_response("The job is already over"),
+                },
+            },
+        },
+        "/jobs/{id}/events": {
+            "get": {
+                "summary": "Server-sent events with the state changes and logs of a job",
+                "parameters": [job_id_parameter()],
+                "responses": {
+                    "200": {
+                        "description": "Event stream, ending once the job is over",
+                        "content": { "text/event-stream": { "schema": { "type": "string" } } },
+                    },
+                    "404": error_response("Unknown job"),
+                },
+            },
+        },
+        "/jobs/{id}/log": {
+            "get": {
+                "summary": "The log of a job as written so far, a single `Range` is honored",
+                "description": "A log reaching GGML_MAX_LOG_MB is rotated, only its latest part is served.",
+                "parameters": [job_id_parameter()],
+                "responses": {
+                    "200": {
+                        "description": "The log",
+                        "content": { "text/plain": { "schema": { "type": "string" } } },
+                    },
+                    "206": {
+                        "description": "The requested range of the log",
+                        "content": { "text/plain": { "schema": { "type": "string" } } },
+                    },
+                    "404": error_response("Unknown job, or no log yet"),
+                    "416": { "description": "The range is outside the log" },
+                },
+            },
+        },
+        "/jobs/{id}/bundle": {
+            "get": {
+                "summary": "The outputs of a finished job in a single tar archive, named after the model",
+                "parameters": [job_id_parameter()],
+                "responses": {
+                    "200": {
+                        "description": "The archive",
+                        "content": { "application/x-tar": { "schema": { "type": "string", "format": "binary" } } },
+                    },
+                    "404": error_response("Unknown job"),
+                    "409": error_response("The job isn't Done"),
+                    "410": error_response("The outputs are no longer on disk"),
+                },
+            },
+        },
+        "/jobs/{id}/ws": {
+            "get": {
+                "summary": "WebSocket with the state changes and logs of a job",
+                "description": "Text messages `{\"event\": \"state\" | \"log\" | \"done\" | \"error\", \"data\": ...}`, starting with the current state and the events so far. Sending `cancel` or `{\"type\": \"cancel\"}` cancels the job. The server closes the socket once the job is over.",
+                "parameters": [job_id_parameter()],
+                "responses": {
+                    "101": { "description": "Switched to the WebSocket protocol" },
+                    "400": error_response("Not a WebSocket handshake"),
+                    "404": error_response("Unknown job"),
+                },
+            },
+        },
+        "/download/{filename}": {
+            "get": {
+                "summary": "Download a converted file, a single `Range` is honored. `<filename>.sha256` returns its SHA-256.",
+                "description": "When the service signs its download urls, they carry `expires` and `token` and only those are served.",
+                "parameters": [
+                    {
+                        "name": "filename",
+                        "in": "path",
+                        "required": true,
+                        "schema": { "type": "string" },
+                    },
+                    {
+                        "name": "expires",
+                        "in": "query",
+                        "description": "Unix time the signed url expires at",
+                        "schema": { "type": "integer", "format": "int64" },
+                    },
+                    {
+                        "name": "token",
+                        "in": "query",
+                        "description": "Signature of the file name and expiry",
+                        "schema": { "type": "string" },
+                    },
+                ],
+                "responses": {
+                    "200": {
+                        "description": "The file",
+                        "content": {
+                            "application/octet-stream": {
+                                "schema": { "type": "string", "format": "binary" },
+                            },
+                        },
+                    },
+                    "206": { "description": "The requested range of the file" },
+                    "403": error_response("Missing or invalid signature"),
+                    "404": error_response("No such file"),
+                    "410": error_response("The signed url has expired"),
+                    "416": { "description": "The range can't be satisfied" },
+                },
+            },
+        },
+        "/models": {
+            "get": {
+                "summary": "Registered model repos, by name",
+                "responses": {
+                    "200": json_response(
+                        "Download url of each model",
+                        json!({ "type": "object", "additionalProperties": { "type": "string" } }),
+                    ),
+                },
+            },
+            "post": {
+                "summary": "Register a model repo",
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": schema("ModelRegistration") } },
+                },
+                "responses": {
+                    "201": json_response("The registered model", schema("ModelRegistration")),
+                    "400": error_response("Invalid name or url"),
+                    "409": error_response("Already registered"),
+                },
+            },
+        },
+        "/models/{name}": {
+            "delete": {
+                "summary": "Unregister a model repo, the name is percent-encoded",
+                "parameters": [{
+                    "name": "name",
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" },
+                }],
+                "responses": {
+                    "200": json_response("The unregistered model", schema("ModelRegistration")),
+                    "404": error_response("Unknown model"),
+                },
+            },
+        },
+        "/models/{name}/quants": {
+            "get": {
+                "summary": "Outputs already converted from a model, the name is percent-encoded",
+                "parameters": [{
+                    "name": "name",
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" },
+                }],
+                "responses": {
+                    "200": json_response(
+                        "The converted outputs",
+                        json!({ "type": "array", "items": schema("ConvertedQuant") }),
+                    ),
+                    "400": error_response("Invalid model name"),
+                },
+            },
+        },
+        "/models/{name}/cancel": {
+            "post": {
+                "summary": "Cancel every unfinished job of a model, the name is percent-encoded",
+                "parameters": [{
+                    "name": "name",
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" },
+                }],
+                "responses": {
+                    "200": json_response(
+                        "The jobs cancelled, none when the model had no unfinished job",
+                        json!({
+                            "type": "object",
+                            "properties": {
+                                "name": { "type": "string" },
+                                "cancelled": {
+                                    "type": "array",
+                                    "items": { "type": "string", "format": "uuid" },
+                                },
+                            },
+                        }),
+                    ),
+                    "400": error_response("Invalid model name"),
+                },
+            },
+        },
+        "/models/{name}/cache": {
+            "delete": {
+                "summary": "Remove the downloaded repo of a model, the name is percent-encoded",
+                "description": "Along with the downloads of its other revisions.",
+                "parameters": [{
+                    "name": "name",
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" },
+                }],
+                "responses": {
+                    "200": json_response(
+                        "The repo was removed",
+                        json!({
+                            "type": "object",
+                            "properties": {
+                                "name": { "type": "string" },
+                                "freed_bytes": { "type": "integer", "format": "int64" },
+                            },
+                        }),
+                    ),
+                    "400": error_response("Invalid model name"),
+                    "404": error_response("The model isn't downloaded"),
+                    "409": error_response("A job is using the model"),
+                },
+            },
+        },
+        "/upload/{repo_id}": {
+            "post": {
+                "summary": "Upload a model as a tarball of its directory, conversions of the repo then skip the download",
+                "parameters": [
+                    {
+                        "name": "repo_id",
+                        "in": "path",
+                        "required": true,
+                        "description": "`owner/name`, slashes included",
+                        "schema": { "type": "string" },
+                    },
+                    {
+                        "name": "Content-Range",
+                        "in": "header",
+                        "required": false,
+                        "description": "`bytes <start>-<end>/<total>` to send the archive in parts, each starting where the previous one ended",
+                        "schema": { "type": "string" },
+                    },
+                ],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/x-tar": { "schema": { "type": "string", "format": "binary" } },
+                        "application/gzip": { "schema": { "type": "string", "format": "binary" } },
+                    },
+                },
+                "responses": {
+                    "201": json_response(
+                        "The archive was extracted into the models directory",
+                        json!({
+                            "type": "object",
+                            "properties": {
+                                "name": { "type": "string" },
+                                "files": { "type": "integer" },
+                                "size": { "type": "integer", "format": "int64" },
+                            },
+                        }),
+                    ),
+                    "202": json_response(
+                        "The part was stored, the archive isn't complete yet",
+                        json!({
+                            "type": "object",
+                            "properties": {
+                                "name": { "type": "string" },
+                                "received": { "type": "integer", "format": "int64" },
+                                "total": { "type": "integer", "format": "int64" },
+                            },
+                        }),
+                    ),
+                    "400": error_response("Invalid repo id or range, or an archive without a model"),
+                    "409": error_response("The model is already there or being uploaded, or the part doesn't start where the received bytes end"),
+                    "411": error_response("Missing Content-Length"),
+                    "413": error_response("The archive exceeds GGML_MAX_UPLOAD_MB"),
+                    "507": error_response("Not enough free space"),
+                },
+            },
+        },
+        "/outputs/{filename}": {
+            "delete": {
+                "summary": "Remove a converted file and its digest",
+                "parameters": [{
+                    "name": "filename",
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" },
+                }],
+                "responses": {
+                    "200": json_response(
+                        "The file was removed",
+                        json!({
+                            "type": "object",
+                            "properties": {
+                                "filename": { "type": "string" },
+                                "freed_bytes": { "type": "integer", "format": "int64" },
+                            },
+                        }),
+                    ),
+                    "400": error_response("Invalid file name"),
+                    "404": error_response("No such file"),
+                    "409": error_response("A job is writing the file"),
+                },
+            },
+        },
+        "/health": {
+            "get": {
+                "summary": "Liveness",
+                "responses": { "200": { "description": "The service is up" } },
+            },
+        },
+        "/ready": {
+            "get": {
+                "summary": "Readiness",
+                "responses": {
+                    "200": { "description": "Everything a conversion needs is in place" },
+                    "503": { "description": "Tools or writable directories are missing" },
+                },
+            },
+        },
+        "/selftest": {
+            "post": {
+                "summary": "Convert a tiny model through the whole pipeline",
+                "description": "Builds llama.cpp if needed, then converts a bundled micro-model to GGUF and quantizes it. The report is reused for 5 minutes.",
+                "responses": {
+                    "200": json_response("Every stage passed", schema("SelfTestReport")),
+                    "503": json_response("A stage failed, the ones after it were skipped", schema("SelfTestReport")),
+                },
+            },
+        },
+        "/version": {
+            "get": {
+                "summary": "Versions of the service, llama.cpp and the tools it runs",
+                "responses": { "200": { "description": "The versions" } },
+            },
+        },
+        "/quants": {
+            "get": {
+                "summary": "Quant types the quantize tool of a llama.cpp build accepts",
+                "description": "Read from the usage of the tool. Until the ref is built, the quants of the service are given instead, with `source` set to `service`.",
+                "parameters": [{
+                    "name": "llama_cpp_ref",
+                    "in": "query",
+                    "description": "llama.cpp tag or commit, the default one when unset",
+                    "schema": { "type": "string" },
+                }],
+                "responses": {
+                    "200": json_response("The quant types", json!({
+                        "type": "object",
+                        "properties": {
+                            "llama_cpp_ref": { "type": "string" },
+                            "source": { "type": "string", "enum": ["build", "service"] },
+                            "quants": { "type": "array", "items": { "type": "string" } },
+                            "accepted": {
+                                "type": "array",
+                                "items": { "type": "string" },
+                                "description": "The quants conversions take as `quant_info`",
+                            },
+                        },
+                    })),
+                    "400": error_response("Invalid llama.cpp ref"),
+                },
+            },
+        },
+        "/metrics": {
+            "get": {
+                "summary": "Prometheus metrics",
+                "responses": {
+                    "200": {
+                        "description": "Metrics in the Prometheus text format",
+                        "content": { "text/plain": { "schema": { "type": "string" } } },
+                    },
+                },
+            },
+        },
+    });
+    paths["/stats"] = stats;
+    paths
+}
+
+/// Enum values are taken from the types themselves, so they can't drift from what the service
+/// accepts
+fn schemas() -> Value {
+    let builtin_models: Vec<Value> = [
+        ModelType::Llama2_7b,
+        ModelType::Llama2Chat7b,
+        ModelType::Llama2Chinese7b,
+    ]
+    .iter()
+    .map(|model| serde_json::to_value(model).unwrap())
+    .collect();
+    let quants: Vec<String> = QuantInfo::ALL
+        .iter()
+        .chain([&QuantInfo::NoQuant])
+        .flat_map(|quant| [format!("{:?}", quant), quant.to_string()])
+        .collect();
+    let formats: Vec<Value> = [OutputFormat::Ggml, OutputFormat::Gguf]
+        .iter()
+        .map(|format| serde_json::to_value(format).unwrap())
+        .collect();
+    let codes: Vec<Value> = ErrorCode::ALL
+        .iter()
+        .map(|code| serde_json::to_value(code).unwrap())
+        .collect();
+    let states: Vec<Value> = [
+        JobState::Queued,
+        JobState::Downloading,
+        JobState::Converting,
+        JobState::Quantizing,
+        JobState::Done,
+        JobState::Failed,
+        JobState::Cancelled,
+        JobState::Interrupted,
+    ]
+    .iter()
+    .map(|state| serde_json::to_value(state).unwrap())
+    .collect();
+
+    // built apart, the schemas would exceed the recursion limit of `json!`
+    let job_status = json!({
+        "type": "object",
+        "required": ["model", "quant", "state", "started_at", "updated_at"],
+        "properties": {
+            "model": { "type": "string" },
+            "quant": { "type": "string" },
+            "state": schema("JobState"),
+            "started_at": { "type": "integer", "description": "Unix time, in seconds" },
+            "updated_at": { "type": "integer", "description": "Unix time, in seconds" },
+            "error": { "type": "string" },
+            "stderr": { "type": "string" },
+            "download_url": { "type": "string" },
+            "download_urls": { "type": "array", "items": { "type": "string" } },
+            "outputs": { "type": "array", "items": schema("Output") },
+            "progress_pct": {
+                "type": "integer",
+                "minimum": 0,
+                "maximum": 100,
+                "description": "Share of the tensors converted, unset while unknown",
+            },
+            "timings": schema("Timings"),
+            "size_bytes": {
+                "type": "integer",
+                "format": "int64",
+                "description": "Total size of the outputs, once done",
+            },
+            "quant_statuses": {
+                "type": "array",
+                "items": schema("QuantStatus"),
+                "description": "Outcome of each target, once done",
+            },
+            "deadline_at": {
+                "type": "integer",
+                "description": "Unix time the job fails at with a `DeadlineExceeded` error if still running, set once it got a conversion slot, see GGML_JOB_DEADLINE_SECS",
+            },
+            "remaining_secs": {
+                "type": "integer",
+                "minimum": 0,
+                "description": "Seconds left before the deadline, while the job runs",
+            },
+            "architecture": {
+                "type": "string",
+                "description": "Architecture of the model, from its config.json once downloaded",
+            },
+            "revision": { "type": "string", "description": "Revision of the repo requested" },
+            "commit": {
+                "type": "string",
+                "description": "Commit of the repo the model was downloaded from, once downloaded",
+            },
+            "queue_position": {
+                "type": "integer",
+                "minimum": 0,
+                "description": "Queued jobs ahead of this one, 0 when it runs next. Unset once it left the queue",
+            },
+            "progress": {
+                "type": "object",
+                "properties": {
+                    "downloaded": { "type": "integer" },
+                    "total": { "type": "integer" },
+                    "files": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "name": { "type": "string" },
+                                "downloaded": { "type": "integer" },
+                                "total": { "type": "integer" },
+                            },
+                        },
+                    },
+                },
+            },
+        },
+    });
+
+    json!({
+        "ModelType": {
+            "description": "One of the built-in models, any Hugging Face repo id as `owner/name`, or a model directory under `GGML_LOCAL_MODELS_DIR`, whose name the outputs take",
+            "oneOf": [
+                { "type": "string", "example": builtin_models[0] },
+                {
+                    "type": "object",
+                    "required": ["local_path"],
+                    "properties": {
+                        "local_path": {
+                            "type": "string",
+                            "description": "Absolute, or relative to `GGML_LOCAL_MODELS_DIR`",
+                        },
+                    },
+                },
+            ],
+            "x-builtin-models": builtin_models,
+        },
+        "QuantInfo": {
+            "type": "string",
+            "description": "Quantization, by variant name or llama.cpp name. `NoQuant` (`unquantized`) skips quantizing, the output being the converted model itself.",
+            "enum": quants,
+        },
+        "OutputFormat": {
+            "type": "string",
+            "enum": formats,
+            "default": "Gguf",
+        },
+        "ModelInfo": {
+            "type": "object",
+            "required": ["name"],
+            "properties": {
+                "name": schema("ModelType"),
+                "quant_info": {
+                    "description": "One quantization of `format`, or several sharing a single conversion. Required unless `targets` are given. `null` is `NoQuant`, converting only.",
+                    "nullable": true,
+                    "oneOf": [
+                        schema("QuantInfo"),
+                        { "type": "array", "items": schema("QuantInfo"), "minItems": 1 },
+                    ],
+                },
+                "format": schema("OutputFormat"),
+                "targets": {
+                    "type": "array",
+                    "description": "Quantizations in several formats instead of `quant_info` and `format`, the model being converted once per format. Each pair at most once.",
+                    "items": {
+                        "type": "object",
+                        "required": ["format", "quant"],
+                        "properties": {
+                            "format": schema("OutputFormat"),
+                            "quant": schema("QuantInfo"),
+                        },
+                    },
+                    "minItems": 1,
+                },
+                "llama_cpp_ref": {
+                    "type": "string",
+                    "description": "llama.cpp tag or commit to convert with",
+                },
+                "revision": {
+                    "type": "string",
+                    "description": "Branch, tag or commit of the repo to convert, `main` when unset. Letters, digits, `.`, `_`, `-` and `/`. Outputs are never reused for a revision, they are named the same for every revision.",
+                    "example": "refs/pr/1",
+                },
+                "hf_token": {
+                    "type": "string",
+                    "description": "Hugging Face token for gated repos, the service's HF_TOKEN when unset",
+                    "writeOnly": true,
+                },
+                "callback_url": {
+                    "type": "string",
+                    "format": "uri",
+                    "description": "Notified with a POST once the conversion is over",
+                },
+                "imatrix": {
+                    "type": "object",
+                    "description": "Quantize with an importance matrix computed over calibration text, Gguf only",
+                    "required": ["dataset"],
+                    "properties": {
+                        "dataset": {
+                            "type": "string",
+                            "description": "http(s) url of the text, or the name of a file of the service's imatrix directory",
+                        },
+                        "chunks": { "type": "integer", "minimum": 1 },
+                    },
+                },
+                "gpu": {
+                    "type": "object",
+                    "description": "Offload the imatrix computation to a GPU, needs `imatrix` and llama.cpp built with a GPU backend. Quantization always runs on the CPU.",
+                    "required": ["layers"],
+                    "properties": {
+                        "layers": { "type": "integer", "minimum": 1, "description": "`--n-gpu-layers`" },
+                        "main_gpu": { "type": "integer", "minimum": 0, "description": "`--main-gpu`" },
+                    },
+                },
+                "converter_args": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Extra arguments of the converter script: `--vocab-type spm|bpe|hfft`, `--pad-vocab` and `--outtype f32|f16|bf16|q8_0|auto`. For the Ggml format, `--vocab-type` is inferred from the tokenizer files of the repo unless given",
+                    "example": ["--pad-vocab", "--vocab-type", "bpe"],
+                },
+                "output_name": {
+                    "type": "string",
+                    "description": "Name of the quantized outputs instead of the derived one. Reduced to letters, digits, `.`, `_` and `-`, given the extension of the format, and suffixed with the quant when there are several. A `NoQuant` output keeps the name of the converted model.",
+                },
+                "verify": {
+                    "type": "boolean",
+                    "default": false,
+                    "description": "Load each quantized output in llama.cpp and fail the targets that don't load, at the cost of the time and memory of loading the model",
+                },
+                "fail_fast": {
+                    "type": "boolean",
+                    "default": false,
+                    "description": "Fail the job on the first target that fails to quantize or verify. By default the other targets still run, the job is Done if any succeeds, and `quant_statuses` tells which failed",
+                },
+                "require_safetensors": {
+                    "type": "boolean",
+                    "default": false,
+                    "description": "Fail rather than load pickle weights, the repo must have .safetensors ones",
+                },
+            },
+        },
+        "ConversionResult": {
+            "type": "object",
+            "required": ["quant", "download_urls", "outputs", "sha256s", "size_bytes", "timings", "quant_statuses"],
+            "properties": {
+                "quant": {
+                    "type": "string",
+                    "description": "The quants, comma separated, each prefixed with its format when `targets` were given, e.g. `gguf-q4_K_M`",
+                },
+                "download_url": {
+                    "type": "string",
+                    "description": "Set when a single quantization was requested",
+                },
+                "download_urls": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "`/download/{filename}`, prefixed with GGML_PUBLIC_BASE_URL when set, or the bucket urls of uploaded outputs",
+                },
+                "outputs": {
+                    "type": "array",
+                    "items": schema("Output"),
+                    "description": "The download urls with the format and quant of each",
+                },
+                "sha256": {
+                    "type": "string",
+                    "description": "Set when a single quantization was requested",
+                },
+                "sha256s": { "type": "array", "items": { "type": "string" } },
+                "size_bytes": { "type": "integer", "format": "int64" },
+                "base_ggml_size_bytes": { "type": "integer", "format": "int64" },
+                "timings": schema("Timings"),
+                "quant_statuses": {
+                    "type": "array",
+                    "items": schema("QuantStatus"),
+                    "description": "Outcome of each target, the outputs being those of the Done ones",
+                },
+                "unquantized": {
+                    "type": "boolean",
+                    "description": "No output is quantized, only NoQuant having been requested",
+                },
+            },
+        },
+        "QuantStatus": {
+            "type": "object",
+            "required": ["format", "quant", "state"],
+            "properties": {
+                "format": schema("OutputFormat"),
+                "quant": schema("QuantInfo"),
+                "state": { "type": "string", "enum": ["Done", "Failed"] },
+                "error": { "type": "string", "description": "Why the target failed" },
+            },
+        },
+        "Output": {
+            "type": "object",
+            "required": ["format", "quant", "download_url"],
+            "properties": {
+                "format": schema("OutputFormat"),
+                "quant": schema("QuantInfo"),
+                "download_url": { "type": "string" },
+                "unquantized": { "type": "boolean", "description": "The converted model as is, for NoQuant" },
+            },
+        },
+        "Timings": {
+            "type": "object",
+            "description": "Seconds spent in each stage, zero for the stages that didn't run",
+            "properties": {
+                "download_secs": { "type": "number" },
+                "build_secs": { "type": "number" },
+                "convert_secs": { "type": "number" },
+                "quantize_secs": { "type": "number", "description": "Including the importance matrix" },
+                "verify_secs": { "type": "number", "description": "Loading the outputs, for requests asking to `verify` them" },
+                "total_secs": { "type": "number", "description": "From the moment the job got a conversion slot" },
+            },
+        },
+        "ConvertedQuant": {
+            "type": "object",
+            "required": ["quant", "format", "filename", "size_bytes"],
+            "properties": {
+                "quant": { "type": "string" },
+                "format": schema("OutputFormat"),
+                "filename": { "type": "string" },
+                "size_bytes": { "type": "integer", "format": "int64" },
+                "sha256": {
+                    "type": "string",
+                    "description": "Set once the digest of the file has been computed",
+                },
+            },
+        },
+        "BatchCreated": {
+            "type": "object",
+            "required": ["batch_id", "job_ids"],
+            "properties": {
+                "batch_id": { "type": "string", "format": "uuid" },
+                "job_ids": { "type": "array", "items": { "type": "string", "format": "uuid" } },
+            },
+        },
+        "BatchStatus": {
+            "type": "object",
+            "required": ["batch_id", "job_ids", "counts", "finished"],
+            "properties": {
+                "batch_id": { "type": "string", "format": "uuid" },
+                "job_ids": { "type": "array", "items": { "type": "string", "format": "uuid" } },
+                "counts": {
+                    "type": "object",
+                    "description": "Number of jobs per state, states without jobs left out",
+                    "additionalProperties": { "type": "integer" },
+                },
+                "finished": { "type": "boolean", "description": "Every job reached a final state" },
+            },
+        },
+        "SelfTestReport": {
+            "type": "object",
+            "required": ["passed", "stages", "ran_at"],
+            "properties": {
+                "passed": { "type": "boolean" },
+                "stages": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["stage", "status", "seconds"],
+                        "properties": {
+                            "stage": { "type": "string", "enum": ["build", "convert", "quantize"] },
+                            "status": { "type": "string", "enum": ["passed", "failed", "skipped"] },
+                            "seconds": { "type": "number" },
+                            "error": { "type": "string" },
+                        },
+                    },
+                },
+                "ran_at": { "type": "integer", "description": "Unix time, in seconds" },
+            },
+        },
+        "JobCreated": {
+            "type": "object",
+            "required": ["job_id"],
+            "properties": { "job_id": { "type": "string", "format": "uuid" } },
+        },
+        "JobState": {
+            "type": "string",
+            "enum": states,
+        },
+        "JobStatus": job_status,
+        "JobSummary": {
+            "type": "object",
+            "required": ["id", "model", "quant", "state", "started_at", "updated_at"],
+            "properties": {
+                "id": { "type": "string", "format": "uuid" },
+                "model": { "type": "string" },
+                "quant": { "type": "string" },
+                "state": schema("JobState"),
+                "started_at": { "type": "integer" },
+                "updated_at": { "type": "integer" },
+                "error": { "type": "string" },
+                "output_files": { "type": "array", "items": { "type": "string" } },
+            },
+        },
+        "Counts": {
+            "type": "object",
+            "required": ["total", "done", "failed", "cancelled", "unfinished"],
+            "properties": {
+                "total": { "type": "integer" },
+                "done": { "type": "integer" },
+                "failed": { "type": "integer", "description": "Including the jobs interrupted by a shutdown" },
+                "cancelled": { "type": "integer" },
+                "unfinished": { "type": "integer", "description": "Queued or running" },
+            },
+        },
+        "Stats": {
+            "type": "object",
+            "required": ["jobs", "by_model", "by_quant", "bytes_produced"],
+            "properties": {
+                "jobs": schema("Counts"),
+                "by_model": { "type": "object", "additionalProperties": schema("Counts") },
+                "by_quant": {
+                    "type": "object",
+                    "additionalProperties": schema("Counts"),
+                    "description": "A job with several quants counts for each of them",
+                },
+                "success_rate": {
+                    "type": "number",
+                    "description": "Share of the finished jobs that are done, cancelled jobs aside. Unset until one finishes",
+                },
+                "failure_rate": { "type": "number" },
+                "average_timings": schema("Timings"),
+                "bytes_produced": { "type": "integer", "format": "int64", "description": "Total size of the outputs of the done jobs" },
+            },
+        },
+        "JobList": {
+            "type": "object",
+            "required": ["queue_depth", "max_concurrent", "job_count", "jobs"],
+            "properties": {
+                "queue_depth": { "type": "integer" },
+                "max_concurrent": { "type": "integer" },
+                "job_count": { "type": "integer", "description": "Jobs in the store, whatever the filter" },
+                "max_jobs": {
+                    "type": "integer",
+                    "description": "GGML_MAX_JOBS, the least recently updated finished jobs are evicted beyond it. Unset without a limit.",
+                },
+                "jobs": { "type": "array", "items": schema("JobSummary") },
+            },
+        },
+        "ModelRegistration": {
+            "type": "object",
+            "required": ["name", "url"],
+            "properties": {
+                "name": { "type": "string" },
+                "url": { "type": "string", "format": "uri" },
+            },
+        },
+        "Error": {
+            "type": "object",
+            "required": ["code", "message"],
+            "properties": {
+                "code": {
+                    "type": "string",
+                    "enum": codes,
+                    "description": "Stable, unlike the message. JOB_FINISHED has the `state` of the job in `details`, INVALID_REQUEST its `problems`, RATE_LIMITED `retry_after_secs`, SUBPROCESS_FAILED the `stage`, `exit_status` and `stderr` of the subprocess.",
+                },
+                "message": { "type": "string" },
+                "details": { "type": "object" },
+                "request_id": { "type": "string", "description": "The `X-Request-Id` of the response" },
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConversionResult, ModelInfo};
+
+    /// Names of the fields of a struct. They are matched without `..`, so adding a field
+    /// stops the test from compiling until it is listed here too.
+    macro_rules! fields {
+        ($ty:ident { $($field:ident),* $(,)? }) => {{
+            let _exhaustive = |value: $ty| {
+                let $ty { $($field: _),* } = value;
+            };
+            vec![$(stringify!($field)),*]
+        }};
+    }
+
+    #[test]
+    fn describes_every_field_of_the_request_and_of_the_result() {
+        let spec = spec();
+        let structs = [
+            (
+                "ModelInfo",
+                fields!(ModelInfo {
+                    name,
+                    quant_info,
+                    format,
+                    targets,
+                    llama_cpp_ref,
+                    revision,
+                    hf_token,
+                    callback_url,
+                    require_safetensors,
+                    imatrix,
+                    output_name,
+                    gpu,
+                    converter_args,
+                    verify,
+                    fail_fast,
+                }),
+            ),
+            (
+                "ConversionResult",
+                fields!(ConversionResult {
+                    quant,
+                    download_url,
+                    download_urls,
+                    outputs,
+                    sha256,
+                    sha256s,
+                    size_bytes,
+                    base_ggml_size_bytes,
+                    timings,
+                    quant_statuses,
+                    unquantized,
+                }),
+            ),
+        ];
+
+        for (name, fields) in structs {
+            let properties = spec["components"]["schemas"][name]["properties"]
+                .as_object()
+                .unwrap();
+            for field in &fields {
+                assert!(
+                    properties.contains_key(*field),
+                    "{name}.{field} isn't described"
+                );
+            }
+            for property in properties.keys() {
+                assert!(
+                    fields.contains(&property.as_str()),
+                    "{name}.{property} doesn't exist"
+                );
+            }
+        }
+    }
+}
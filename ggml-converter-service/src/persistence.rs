@@ -0,0 +1,192 @@
+use crate::{
+    job::{now_secs, Job, JobId, JobState, QuantStatus, Timings},
+    ModelInfo,
+};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Reason recorded on jobs that were still running when the service went down
+pub const INTERRUPTED_BY_RESTART: &str = "interrupted by restart";
+
+/// The part of a job that outlives the process
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct JobRecord {
+    pub id: JobId,
+    pub model_info: ModelInfo,
+    pub state: JobState,
+    pub error: Option<String>,
+    #[serde(default)]
+    pub stderr: Option<String>,
+    pub download_urls: Vec<String>,
+    #[serde(default)]
+    pub architecture: Option<String>,
+    #[serde(default)]
+    pub commit: Option<String>,
+    #[serde(default)]
+    pub timings: Option<Timings>,
+    #[serde(default)]
+    pub size_bytes: Option<u64>,
+    #[serde(default)]
+    pub quant_statuses: Vec<QuantStatus>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl From<&Job> for JobRecord {
+    fn from(job: &Job) -> Self {
+        JobRecord {
+            id: job.id,
+            model_info: job.model_info.clone(),
+            state: job.state,
+            error: job.error.clone(),
+            stderr: job.stderr.clone(),
+            download_urls: job.download_urls.clone(),
+            architecture: job.architecture.clone(),
+            commit: job.commit.clone(),
+            timings: job.timings,
+            size_bytes: job.size_bytes,
+            quant_statuses: job.quant_statuses.clone(),
+            created_at: job.started_at,
+            updated_at: job.updated_at,
+        }
+    }
+}
+
+impl JobRecord {
+    /// Turn the record back into a job, failing it if it never got to finish
+    pub fn restore(self) -> Job {
+        let mut job = Job::new(self.model_info);
+        job.id = self.id;
+        job.started_at = self.created_at;
+        job.updated_at = self.updated_at;
+        job.download_urls = self.download_urls;
+        job.architecture = self.architecture;
+        job.commit = self.commit;
+        job.timings = self.timings;
+        job.size_bytes = self.size_bytes;
+        job.quant_statuses = self.quant_statuses;
+        job.state = self.state;
+        job.error = self.error;
+        job.stderr = self.stderr;
+        if !job.state.is_finished() {
+            job.state = JobState::Failed;
+            job.error = Some(INTERRUPTED_BY_RESTART.to_string());
+            job.updated_at = now_secs();
+        }
+        job
+    }
+}
+
+/// Durable backend of the `JobStore`. Without one, jobs only live in memory.
+pub trait JobPersistence: std::fmt::Debug + Send + Sync {
+    fn load(&self) -> Result<Vec<JobRecord>, Box<dyn std::error::Error>>;
+
+    /// Replace the persisted jobs with the given ones
+    fn save(&self, records: &[JobRecord]) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+/// Keeps all jobs in a single JSON file, rewritten on every change
+#[derive(Debug)]
+pub struct JsonFilePersistence {
+    path: PathBuf,
+}
+
+impl JsonFilePersistence {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        JsonFilePersistence { path: path.into() }
+    }
+}
+
+impl JobPersistence for JsonFilePersistence {
+    fn load(&self) -> Result<Vec<JobRecord>, Box<dyn std::error::Error>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let content = std::fs::read_to_string(&self.path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    fn save(&self, records: &[JobRecord]) -> Result<(), Box<dyn std::error::Error>> {
+        // write aside and rename so a crash never leaves a truncated file behind
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(records)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        job::JobStore,
+        tests::{model_info, TestDir, ENV},
+    };
+    use serde_json::json;
+
+    #[test]
+    fn restores_the_jobs_failing_the_unfinished_ones() {
+        let root = TestDir::new();
+        let path = root.join("jobs.json");
+        let jobs = JobStore::persistent(JsonFilePersistence::new(&path)).unwrap();
+        let done = Job::new(model_info(
+            json!({"name": "owner/done", "quant_info": "Q4"}),
+        ));
+        let done = jobs.insert_unless_running(done).unwrap();
+        let timings = Timings {
+            convert_secs: 1.5,
+            total_secs: 2.0,
+            ..Timings::default()
+        };
+        let download_url = "http://localhost:3000/download/done-q4_0.bin".to_string();
+        jobs.finish(done, vec![download_url.clone()], timings, 42, Vec::new());
+        let running = Job::new(model_info(
+            json!({"name": "owner/running", "quant_info": "Q8"}),
+        ));
+        let running = jobs.insert_unless_running(running).unwrap();
+        jobs.update_state(running, JobState::Converting);
+        drop(jobs);
+
+        let jobs = JobStore::persistent(JsonFilePersistence::new(&path)).unwrap();
+
+        let done = jobs.get(done).unwrap();
+        assert_eq!(done.state, JobState::Done);
+        assert_eq!(done.model_info.name.to_string(), "owner/done");
+        assert_eq!(done.download_urls, [download_url]);
+        assert_eq!(done.timings, Some(timings));
+        assert_eq!(done.size_bytes, Some(42));
+        assert_eq!(done.error, None);
+        let running = jobs.get(running).unwrap();
+        assert_eq!(running.state, JobState::Failed);
+        assert_eq!(running.error.as_deref(), Some(INTERRUPTED_BY_RESTART));
+
+        // the failure is written back, not recomputed on every start
+        let records = JsonFilePersistence::new(&path).load().unwrap();
+        let record = records
+            .iter()
+            .find(|record| record.id == running.id)
+            .unwrap();
+        assert_eq!(record.state, JobState::Failed);
+    }
+
+    #[test]
+    fn refuses_to_start_on_a_corrupt_jobs_file() {
+        let _env = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let root = TestDir::new();
+        let path = root.join("jobs.json");
+        std::fs::write(&path, "{ truncated").unwrap();
+
+        std::env::set_var("GGML_JOBS_FILE", &path);
+        let store = crate::job_store();
+        std::env::remove_var("GGML_JOBS_FILE");
+
+        let err = store.unwrap_err();
+        assert!(
+            err.starts_with(&format!(
+                "Failed to load the persisted jobs from {}: ",
+                path.display()
+            )),
+            "{err}"
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! `GET /quants`: the quant types the quantize tool of a llama.cpp build accepts, which vary
+//! with the llama.cpp ref, read from its usage.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// Line of the usage ahead of the quant types
+const TYPES_HEADER: &str = "Allowed quantization types:";
+
+/// The quant types listed by the usage of the quantize tool, in its order. Each is on a line
+/// of its own, `  2  or  Q4_0   :  3.56G, +0.2166 ppl @ LLaMA-v1-7B`, some without the number.
+pub fn parse_help(help: &str) -> Vec<String> {
+    let mut quants: Vec<String> = Vec::new();
+    for line in help
+        .lines()
+        .skip_while(|line| !line.contains(TYPES_HEADER))
+        .skip(1)
+    {
+        let Some((name, _)) = line.split_once(':') else {
+            continue;
+        };
+        let name = match name.split_once(" or ") {
+            Some((number, name)) if number.trim().parse::<u32>().is_ok() => name,
+            Some(_) => continue,
+            None => name,
+        };
+        let name = name.trim();
+        let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if valid && !quants.iter().any(|quant| quant == name) {
+            quants.push(name.to_string());
+        }
+    }
+    quants
+}
+
+/// A build: its quantize tool and the time the tool was built
+type Build = (PathBuf, SystemTime);
+
+/// The quant types of each build
+#[derive(Debug, Clone, Default)]
+pub struct QuantsCache {
+    builds: Arc<Mutex<HashMap<Build, Vec<String>>>>,
+}
+
+impl QuantsCache {
+    /// The quant types the tool accepts, `None` if its usage lists none. A rebuilt tool is
+    /// read again.
+    pub async fn get(&self, quantizer: &Path) -> Option<Vec<String>> {
+        let built_at = std::fs::metadata(quantizer).and_then(|metadata| metadata.modified());
+        let key = (quantizer.to_path_buf(), built_at.ok()?);
+        if let Some(quants) = self.builds.lock().unwrap().get(&key) {
+            return Some(quants.clone());
+        }
+        let output = tokio::process::Command::new(quantizer)
+            .arg("--help")
+            .output()
+            .await
+            .ok()?;
+        // the usage goes to stdout or stderr and exits with 1, depending on the version
+        let help = [&output.stdout, &output.stderr]
+            .iter()
+            .map(|usage| String::from_utf8_lossy(usage))
+            .collect::<String>();
+        let quants = parse_help(&help);
+        if quants.is_empty() {
+            return None;
+        }
+        self.builds.lock().unwrap().insert(key, quants.clone());
+        Some(quants)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{script, TestDir};
+
+    /// The usage of a llama.cpp quantize tool, trimmed
+    const HELP: &str = "\
+usage: ./quantize [--help] [--allow-requantize] [--leave-output-tensor] [--pure] model-f32.gguf [model-quant.gguf] type [nthreads]
+
+  --allow-requantize: Allows requantizing tensors that have already been quantized
+  --leave-output-tensor: Will leave output.weight un(re)quantized
+  --pure: Disable k-quant mixtures and quantize all tensors to the same type
+
+Allowed quantization types:
+   2  or  Q4_0    :  3.56G, +0.2166 ppl @ LLaMA-v1-7B
+   3  or  Q4_1    :  3.90G, +0.1585 ppl @ LLaMA-v1-7B
+   8  or  Q5_0    :  4.33G, +0.0683 ppl @ LLaMA-v1-7B
+  10  or  Q2_K    :  2.63G, +0.6717 ppl @ LLaMA-v1-7B
+  12  or  Q3_K    : alias for Q3_K_M
+  11  or  Q3_K_S  :  2.75G, +0.5551 ppl @ LLaMA-v1-7B
+   7  or  Q8_0    :  6.70G, +0.0004 ppl @ LLaMA-v1-7B
+   1  or  F16     : 13.00G              @ 7B
+          COPY    : only copy tensors, no quantizing
+";
+
+    #[test]
+    fn parses_the_quant_types_of_the_usage() {
+        assert_eq!(
+            parse_help(HELP),
+            ["Q4_0", "Q4_1", "Q5_0", "Q2_K", "Q3_K", "Q3_K_S", "Q8_0", "F16", "COPY"]
+        );
+        assert!(parse_help("usage: ./quantize model type\n").is_empty());
+    }
+
+    #[tokio::test]
+    async fn runs_the_tool_once_per_build() {
+        let root = TestDir::new();
+        let quantizer = root.join("quantize");
+        std::fs::write(root.join("usage"), HELP).unwrap();
+        // older tools print the usage to stderr and exit with 1
+        script(
+            &quantizer,
+            &format!(
+                "echo run >> {0}/runs\ncat {0}/usage >&2\nexit 1",
+                root.path().display()
+            ),
+        );
+        let runs = || {
+            std::fs::read_to_string(root.join("runs"))
+                .unwrap()
+                .lines()
+                .count()
+        };
+        let cache = QuantsCache::default();
+        let before = runs();
+
+        let quants = cache.get(&quantizer).await.unwrap();
+        assert_eq!(quants.len(), 9);
+        assert_eq!(cache.get(&quantizer).await, Some(quants));
+        assert_eq!(runs(), before + 1);
+    }
+}
@@ -0,0 +1,234 @@
+use crate::job::JobContext;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{Notify, OwnedMutexGuard, OwnedSemaphorePermit, Semaphore};
+
+/// Default number of conversions allowed to run the heavy pipeline at once
+pub const DEFAULT_MAX_CONCURRENT_JOBS: usize = 1;
+
+/// What a conversion does when another one is writing one of its output files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputConflict {
+    /// The request is refused with a 409
+    Reject,
+    /// The job stays `Queued` until the other conversion is over
+    Wait,
+}
+
+/// Bounds how many conversions run at the same time, the others wait in `Queued`. Also keeps
+/// two conversions from writing the same output file at once, or from downloading and
+/// converting the same model at once.
+#[derive(Debug, Clone)]
+pub struct ConversionQueue {
+    permits: Arc<Semaphore>,
+    max_concurrent: usize,
+    on_output_conflict: OutputConflict,
+    /// Output files held by a conversion
+    writing: Arc<Mutex<HashSet<PathBuf>>>,
+    /// Signaled whenever files are released
+    released: Arc<Notify>,
+    /// Models downloaded or converted by a conversion, by the name of their directory
+    models: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl ConversionQueue {
+    pub fn new(max_concurrent: usize, on_output_conflict: OutputConflict) -> Self {
+        ConversionQueue {
+            permits: Arc::new(Semaphore::new(max_concurrent)),
+            max_concurrent,
+            on_output_conflict,
+            writing: Arc::default(),
+            released: Arc::default(),
+            models: Arc::default(),
+        }
+    }
+
+    /// Read the limit from `GGML_MAX_CONCURRENT_JOBS`, and from `GGML_OUTPUT_CONFLICT`
+    /// (`reject` or `wait`, `reject` by default) what to do about a file being written already
+    pub fn from_env() -> Result<Self, String> {
+        let max_concurrent = std::env::var("GGML_MAX_CONCURRENT_JOBS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&value| value > 0)
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_JOBS);
+        let on_output_conflict = match std::env::var("GGML_OUTPUT_CONFLICT").as_deref() {
+            Ok("reject") | Err(_) => OutputConflict::Reject,
+            Ok("wait") => OutputConflict::Wait,
+            Ok(other) => {
+                return Err(format!(
+                    "Invalid GGML_OUTPUT_CONFLICT '{other}', expected reject or wait"
+                ))
+            }
+        };
+        Ok(Self::new(max_concurrent, on_output_conflict))
+    }
+
+    pub fn on_output_conflict(&self) -> OutputConflict {
+        self.on_output_conflict
+    }
+
+    /// Hold all the files or none of them, `Err` with a file another conversion holds
+    pub fn try_lock_outputs(&self, paths: Vec<PathBuf>) -> Result<OutputsGuard, PathBuf> {
+        let mut writing = self.writing.lock().unwrap();
+        if let Some(held) = paths.iter().find(|path| writing.contains(*path)) {
+            return Err(held.clone());
+        }
+        writing.extend(paths.iter().cloned());
+        Ok(OutputsGuard {
+            queue: self.clone(),
+            paths,
+        })
+    }
+
+    /// One of the files that a conversion holds, if any
+    pub fn held_output(&self, paths: &[PathBuf]) -> Option<PathBuf> {
+        let writing = self.writing.lock().unwrap();
+        paths.iter().find(|path| writing.contains(*path)).cloned()
+    }
+
+    /// Wait until none of the files is held anymore and hold them, giving up if the job is
+    /// cancelled while waiting
+    pub async fn lock_outputs(
+        &self,
+        paths: Vec<PathBuf>,
+        ctx: &JobContext,
+    ) -> Option<OutputsGuard> {
+        loop {
+            // created before trying, a release in between still wakes it up
+            let released = self.released.notified();
+            if let Ok(guard) = self.try_lock_outputs(paths.clone()) {
+                return Some(guard);
+            }
+            tokio::select! {
+                _ = released => {}
+                _ = ctx.token.cancelled() => return None,
+            }
+        }
+    }
+
+    /// Wait until no other conversion downloads or converts the model and hold it, giving up
+    /// if the job is cancelled while waiting. Conversions of other models go on meanwhile.
+    pub async fn lock_model(&self, name: &str, ctx: &JobContext) -> Option<ModelGuard> {
+        let lock = self
+            .models
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone();
+        let guard = match lock.clone().try_lock_owned() {
+            Ok(guard) => guard,
+            Err(_) => {
+                ctx.events.log(format!(
+                    "Waiting for another conversion of '{name}' to be done with the model"
+                ));
+                tokio::select! {
+                    guard = lock.lock_owned() => guard,
+                    _ = ctx.token.cancelled() => return None,
+                }
+            }
+        };
+        Some(ModelGuard {
+            queue: self.clone(),
+            name: name.to_string(),
+            guard: Some(guard),
+        })
+    }
+
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+
+    /// Wait for a free slot, giving up if the job is cancelled while waiting
+    pub async fn acquire(&self, ctx: &JobContext) -> Option<OwnedSemaphorePermit> {
+        tokio::select! {
+            permit = self.permits.clone().acquire_owned() => permit.ok(),
+            _ = ctx.token.cancelled() => None,
+        }
+    }
+}
+
+/// Output files held by a conversion, released when dropped
+#[derive(Debug)]
+pub struct OutputsGuard {
+    queue: ConversionQueue,
+    paths: Vec<PathBuf>,
+}
+
+impl Drop for OutputsGuard {
+    fn drop(&mut self) {
+        let mut writing = self.queue.writing.lock().unwrap();
+        for path in &self.paths {
+            writing.remove(path);
+        }
+        self.queue.released.notify_waiters();
+    }
+}
+
+/// A model held by a conversion, released when dropped
+#[derive(Debug)]
+pub struct ModelGuard {
+    queue: ConversionQueue,
+    name: String,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for ModelGuard {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        // the lock is only ever handed out under the map's lock, no one else has it when the
+        // map holds the last reference
+        let mut models = self.queue.models.lock().unwrap();
+        if models
+            .get(&self.name)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            models.remove(&self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::job_context;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn serializes_the_conversions_of_a_model_only() {
+        let queue = ConversionQueue::new(2, OutputConflict::Reject);
+        let ctx = job_context();
+        let first = queue.lock_model("tiny", &ctx).await.unwrap();
+
+        let second = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.lock_model("tiny", &job_context()).await.is_some() }
+        });
+        // another model isn't held up meanwhile
+        let other = tokio::time::timeout(Duration::from_secs(1), queue.lock_model("other", &ctx))
+            .await
+            .expect("another model waited");
+        assert!(other.is_some());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!second.is_finished());
+
+        drop(first);
+        let locked = tokio::time::timeout(Duration::from_secs(1), second)
+            .await
+            .expect("the model still held");
+        assert!(locked.unwrap());
+    }
+
+    #[tokio::test]
+    async fn gives_up_waiting_for_a_model_once_cancelled() {
+        let queue = ConversionQueue::new(2, OutputConflict::Reject);
+        let _held = queue.lock_model("tiny", &job_context()).await.unwrap();
+        let ctx = job_context();
+        ctx.token.cancel();
+
+        assert!(queue.lock_model("tiny", &ctx).await.is_none());
+    }
+}
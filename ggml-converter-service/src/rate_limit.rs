@@ -0,0 +1,194 @@
+//! Per client limit on the number of conversions requested, each one takes hours of CPU
+
+use crate::error::{ApiError, ErrorCode};
+use axum::{
+    extract::ConnectInfo,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{Headers, IntoResponse, Response},
+};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Counts the requests of each client over fixed one minute windows.
+///
+/// Cloning is cheap, all clones share the same counts.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    per_minute: u32,
+    /// Proxies whose `X-Forwarded-For` is believed, anyone else could set it to dodge the limit
+    trusted_proxies: Arc<Vec<IpAddr>>,
+    windows: Arc<Mutex<HashMap<IpAddr, (Instant, u32)>>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        RateLimiter {
+            per_minute,
+            trusted_proxies: Arc::default(),
+            windows: Arc::default(),
+        }
+    }
+
+    pub fn with_trusted_proxies(mut self, trusted_proxies: Vec<IpAddr>) -> Self {
+        self.trusted_proxies = Arc::new(trusted_proxies);
+        self
+    }
+
+    /// Limit from `GGML_RATE_LIMIT_PER_MINUTE`, 10 by default, 0 turns the limit off. The
+    /// proxies in front of the service are listed in `GGML_TRUSTED_PROXIES`, comma separated.
+    pub fn from_env() -> Self {
+        let per_minute = std::env::var("GGML_RATE_LIMIT_PER_MINUTE")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(10);
+        let trusted_proxies = std::env::var("GGML_TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .filter_map(|proxy| match proxy.parse() {
+                Ok(proxy) => Some(proxy),
+                Err(_) => {
+                    tracing::warn!("Ignoring the invalid trusted proxy '{proxy}'");
+                    None
+                }
+            })
+            .collect();
+        RateLimiter::new(per_minute).with_trusted_proxies(trusted_proxies)
+    }
+
+    /// Count a request from the client, `Err` holds how long until it may try again
+    pub fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        if self.per_minute == 0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        // forget the clients whose window is over, so the map doesn't grow with every address
+        windows.retain(|_, (start, _)| now.duration_since(*start) < WINDOW);
+        let (start, count) = windows.entry(client).or_insert((now, 0));
+        if *count >= self.per_minute {
+            return Err(WINDOW.saturating_sub(now.duration_since(*start)));
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    /// The client's address: the peer's, unless it is a trusted proxy. Then it is the last
+    /// address of `X-Forwarded-For` that isn't a trusted proxy, the ones before it could have
+    /// been made up by the client.
+    pub fn client_ip<B>(&self, req: &Request<B>) -> Option<IpAddr> {
+        let peer = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())?;
+        if !self.trusted_proxies.contains(&peer) {
+            return Some(peer);
+        }
+        let forwarded = req
+            .headers()
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|ip| ip.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<_>>();
+        for ip in forwarded.into_iter().rev() {
+            match ip {
+                Some(ip) if self.trusted_proxies.contains(&ip) => continue,
+                Some(ip) => return Some(ip),
+                // a garbled hop, nothing before it can be believed
+                None => break,
+            }
+        }
+        Some(peer)
+    }
+}
+
+/// Middleware answering `429 Too Many Requests` to clients over the limit
+pub async fn limit<B>(req: Request<B>, next: Next<B>, limiter: RateLimiter) -> Response {
+    let Some(client) = limiter.client_ip(&req) else {
+        return next.run(req).await;
+    };
+    match limiter.check(client) {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => {
+            // rounded up, a client retrying right after `Retry-After` must get through
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            tracing::warn!("Rate limited {client}, retry in {secs}s");
+            (
+                Headers([(header::RETRY_AFTER, secs.to_string())]),
+                ApiError::new(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    ErrorCode::RateLimited,
+                    format!("Too many requests, retry in {secs} seconds"),
+                )
+                .with_details(json!({ "retry_after_secs": secs })),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(peer: &str, forwarded_for: Option<&str>) -> Request<()> {
+        let mut req = Request::builder();
+        if let Some(forwarded_for) = forwarded_for {
+            req = req.header("x-forwarded-for", forwarded_for);
+        }
+        let mut req = req.body(()).unwrap();
+        let peer: SocketAddr = format!("{peer}:40000").parse().unwrap();
+        req.extensions_mut().insert(ConnectInfo(peer));
+        req
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn ignores_x_forwarded_for_from_an_untrusted_peer() {
+        let limiter = RateLimiter::new(10).with_trusted_proxies(vec!["10.0.0.1".parse().unwrap()]);
+
+        assert_eq!(
+            limiter.client_ip(&request("203.0.113.7", Some("198.51.100.1"))),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn takes_the_client_from_x_forwarded_for_of_a_trusted_proxy() {
+        let limiter = RateLimiter::new(10).with_trusted_proxies(vec![
+            "10.0.0.1".parse().unwrap(),
+            "10.0.0.2".parse().unwrap(),
+        ]);
+
+        assert_eq!(
+            limiter.client_ip(&request("10.0.0.1", Some("198.51.100.1"))),
+            ip("198.51.100.1")
+        );
+        // the first address is the client's to make up, the last untrusted hop is not
+        assert_eq!(
+            limiter.client_ip(&request(
+                "10.0.0.1",
+                Some("1.2.3.4, 198.51.100.1, 10.0.0.2")
+            )),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            limiter.client_ip(&request("10.0.0.1", None)),
+            ip("10.0.0.1")
+        );
+    }
+}
@@ -0,0 +1,47 @@
+//! The `X-Request-Id` of each request: the client's own when it sends a valid one, a random
+//! UUID otherwise. It is echoed in the response and its error body, and set on the spans of
+//! the request and of the jobs it starts, so a client report can be matched with the logs.
+
+use crate::job::JobId;
+use axum::{middleware::Next, response::Response};
+use http::{HeaderValue, Request};
+use tracing::{info_span, Instrument};
+
+pub const HEADER: &str = "x-request-id";
+
+/// Longest id taken from a client
+const MAX_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The id of the request being handled, `None` outside of one, e.g. in a spawned task
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Printable ASCII, so it can go back in a header and in the logs as is
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LEN && id.chars().all(|c| c.is_ascii_graphic())
+}
+
+pub async fn propagate<B>(req: Request<B>, next: Next<B>) -> Response {
+    let id = req
+        .headers()
+        .get(HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(str::to_string)
+        // a random UUID, like the job ids
+        .unwrap_or_else(|| JobId::new().to_string());
+    let span = info_span!("request", request_id = %id);
+    let mut response = REQUEST_ID
+        .scope(id.clone(), next.run(req))
+        .instrument(span)
+        .await;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(HEADER, value);
+    }
+    response
+}
@@ -0,0 +1,463 @@
+use crate::job::JobContext;
+use async_trait::async_trait;
+use std::{
+    ffi::OsString,
+    path::PathBuf,
+    process::{Output, Stdio},
+    time::Duration,
+};
+
+/// Pipeline stages running subprocesses, each with its own timeout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Clone,
+    Build,
+    Convert,
+    Imatrix,
+    Quantize,
+    Verify,
+}
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let stage = match self {
+            Stage::Clone => "clone",
+            Stage::Build => "build",
+            Stage::Convert => "convert",
+            Stage::Imatrix => "imatrix",
+            Stage::Quantize => "quantize",
+            Stage::Verify => "verify",
+        };
+        write!(f, "{}", stage)
+    }
+}
+impl Stage {
+    /// Every stage, in the order of the variants
+    const ALL: [Stage; 6] = [
+        Stage::Clone,
+        Stage::Build,
+        Stage::Convert,
+        Stage::Imatrix,
+        Stage::Quantize,
+        Stage::Verify,
+    ];
+
+    /// Environment variable overriding the timeout of the stage, in seconds
+    fn timeout_var(&self) -> &'static str {
+        match self {
+            Stage::Clone => "GGML_CLONE_TIMEOUT_SECS",
+            Stage::Build => "GGML_BUILD_TIMEOUT_SECS",
+            Stage::Convert => "GGML_CONVERT_TIMEOUT_SECS",
+            Stage::Imatrix => "GGML_IMATRIX_TIMEOUT_SECS",
+            Stage::Quantize => "GGML_QUANTIZE_TIMEOUT_SECS",
+            Stage::Verify => "GGML_VERIFY_TIMEOUT_SECS",
+        }
+    }
+
+    fn default_timeout(&self) -> Duration {
+        let secs = match self {
+            Stage::Clone => 60 * 60,
+            Stage::Build => 30 * 60,
+            Stage::Convert => 2 * 60 * 60,
+            Stage::Imatrix => 2 * 60 * 60,
+            Stage::Quantize => 60 * 60,
+            Stage::Verify => 10 * 60,
+        };
+        Duration::from_secs(secs)
+    }
+}
+
+/// How long each stage may run before its subprocess is killed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts([Duration; Stage::ALL.len()]);
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts(Stage::ALL.map(|stage| stage.default_timeout()))
+    }
+}
+
+impl Timeouts {
+    /// The defaults, overridden by `GGML_<STAGE>_TIMEOUT_SECS`, e.g.
+    /// `GGML_CONVERT_TIMEOUT_SECS`
+    pub fn from_env() -> Result<Self, String> {
+        let mut timeouts = Timeouts::default();
+        for stage in Stage::ALL {
+            let var = stage.timeout_var();
+            if let Ok(secs) = std::env::var(var) {
+                match secs.parse::<u64>() {
+                    Ok(secs) if secs > 0 => {
+                        timeouts = timeouts.with(stage, Duration::from_secs(secs))
+                    }
+                    _ => {
+                        return Err(format!(
+                            "Invalid {var} '{secs}', expected a positive number of seconds"
+                        ))
+                    }
+                }
+            }
+        }
+        Ok(timeouts)
+    }
+
+    pub fn with(mut self, stage: Stage, timeout: Duration) -> Self {
+        self.0[stage as usize] = timeout;
+        self
+    }
+
+    pub fn get(&self, stage: Stage) -> Duration {
+        self.0[stage as usize]
+    }
+}
+
+/// A subprocess of the pipeline, described independently of how it gets run
+#[derive(Debug, Clone)]
+pub struct CommandSpec {
+    pub program: OsString,
+    pub args: Vec<OsString>,
+    /// Every command sets its own, the process' working directory is shared by all the jobs
+    pub current_dir: PathBuf,
+    pub envs: Vec<(OsString, OsString)>,
+}
+
+impl CommandSpec {
+    pub fn new(program: impl Into<OsString>, current_dir: impl Into<PathBuf>) -> Self {
+        CommandSpec {
+            program: program.into(),
+            args: Vec::new(),
+            current_dir: current_dir.into(),
+            envs: Vec::new(),
+        }
+    }
+
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn env(mut self, key: impl Into<OsString>, value: impl Into<OsString>) -> Self {
+        self.envs.push((key.into(), value.into()));
+        self
+    }
+}
+
+/// The command line, as logged. The environment is left out, it may hold credentials.
+impl std::fmt::Display for CommandSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.program.to_string_lossy())?;
+        for arg in &self.args {
+            write!(f, " {}", arg.to_string_lossy())?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs the subprocesses of the pipeline. The pipeline only goes through this trait, so it
+/// can be driven without git, python or make installed.
+#[async_trait]
+pub trait CommandRunner: std::fmt::Debug + Send + Sync {
+    /// Run the command to completion, forwarding its output to the job's events.
+    ///
+    /// Fails with `Interrupted` when the job is cancelled and `TimedOut` when the stage's
+    /// timeout expires first.
+    async fn run(
+        &self,
+        command: CommandSpec,
+        stage: Stage,
+        ctx: &JobContext,
+    ) -> std::io::Result<Output>;
+}
+
+/// Spawns real processes, killing them if the job is cancelled or times out
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessRunner {
+    timeouts: Timeouts,
+}
+
+impl ProcessRunner {
+    pub fn new(timeouts: Timeouts) -> Self {
+        ProcessRunner { timeouts }
+    }
+}
+
+#[async_trait]
+impl CommandRunner for ProcessRunner {
+    async fn run(
+        &self,
+        command: CommandSpec,
+        stage: Stage,
+        ctx: &JobContext,
+    ) -> std::io::Result<Output> {
+        let mut child = tokio::process::Command::new(&command.program)
+            .args(&command.args)
+            .current_dir(&command.current_dir)
+            .envs(command.envs.iter().map(|(key, value)| (key, value)))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+
+        let run = async {
+            let (stdout, stderr, status) = tokio::join!(
+                forward_lines(stdout, ctx),
+                forward_lines(stderr, ctx),
+                child.wait()
+            );
+            Ok(Output {
+                status: status?,
+                stdout: stdout?.into_bytes(),
+                stderr: stderr?.into_bytes(),
+            })
+        };
+
+        let timeout = self.timeouts.get(stage);
+        tokio::select! {
+            output = tokio::time::timeout(timeout, run) => output.unwrap_or_else(|_| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("The {stage} step timed out after {} seconds", timeout.as_secs()),
+                ))
+            }),
+            _ = ctx.token.cancelled() => Err(std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                "job cancelled",
+            )),
+        }
+    }
+}
+
+/// Publish each line read from the pipe as a log event, returning everything read
+async fn forward_lines<R>(pipe: Option<R>, ctx: &JobContext) -> std::io::Result<String>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncBufReadExt;
+
+    let mut captured = String::new();
+    if let Some(pipe) = pipe {
+        let mut lines = tokio::io::BufReader::new(pipe).lines();
+        while let Some(line) = lines.next_line().await? {
+            captured.push_str(&line);
+            captured.push('\n');
+            ctx.events.log(line);
+        }
+    }
+    Ok(captured)
+}
+
+/// A runner for the tests: records every command and answers them with `respond` instead of
+/// running them
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::{
+        os::unix::process::ExitStatusExt,
+        process::ExitStatus,
+        sync::{Arc, Mutex},
+    };
+    use tokio::sync::Semaphore;
+
+    type Respond = dyn Fn(&CommandSpec, Stage) -> std::io::Result<Output> + Send + Sync;
+
+    pub struct MockCommandRunner {
+        calls: Mutex<Vec<(CommandSpec, Stage)>>,
+        respond: Box<Respond>,
+        /// Commands of the stage wait for a permit of the semaphore before being answered
+        held: Option<(Stage, Arc<Semaphore>)>,
+    }
+
+    impl std::fmt::Debug for MockCommandRunner {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("MockCommandRunner")
+                .field("calls", &self.calls)
+                .finish()
+        }
+    }
+
+    impl MockCommandRunner {
+        pub fn new(
+            respond: impl Fn(&CommandSpec, Stage) -> std::io::Result<Output> + Send + Sync + 'static,
+        ) -> Self {
+            MockCommandRunner {
+                calls: Mutex::default(),
+                respond: Box::new(respond),
+                held: None,
+            }
+        }
+
+        /// Keep each command of the stage running until a permit is added to `gate`, or its
+        /// job is cancelled
+        pub fn hold(mut self, stage: Stage, gate: Arc<Semaphore>) -> Self {
+            self.held = Some((stage, gate));
+            self
+        }
+
+        /// Every command succeeds, writing what the llama.cpp tools would, see `simulate`
+        pub fn llama_cpp() -> Self {
+            MockCommandRunner::new(simulate)
+        }
+
+        /// The commands run so far, in order
+        pub fn calls(&self) -> Vec<(CommandSpec, Stage)> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        pub fn stages(&self) -> Vec<Stage> {
+            self.calls().into_iter().map(|(_, stage)| stage).collect()
+        }
+
+        /// The command lines of the stage, as logged
+        pub fn commands(&self, stage: Stage) -> Vec<String> {
+            self.calls()
+                .into_iter()
+                .filter(|(_, called)| *called == stage)
+                .map(|(command, _)| command.to_string())
+                .collect()
+        }
+    }
+
+    #[async_trait]
+    impl CommandRunner for MockCommandRunner {
+        async fn run(
+            &self,
+            command: CommandSpec,
+            stage: Stage,
+            ctx: &JobContext,
+        ) -> std::io::Result<Output> {
+            self.calls.lock().unwrap().push((command.clone(), stage));
+            let cancelled =
+                || std::io::Error::new(std::io::ErrorKind::Interrupted, "job cancelled");
+            if ctx.token.is_cancelled() {
+                return Err(cancelled());
+            }
+            if let Some((_, gate)) = self.held.as_ref().filter(|(held, _)| *held == stage) {
+                tokio::select! {
+                    permit = gate.acquire() => permit.unwrap().forget(),
+                    _ = ctx.token.cancelled() => return Err(cancelled()),
+                }
+            }
+            (self.respond)(&command, stage)
+        }
+    }
+
+    /// Output of a process that exited with the code
+    pub fn exited(code: i32, stderr: &str) -> Output {
+        Output {
+            status: ExitStatus::from_raw(code << 8),
+            stdout: Vec::new(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    /// Output of a process killed by the signal
+    pub fn killed(signal: i32) -> Output {
+        Output {
+            status: ExitStatus::from_raw(signal),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        }
+    }
+
+    /// Succeed, writing the file each llama.cpp tool would: the checkout cloned, the tools
+    /// built, the converted model, the importance matrix and the quantized model
+    pub fn simulate(command: &CommandSpec, stage: Stage) -> std::io::Result<Output> {
+        let args: Vec<&std::ffi::OsStr> = command.args.iter().map(OsString::as_os_str).collect();
+        let after = |flag: &str| {
+            args.iter()
+                .position(|arg| *arg == flag)
+                .and_then(|index| args.get(index + 1))
+        };
+        match stage {
+            Stage::Clone if args.first().is_some_and(|arg| *arg == "clone") => {
+                if let Some(dir) = args.last() {
+                    std::fs::create_dir_all(dir)?;
+                }
+            }
+            Stage::Build if command.program == "make" => {
+                for tool in ["quantize", "main", "imatrix"] {
+                    std::fs::write(command.current_dir.join(tool), "")?;
+                }
+            }
+            Stage::Convert => {
+                if let Some(outfile) = after("--outfile") {
+                    std::fs::write(outfile, "converted")?;
+                }
+            }
+            Stage::Imatrix => {
+                if let Some(matrix) = after("-o") {
+                    std::fs::write(matrix, "imatrix")?;
+                }
+            }
+            // quantize [--imatrix <file>] <model> <outfile> <type>
+            Stage::Quantize if args.len() >= 3 => {
+                std::fs::write(args[args.len() - 2], "quantized")?;
+            }
+            _ => {}
+        }
+        Ok(exited(0, ""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{job_context, TestDir};
+
+    #[tokio::test]
+    async fn kills_a_subprocess_running_past_the_timeout_of_its_stage() {
+        let root = TestDir::new();
+        let runner = ProcessRunner::new(
+            Timeouts::default().with(Stage::Convert, Duration::from_millis(200)),
+        );
+        // the shell is replaced by sleep, the pid written is the one to be killed
+        let command = CommandSpec::new("sh", root.path())
+            .arg("-c")
+            .arg("echo $$ > pid && exec sleep 30");
+
+        let started = std::time::Instant::now();
+        let err = runner
+            .run(command, Stage::Convert, &job_context())
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let pid = std::fs::read_to_string(root.join("pid")).unwrap();
+        let stat = std::path::PathBuf::from(format!("/proc/{}/stat", pid.trim()));
+        crate::tests::eventually("the child to be killed", || {
+            // gone, or a zombie until it is reaped
+            std::fs::read_to_string(&stat).map_or(true, |stat| {
+                stat.rsplit(')')
+                    .next()
+                    .unwrap()
+                    .trim_start()
+                    .starts_with('Z')
+            })
+        })
+        .await;
+    }
+
+    #[test]
+    fn rejects_an_invalid_timeout_at_startup() {
+        std::env::set_var("GGML_VERIFY_TIMEOUT_SECS", "ten");
+        let invalid = Timeouts::from_env();
+        std::env::set_var("GGML_VERIFY_TIMEOUT_SECS", "0");
+        let zero = Timeouts::from_env();
+        std::env::set_var("GGML_VERIFY_TIMEOUT_SECS", "30");
+        let valid = Timeouts::from_env();
+        std::env::remove_var("GGML_VERIFY_TIMEOUT_SECS");
+
+        assert_eq!(
+            invalid,
+            Err(
+                "Invalid GGML_VERIFY_TIMEOUT_SECS 'ten', expected a positive number of seconds"
+                    .to_string()
+            )
+        );
+        assert!(zero.is_err());
+        let valid = valid.unwrap();
+        assert_eq!(valid.get(Stage::Verify), Duration::from_secs(30));
+        assert_eq!(valid.get(Stage::Convert), Duration::from_secs(2 * 60 * 60));
+    }
+}
@@ -0,0 +1,514 @@
+//! Upload of the converted files to an S3-compatible bucket, for deployments where the
+//! outputs directory of one node isn't reachable from the others.
+//!
+//! Requests are signed with AWS Signature Version 4 and addressed path-style
+//! (`{endpoint}/{bucket}/{key}`), which every S3-compatible store accepts.
+
+use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+use reqwest::{blocking, Method};
+use std::{
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Files above this size are uploaded in parts of this size, a single PUT is limited to 5 GiB
+const PART_SIZE: u64 = 256 * 1024 * 1024;
+
+/// The payload isn't hashed, the multi-GB files would have to be read twice
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Where and how to upload, from the `GGML_S3_*` environment variables
+#[derive(Clone)]
+pub struct S3Config {
+    /// e.g. `https://s3.us-east-1.amazonaws.com` or `http://minio:9000`
+    pub endpoint: String,
+    pub bucket: String,
+    /// Prepended to the file names, e.g. `models/`
+    pub prefix: String,
+    pub region: String,
+    access_key_id: String,
+    secret_access_key: String,
+    /// Hand out presigned urls valid this long instead of plain object urls
+    pub presign: Option<Duration>,
+}
+
+impl std::fmt::Debug for S3Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Config")
+            .field("endpoint", &self.endpoint)
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("region", &self.region)
+            .field("presign", &self.presign)
+            .finish_non_exhaustive()
+    }
+}
+
+impl S3Config {
+    /// `None` unless `GGML_S3_BUCKET` is set, outputs are then only served locally.
+    ///
+    /// `GGML_S3_ENDPOINT` defaults to AWS in `GGML_S3_REGION` (`us-east-1` by default), the
+    /// credentials come from `GGML_S3_ACCESS_KEY_ID`/`GGML_S3_SECRET_ACCESS_KEY` or the usual
+    /// `AWS_ACCESS_KEY_ID`/`AWS_SECRET_ACCESS_KEY`. `GGML_S3_PRESIGN_SECS` turns on presigned
+    /// download urls.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let Some(bucket) = var("GGML_S3_BUCKET") else {
+            return Ok(None);
+        };
+        let region = var("GGML_S3_REGION").unwrap_or_else(|| "us-east-1".to_string());
+        let endpoint = var("GGML_S3_ENDPOINT")
+            .unwrap_or_else(|| format!("https://s3.{region}.amazonaws.com"))
+            .trim_end_matches('/')
+            .to_string();
+        if reqwest::Url::parse(&endpoint).map_or(true, |url| !url.has_host()) {
+            return Err(format!("Invalid GGML_S3_ENDPOINT '{endpoint}'"));
+        }
+        let access_key_id = var("GGML_S3_ACCESS_KEY_ID")
+            .or_else(|| var("AWS_ACCESS_KEY_ID"))
+            .ok_or("GGML_S3_BUCKET is set but GGML_S3_ACCESS_KEY_ID is missing")?;
+        let secret_access_key = var("GGML_S3_SECRET_ACCESS_KEY")
+            .or_else(|| var("AWS_SECRET_ACCESS_KEY"))
+            .ok_or("GGML_S3_BUCKET is set but GGML_S3_SECRET_ACCESS_KEY is missing")?;
+        let presign = match var("GGML_S3_PRESIGN_SECS") {
+            Some(secs) => match secs.parse() {
+                // presigned urls can't outlive a week
+                Ok(secs) if secs > 0 && secs <= 7 * 24 * 3600 => Some(Duration::from_secs(secs)),
+                _ => return Err(format!("Invalid GGML_S3_PRESIGN_SECS '{secs}'")),
+            },
+            None => None,
+        };
+        Ok(Some(S3Config {
+            endpoint,
+            bucket,
+            prefix: var("GGML_S3_PREFIX").unwrap_or_default(),
+            region,
+            access_key_id,
+            secret_access_key,
+            presign,
+        }))
+    }
+
+    /// Key of the object holding the file: the prefix, with a `/` added when it lacks one,
+    /// then the file name
+    pub fn object_key(&self, filename: &str) -> String {
+        match self.prefix.trim_matches('/') {
+            "" => filename.to_string(),
+            prefix => format!("{prefix}/{filename}"),
+        }
+    }
+
+    /// Path of the object on the endpoint, each segment percent-encoded
+    fn object_path(&self, key: &str) -> String {
+        let key: Vec<String> = key.split('/').map(uri_encode).collect();
+        format!("/{}/{}", uri_encode(&self.bucket), key.join("/"))
+    }
+
+    /// `host[:port]` of the endpoint, as signed
+    fn host(&self) -> String {
+        let url = reqwest::Url::parse(&self.endpoint).expect("validated in from_env");
+        let host = url.host_str().unwrap_or_default();
+        match url.port() {
+            Some(port) => format!("{host}:{port}"),
+            None => host.to_string(),
+        }
+    }
+
+    /// Where clients download the object from: a presigned url when enabled, the plain
+    /// object url otherwise, which needs the bucket to be readable
+    pub fn download_url(&self, key: &str) -> String {
+        let path = self.object_path(key);
+        match self.presign {
+            Some(expires) => {
+                let query = self.presign_query(&path, expires, SystemTime::now());
+                format!("{}{path}?{query}", self.endpoint)
+            }
+            None => format!("{}{path}", self.endpoint),
+        }
+    }
+
+    /// Query string of a presigned GET of the object
+    fn presign_query(&self, path: &str, expires: Duration, now: SystemTime) -> String {
+        let (date, timestamp) = amz_dates(now);
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let mut query = vec![
+            ("X-Amz-Algorithm", "AWS4-HMAC-SHA256".to_string()),
+            (
+                "X-Amz-Credential",
+                format!("{}/{scope}", self.access_key_id),
+            ),
+            ("X-Amz-Date", timestamp.clone()),
+            ("X-Amz-Expires", expires.as_secs().to_string()),
+            ("X-Amz-SignedHeaders", "host".to_string()),
+        ];
+        let signed_query = canonical_query(&query);
+        let canonical_request = format!(
+            "GET\n{path}\n{signed_query}\nhost:{}\n\nhost\n{UNSIGNED_PAYLOAD}",
+            self.host()
+        );
+        let signature = self.signature(&canonical_request, &date, &timestamp);
+        query.push(("X-Amz-Signature", signature));
+        canonical_query(&query)
+    }
+
+    /// Hex signature of the canonical request
+    fn signature(&self, canonical_request: &str, date: &str, timestamp: &str) -> String {
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            hex(&openssl::sha::sha256(canonical_request.as_bytes()))
+        );
+        let key = [date, &self.region, "s3", "aws4_request"].iter().fold(
+            format!("AWS4{}", self.secret_access_key).into_bytes(),
+            |key, part| hmac(&key, part.as_bytes()),
+        );
+        hex(&hmac(&key, string_to_sign.as_bytes()))
+    }
+
+    /// Send a signed request for the object
+    fn send(
+        &self,
+        client: &blocking::Client,
+        method: Method,
+        key: &str,
+        query: &[(&str, String)],
+        body: Option<blocking::Body>,
+    ) -> Result<blocking::Response, String> {
+        let path = self.object_path(key);
+        let (date, timestamp) = amz_dates(SystemTime::now());
+        let host = self.host();
+        let canonical_query = canonical_query(query);
+        let canonical_request = format!(
+            "{method}\n{path}\n{canonical_query}\nhost:{host}\nx-amz-content-sha256:{UNSIGNED_PAYLOAD}\nx-amz-date:{timestamp}\n\nhost;x-amz-content-sha256;x-amz-date\n{UNSIGNED_PAYLOAD}"
+        );
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{date}/{}/s3/aws4_request, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+            self.access_key_id,
+            self.region,
+            self.signature(&canonical_request, &date, &timestamp)
+        );
+
+        let mut url = format!("{}{path}", self.endpoint);
+        if !canonical_query.is_empty() {
+            url = format!("{url}?{canonical_query}");
+        }
+        let mut request = client
+            .request(method.clone(), &url)
+            .header("x-amz-content-sha256", UNSIGNED_PAYLOAD)
+            .header("x-amz-date", &timestamp)
+            .header("authorization", authorization);
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+        let response = request
+            .send()
+            .map_err(|err| format!("{method} {url} failed: {err}"))?;
+        match response.status().is_success() {
+            true => Ok(response),
+            false => {
+                let status = response.status();
+                let body = response.text().unwrap_or_default();
+                Err(format!("{method} {url} answered {status}: {body}"))
+            }
+        }
+    }
+
+    /// Whether the object exists. Blocking, run it off the async runtime.
+    pub fn exists(&self, key: &str) -> Result<bool, String> {
+        match self.send(&client()?, Method::HEAD, key, &[], None) {
+            Ok(_) => Ok(true),
+            Err(err) if err.contains(" answered 404") => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Upload the file as the object, streaming it from disk. Blocking, run it off the async
+    /// runtime.
+    pub fn upload(&self, path: &Path, key: &str) -> Result<(), String> {
+        let client = client()?;
+        let len = std::fs::metadata(path)
+            .map_err(|err| format!("Reading {}: {err}", path.display()))?
+            .len();
+        if len <= PART_SIZE {
+            let file = open(path)?;
+            self.send(
+                &client,
+                Method::PUT,
+                key,
+                &[],
+                Some(blocking::Body::sized(file, len)),
+            )?;
+            return Ok(());
+        }
+
+        let response = self.send(
+            &client,
+            Method::POST,
+            key,
+            &[("uploads", String::new())],
+            None,
+        )?;
+        let body = response.text().map_err(|err| err.to_string())?;
+        let upload_id = xml_value(&body, "UploadId")
+            .ok_or_else(|| format!("No UploadId in the answer to the multipart upload: {body}"))?
+            .to_string();
+        match self.upload_parts(&client, path, key, len, &upload_id) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                // parts of an unfinished upload are billed until it is aborted
+                let aborted = self.send(
+                    &client,
+                    Method::DELETE,
+                    key,
+                    &[("uploadId", upload_id)],
+                    None,
+                );
+                if let Err(abort_err) = aborted {
+                    tracing::warn!("Failed to abort the upload of {key}: {abort_err}");
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn upload_parts(
+        &self,
+        client: &blocking::Client,
+        path: &Path,
+        key: &str,
+        len: u64,
+        upload_id: &str,
+    ) -> Result<(), String> {
+        let mut parts = String::new();
+        for (index, offset) in (0..len).step_by(PART_SIZE as usize).enumerate() {
+            let part_number = index + 1;
+            let size = PART_SIZE.min(len - offset);
+            let mut file = open(path)?;
+            file.seek(SeekFrom::Start(offset))
+                .map_err(|err| format!("Reading {}: {err}", path.display()))?;
+            let response = self.send(
+                client,
+                Method::PUT,
+                key,
+                &[
+                    ("partNumber", part_number.to_string()),
+                    ("uploadId", upload_id.to_string()),
+                ],
+                Some(blocking::Body::sized(file.take(size), size)),
+            )?;
+            let etag = response
+                .headers()
+                .get("etag")
+                .and_then(|etag| etag.to_str().ok())
+                .ok_or_else(|| format!("No ETag for part {part_number} of {key}"))?;
+            parts.push_str(&format!(
+                "<Part><PartNumber>{part_number}</PartNumber><ETag>{etag}</ETag></Part>"
+            ));
+        }
+        let complete = format!("<CompleteMultipartUpload>{parts}</CompleteMultipartUpload>");
+        let response = self.send(
+            client,
+            Method::POST,
+            key,
+            &[("uploadId", upload_id.to_string())],
+            Some(complete.into()),
+        )?;
+        // S3 can answer 200 and still report a failure in the body
+        let body = response.text().unwrap_or_default();
+        match body.contains("<Error>") {
+            true => Err(format!("Completing the upload of {key} failed: {body}")),
+            false => Ok(()),
+        }
+    }
+}
+
+/// No timeout, uploads of multi-GB files take as long as they take
+fn client() -> Result<blocking::Client, String> {
+    blocking::Client::builder()
+        .timeout(None)
+        .build()
+        .map_err(|err| err.to_string())
+}
+
+fn open(path: &Path) -> Result<std::fs::File, String> {
+    std::fs::File::open(path).map_err(|err| format!("Reading {}: {err}", path.display()))
+}
+
+/// Text of the first `<tag>` of an XML document
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{tag}>"))?;
+    Some(&xml[start..end])
+}
+
+pub fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let key = PKey::hmac(key).expect("any key is a valid HMAC key");
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).expect("SHA-256 is available");
+    signer
+        .sign_oneshot_to_vec(data)
+        .expect("HMAC never fails on a buffer")
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Percent-encode everything but the unreserved characters, as SigV4 requires
+fn uri_encode(s: &str) -> String {
+    s.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Query parameters encoded and sorted by name, as they are signed
+fn canonical_query(query: &[(&str, String)]) -> String {
+    let mut pairs: Vec<String> = query
+        .iter()
+        .map(|(name, value)| format!("{}={}", uri_encode(name), uri_encode(value)))
+        .collect();
+    pairs.sort();
+    pairs.join("&")
+}
+
+/// `YYYYMMDD` and `YYYYMMDDTHHMMSSZ` of the time, in UTC
+fn amz_dates(time: SystemTime) -> (String, String) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+    // days since the epoch to a civil date, from Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let date = format!("{year:04}{month:02}{day:02}");
+    let timestamp = format!(
+        "{date}T{:02}{:02}{:02}Z",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    );
+    (date, timestamp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{serve_router, TestDir};
+    use axum::{
+        body::Bytes,
+        extract::Extension,
+        http::{HeaderMap, Method, StatusCode, Uri},
+        Router,
+    };
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// A bucket keeping the objects in memory, PUT and HEAD only
+    async fn bucket(
+        method: Method,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Bytes,
+        Extension(objects): Extension<Objects>,
+    ) -> StatusCode {
+        let signed = headers["authorization"]
+            .to_str()
+            .unwrap()
+            .starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/");
+        if !signed || !headers.contains_key("x-amz-date") {
+            return StatusCode::FORBIDDEN;
+        }
+        let mut objects = objects.lock().unwrap();
+        match method {
+            Method::PUT => {
+                objects.insert(uri.path().to_string(), body.to_vec());
+                StatusCode::OK
+            }
+            Method::HEAD if objects.contains_key(uri.path()) => StatusCode::OK,
+            Method::HEAD => StatusCode::NOT_FOUND,
+            _ => StatusCode::METHOD_NOT_ALLOWED,
+        }
+    }
+
+    fn s3_config(endpoint: String, prefix: &str) -> S3Config {
+        S3Config {
+            endpoint,
+            bucket: "models".to_string(),
+            prefix: prefix.to_string(),
+            region: "us-east-1".to_string(),
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            presign: None,
+        }
+    }
+
+    #[test]
+    fn keys_the_object_under_the_prefix() {
+        let endpoint = "http://minio:9000".to_string();
+
+        assert_eq!(
+            s3_config(endpoint.clone(), "").object_key("tiny-q4_0.gguf"),
+            "tiny-q4_0.gguf"
+        );
+        for prefix in ["ggml", "ggml/", "/ggml/"] {
+            assert_eq!(
+                s3_config(endpoint.clone(), prefix).object_key("tiny-q4_0.gguf"),
+                "ggml/tiny-q4_0.gguf"
+            );
+        }
+        assert_eq!(
+            s3_config(endpoint, "ggml").download_url("ggml/tiny q4.gguf"),
+            "http://minio:9000/models/ggml/tiny%20q4.gguf"
+        );
+    }
+
+    #[tokio::test]
+    async fn uploads_a_file_to_its_key() {
+        let objects = Objects::default();
+        let endpoint = serve_router(
+            Router::new()
+                .fallback(axum::routing::any(bucket))
+                .layer(Extension(objects.clone())),
+        );
+        let s3 = s3_config(endpoint, "ggml/");
+        let dir = TestDir::new();
+        let file = dir.join("tiny-q4_0.gguf");
+        std::fs::write(&file, "quantized").unwrap();
+        let key = s3.object_key("tiny-q4_0.gguf");
+
+        let exists = tokio::task::spawn_blocking(move || {
+            let before = s3.exists(&key)?;
+            s3.upload(&file, &key)?;
+            Ok::<_, String>((before, s3.exists(&key)?))
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(exists, (false, true));
+        assert_eq!(
+            *objects.lock().unwrap(),
+            HashMap::from([(
+                "/models/ggml/tiny-q4_0.gguf".to_string(),
+                b"quantized".to_vec()
+            )])
+        );
+    }
+}
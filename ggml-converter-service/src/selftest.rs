@@ -0,0 +1,234 @@
+//! `POST /selftest`: a conversion of a tiny model through the real pipeline, to tell whether a
+//! deployment works end to end without downloading gigabytes.
+//!
+//! The model is a GPT-2 of a single layer with 64-wide embeddings and a byte-level vocabulary,
+//! written by the service itself with zeroed weights. It converts and quantizes in seconds.
+
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    future::Future,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// How long a report is served again before the next request runs the self-test anew
+pub const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
+const VOCAB_SIZE: usize = 256;
+const EMBEDDING: usize = 64;
+const CONTEXT: usize = 64;
+
+/// Outcome of a stage of the self-test
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StageStatus {
+    Passed,
+    Failed,
+    /// Not run, an earlier stage failed
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StageReport {
+    pub stage: &'static str,
+    pub status: StageStatus,
+    pub seconds: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SelfTestReport {
+    pub passed: bool,
+    pub stages: Vec<StageReport>,
+    /// Unix time the self-test ran at, the report may come from the cache
+    pub ran_at: u64,
+}
+
+impl SelfTestReport {
+    pub fn new(stages: Vec<StageReport>) -> Self {
+        SelfTestReport {
+            passed: stages
+                .iter()
+                .all(|stage| stage.status == StageStatus::Passed),
+            stages,
+            ran_at: crate::job::now_secs(),
+        }
+    }
+}
+
+/// Runs the stages in order, the ones after a failure are skipped
+#[derive(Debug, Default)]
+pub struct Stages {
+    reports: Vec<StageReport>,
+}
+
+impl Stages {
+    /// Run the stage unless an earlier one failed, timing it
+    pub async fn run<T, E: std::fmt::Display>(
+        &mut self,
+        stage: &'static str,
+        run: impl Future<Output = Result<T, E>>,
+    ) -> Option<T> {
+        if self
+            .reports
+            .iter()
+            .any(|report| report.status != StageStatus::Passed)
+        {
+            self.reports.push(StageReport {
+                stage,
+                status: StageStatus::Skipped,
+                seconds: 0.0,
+                error: None,
+            });
+            return None;
+        }
+        let start = Instant::now();
+        let result = run.await;
+        let seconds = start.elapsed().as_secs_f64();
+        let (status, error, value) = match result {
+            Ok(value) => (StageStatus::Passed, None, Some(value)),
+            Err(err) => (StageStatus::Failed, Some(err.to_string()), None),
+        };
+        self.reports.push(StageReport {
+            stage,
+            status,
+            seconds,
+            error,
+        });
+        value
+    }
+
+    pub fn report(self) -> SelfTestReport {
+        SelfTestReport::new(self.reports)
+    }
+}
+
+/// The last report, shared by the requests
+#[derive(Debug, Clone, Default)]
+pub struct SelfTestCache {
+    last: Arc<tokio::sync::Mutex<Option<(Instant, SelfTestReport)>>>,
+}
+
+impl SelfTestCache {
+    /// The report of the last run if recent enough, else the report of a new one. Requests
+    /// arriving during a run wait for it rather than starting their own.
+    pub async fn get_or_run<F>(&self, run: impl FnOnce() -> F) -> SelfTestReport
+    where
+        F: Future<Output = SelfTestReport>,
+    {
+        let mut last = self.last.lock().await;
+        if let Some((ran_at, report)) = last.as_ref() {
+            if ran_at.elapsed() < CACHE_TTL {
+                return report.clone();
+            }
+        }
+        let report = run().await;
+        *last = Some((Instant::now(), report.clone()));
+        report
+    }
+}
+
+/// Write the tiny model as a Hugging Face GPT-2 repo
+pub fn write_fixture(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let config = json!({
+        "architectures": ["GPT2LMHeadModel"],
+        "model_type": "gpt2",
+        "vocab_size": VOCAB_SIZE,
+        "n_embd": EMBEDDING,
+        "n_head": 2,
+        "n_layer": 1,
+        "n_positions": CONTEXT,
+        "n_ctx": CONTEXT,
+        "layer_norm_epsilon": 1e-5,
+        "activation_function": "gelu_new",
+        "bos_token_id": 0,
+        "eos_token_id": 0,
+    });
+    std::fs::write(dir.join("config.json"), config.to_string())?;
+
+    // GPT-2's byte-level alphabet, each byte is a token and there is nothing to merge
+    let vocab: serde_json::Map<String, serde_json::Value> = byte_alphabet()
+        .into_iter()
+        .enumerate()
+        .map(|(id, token)| (token.to_string(), json!(id)))
+        .collect();
+    std::fs::write(
+        dir.join("vocab.json"),
+        serde_json::Value::Object(vocab).to_string(),
+    )?;
+    std::fs::write(dir.join("merges.txt"), "#version: 0.2\n")?;
+    let tokenizer_config =
+        json!({ "model_max_length": CONTEXT, "tokenizer_class": "GPT2Tokenizer" });
+    std::fs::write(
+        dir.join("tokenizer_config.json"),
+        tokenizer_config.to_string(),
+    )?;
+
+    let layer = |name: &str| format!("h.0.{name}");
+    let tensors: Vec<(String, Vec<usize>)> = vec![
+        ("wte.weight".to_string(), vec![VOCAB_SIZE, EMBEDDING]),
+        ("wpe.weight".to_string(), vec![CONTEXT, EMBEDDING]),
+        (layer("ln_1.weight"), vec![EMBEDDING]),
+        (layer("ln_1.bias"), vec![EMBEDDING]),
+        (layer("attn.c_attn.weight"), vec![EMBEDDING, 3 * EMBEDDING]),
+        (layer("attn.c_attn.bias"), vec![3 * EMBEDDING]),
+        (layer("attn.c_proj.weight"), vec![EMBEDDING, EMBEDDING]),
+        (layer("attn.c_proj.bias"), vec![EMBEDDING]),
+        (layer("ln_2.weight"), vec![EMBEDDING]),
+        (layer("ln_2.bias"), vec![EMBEDDING]),
+        (layer("mlp.c_fc.weight"), vec![EMBEDDING, 4 * EMBEDDING]),
+        (layer("mlp.c_fc.bias"), vec![4 * EMBEDDING]),
+        (layer("mlp.c_proj.weight"), vec![4 * EMBEDDING, EMBEDDING]),
+        (layer("mlp.c_proj.bias"), vec![EMBEDDING]),
+        ("ln_f.weight".to_string(), vec![EMBEDDING]),
+        ("ln_f.bias".to_string(), vec![EMBEDDING]),
+    ];
+    std::fs::write(dir.join("model.safetensors"), safetensors(&tensors))
+}
+
+/// The characters GPT-2 stands each byte for, printable ones as themselves
+fn byte_alphabet() -> Vec<char> {
+    let mut shifted = 0;
+    (0..=255u32)
+        .map(|byte| {
+            let printable = matches!(byte, 0x21..=0x7E | 0xA1..=0xAC | 0xAE..=0xFF);
+            let code = match printable {
+                true => byte,
+                false => {
+                    shifted += 1;
+                    255 + shifted
+                }
+            };
+            char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+        })
+        .collect()
+}
+
+/// A safetensors file of zeroed F32 tensors: the length of the JSON header, the header giving
+/// each tensor's place in the data, then the data
+fn safetensors(tensors: &[(String, Vec<usize>)]) -> Vec<u8> {
+    let mut header = serde_json::Map::new();
+    header.insert("__metadata__".to_string(), json!({ "format": "pt" }));
+    let mut offset = 0;
+    for (name, shape) in tensors {
+        let len = shape.iter().product::<usize>() * std::mem::size_of::<f32>();
+        header.insert(
+            name.clone(),
+            json!({ "dtype": "F32", "shape": shape, "data_offsets": [offset, offset + len] }),
+        );
+        offset += len;
+    }
+    let mut header = serde_json::Value::Object(header).to_string().into_bytes();
+    // the data starts 8-byte aligned
+    while !header.len().is_multiple_of(8) {
+        header.push(b' ');
+    }
+    let mut file = (header.len() as u64).to_le_bytes().to_vec();
+    file.extend_from_slice(&header);
+    file.resize(file.len() + offset, 0);
+    file
+}
@@ -0,0 +1,91 @@
+//! Graceful shutdown: stop taking conversions, let the running ones finish for a while, then
+//! interrupt whatever is left
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
+
+/// How long running conversions get to finish, from `GGML_SHUTDOWN_TIMEOUT_SECS`, 30 seconds
+/// by default
+pub fn timeout() -> Duration {
+    let secs = std::env::var("GGML_SHUTDOWN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(30);
+    Duration::from_secs(secs)
+}
+
+/// Resolves on SIGTERM or Ctrl-C
+pub async fn signal() {
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                tracing::warn!("Failed to listen for SIGTERM: {err}");
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
+
+/// Whether the service is shutting down, and how many pipelines are still running.
+///
+/// Cloning is cheap, all clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    running: Arc<AtomicUsize>,
+}
+
+impl Shutdown {
+    /// Stop accepting conversions
+    pub fn begin(&self) {
+        self.token.cancel();
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Resolves once the shutdown has begun
+    pub async fn begun(self) {
+        self.token.cancelled().await
+    }
+
+    /// Count a pipeline as running until the guard is dropped
+    pub fn track(&self) -> RunningGuard {
+        self.running.fetch_add(1, Ordering::SeqCst);
+        RunningGuard(self.running.clone())
+    }
+
+    /// Wait for every pipeline to be over, `false` if some still run after `timeout`
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let idle = async {
+            while self.running.load(Ordering::SeqCst) > 0 {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+        };
+        tokio::time::timeout(timeout, idle).await.is_ok()
+    }
+}
+
+/// Held by a running pipeline, see [`Shutdown::track`]
+#[derive(Debug)]
+pub struct RunningGuard(Arc<AtomicUsize>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,188 @@
+//! Time-limited download urls, `/download/{filename}?expires=<unix time>&token=<hmac>`, so
+//! the outputs can't be fetched by anyone who guesses a file name.
+//!
+//! The token is the HMAC-SHA256 of the file name and the expiry with a secret of the server,
+//! it can't be forged nor moved to another file or expiry without the secret.
+
+use crate::{
+    error::AppError,
+    s3::{hex, hmac},
+};
+use serde::Deserialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long a url stays valid unless `GGML_DOWNLOAD_URL_TTL_SECS` says otherwise
+const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Signs and checks download urls, from `GGML_DOWNLOAD_SECRET`
+#[derive(Clone)]
+pub struct UrlSigner {
+    secret: Vec<u8>,
+    pub ttl: Duration,
+}
+
+impl std::fmt::Debug for UrlSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UrlSigner")
+            .field("secret", &"<redacted>")
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+/// The query of a signed url
+#[derive(Debug, Deserialize)]
+pub struct DownloadToken {
+    pub expires: Option<u64>,
+    pub token: Option<String>,
+}
+
+impl UrlSigner {
+    /// `None` unless `GGML_DOWNLOAD_SECRET` is set, downloads are open then
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let secret = match std::env::var("GGML_DOWNLOAD_SECRET") {
+            Ok(secret) if !secret.is_empty() => secret,
+            _ => return Ok(None),
+        };
+        // a short secret could be brute-forced from a single signed url
+        if secret.len() < 16 {
+            return Err("GGML_DOWNLOAD_SECRET must be at least 16 characters long".to_string());
+        }
+        let ttl = match std::env::var("GGML_DOWNLOAD_URL_TTL_SECS") {
+            Ok(secs) => match secs.parse::<u64>() {
+                Ok(secs) if secs > 0 => Duration::from_secs(secs),
+                _ => {
+                    return Err(format!(
+                        "Invalid GGML_DOWNLOAD_URL_TTL_SECS '{secs}', expected a positive number of seconds"
+                    ))
+                }
+            },
+            Err(_) => DEFAULT_TTL,
+        };
+        Ok(Some(UrlSigner {
+            secret: secret.into_bytes(),
+            ttl,
+        }))
+    }
+
+    /// `/download/{filename}`, valid for the configured time from now
+    pub fn sign(&self, filename: &str) -> String {
+        let expires = unix_time() + self.ttl.as_secs();
+        format!(
+            "/download/{filename}?expires={expires}&token={}",
+            self.token(filename, expires)
+        )
+    }
+
+    /// Check the url a file was requested with: 403 when it isn't signed by this server for
+    /// this file, 410 once it has expired
+    pub fn verify(&self, filename: &str, query: &DownloadToken) -> Result<(), AppError> {
+        let (expires, token) = match (query.expires, &query.token) {
+            (Some(expires), Some(token)) => (expires, token),
+            _ => {
+                return Err(AppError::Forbidden(
+                    "Downloads require a signed url".to_string(),
+                ))
+            }
+        };
+        let expected = self.token(filename, expires);
+        // compared in constant time, timing mustn't tell how much of a guess was right
+        if token.len() != expected.len()
+            || !openssl::memcmp::eq(token.as_bytes(), expected.as_bytes())
+        {
+            return Err(AppError::Forbidden("Invalid download token".to_string()));
+        }
+        // only checked once the token is known to be genuine, the expiry is part of it
+        if expires <= unix_time() {
+            return Err(AppError::Gone(format!(
+                "The download url of '{filename}' has expired"
+            )));
+        }
+        Ok(())
+    }
+
+    fn token(&self, filename: &str, expires: u64) -> String {
+        // the separator can't be part of a file name, no other pair signs the same
+        hex(&hmac(
+            &self.secret,
+            format!("{filename}\n{expires}").as_bytes(),
+        ))
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer() -> UrlSigner {
+        UrlSigner {
+            secret: b"a secret of the server".to_vec(),
+            ttl: Duration::from_secs(60),
+        }
+    }
+
+    /// The query of a signed url
+    fn query_of(url: &str) -> DownloadToken {
+        let url = reqwest::Url::parse(&format!("http://localhost{url}")).unwrap();
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+        DownloadToken {
+            expires: param("expires").map(|expires| expires.parse().unwrap()),
+            token: param("token"),
+        }
+    }
+
+    #[test]
+    fn accepts_the_url_it_signed() {
+        let signer = signer();
+        let url = signer.sign("tiny-q4_0.gguf");
+
+        assert!(url.starts_with("/download/tiny-q4_0.gguf?expires="));
+        assert!(signer.verify("tiny-q4_0.gguf", &query_of(&url)).is_ok());
+    }
+
+    #[test]
+    fn forbids_the_token_of_another_file() {
+        let signer = signer();
+        let query = query_of(&signer.sign("tiny-q4_0.gguf"));
+
+        let err = signer.verify("tiny-q8_0.gguf", &query).unwrap_err();
+
+        assert!(matches!(err, AppError::Forbidden(_)), "{err:?}");
+    }
+
+    #[test]
+    fn forbids_a_tampered_expiry() {
+        let signer = signer();
+        let mut query = query_of(&signer.sign("tiny-q4_0.gguf"));
+        query.expires = query.expires.map(|expires| expires + 3600);
+
+        let err = signer.verify("tiny-q4_0.gguf", &query).unwrap_err();
+
+        assert!(matches!(err, AppError::Forbidden(_)), "{err:?}");
+    }
+
+    #[test]
+    fn reports_an_expired_url_as_gone() {
+        let signer = signer();
+        let expires = unix_time() - 1;
+        let query = DownloadToken {
+            expires: Some(expires),
+            token: Some(signer.token("tiny-q4_0.gguf", expires)),
+        };
+
+        let err = signer.verify("tiny-q4_0.gguf", &query).unwrap_err();
+
+        assert!(matches!(err, AppError::Gone(_)), "{err:?}");
+    }
+}
@@ -0,0 +1,170 @@
+//! `GET /stats`: totals of the conversions in the job store, to see at a glance what the
+//! service converts and how well. Jobs removed by the cleanup or evicted beyond
+//! `GGML_MAX_JOBS` no longer count, unlike in the Prometheus counters.
+
+use crate::job::{target_state, Job, JobState, Timings};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    pub jobs: Counts,
+    pub by_model: BTreeMap<String, Counts>,
+    /// A job with several quants counts for each of them, with the outcome of the quant
+    pub by_quant: BTreeMap<String, Counts>,
+    /// Share of the finished jobs that are done, cancelled jobs aside. Unset until one finishes.
+    pub success_rate: Option<f64>,
+    pub failure_rate: Option<f64>,
+    /// Mean of the timings of the done jobs, unset until one is done
+    pub average_timings: Option<Timings>,
+    /// Total size of the outputs of the done jobs
+    pub bytes_produced: u64,
+}
+
+/// Jobs per outcome
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Counts {
+    pub total: usize,
+    pub done: usize,
+    /// Including the jobs interrupted by a shutdown
+    pub failed: usize,
+    pub cancelled: usize,
+    /// Queued or running
+    pub unfinished: usize,
+}
+
+impl Counts {
+    fn add(&mut self, state: JobState) {
+        self.total += 1;
+        match state {
+            JobState::Done => self.done += 1,
+            JobState::Failed | JobState::Interrupted => self.failed += 1,
+            JobState::Cancelled => self.cancelled += 1,
+            _ => self.unfinished += 1,
+        }
+    }
+}
+
+impl Stats {
+    pub fn new(jobs: &[Job]) -> Self {
+        let mut stats = Stats::default();
+        let mut timings: Vec<Timings> = Vec::new();
+        for job in jobs {
+            stats.jobs.add(job.state);
+            stats
+                .by_model
+                .entry(job.model_info.name.to_string())
+                .or_default()
+                .add(job.state);
+            let mut quants: Vec<String> = Vec::new();
+            for (format, quant) in job.model_info.targets() {
+                let state = target_state(job.state, &job.quant_statuses, format, &quant);
+                let quant = quant.to_string();
+                if !quants.contains(&quant) {
+                    stats.by_quant.entry(quant.clone()).or_default().add(state);
+                    quants.push(quant);
+                }
+            }
+            if job.state == JobState::Done {
+                timings.extend(job.timings);
+                stats.bytes_produced += job.size_bytes.unwrap_or_default();
+            }
+        }
+
+        let finished = stats.jobs.done + stats.jobs.failed;
+        if finished > 0 {
+            let success_rate = stats.jobs.done as f64 / finished as f64;
+            stats.success_rate = Some(success_rate);
+            stats.failure_rate = Some(1.0 - success_rate);
+        }
+        stats.average_timings = average(&timings);
+        stats
+    }
+}
+
+fn average(timings: &[Timings]) -> Option<Timings> {
+    if timings.is_empty() {
+        return None;
+    }
+    let mean =
+        |secs: fn(&Timings) -> f64| timings.iter().map(secs).sum::<f64>() / timings.len() as f64;
+    Some(Timings {
+        download_secs: mean(|timings| timings.download_secs),
+        build_secs: mean(|timings| timings.build_secs),
+        convert_secs: mean(|timings| timings.convert_secs),
+        quantize_secs: mean(|timings| timings.quantize_secs),
+        verify_secs: mean(|timings| timings.verify_secs),
+        total_secs: mean(|timings| timings.total_secs),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::model_info;
+    use serde_json::{json, Value};
+
+    fn job(name: &str, quant_info: Value, state: JobState) -> Job {
+        let mut job = Job::new(model_info(json!({"name": name, "quant_info": quant_info})));
+        job.state = state;
+        job
+    }
+
+    fn done(name: &str, total_secs: f64, size_bytes: u64) -> Job {
+        let mut job = job(name, json!("Q4"), JobState::Done);
+        job.timings = Some(Timings {
+            convert_secs: total_secs / 2.0,
+            total_secs,
+            ..Timings::default()
+        });
+        job.size_bytes = Some(size_bytes);
+        job
+    }
+
+    #[test]
+    fn totals_the_jobs_by_outcome_model_and_quant() {
+        let jobs = [
+            done("acme/tiny", 2.0, 10),
+            done("acme/tiny", 4.0, 20),
+            job("acme/tiny", json!("Q8"), JobState::Failed),
+            job("acme/small", json!("Q4"), JobState::Cancelled),
+            job("acme/small", json!(["Q4", "Q8"]), JobState::Converting),
+        ];
+
+        let stats = serde_json::to_value(Stats::new(&jobs)).unwrap();
+
+        let counts = |total, done, failed, cancelled, unfinished| {
+            json!({
+                "total": total,
+                "done": done,
+                "failed": failed,
+                "cancelled": cancelled,
+                "unfinished": unfinished,
+            })
+        };
+        assert_eq!(stats["jobs"], counts(5, 2, 1, 1, 1));
+        assert_eq!(
+            stats["by_model"],
+            json!({"acme/small": counts(2, 0, 0, 1, 1), "acme/tiny": counts(3, 2, 1, 0, 0)})
+        );
+        assert_eq!(
+            stats["by_quant"],
+            json!({"q4_0": counts(4, 2, 0, 1, 1), "q8_0": counts(2, 0, 1, 0, 1)})
+        );
+        // the cancelled job aside
+        assert_eq!(stats["success_rate"], json!(2.0 / 3.0));
+        assert_eq!(stats["failure_rate"], json!(1.0 - 2.0 / 3.0));
+        assert_eq!(stats["average_timings"]["total_secs"], 3.0);
+        assert_eq!(stats["average_timings"]["convert_secs"], 1.5);
+        assert_eq!(stats["bytes_produced"], 30);
+    }
+
+    #[test]
+    fn leaves_the_rates_unset_until_a_job_finishes() {
+        let stats = Stats::new(&[job("acme/tiny", json!("Q4"), JobState::Queued)]);
+
+        assert_eq!(stats.success_rate, None);
+        assert_eq!(stats.failure_rate, None);
+        assert!(stats.average_timings.is_none());
+    }
+}
@@ -0,0 +1,242 @@
+//! Where the outputs are served from. The handlers and the cleanup only go through the
+//! `Storage` trait, so the outputs directory can be swapped for another backend without
+//! touching them. The pipeline itself writes into `GGML_OUTPUTS_DIR`, the llama.cpp tools only
+//! take paths.
+
+use async_trait::async_trait;
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWriteExt};
+
+/// Extension of the files being written, never listed
+const PARTIAL_SUFFIX: &str = ".tmp";
+
+#[async_trait]
+pub trait Storage: std::fmt::Debug + Send + Sync {
+    /// Store the data under the name, returning its size. The previous file of that name is
+    /// only replaced once the data is all written.
+    async fn put(&self, name: &str, data: &mut (dyn AsyncRead + Send + Unpin)) -> Result<u64>;
+
+    /// The stored file, failing with `NotFound` when there is none
+    async fn get(&self, name: &str) -> Result<StoredFile>;
+
+    async fn exists(&self, name: &str) -> Result<bool>;
+
+    /// Remove the file, returning its size, `None` when there was none
+    async fn delete(&self, name: &str) -> Result<Option<u64>>;
+
+    /// The stored files, by name, leaving out the ones being written
+    async fn list(&self) -> Result<Vec<ListedFile>>;
+}
+
+/// Anything a stored file can be read from, seeking for `Range` requests
+pub trait Reader: AsyncRead + AsyncSeek + Send + Unpin {}
+impl<T: AsyncRead + AsyncSeek + Send + Unpin> Reader for T {}
+
+/// A stored file open for reading
+pub struct StoredFile {
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    pub reader: Box<dyn Reader>,
+}
+
+impl StoredFile {
+    /// Open a file on disk
+    pub async fn open(path: &Path) -> Result<Self> {
+        let file = tokio::fs::File::open(path).await?;
+        let metadata = file.metadata().await?;
+        Ok(StoredFile {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            reader: Box::new(file),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedFile {
+    pub name: String,
+    pub len: u64,
+}
+
+/// The files of a directory, `GGML_OUTPUTS_DIR` by default
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        LocalStorage { dir: dir.into() }
+    }
+
+    /// Path of the file, for names that can't leave the directory
+    fn path(&self, name: &str) -> Result<PathBuf> {
+        let plain = !name.is_empty()
+            && name != "."
+            && name != ".."
+            && !name.contains('/')
+            && !name.contains('\\');
+        match plain {
+            true => Ok(self.dir.join(name)),
+            false => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid file name '{name}'"),
+            )),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, name: &str, data: &mut (dyn AsyncRead + Send + Unpin)) -> Result<u64> {
+        let path = self.path(name)?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        // written aside then moved, a reader never sees half a file
+        let mut partial = path.clone().into_os_string();
+        partial.push(PARTIAL_SUFFIX);
+        let mut file = tokio::fs::File::create(&partial).await?;
+        let len = tokio::io::copy(data, &mut file).await?;
+        file.flush().await?;
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(len)
+    }
+
+    async fn get(&self, name: &str) -> Result<StoredFile> {
+        let path = self.path(name)?;
+        // a directory opens fine on unix, it isn't a stored file
+        if !tokio::fs::metadata(&path).await?.is_file() {
+            return Err(ErrorKind::NotFound.into());
+        }
+        StoredFile::open(&path).await
+    }
+
+    async fn exists(&self, name: &str) -> Result<bool> {
+        match tokio::fs::metadata(self.path(name)?).await {
+            Ok(metadata) => Ok(metadata.is_file()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn delete(&self, name: &str) -> Result<Option<u64>> {
+        let path = self.path(name)?;
+        let len = match tokio::fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_file() => metadata.len(),
+            Ok(_) => return Ok(None),
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(Some(len)),
+            // removed meanwhile
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<ListedFile>> {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            // created by the first conversion
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        let mut files = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let metadata = entry.metadata().await?;
+            if metadata.is_file() && !name.ends_with(PARTIAL_SUFFIX) {
+                files.push(ListedFile {
+                    name,
+                    len: metadata.len(),
+                });
+            }
+        }
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::TestDir;
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn stores_reads_lists_and_deletes_files() {
+        let root = TestDir::new();
+        // created by the first file stored
+        let storage = LocalStorage::new(root.join("outputs"));
+        assert_eq!(storage.list().await.unwrap(), []);
+
+        let len = storage
+            .put("tiny.gguf", &mut &b"converted"[..])
+            .await
+            .unwrap();
+        assert_eq!(len, 9);
+        storage
+            .put("tiny-q4_0.gguf", &mut &b"quantized"[..])
+            .await
+            .unwrap();
+        // replaced whole
+        storage
+            .put("tiny-q4_0.gguf", &mut &b"requantized"[..])
+            .await
+            .unwrap();
+        std::fs::write(root.join("outputs/tiny-q8_0.gguf.tmp"), "half").unwrap();
+
+        let mut file = storage.get("tiny-q4_0.gguf").await.unwrap();
+        assert_eq!(file.len, 11);
+        let mut content = String::new();
+        file.reader.read_to_string(&mut content).await.unwrap();
+        assert_eq!(content, "requantized");
+        assert!(storage.exists("tiny.gguf").await.unwrap());
+        assert_eq!(
+            storage.list().await.unwrap(),
+            [
+                ListedFile {
+                    name: "tiny-q4_0.gguf".to_string(),
+                    len: 11,
+                },
+                ListedFile {
+                    name: "tiny.gguf".to_string(),
+                    len: 9,
+                },
+            ]
+        );
+
+        assert_eq!(storage.delete("tiny.gguf").await.unwrap(), Some(9));
+        assert_eq!(storage.delete("tiny.gguf").await.unwrap(), None);
+        assert!(!storage.exists("tiny.gguf").await.unwrap());
+        let err = storage.get("tiny.gguf").await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn keeps_names_inside_the_directory() {
+        let root = TestDir::new();
+        std::fs::create_dir_all(root.join("outputs/dir")).unwrap();
+        std::fs::write(root.join("secret"), "secret").unwrap();
+        let storage = LocalStorage::new(root.join("outputs"));
+
+        for name in ["../secret", "", ".", "..", "a\\b"] {
+            let err = storage.get(name).await.err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{name}");
+            assert!(storage.delete(name).await.is_err(), "{name}");
+        }
+        // a directory isn't a stored file
+        assert_eq!(
+            storage.get("dir").await.err().unwrap().kind(),
+            ErrorKind::NotFound
+        );
+        assert!(!storage.exists("dir").await.unwrap());
+        assert_eq!(storage.delete("dir").await.unwrap(), None);
+    }
+}
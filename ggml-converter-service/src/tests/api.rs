@@ -0,0 +1,2017 @@
+//! The routes, served on a port of their own with the subprocesses mocked
+
+use super::{
+    config, eventually, llama_cpp_checkout, llama_model, local_model, model_info, script, serve,
+    services, TestDir, ENV,
+};
+use crate::{
+    converter::ConverterEnv,
+    job::{Job, JobId, JobState},
+    queue::{ConversionQueue, OutputConflict},
+    rate_limit::RateLimiter,
+    runner::{
+        mock::{exited, killed, simulate, MockCommandRunner},
+        Stage,
+    },
+};
+use serde_json::{json, Value};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Semaphore;
+
+/// Post the conversion request, returning the id of the job created
+async fn convert(url: &str, request: Value) -> String {
+    let response = reqwest::Client::new()
+        .post(format!("{url}/ggml"))
+        .json(&request)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 202);
+    let created: Value = response.json().await.unwrap();
+    created["job_id"].as_str().unwrap().to_string()
+}
+
+/// Register the model under the name
+async fn register(url: &str, name: &str, model_url: &str) {
+    let response = reqwest::Client::new()
+        .post(format!("{url}/models"))
+        .json(&json!({"name": name, "url": model_url}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 201);
+}
+
+/// A runner whose clones of a model bring a Llama model along
+fn cloning_runner() -> MockCommandRunner {
+    MockCommandRunner::new(|command, stage| {
+        if stage == Stage::Clone && command.program == "git" && command.args[0] == "clone" {
+            llama_model(std::path::Path::new(command.args.last().unwrap()));
+        }
+        simulate(command, stage)
+    })
+}
+
+/// Poll the job until it is over, returning its last status
+async fn finished_job(url: &str, job_id: &str) -> Value {
+    for _ in 0..200 {
+        let status: Value = reqwest::get(format!("{url}/jobs/{job_id}"))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        if !matches!(
+            status["state"].as_str(),
+            Some("Queued" | "Downloading" | "Converting" | "Quantizing")
+        ) {
+            return status;
+        }
+        tokio::time::sleep(Duration::from_millis(25)).await;
+    }
+    panic!("job {job_id} still running");
+}
+
+#[tokio::test]
+async fn reports_the_state_of_a_job() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "tiny");
+    let url = serve(services(config, Arc::new(MockCommandRunner::llama_cpp())));
+
+    let job_id = convert(
+        &url,
+        json!({"name": {"local_path": "tiny"}, "quant_info": "Q4"}),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    assert_eq!(status["quant"], "q4_0");
+    assert!(status["download_url"].is_string());
+}
+
+#[tokio::test]
+async fn converts_a_model_from_the_local_models_directory() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    let dir = local_model(&config, "on-disk").canonicalize().unwrap();
+    llama_model(&root.join("elsewhere"));
+    let runner = Arc::new(MockCommandRunner::llama_cpp());
+    let url = serve(services(config, runner.clone()));
+
+    let job_id = convert(
+        &url,
+        json!({"name": {"local_path": "on-disk"}, "quant_info": "Q4"}),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    assert!(runner.commands(Stage::Clone).is_empty());
+    let convert = &runner.commands(Stage::Convert)[0];
+    assert!(convert.contains(&dir.display().to_string()), "{convert}");
+
+    let response = reqwest::Client::new()
+        .post(format!("{url}/ggml"))
+        .json(&json!({"name": {"local_path": "../elsewhere"}, "quant_info": "Q4"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 403);
+}
+
+#[tokio::test]
+async fn runs_the_configured_converter_with_the_requested_args() {
+    let root = TestDir::new();
+    let mut config = config(root.path());
+    let checkout = llama_cpp_checkout(&config);
+    std::fs::rename(
+        checkout.join("convert-hf-to-gguf.py"),
+        checkout.join("convert-renamed.py"),
+    )
+    .unwrap();
+    config.gguf_converter = "convert-renamed.py".to_string();
+    local_model(&config, "with-args");
+    let runner = Arc::new(MockCommandRunner::llama_cpp());
+    let url = serve(services(config, runner.clone()));
+
+    let job_id = convert(
+        &url,
+        json!({
+            "name": {"local_path": "with-args"},
+            "quant_info": "Q4",
+            "converter_args": ["--pad-vocab", "--vocab-type", "bpe", "--outtype=f16"],
+        }),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    let convert = &runner.commands(Stage::Convert)[0];
+    let script = checkout.join("convert-renamed.py");
+    assert!(
+        convert.contains(&format!(
+            "{} --pad-vocab --vocab-type bpe --outtype=f16 ",
+            script.display()
+        )),
+        "{convert}"
+    );
+}
+
+#[tokio::test]
+async fn answers_the_state_of_a_job_as_plain_text_on_request() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "plain");
+    let url = serve(services(config, Arc::new(MockCommandRunner::llama_cpp())));
+    let job_id = convert(
+        &url,
+        json!({"name": {"local_path": "plain"}, "quant_info": "Q4"}),
+    )
+    .await;
+    let status = finished_job(&url, &job_id).await;
+    let get = |accept: &'static str| {
+        reqwest::Client::new()
+            .get(format!("{url}/jobs/{job_id}"))
+            .header("accept", accept)
+            .send()
+    };
+
+    let response = get("text/plain").await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+    assert_eq!(
+        response.text().await.unwrap(),
+        format!("Done {}\n", status["download_url"].as_str().unwrap())
+    );
+
+    let response = get("application/json").await.unwrap();
+    assert_eq!(response.headers()["content-type"], "application/json");
+    let json: Value = response.json().await.unwrap();
+    assert_eq!(json["state"], "Done");
+}
+
+#[tokio::test]
+async fn gives_each_error_its_code() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "coded");
+    let url = serve(services(config, Arc::new(MockCommandRunner::llama_cpp())));
+    let finished = convert(
+        &url,
+        json!({"name": {"local_path": "coded"}, "quant_info": "Q4"}),
+    )
+    .await;
+    finished_job(&url, &finished).await;
+    let client = reqwest::Client::new();
+    let unknown = JobId::new();
+
+    for (request, status, code) in [
+        (
+            client.get(format!("{url}/jobs/{unknown}")),
+            404,
+            "JOB_NOT_FOUND",
+        ),
+        (
+            client.get(format!("{url}/batch/{unknown}")),
+            404,
+            "BATCH_NOT_FOUND",
+        ),
+        (
+            client.get(format!("{url}/batch/not-an-id")),
+            400,
+            "BAD_REQUEST",
+        ),
+        (
+            client.get(format!("{url}/download/missing.gguf")),
+            404,
+            "FILE_NOT_FOUND",
+        ),
+        (
+            client.delete(format!("{url}/jobs/{finished}")),
+            409,
+            "JOB_FINISHED",
+        ),
+        (
+            client
+                .post(format!("{url}/ggml"))
+                .header("content-type", "application/json")
+                .body("{"),
+            400,
+            "BAD_REQUEST",
+        ),
+        (
+            client
+                .post(format!("{url}/validate"))
+                .json(&json!({"name": "no-owner", "quant_info": "Q4"})),
+            400,
+            "INVALID_REQUEST",
+        ),
+        // rejected by axum itself
+        (
+            client.get(format!("{url}/jobs?limit=many")),
+            422,
+            "BAD_REQUEST",
+        ),
+        (client.get(format!("{url}/no-such-route")), 404, "NOT_FOUND"),
+        (client.put(format!("{url}/ggml")), 405, "METHOD_NOT_ALLOWED"),
+        (client.get(format!("{url}/custom_error")), 500, "INTERNAL"),
+    ] {
+        let request = request.build().unwrap();
+        let route = format!("{} {}", request.method(), request.url().path());
+        let response = client.execute(request).await.unwrap();
+
+        assert_eq!(response.status(), status, "{route}");
+        let error: Value = response.json().await.unwrap();
+        assert_eq!(error["code"], code, "{route}: {error}");
+        assert!(error["message"].is_string(), "{route}: {error}");
+        if code == "JOB_FINISHED" {
+            assert_eq!(error["details"]["state"], "Done");
+        }
+    }
+}
+
+#[tokio::test]
+async fn converts_each_format_of_the_targets_once() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "targeted");
+    let runner = Arc::new(MockCommandRunner::llama_cpp());
+    let url = serve(services(config, runner.clone()));
+
+    let job_id = convert(
+        &url,
+        json!({
+            "name": {"local_path": "targeted"},
+            "targets": [
+                {"format": "Gguf", "quant": "Q4_K_M"},
+                {"format": "Ggml", "quant": "Q4"},
+                {"format": "Gguf", "quant": "Q8"},
+            ],
+        }),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    let file_name = |path: &str| {
+        std::path::Path::new(path)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned()
+    };
+    // the script and the output of each conversion
+    let converted: Vec<(String, String)> = runner
+        .commands(Stage::Convert)
+        .iter()
+        .map(|command| {
+            let args: Vec<&str> = command.split(' ').collect();
+            (file_name(args[1]), file_name(args[args.len() - 1]))
+        })
+        .collect();
+    assert_eq!(
+        converted,
+        [
+            ("convert-hf-to-gguf.py".into(), "targeted.gguf".into()),
+            ("convert.py".into(), "targeted-ggml.bin".into()),
+        ]
+    );
+    // the input, the output and the type of each quantization
+    let quantized: Vec<[String; 3]> = runner
+        .commands(Stage::Quantize)
+        .iter()
+        .map(|command| {
+            let args: Vec<&str> = command.rsplitn(4, ' ').collect();
+            [file_name(args[2]), file_name(args[1]), args[0].to_string()]
+        })
+        .collect();
+    assert_eq!(
+        quantized,
+        [
+            ["targeted.gguf", "targeted-q4_K_M.gguf.tmp", "q4_K_M"],
+            ["targeted-ggml.bin", "targeted-ggml-q4_0.bin.tmp", "q4_0"],
+            ["targeted.gguf", "targeted-q8_0.gguf.tmp", "q8_0"],
+        ]
+        .map(|quantization| quantization.map(String::from))
+    );
+    assert_eq!(status["quant"], "gguf-q4_K_M,ggml-q4_0,gguf-q8_0");
+    assert_eq!(
+        status["outputs"][1],
+        json!({
+            "download_url": "/download/targeted-ggml-q4_0.bin",
+            "format": "Ggml",
+            "quant": "Q4",
+            "unquantized": false,
+        })
+    );
+}
+
+#[tokio::test]
+async fn sets_the_converter_env_for_the_converter_only() {
+    let root = TestDir::new();
+    let mut config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "with-env");
+    {
+        let _env = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        std::env::set_var("GGML_CONVERT_ENV_HF_HOME", "/data/hf");
+        std::env::set_var("GGML_CONVERT_ENV_HF_TOKEN", "hf_secret");
+        config.converter_env = ConverterEnv::from_env();
+        std::env::remove_var("GGML_CONVERT_ENV_HF_HOME");
+        std::env::remove_var("GGML_CONVERT_ENV_HF_TOKEN");
+    }
+    assert_eq!(
+        config.converter_env.to_string(),
+        "HF_HOME=/data/hf HF_TOKEN=<redacted>"
+    );
+    let runner = Arc::new(MockCommandRunner::llama_cpp());
+    let url = serve(services(config, runner.clone()));
+
+    let job_id = convert(
+        &url,
+        json!({"name": {"local_path": "with-env"}, "quant_info": "Q4"}),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    let envs = |stage| -> Vec<(String, String)> {
+        let calls = runner.calls();
+        let (command, _) = calls.iter().find(|(_, called)| *called == stage).unwrap();
+        command
+            .envs
+            .iter()
+            .map(|(var, value)| {
+                (
+                    var.to_string_lossy().into_owned(),
+                    value.to_string_lossy().into_owned(),
+                )
+            })
+            .collect()
+    };
+    let converter_envs = envs(Stage::Convert);
+    for var in [("HF_HOME", "/data/hf"), ("HF_TOKEN", "hf_secret")] {
+        assert!(
+            converter_envs.contains(&(var.0.to_string(), var.1.to_string())),
+            "{converter_envs:?}"
+        );
+    }
+    assert!(!envs(Stage::Quantize)
+        .iter()
+        .any(|(var, _)| var.starts_with("HF_")));
+}
+
+#[tokio::test]
+async fn echoes_the_request_id_or_generates_one() {
+    let root = TestDir::new();
+    let url = serve(services(
+        config(root.path()),
+        Arc::new(MockCommandRunner::llama_cpp()),
+    ));
+    let client = reqwest::Client::new();
+    let unknown_job = format!("{url}/jobs/{}", JobId::new());
+
+    let response = client
+        .get(&unknown_job)
+        .header("x-request-id", "client-trace-42")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["x-request-id"], "client-trace-42");
+    let error: Value = response.json().await.unwrap();
+    assert_eq!(error["request_id"], "client-trace-42");
+
+    // an id unfit for a header or the logs is replaced too
+    for header in [None, Some("with space")] {
+        let mut request = client.get(&unknown_job);
+        if let Some(id) = header {
+            request = request.header("x-request-id", id);
+        }
+        let response = request.send().await.unwrap();
+        let id = response.headers()["x-request-id"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(id.parse::<JobId>().is_ok(), "{id}");
+        let error: Value = response.json().await.unwrap();
+        assert_eq!(error["request_id"], id);
+    }
+}
+
+#[tokio::test]
+async fn converts_without_quantizing_for_a_null_quant_info() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "full-precision");
+    let outputs_dir = config.outputs_dir.clone();
+    let runner = Arc::new(MockCommandRunner::llama_cpp());
+    let url = serve(services(config, runner.clone()));
+
+    let job_id = convert(
+        &url,
+        json!({"name": {"local_path": "full-precision"}, "quant_info": null}),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    assert!(runner.commands(Stage::Quantize).is_empty());
+    assert_eq!(runner.commands(Stage::Convert).len(), 1);
+    assert_eq!(
+        status["download_urls"],
+        json!(["/download/full-precision.gguf"])
+    );
+    assert_eq!(status["outputs"][0]["unquantized"], true);
+    // the output is the converted model, kept
+    assert_eq!(
+        std::fs::read_to_string(outputs_dir.join("full-precision.gguf")).unwrap(),
+        "converted"
+    );
+}
+
+#[tokio::test]
+async fn answers_404_for_an_unknown_job() {
+    let root = TestDir::new();
+    let url = serve(services(
+        config(root.path()),
+        Arc::new(MockCommandRunner::llama_cpp()),
+    ));
+    let job_id = JobId::new();
+
+    let response = reqwest::get(format!("{url}/jobs/{job_id}")).await.unwrap();
+
+    assert_eq!(response.status(), 404);
+    assert_eq!(response.headers()["content-type"], "application/json");
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["code"], "JOB_NOT_FOUND");
+    assert_eq!(body["message"], format!("Job '{job_id}' not found"));
+}
+
+#[tokio::test]
+async fn filters_the_jobs_listed_by_state() {
+    let root = TestDir::new();
+    let services = services(
+        config(root.path()),
+        Arc::new(MockCommandRunner::llama_cpp()),
+    );
+    let mut ids = Vec::new();
+    for (model, state) in [
+        ("owner/queued", JobState::Queued),
+        ("owner/converting", JobState::Converting),
+        ("owner/failed", JobState::Failed),
+    ] {
+        let job = Job::new(model_info(json!({"name": model, "quant_info": "Q4"})));
+        let id = services.jobs.insert_unless_running(job).unwrap();
+        services.jobs.update_state(id, state);
+        ids.push(id.to_string());
+    }
+    let url = serve(services);
+    let listed = |query: &'static str| {
+        let url = url.clone();
+        async move {
+            let list: Value = reqwest::get(format!("{url}/jobs{query}"))
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            let mut ids: Vec<String> = list["jobs"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|job| job["id"].as_str().unwrap().to_string())
+                .collect();
+            ids.sort();
+            ids
+        }
+    };
+
+    let mut all = ids.clone();
+    all.sort();
+    assert_eq!(listed("").await, all);
+    assert_eq!(listed("?state=Converting").await, [ids[1].clone()]);
+    assert_eq!(listed("?state=Failed").await, [ids[2].clone()]);
+    assert!(listed("?state=Done").await.is_empty());
+}
+
+#[tokio::test]
+async fn rejects_an_invalid_request_with_a_descriptive_400() {
+    let root = TestDir::new();
+    let url = serve(services(
+        config(root.path()),
+        Arc::new(MockCommandRunner::llama_cpp()),
+    ));
+    let client = reqwest::Client::new();
+    let post = |body: &'static str| {
+        client
+            .post(format!("{url}/ggml"))
+            .header("content-type", "application/json")
+            .body(body)
+            .send()
+    };
+
+    for (body, expected) in [
+        (
+            r#"{"name": "no-owner", "quant_info": "Q4"}"#,
+            "Invalid model name 'no-owner'",
+        ),
+        (
+            r#"{"name": "acme/tiny", "quant_info": "Q9"}"#,
+            "invalid quant_info",
+        ),
+        ("name=acme/tiny", "The request body is not valid JSON"),
+    ] {
+        let response = post(body).await.unwrap();
+
+        assert_eq!(response.status(), 400, "{body}");
+        let error: Value = response.json().await.unwrap();
+        assert_eq!(error["code"], "BAD_REQUEST");
+        let message = error["message"].as_str().unwrap();
+        assert!(message.contains(expected), "{body}: {message}");
+    }
+}
+
+#[tokio::test]
+async fn validates_a_request_without_queueing_it() {
+    let root = TestDir::new();
+    let services = services(
+        config(root.path()),
+        Arc::new(MockCommandRunner::llama_cpp()),
+    );
+    let jobs = services.jobs.clone();
+    let url = serve(services);
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post(format!("{url}/validate"))
+        .json(&json!({"name": "acme/validated", "quant_info": "Q4"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let resolved: Value = response.json().await.unwrap();
+    assert_eq!(resolved["converted_file"], "validated.gguf");
+    assert_eq!(resolved["output_files"], json!(["validated-q4_0.gguf"]));
+    assert!(resolved["model_url"]
+        .as_str()
+        .unwrap()
+        .ends_with("acme/validated"));
+    assert!(jobs.list(None, None).is_empty());
+
+    let response = client
+        .post(format!("{url}/validate"))
+        .json(&json!({"name": "no-owner", "quant_info": "Q4", "callback_url": "http://127.0.0.1/hook"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 400);
+    let error: Value = response.json().await.unwrap();
+    assert_eq!(error["code"], "INVALID_REQUEST");
+    let problems = error["details"]["problems"].as_array().unwrap();
+    assert_eq!(problems.len(), 2, "{problems:?}");
+    assert!(problems[0]
+        .as_str()
+        .unwrap()
+        .contains("Invalid model name 'no-owner'"));
+}
+
+#[tokio::test]
+async fn rate_limits_a_client_hammering_ggml() {
+    let root = TestDir::new();
+    let mut services = services(
+        config(root.path()),
+        Arc::new(MockCommandRunner::llama_cpp()),
+    );
+    services.rate_limiter = RateLimiter::new(3);
+    let url = serve(services);
+    let client = reqwest::Client::new();
+
+    let mut statuses = Vec::new();
+    for i in 0..5 {
+        let response = client
+            .post(format!("{url}/ggml"))
+            // made up by the client, who isn't a trusted proxy
+            .header("x-forwarded-for", format!("198.51.100.{i}"))
+            .json(&json!({"name": "no-owner", "quant_info": "Q4"}))
+            .send()
+            .await
+            .unwrap();
+        statuses.push(response.status().as_u16());
+        if response.status() == 429 {
+            let retry_after: u64 = response.headers()["retry-after"]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!((1..=60).contains(&retry_after), "{retry_after}");
+        }
+    }
+
+    assert_eq!(statuses, [400, 400, 400, 429, 429]);
+}
+
+#[tokio::test]
+async fn accepts_no_new_job_once_shutting_down() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "late");
+    let services = services(config, Arc::new(MockCommandRunner::llama_cpp()));
+    let (jobs, shutdown) = (services.jobs.clone(), services.shutdown.clone());
+    let url = serve(services);
+
+    shutdown.begin();
+    let response = reqwest::Client::new()
+        .post(format!("{url}/ggml"))
+        .json(&json!({"name": {"local_path": "late"}, "quant_info": "Q4"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 503);
+    let error: Value = response.json().await.unwrap();
+    assert!(
+        error["message"].as_str().unwrap().contains("shutting down"),
+        "{error}"
+    );
+    assert_eq!(jobs.count(), 0);
+    assert!(shutdown.drain(Duration::from_millis(100)).await);
+}
+
+#[tokio::test]
+async fn gives_the_same_job_back_for_the_same_idempotency_key() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    for name in ["keyed", "other-key"] {
+        local_model(&config, name);
+    }
+    let url = serve(services(config, Arc::new(MockCommandRunner::llama_cpp())));
+    let client = reqwest::Client::new();
+    let post = |key: &'static str, name: &'static str| {
+        client
+            .post(format!("{url}/ggml"))
+            .header("idempotency-key", key)
+            .json(&json!({"name": {"local_path": name}, "quant_info": "Q4"}))
+            .send()
+    };
+    let job_id = |response: reqwest::Response| async move {
+        assert_eq!(response.status(), 202);
+        let created: Value = response.json().await.unwrap();
+        created["job_id"].as_str().unwrap().to_string()
+    };
+
+    let first = job_id(post("retry-1", "keyed").await.unwrap()).await;
+    // the key alone decides, whatever the request
+    let retried = job_id(post("retry-1", "other-key").await.unwrap()).await;
+    let other = job_id(post("retry-2", "other-key").await.unwrap()).await;
+
+    assert_eq!(retried, first);
+    assert_ne!(other, first);
+    let response = post("not a key", "keyed").await.unwrap();
+    assert_eq!(response.status(), 400);
+}
+
+#[tokio::test]
+async fn coalesces_identical_requests_into_one_job() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "twice");
+    let gate = Arc::new(Semaphore::new(0));
+    let runner = Arc::new(MockCommandRunner::llama_cpp().hold(Stage::Convert, gate.clone()));
+    let url = serve(services(config, runner.clone()));
+    let request = json!({"name": {"local_path": "twice"}, "quant_info": "Q4"});
+
+    let spawn = |request: Value| {
+        let url = url.clone();
+        tokio::spawn(async move { convert(&url, request).await })
+    };
+
+    let (first, second) = tokio::join!(spawn(request.clone()), spawn(request));
+    let (first, second) = (first.unwrap(), second.unwrap());
+    gate.add_permits(1);
+
+    assert_eq!(first, second);
+    let status = finished_job(&url, &first).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    assert_eq!(runner.commands(Stage::Convert).len(), 1);
+    assert_eq!(runner.commands(Stage::Quantize).len(), 1);
+}
+
+#[tokio::test]
+async fn answers_409_to_a_second_writer_of_an_output() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "contended");
+    let gate = Arc::new(Semaphore::new(0));
+    let runner = MockCommandRunner::llama_cpp().hold(Stage::Convert, gate.clone());
+    let mut services = services(config, Arc::new(runner));
+    // the conversion may be shared, only the quantized outputs may not
+    services.queue = ConversionQueue::new(2, OutputConflict::Wait);
+    let url = serve(services);
+    let first = convert(
+        &url,
+        json!({"name": {"local_path": "contended"}, "quant_info": ["Q4", "Q8"]}),
+    )
+    .await;
+
+    // not the same conversion, but it would write contended-q4_0.gguf too
+    let response = reqwest::Client::new()
+        .post(format!("{url}/ggml"))
+        .json(&json!({"name": {"local_path": "contended"}, "quant_info": "Q4"}))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 409);
+    let error: Value = response.json().await.unwrap();
+    assert_eq!(
+        error["message"],
+        format!("'contended-q4_0.gguf' is already being written by job {first}")
+    );
+    gate.add_permits(1);
+    let status = finished_job(&url, &first).await;
+    assert_eq!(status["state"], "Done", "{status}");
+}
+
+#[tokio::test]
+async fn runs_one_job_at_a_time_with_a_limit_of_one() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    let gate = Arc::new(Semaphore::new(0));
+    let runner = MockCommandRunner::llama_cpp().hold(Stage::Convert, gate.clone());
+    let mut services = services(config.clone(), Arc::new(runner));
+    services.queue = ConversionQueue::new(1, OutputConflict::Reject);
+    let jobs = services.jobs.clone();
+    let url = serve(services);
+    let mut ids = Vec::new();
+    for name in ["one", "two", "three"] {
+        local_model(&config, name);
+        let id = convert(
+            &url,
+            json!({"name": {"local_path": name}, "quant_info": "Q4"}),
+        )
+        .await;
+        ids.push(id.parse::<JobId>().unwrap());
+    }
+    let states =
+        || -> Vec<JobState> { ids.iter().map(|&id| jobs.get(id).unwrap().state).collect() };
+    let running = || {
+        states()
+            .iter()
+            .filter(|state| **state != JobState::Queued && !state.is_finished())
+            .count()
+    };
+
+    for done in 1..=3 {
+        eventually("a job to convert", || {
+            states().contains(&JobState::Converting)
+        })
+        .await;
+        // the others stay queued for as long as the conversion is held
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(running(), 1, "{:?}", states());
+        assert_eq!(
+            states()
+                .iter()
+                .filter(|state| **state == JobState::Queued)
+                .count(),
+            3 - done
+        );
+        gate.add_permits(1);
+        eventually("the job to be done", || {
+            states()
+                .iter()
+                .filter(|state| **state == JobState::Done)
+                .count()
+                == done
+        })
+        .await;
+    }
+}
+
+#[tokio::test]
+async fn converts_a_registered_model_from_its_url() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    let runner = Arc::new(cloning_runner());
+    let url = serve(services(config, runner.clone()));
+    let model_url = "https://git.example.com/acme/tiny";
+
+    register(&url, "acme/registered-tiny", model_url).await;
+    let job_id = convert(
+        &url,
+        json!({"name": "acme/registered-tiny", "quant_info": "Q4"}),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    let clone = &runner.commands(Stage::Clone)[0];
+    assert!(
+        clone.starts_with(&format!("git clone {model_url} ")),
+        "{clone}"
+    );
+}
+
+#[tokio::test]
+async fn resumes_an_interrupted_clone_on_the_retry() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    let interrupted = std::sync::atomic::AtomicBool::new(false);
+    let runner = Arc::new(MockCommandRunner::new(move |command, stage| {
+        let args: Vec<_> = command
+            .args
+            .iter()
+            .map(|arg| arg.to_str().unwrap())
+            .collect();
+        match (stage, args.as_slice()) {
+            // the first clone dies once it has a commit, before the checkout
+            (Stage::Clone, ["clone", .., dir])
+                if !interrupted.swap(true, std::sync::atomic::Ordering::SeqCst) =>
+            {
+                std::fs::create_dir_all(std::path::Path::new(dir).join(".git")).unwrap();
+                Ok(exited(128, "fatal: early EOF"))
+            }
+            (Stage::Clone, ["lfs", "pull"]) => {
+                llama_model(&command.current_dir);
+                simulate(command, stage)
+            }
+            _ => simulate(command, stage),
+        }
+    }));
+    let url = serve(services(config, runner.clone()));
+
+    register(&url, "acme/resumed", "https://git.example.com/acme/resumed").await;
+    let job_id = convert(&url, json!({"name": "acme/resumed", "quant_info": "Q4"})).await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    let clone = runner.commands(Stage::Clone);
+    assert!(clone[0].starts_with("git clone "), "{clone:?}");
+    // then the commit converted is recorded
+    assert_eq!(clone[5..], ["git rev-parse HEAD"]);
+    assert_eq!(
+        clone[1..5],
+        [
+            "git rev-parse --verify --quiet HEAD",
+            "git fetch origin",
+            "git reset --hard HEAD",
+            "git lfs pull",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn cancels_the_jobs_of_a_model_only() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    let gate = Arc::new(Semaphore::new(0));
+    let runner = cloning_runner().hold(Stage::Clone, gate);
+    let mut services = services(config, Arc::new(runner));
+    services.queue = ConversionQueue::new(2, OutputConflict::Wait);
+    let jobs = services.jobs.clone();
+    let url = serve(services);
+    for name in ["acme/cancelled", "acme/kept"] {
+        register(&url, name, &format!("https://git.example.com/{name}")).await;
+    }
+    let cancelled = [
+        convert(&url, json!({"name": "acme/cancelled", "quant_info": "Q4"})).await,
+        convert(&url, json!({"name": "acme/cancelled", "quant_info": "Q8"})).await,
+    ];
+    let kept = convert(&url, json!({"name": "acme/kept", "quant_info": "Q4"})).await;
+    let cancel = || async {
+        let response = reqwest::Client::new()
+            .post(format!("{url}/models/acme%2Fcancelled/cancel"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        response.json::<Value>().await.unwrap()
+    };
+
+    let body = cancel().await;
+
+    assert_eq!(body["name"], "acme/cancelled");
+    let mut ids: Vec<&str> = body["cancelled"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|id| id.as_str().unwrap())
+        .collect();
+    ids.sort_unstable();
+    let mut expected: Vec<&str> = cancelled.iter().map(String::as_str).collect();
+    expected.sort_unstable();
+    assert_eq!(ids, expected);
+    for job_id in &cancelled {
+        let job = jobs.get(job_id.parse().unwrap()).unwrap();
+        assert_eq!(job.state, JobState::Cancelled);
+    }
+    let kept = jobs.get(kept.parse().unwrap()).unwrap();
+    assert!(!kept.state.is_finished(), "{:?}", kept.state);
+    // none of its jobs is left to cancel
+    assert_eq!(cancel().await["cancelled"], json!([]));
+}
+
+#[tokio::test]
+async fn clones_the_requested_revision() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    let models_dir = config.models_dir.clone();
+    let runner = Arc::new(cloning_runner());
+    let url = serve(services(config, runner.clone()));
+    let model_url = "https://git.example.com/acme/pinned";
+    register(&url, "acme/pinned", model_url).await;
+
+    let job_id = convert(
+        &url,
+        json!({"name": "acme/pinned", "quant_info": "Q4", "revision": "release/v1.0"}),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    let repo_dir = models_dir.join("pinned@release%2Fv1.0");
+    let clone = runner.commands(Stage::Clone);
+    assert_eq!(
+        clone[..2],
+        [
+            format!("git clone --no-checkout {model_url} {}", repo_dir.display()),
+            "git checkout release/v1.0 --".to_string(),
+        ]
+    );
+    let response = reqwest::Client::new()
+        .post(format!("{url}/ggml"))
+        .json(&json!({"name": "acme/pinned", "quant_info": "Q4", "revision": "--upload-pack=x"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+}
+
+#[tokio::test]
+async fn lists_and_deletes_a_registered_model() {
+    let root = TestDir::new();
+    let url = serve(services(
+        config(root.path()),
+        Arc::new(MockCommandRunner::llama_cpp()),
+    ));
+    let client = reqwest::Client::new();
+    let model_url = "https://git.example.com/acme/listed";
+    let listed = || async {
+        let models: Value = reqwest::get(format!("{url}/models"))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        models["acme/listed-model"].as_str().map(str::to_string)
+    };
+    client
+        .post(format!("{url}/models"))
+        .json(&json!({"name": "acme/listed-model", "url": model_url}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(listed().await.as_deref(), Some(model_url));
+
+    // the slash of the repo id has to be percent-encoded to stay in a single segment
+    let response = client
+        .delete(format!("{url}/models/acme/listed-model"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+    assert_eq!(listed().await.as_deref(), Some(model_url));
+
+    let response = client
+        .delete(format!("{url}/models/acme%2Flisted-model"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let deleted: Value = response.json().await.unwrap();
+    assert_eq!(
+        deleted,
+        json!({"name": "acme/listed-model", "url": model_url})
+    );
+    assert_eq!(listed().await, None);
+
+    let response = client
+        .delete(format!("{url}/models/acme%2Flisted-model"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["code"], "MODEL_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn writes_a_file_per_quant() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "tiny");
+    let runner = Arc::new(MockCommandRunner::llama_cpp());
+    let url = serve(services(config.clone(), runner.clone()));
+
+    let job_id = convert(
+        &url,
+        json!({"name": {"local_path": "tiny"}, "quant_info": ["Q4", "Q8"]}),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    let urls: Vec<&str> = status["download_urls"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|url| url.as_str().unwrap())
+        .collect();
+    assert_eq!(urls.len(), 2);
+    assert_ne!(urls[0], urls[1]);
+    // a single conversion feeds both quantizations
+    assert_eq!(runner.commands(Stage::Convert).len(), 1);
+    assert_eq!(runner.commands(Stage::Quantize).len(), 2);
+    for url in urls {
+        let filename = url.rsplit('/').next().unwrap();
+        let output = config.outputs_dir.join(filename);
+        assert_eq!(std::fs::read_to_string(output).unwrap(), "quantized");
+    }
+}
+
+#[tokio::test]
+async fn quantizes_with_the_computed_importance_matrix() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "calibrated");
+    let imatrix_dir = config.models_dir.join("imatrix");
+    std::fs::create_dir_all(&imatrix_dir).unwrap();
+    std::fs::write(imatrix_dir.join("calibration.txt"), "The quick brown fox").unwrap();
+    let runner = Arc::new(MockCommandRunner::llama_cpp());
+    let url = serve(services(config, runner.clone()));
+
+    let job_id = convert(
+        &url,
+        json!({
+            "name": {"local_path": "calibrated"},
+            "quant_info": "Q4_K_M",
+            "format": "Gguf",
+            "imatrix": {"dataset": "calibration.txt", "chunks": 10},
+        }),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    let imatrix = runner.calls();
+    let (imatrix, _) = imatrix
+        .iter()
+        .find(|(_, stage)| *stage == Stage::Imatrix)
+        .expect("the importance matrix is computed");
+    let args: Vec<String> = imatrix
+        .args
+        .iter()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(
+        args[2..4],
+        [
+            "-f",
+            &imatrix_dir.join("calibration.txt").display().to_string()
+        ]
+    );
+    assert_eq!(args[6..], ["--chunks", "10"]);
+    let matrix = args[5].trim_end_matches(".tmp");
+    let quantize = runner.commands(Stage::Quantize);
+    assert_eq!(quantize.len(), 1);
+    assert!(
+        quantize[0].contains(&format!("--imatrix {matrix} ")),
+        "{quantize:?} {matrix}"
+    );
+    assert!(std::path::Path::new(matrix).is_file());
+}
+
+#[tokio::test]
+async fn offloads_the_importance_matrix_only_to_a_gpu_build() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    let imatrix_tool = llama_cpp_checkout(&config).join("imatrix");
+    let imatrix_dir = config.models_dir.join("imatrix");
+    std::fs::create_dir_all(&imatrix_dir).unwrap();
+    std::fs::write(imatrix_dir.join("calibration.txt"), "The quick brown fox").unwrap();
+    let runner = Arc::new(MockCommandRunner::llama_cpp());
+    let url = serve(services(config.clone(), runner.clone()));
+    let request = |name: &str| {
+        local_model(&config, name);
+        json!({
+            "name": {"local_path": name},
+            "quant_info": "Q4_K_M",
+            "format": "Gguf",
+            "imatrix": {"dataset": "calibration.txt"},
+            "gpu": {"layers": 20},
+        })
+    };
+
+    script(&imatrix_tool, "echo '  -t N, --threads N'");
+    let job_id = convert(&url, request("on-cpu")).await;
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Failed", "{status}");
+    assert!(
+        status["error"]
+            .as_str()
+            .unwrap()
+            .contains("was built without GPU support"),
+        "{status}"
+    );
+    assert!(runner.commands(Stage::Imatrix).is_empty());
+
+    script(&imatrix_tool, "echo '  -ngl N, --n-gpu-layers N'");
+    let job_id = convert(&url, request("on-gpu")).await;
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    let imatrix = runner.commands(Stage::Imatrix);
+    assert!(imatrix[0].ends_with(" --n-gpu-layers 20"), "{imatrix:?}");
+    // quantize has no GPU support
+    let quantize = runner.commands(Stage::Quantize);
+    assert!(!quantize[0].contains("--n-gpu-layers"), "{quantize:?}");
+}
+
+#[tokio::test]
+async fn converts_an_uploaded_model() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    let model_dir = root.join("fixture");
+    llama_model(&model_dir);
+    let mut archive = tar::Builder::new(Vec::new());
+    archive.append_dir_all("uploaded", &model_dir).unwrap();
+    let archive = archive.into_inner().unwrap();
+    let runner = Arc::new(MockCommandRunner::llama_cpp());
+    let url = serve(services(config.clone(), runner.clone()));
+    let client = reqwest::Client::new();
+    let upload = |part: &[u8], start: usize| {
+        let end = start + part.len() - 1;
+        client
+            .post(format!("{url}/upload/acme/uploaded"))
+            .header(
+                "content-range",
+                format!("bytes {start}-{end}/{}", archive.len()),
+            )
+            .body(part.to_vec())
+            .send()
+    };
+
+    // in two parts, as a resumed upload
+    let half = archive.len() / 2;
+    let response = upload(&archive[..half], 0).await.unwrap();
+    assert_eq!(response.status(), 202);
+    let response = upload(&archive[half..], half).await.unwrap();
+    assert_eq!(response.status(), 201);
+    let uploaded: Value = response.json().await.unwrap();
+    assert_eq!(uploaded["name"], "acme/uploaded");
+    assert_eq!(uploaded["files"], 3);
+    assert!(config.models_dir.join("uploaded/config.json").is_file());
+
+    let job_id = convert(&url, json!({"name": "acme/uploaded", "quant_info": "Q4"})).await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    assert_eq!(
+        status["download_urls"],
+        json!(["/download/uploaded-q4_0.gguf"])
+    );
+    // converted from the upload, nothing was cloned
+    assert!(runner.commands(Stage::Clone).is_empty());
+    let convert = runner.commands(Stage::Convert);
+    assert!(
+        convert[0].contains(&config.models_dir.join("uploaded").display().to_string()),
+        "{convert:?}"
+    );
+}
+
+#[tokio::test]
+async fn runs_a_batch_as_one_job_per_entry() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    let names = ["batch-one", "batch-two", "batch-three"];
+    for name in names {
+        local_model(&config, name);
+    }
+    let url = serve(services(config, Arc::new(MockCommandRunner::llama_cpp())));
+    let entries: Vec<Value> = names
+        .iter()
+        .map(|name| json!({"name": {"local_path": name}, "quant_info": "Q4"}))
+        .collect();
+
+    let response = reqwest::Client::new()
+        .post(format!("{url}/batch"))
+        .json(&json!({ "jobs": entries }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 202);
+    let created: Value = response.json().await.unwrap();
+    let job_ids: Vec<&str> = created["job_ids"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|id| id.as_str().unwrap())
+        .collect();
+    assert_eq!(job_ids.len(), 3);
+    for job_id in &job_ids {
+        let status = finished_job(&url, job_id).await;
+        assert_eq!(status["state"], "Done", "{status}");
+    }
+    let batch: Value = reqwest::get(format!(
+        "{url}/batch/{}",
+        created["batch_id"].as_str().unwrap()
+    ))
+    .await
+    .unwrap()
+    .json()
+    .await
+    .unwrap();
+    assert_eq!(batch["batch_id"], created["batch_id"]);
+    assert_eq!(batch["job_ids"], created["job_ids"]);
+    assert_eq!(batch["counts"], json!({"Done": 3}));
+    assert_eq!(batch["finished"], true);
+}
+
+#[tokio::test]
+async fn writes_the_output_under_the_requested_name() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "renamed");
+    let url = serve(services(
+        config.clone(),
+        Arc::new(MockCommandRunner::llama_cpp()),
+    ));
+
+    let job_id = convert(
+        &url,
+        json!({
+            "name": {"local_path": "renamed"},
+            "quant_info": "Q4",
+            "format": "Gguf",
+            "output_name": "../my model.bin",
+        }),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    // out of its directory, its characters replaced and its extension the format's
+    assert_eq!(status["download_urls"], json!(["/download/my_model.gguf"]));
+    assert_eq!(
+        std::fs::read_to_string(config.outputs_dir.join("my_model.gguf")).unwrap(),
+        "quantized"
+    );
+    assert!(!root.join("my model.bin").exists());
+
+    let response = reqwest::Client::new()
+        .post(format!("{url}/ggml"))
+        .json(&json!({
+            "name": {"local_path": "renamed"},
+            "quant_info": "Q4",
+            "output_name": "../..",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+}
+
+#[tokio::test]
+async fn removes_the_intermediate_model_unless_asked_to_keep_it() {
+    for keep_intermediate in [false, true] {
+        let root = TestDir::new();
+        let mut config = config(root.path());
+        config.keep_intermediate = keep_intermediate;
+        llama_cpp_checkout(&config);
+        local_model(&config, "intermediate");
+        let url = serve(services(
+            config.clone(),
+            Arc::new(MockCommandRunner::llama_cpp()),
+        ));
+
+        let job_id = convert(
+            &url,
+            json!({"name": {"local_path": "intermediate"}, "quant_info": "Q4", "format": "Gguf"}),
+        )
+        .await;
+
+        let status = finished_job(&url, &job_id).await;
+        assert_eq!(status["state"], "Done", "{status}");
+        assert!(config.outputs_dir.join("intermediate-q4_0.gguf").is_file());
+        assert_eq!(
+            config.outputs_dir.join("intermediate.gguf").is_file(),
+            keep_intermediate
+        );
+    }
+}
+
+#[tokio::test]
+async fn rejects_an_architecture_the_converter_doesnt_support() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    let model_dir = local_model(&config, "falcon");
+    std::fs::write(
+        model_dir.join("config.json"),
+        r#"{"architectures": ["FalconForCausalLM"], "model_type": "falcon"}"#,
+    )
+    .unwrap();
+    let runner = Arc::new(MockCommandRunner::llama_cpp());
+    let url = serve(services(config, runner.clone()));
+
+    let job_id = convert(
+        &url,
+        json!({"name": {"local_path": "falcon"}, "quant_info": "Q4", "format": "Gguf"}),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Failed", "{status}");
+    assert_eq!(status["architecture"], "FalconForCausalLM");
+    let error = status["error"].as_str().unwrap();
+    assert!(
+        error.contains(
+            "The model's architecture (FalconForCausalLM) isn't supported by convert-hf-to-gguf.py"
+        ),
+        "{error}"
+    );
+    assert!(runner.commands(Stage::Convert).is_empty());
+}
+
+#[tokio::test]
+async fn fails_the_job_on_an_output_that_doesnt_load() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "unloadable");
+    let outputs_dir = config.outputs_dir.clone();
+    let runner = Arc::new(MockCommandRunner::new(|command, stage| match stage {
+        Stage::Verify => Ok(exited(
+            1,
+            "llama_model_load: error loading model: unknown model architecture",
+        )),
+        _ => simulate(command, stage),
+    }));
+    let url = serve(services(config, runner.clone()));
+
+    let job_id = convert(
+        &url,
+        json!({"name": {"local_path": "unloadable"}, "quant_info": "Q4", "verify": true}),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Failed", "{status}");
+    assert_eq!(
+        status["error"],
+        "Loading the quantized model failed (exit status: 1)"
+    );
+    assert_eq!(
+        status["stderr"],
+        "llama_model_load: error loading model: unknown model architecture"
+    );
+    let verify = runner.commands(Stage::Verify);
+    assert_eq!(verify.len(), 1);
+    assert!(
+        verify[0].contains("unloadable-q4_0.gguf -n 1"),
+        "{verify:?}"
+    );
+    // it would be reused otherwise
+    assert!(!outputs_dir.join("unloadable-q4_0.gguf").exists());
+}
+
+#[tokio::test]
+async fn fails_the_job_on_a_checkout_without_the_converter() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    let checkout = llama_cpp_checkout(&config);
+    std::fs::remove_file(checkout.join("convert-hf-to-gguf.py")).unwrap();
+    local_model(&config, "unconvertible");
+    let runner = Arc::new(MockCommandRunner::llama_cpp());
+    let url = serve(services(config, runner.clone()));
+
+    let job_id = convert(
+        &url,
+        json!({"name": {"local_path": "unconvertible"}, "quant_info": "Q4"}),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Failed", "{status}");
+    assert_eq!(
+        status["error"],
+        format!(
+            "The converter {} is missing",
+            checkout.join("convert-hf-to-gguf.py").display()
+        )
+    );
+    assert!(runner.commands(Stage::Convert).is_empty());
+}
+
+#[tokio::test]
+async fn reports_a_quantize_killed_by_the_kernel_as_out_of_memory() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "oom-killed");
+    local_model(&config, "crashed");
+    let runner = Arc::new(MockCommandRunner::new(|command, stage| {
+        let model = command.args[command.args.len() - 2].to_string_lossy();
+        match stage {
+            Stage::Quantize if model.contains("oom-killed") => Ok(killed(libc::SIGKILL)),
+            Stage::Quantize => Ok(killed(libc::SIGSEGV)),
+            _ => simulate(command, stage),
+        }
+    }));
+    let url = serve(services(config, runner));
+
+    let job_id = convert(
+        &url,
+        json!({"name": {"local_path": "oom-killed"}, "quant_info": "Q4"}),
+    )
+    .await;
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Failed", "{status}");
+    let error = status["error"].as_str().unwrap();
+    assert!(
+        error.starts_with(
+            "Quantization was killed (SIGKILL), most likely by the kernel running out of memory."
+        ),
+        "{error}"
+    );
+
+    // only SIGKILL is the kernel's doing
+    let job_id = convert(
+        &url,
+        json!({"name": {"local_path": "crashed"}, "quant_info": "Q4"}),
+    )
+    .await;
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Failed", "{status}");
+    assert_eq!(
+        status["error"],
+        "Quantization failed (signal: 11 (SIGSEGV))"
+    );
+}
+
+#[tokio::test]
+async fn fails_a_job_still_running_at_its_deadline() {
+    let root = TestDir::new();
+    let mut config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "overdue");
+    config.job_deadline = Some(Duration::from_millis(200));
+    // the conversion never ends on its own
+    let gate = Arc::new(Semaphore::new(0));
+    let runner = Arc::new(MockCommandRunner::llama_cpp().hold(Stage::Convert, gate));
+    let url = serve(services(config, runner.clone()));
+
+    let job_id = convert(
+        &url,
+        json!({"name": {"local_path": "overdue"}, "quant_info": "Q4"}),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Failed", "{status}");
+    let error = status["error"].as_str().unwrap();
+    assert!(
+        error.starts_with("DeadlineExceeded: the job was still running after"),
+        "{error}"
+    );
+    assert!(status["deadline_at"].is_u64());
+    assert!(runner.commands(Stage::Quantize).is_empty());
+}
+
+#[tokio::test]
+async fn goes_on_with_the_other_quants_unless_failing_fast() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    for name in ["lenient", "strict", "hopeless"] {
+        local_model(&config, name);
+    }
+    let outputs_dir = config.outputs_dir.clone();
+    let runner = Arc::new(MockCommandRunner::new(|command, stage| {
+        match command.args.last().unwrap().to_str() {
+            Some("q8_0") if stage == Stage::Quantize => Ok(exited(1, "q8_0 isn't supported")),
+            _ => simulate(command, stage),
+        }
+    }));
+    let url = serve(services(config, runner.clone()));
+    let quants = |model: &str| -> Vec<String> {
+        runner
+            .commands(Stage::Quantize)
+            .iter()
+            .filter(|command| command.contains(&format!("/{model}.gguf ")))
+            .map(|command| command.rsplit(' ').next().unwrap().to_string())
+            .collect()
+    };
+
+    let lenient = convert(
+        &url,
+        json!({"name": {"local_path": "lenient"}, "quant_info": ["Q4", "Q8", "Q5_K_M"]}),
+    )
+    .await;
+    let status = finished_job(&url, &lenient).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    assert_eq!(
+        status["quant_statuses"],
+        json!([
+            {"format": "Gguf", "quant": "Q4", "state": "Done"},
+            {
+                "format": "Gguf",
+                "quant": "Q8",
+                "state": "Failed",
+                "error": "Quantization failed (exit status: 1)",
+            },
+            {"format": "Gguf", "quant": "Q5_K_M", "state": "Done"},
+        ])
+    );
+    assert_eq!(
+        status["download_urls"],
+        json!([
+            "/download/lenient-q4_0.gguf",
+            "/download/lenient-q5_K_M.gguf"
+        ])
+    );
+    assert_eq!(quants("lenient"), ["q4_0", "q8_0", "q5_K_M"]);
+
+    let strict = convert(
+        &url,
+        json!({
+            "name": {"local_path": "strict"},
+            "quant_info": ["Q4", "Q8", "Q5_K_M"],
+            "fail_fast": true,
+        }),
+    )
+    .await;
+    let status = finished_job(&url, &strict).await;
+    assert_eq!(status["state"], "Failed", "{status}");
+    assert_eq!(status["error"], "Quantization failed (exit status: 1)");
+    assert_eq!(status["stderr"], "q8_0 isn't supported");
+    // the quants after the failed one never ran
+    assert_eq!(quants("strict"), ["q4_0", "q8_0"]);
+    // the conversion doesn't outlive the failure
+    assert!(!outputs_dir.join("strict.gguf").exists());
+
+    let hopeless = convert(
+        &url,
+        json!({"name": {"local_path": "hopeless"}, "quant_info": "Q8"}),
+    )
+    .await;
+    let status = finished_job(&url, &hopeless).await;
+    assert_eq!(status["state"], "Failed", "{status}");
+    assert!(!outputs_dir.join("hopeless.gguf").exists());
+}
+
+#[tokio::test]
+async fn reports_the_time_spent_in_each_stage() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "timed");
+    let url = serve(services(config, Arc::new(MockCommandRunner::llama_cpp())));
+
+    let job_id = convert(
+        &url,
+        json!({"name": {"local_path": "timed"}, "quant_info": "Q4"}),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    let secs = |stage: &str| {
+        let secs = status["timings"][stage].as_f64().expect(stage);
+        assert!(secs >= 0.0, "{stage}: {secs}");
+        secs
+    };
+    let stages: f64 = [
+        "download_secs",
+        "build_secs",
+        "convert_secs",
+        "quantize_secs",
+        "verify_secs",
+    ]
+    .into_iter()
+    .map(secs)
+    .sum();
+    assert!(secs("total_secs") >= stages, "{status}");
+}
+
+#[tokio::test]
+async fn serves_the_log_of_a_finished_job() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "logged");
+    let url = serve(services(
+        config.clone(),
+        Arc::new(MockCommandRunner::llama_cpp()),
+    ));
+    let job_id = convert(
+        &url,
+        json!({"name": {"local_path": "logged"}, "quant_info": "Q4"}),
+    )
+    .await;
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    let log_file = config.logs_dir.join(format!("{job_id}.log"));
+    eventually("the log to be complete", || {
+        std::fs::read_to_string(&log_file).is_ok_and(|log| log.contains("Done: "))
+    })
+    .await;
+
+    let response = reqwest::get(format!("{url}/jobs/{job_id}/log"))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/plain; charset=utf-8"
+    );
+    let log = response.text().await.unwrap();
+    assert_eq!(
+        log.lines().last(),
+        Some("Done: /download/logged-q4_0.gguf"),
+        "{log}"
+    );
+    let missing = reqwest::get(format!("{url}/jobs/{}/log", JobId::new()))
+        .await
+        .unwrap();
+    assert_eq!(missing.status(), 404);
+}
+
+#[tokio::test]
+async fn reports_the_size_of_the_outputs() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    local_model(&config, "sized");
+    let url = serve(services(
+        config.clone(),
+        Arc::new(MockCommandRunner::llama_cpp()),
+    ));
+
+    let job_id = convert(
+        &url,
+        json!({"name": {"local_path": "sized"}, "quant_info": ["Q4", "Q8"]}),
+    )
+    .await;
+
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+    let size: u64 = status["download_urls"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|url| {
+            let filename = url.as_str().unwrap().rsplit('/').next().unwrap();
+            std::fs::metadata(config.outputs_dir.join(filename))
+                .unwrap()
+                .len()
+        })
+        .sum();
+    assert_eq!(size, 2 * "quantized".len() as u64);
+    assert_eq!(status["size_bytes"], size);
+}
+
+#[tokio::test]
+async fn counts_a_successful_conversion() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    let url = serve(services(config, Arc::new(cloning_runner())));
+    // registered, so the model gets series of its own
+    register(
+        &url,
+        "acme/metrics-tiny",
+        "https://git.example.com/acme/metrics-tiny",
+    )
+    .await;
+
+    let job_id = convert(
+        &url,
+        json!({"name": "acme/metrics-tiny", "quant_info": "Q4"}),
+    )
+    .await;
+    let status = finished_job(&url, &job_id).await;
+    assert_eq!(status["state"], "Done", "{status}");
+
+    let metrics = reqwest::get(format!("{url}/metrics"))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let series = r#"{model="acme/metrics-tiny",quant="q4_0"}"#;
+    let lines: Vec<&str> = metrics
+        .lines()
+        .filter(|line| line.contains(series))
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            format!("ggml_conversions_started_total{series} 1"),
+            format!("ggml_conversions_succeeded_total{series} 1"),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn reports_the_version_of_the_service() {
+    let root = TestDir::new();
+    let url = serve(services(
+        config(root.path()),
+        Arc::new(MockCommandRunner::llama_cpp()),
+    ));
+
+    let version: Value = reqwest::get(format!("{url}/version"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(version["llama_cpp"]["code_base"], crate::CODE_BASE);
+    // nothing is built yet
+    assert_eq!(version["llama_cpp"]["head"], Value::Null);
+}
+
+#[tokio::test]
+async fn serves_a_page_with_the_conversion_form() {
+    let root = TestDir::new();
+    let url = serve(services(
+        config(root.path()),
+        Arc::new(MockCommandRunner::llama_cpp()),
+    ));
+
+    let response = reqwest::get(format!("{url}/")).await.unwrap();
+
+    assert_eq!(response.status(), 200);
+    assert!(response.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let page = response.text().await.unwrap();
+    assert!(page.contains(r#"<form id="convert">"#));
+    assert!(page.contains(r#"<input type="text" name="name""#));
+    for option in ["q4_0", "q4_K_M", "Gguf", "Ggml"] {
+        assert!(
+            page.contains(&format!(r#"<option value="{option}">"#)),
+            "{option}"
+        );
+    }
+    // the page posts to the JSON API
+    assert!(page.contains(r#"fetch("/ggml""#));
+}
+
+#[tokio::test]
+async fn describes_the_api_in_openapi() {
+    let root = TestDir::new();
+    let url = serve(services(
+        config(root.path()),
+        Arc::new(MockCommandRunner::llama_cpp()),
+    ));
+
+    let spec: Value = reqwest::get(format!("{url}/api-docs/openapi.json"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert!(spec["paths"]["/ggml"]["post"].is_object(), "{spec}");
+    assert_eq!(
+        spec["paths"]["/ggml"]["post"]["requestBody"]["content"]["application/json"]["schema"]
+            ["$ref"],
+        "#/components/schemas/ModelInfo"
+    );
+    assert_eq!(
+        spec["components"]["schemas"]["ModelInfo"]["required"],
+        json!(["name"])
+    );
+}
+
+/// The stages of a self-test report, with their status
+fn stage_statuses(report: &Value) -> Vec<(String, String)> {
+    report["stages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|stage| {
+            let field = |name: &str| stage[name].as_str().unwrap().to_string();
+            (field("stage"), field("status"))
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn reports_each_stage_of_the_self_test() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    let runner = Arc::new(MockCommandRunner::llama_cpp());
+    let url = serve(services(config.clone(), runner.clone()));
+
+    let response = reqwest::Client::new()
+        .post(format!("{url}/selftest"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    let report: Value = response.json().await.unwrap();
+    assert_eq!(report["passed"], true);
+    let passed = |stage: &str| (stage.to_string(), "passed".to_string());
+    assert_eq!(
+        stage_statuses(&report),
+        [passed("build"), passed("convert"), passed("quantize")]
+    );
+    assert_eq!(runner.commands(Stage::Convert).len(), 1);
+    assert_eq!(runner.commands(Stage::Quantize).len(), 1);
+    // nothing is left behind
+    assert_eq!(std::fs::read_dir(&config.outputs_dir).unwrap().count(), 0);
+    assert!(!config.models_dir.join(".selftest").exists());
+}
+
+#[tokio::test]
+async fn skips_the_stages_after_a_failed_one_in_the_self_test() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    let runner = MockCommandRunner::new(|command, stage| match stage {
+        Stage::Convert => Ok(exited(1, "KeyError: 'wte.weight'")),
+        _ => simulate(command, stage),
+    });
+    let url = serve(services(config, Arc::new(runner)));
+
+    let response = reqwest::Client::new()
+        .post(format!("{url}/selftest"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 503);
+    let report: Value = response.json().await.unwrap();
+    assert_eq!(report["passed"], false);
+    let status = |stage: &str, status: &str| (stage.to_string(), status.to_string());
+    assert_eq!(
+        stage_statuses(&report),
+        [
+            status("build", "passed"),
+            status("convert", "failed"),
+            status("quantize", "skipped"),
+        ]
+    );
+    assert_eq!(
+        report["stages"][1]["error"],
+        "Conversion failed (exit status: 1)"
+    );
+}
+
+#[tokio::test]
+async fn answers_a_cors_preflight() {
+    let root = TestDir::new();
+    let url = serve(services(
+        config(root.path()),
+        Arc::new(MockCommandRunner::llama_cpp()),
+    ));
+
+    let response = reqwest::Client::new()
+        .request(reqwest::Method::OPTIONS, format!("{url}/ggml"))
+        .header("origin", "https://app.example.com")
+        .header("access-control-request-method", "POST")
+        .header("access-control-request-headers", "content-type")
+        .send()
+        .await
+        .unwrap();
+
+    assert!(response.status().is_success(), "{}", response.status());
+    let headers = response.headers();
+    // any origin is allowed without GGML_CORS_ORIGINS
+    assert_eq!(
+        headers["access-control-allow-origin"],
+        "https://app.example.com"
+    );
+    assert!(headers.contains_key("access-control-allow-methods"));
+    assert!(headers.contains_key("access-control-allow-headers"));
+}
+
+#[tokio::test]
+async fn runs_two_pipelines_at_once_each_on_its_own_model() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    let gate = Arc::new(Semaphore::new(0));
+    let runner = Arc::new(MockCommandRunner::llama_cpp().hold(Stage::Convert, gate.clone()));
+    let url = serve(services(config.clone(), runner.clone()));
+    let mut jobs = Vec::new();
+    for name in ["alpha", "beta"] {
+        let model_dir = local_model(&config, name);
+        let request = json!({"name": {"local_path": name}, "quant_info": "Q4"});
+        jobs.push((name, model_dir, convert(&url, request).await));
+    }
+
+    // both conversions run side by side
+    eventually("both conversions to start", || {
+        runner.commands(Stage::Convert).len() == 2
+    })
+    .await;
+    gate.add_permits(2);
+
+    for (name, model_dir, job_id) in jobs {
+        let status = finished_job(&url, &job_id).await;
+        assert_eq!(status["state"], "Done", "{status}");
+        assert_eq!(
+            status["download_url"],
+            format!("/download/{name}-q4_0.gguf")
+        );
+        assert!(config
+            .outputs_dir
+            .join(format!("{name}-q4_0.gguf"))
+            .is_file());
+        let converted = runner
+            .calls()
+            .into_iter()
+            .filter(|(command, stage)| {
+                *stage == Stage::Convert && command.args.contains(&model_dir.clone().into())
+            })
+            .count();
+        assert_eq!(converted, 1, "{name}");
+    }
+}
@@ -0,0 +1,105 @@
+//! Downloads of the outputs through `/download/{filename}`
+
+use super::{config, serve, services, TestDir};
+use crate::{parse_range, runner::mock::MockCommandRunner};
+use std::sync::Arc;
+
+/// Serve an outputs directory holding `tiny-q4_0.gguf`, returning the base url
+fn serve_outputs(root: &TestDir) -> String {
+    let config = config(root.path());
+    std::fs::create_dir_all(&config.outputs_dir).unwrap();
+    std::fs::write(config.outputs_dir.join("tiny-q4_0.gguf"), "quantized").unwrap();
+    std::fs::write(root.join("secret"), "secret").unwrap();
+    serve(services(config, Arc::new(MockCommandRunner::llama_cpp())))
+}
+
+#[tokio::test]
+async fn downloads_an_output_as_an_attachment() {
+    let root = TestDir::new();
+    let url = serve_outputs(&root);
+
+    let response = reqwest::get(format!("{url}/download/tiny-q4_0.gguf"))
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/octet-stream"
+    );
+    assert_eq!(
+        response.headers()["content-disposition"],
+        "attachment; filename=\"tiny-q4_0.gguf\""
+    );
+    assert_eq!(response.text().await.unwrap(), "quantized");
+}
+
+#[tokio::test]
+async fn rejects_a_path_out_of_the_outputs_directory() {
+    let root = TestDir::new();
+    let url = serve_outputs(&root);
+
+    // the slashes percent-encoded, or the client would resolve the path before sending it
+    for filename in ["..%2Fsecret", "%2E%2E%2Fsecret", "%2Fetc%2Fpasswd"] {
+        let response = reqwest::get(format!("{url}/download/{filename}"))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 400, "{filename}");
+    }
+    let response = reqwest::get(format!("{url}/download/missing.gguf"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+}
+
+#[test]
+fn parses_a_single_range() {
+    assert_eq!(parse_range("bytes=0-", 10), Ok(Some((0, 9))));
+    assert_eq!(parse_range("bytes=2-4", 10), Ok(Some((2, 4))));
+    assert_eq!(parse_range("bytes=8-20", 10), Ok(Some((8, 9))));
+    assert_eq!(parse_range("bytes=-3", 10), Ok(Some((7, 9))));
+    // a suffix larger than the file is the whole file
+    assert_eq!(parse_range("bytes=-50", 10), Ok(Some((0, 9))));
+}
+
+#[test]
+fn rejects_an_unsatisfiable_range() {
+    assert_eq!(parse_range("bytes=-0", 10), Err(()));
+    assert_eq!(parse_range("bytes=5-2", 10), Err(()));
+    assert_eq!(parse_range("bytes=10-", 10), Err(()));
+    assert_eq!(parse_range("bytes=10-12", 10), Err(()));
+    assert_eq!(parse_range("bytes=-", 10), Err(()));
+    assert_eq!(parse_range("bytes=a-b", 10), Err(()));
+    assert_eq!(parse_range("items=0-1", 10), Err(()));
+    // nothing of an empty file can be sent
+    assert_eq!(parse_range("bytes=0-", 0), Err(()));
+    assert_eq!(parse_range("bytes=-1", 0), Err(()));
+}
+
+#[test]
+fn sends_the_whole_file_for_several_ranges() {
+    assert_eq!(parse_range("bytes=0-1, 4-5", 10), Ok(None));
+}
+
+#[tokio::test]
+async fn sends_the_range_asked_for() {
+    let root = TestDir::new();
+    let url = serve_outputs(&root);
+    let client = reqwest::Client::new();
+    let get = |range: &'static str| {
+        client
+            .get(format!("{url}/download/tiny-q4_0.gguf"))
+            .header("range", range)
+            .send()
+    };
+
+    let response = get("bytes=2-4").await.unwrap();
+    assert_eq!(response.status(), 206);
+    assert_eq!(response.headers()["content-range"], "bytes 2-4/9");
+    assert_eq!(response.text().await.unwrap(), "ant");
+
+    let response = get("bytes=9-").await.unwrap();
+    assert_eq!(response.status(), 416);
+    assert_eq!(response.headers()["content-range"], "bytes */9");
+}
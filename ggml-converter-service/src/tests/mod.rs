@@ -0,0 +1,194 @@
+//! Tests driving the pipeline and the API, with the subprocesses answered by a
+//! `MockCommandRunner` and every file under a directory of their own
+
+mod api;
+mod download;
+mod models;
+mod outputs;
+mod pipeline;
+mod request;
+
+use crate::{
+    build::BuildOptions,
+    cleanup::ActiveDownloads,
+    config::Config,
+    converter::ConverterEnv,
+    idempotency::IdempotencyKeys,
+    job::{JobContext, JobEvents, JobId},
+    queue::{ConversionQueue, OutputConflict},
+    rate_limit::RateLimiter,
+    router,
+    runner::CommandRunner,
+    shutdown::Shutdown,
+    storage::LocalStorage,
+    JobStore, ModelInfo, Services, CODE_BASE,
+};
+use axum::Router;
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio_util::sync::CancellationToken;
+
+/// Held by the tests setting environment variables, as the configuration reads them
+pub static ENV: Mutex<()> = Mutex::new(());
+
+/// A directory of the system's temporary directory, removed with everything in it when dropped
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("ggml-converter-test-{}", JobId::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A configuration keeping every directory under `root`, local models under `root/local`
+pub fn config(root: &Path) -> Config {
+    Config {
+        outputs_dir: root.join("outputs"),
+        models_dir: root.join("models"),
+        llama_cpp_dir: root.join("llama.cpp"),
+        logs_dir: root.join("logs"),
+        local_models_dir: Some(root.join("local")),
+        build: BuildOptions {
+            jobs: 2,
+            flags: Vec::new(),
+        },
+        s3: None,
+        url_signer: None,
+        keep_intermediate: false,
+        ggml_converter: "convert.py".to_string(),
+        gguf_converter: "convert-hf-to-gguf.py".to_string(),
+        converter_env: ConverterEnv::default(),
+        public_base_url: None,
+        job_deadline: None,
+    }
+}
+
+/// A built checkout of the default llama.cpp ref, with its converter scripts and tools. The
+/// GGUF converter supports Llama models only.
+pub fn llama_cpp_checkout(config: &Config) -> PathBuf {
+    let checkout = config.llama_cpp_checkout(CODE_BASE);
+    std::fs::create_dir_all(&checkout).unwrap();
+    for file in ["convert.py", "quantize", "main", "imatrix"] {
+        std::fs::write(checkout.join(file), "").unwrap();
+    }
+    // the architectures the script supports are read from its source
+    std::fs::write(
+        checkout.join("convert-hf-to-gguf.py"),
+        "@Model.register(\"LlamaForCausalLM\")\nclass LlamaModel(Model):\n    pass\n",
+    )
+    .unwrap();
+    checkout
+}
+
+/// Replace the file with an executable shell script running `body`
+pub fn script(path: &Path, body: &str) {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::write(path, format!("#!/bin/sh\n{body}\n")).unwrap();
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    // a process forked by another test meanwhile may still hold the file open for writing,
+    // executing it fails with ETXTBSY until that process execs
+    for _ in 0..100 {
+        match std::process::Command::new(path).arg("--help").output() {
+            Err(err) if err.raw_os_error() == Some(26) => {
+                std::thread::sleep(Duration::from_millis(10))
+            }
+            _ => return,
+        }
+    }
+    panic!("{} never became executable", path.display());
+}
+
+/// A Llama model under the local models directory, converted as `{"local_path": "<name>"}`
+pub fn local_model(config: &Config, name: &str) -> PathBuf {
+    let dir = config.local_models_dir.as_ref().unwrap().join(name);
+    llama_model(&dir);
+    dir
+}
+
+/// The files of a Llama model repo, written in `dir`
+pub fn llama_model(dir: &Path) {
+    std::fs::create_dir_all(dir).unwrap();
+    std::fs::write(
+        dir.join("config.json"),
+        r#"{"architectures": ["LlamaForCausalLM"], "model_type": "llama"}"#,
+    )
+    .unwrap();
+    std::fs::write(dir.join("tokenizer.model"), "tokenizer").unwrap();
+    std::fs::write(dir.join("model.safetensors"), "weights").unwrap();
+}
+
+/// The context of a job nobody cancels
+pub fn job_context() -> JobContext {
+    JobContext {
+        id: JobId::new(),
+        token: CancellationToken::new(),
+        events: JobEvents::default(),
+    }
+}
+
+/// The request of a conversion, as it would be posted to `/ggml`
+pub fn model_info(request: serde_json::Value) -> ModelInfo {
+    serde_json::from_value(request).unwrap()
+}
+
+/// Wait for the condition to hold, failing after a few seconds
+pub async fn eventually(what: &str, condition: impl Fn() -> bool) {
+    for _ in 0..400 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("timed out waiting for {what}");
+}
+
+/// What the handlers share, with the configuration and the runner given
+pub fn services(config: Config, runner: Arc<dyn CommandRunner>) -> Services {
+    Services {
+        jobs: JobStore::default(),
+        queue: ConversionQueue::new(2, OutputConflict::Reject),
+        storage: Arc::new(LocalStorage::new(config.outputs_dir.clone())),
+        config,
+        runner,
+        shutdown: Shutdown::default(),
+        downloads: ActiveDownloads::default(),
+        rate_limiter: RateLimiter::new(60),
+        idempotency_keys: IdempotencyKeys::new(Duration::from_secs(60)),
+    }
+}
+
+/// Serve the routes of the service on a port of their own, returning the base url of the
+/// server
+pub fn serve(services: Services) -> String {
+    serve_router(router(services))
+}
+
+/// Serve the routes on a port of their own, e.g. those of a server the service talks to
+pub fn serve_router(router: Router) -> String {
+    let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+        .serve(router.into_make_service_with_connect_info::<SocketAddr, _>());
+    let url = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+    url
+}
@@ -0,0 +1,53 @@
+//! The registry of the models, mapping repo ids to the urls they're downloaded from
+
+use super::TestDir;
+use crate::load_models_file;
+use std::collections::HashMap;
+
+#[test]
+fn loads_the_models_of_a_models_file() {
+    let root = TestDir::new();
+    let path = root.join("models.json");
+    std::fs::write(
+        &path,
+        r#"{
+            "meta-llama/Llama-2-7b-hf": "https://huggingface.co/meta-llama/Llama-2-7b-hf",
+            "acme/tiny": "https://git.example.com/acme/tiny"
+        }"#,
+    )
+    .unwrap();
+
+    let models = load_models_file(&path).unwrap().unwrap();
+
+    assert_eq!(
+        models,
+        HashMap::from([
+            (
+                "meta-llama/Llama-2-7b-hf".to_string(),
+                "https://huggingface.co/meta-llama/Llama-2-7b-hf".to_string()
+            ),
+            (
+                "acme/tiny".to_string(),
+                "https://git.example.com/acme/tiny".to_string()
+            ),
+        ])
+    );
+}
+
+#[test]
+fn falls_back_to_the_builtin_models_without_a_models_file() {
+    let root = TestDir::new();
+
+    assert_eq!(load_models_file(&root.join("models.json")), Ok(None));
+}
+
+#[test]
+fn rejects_a_models_file_that_isnt_a_map_of_urls() {
+    let root = TestDir::new();
+    let path = root.join("models.json");
+    std::fs::write(&path, r#"["acme/tiny"]"#).unwrap();
+
+    let err = load_models_file(&path).unwrap_err();
+
+    assert!(err.starts_with("Failed to parse the models file"), "{err}");
+}
@@ -0,0 +1,86 @@
+//! The outputs of earlier conversions, as listed and deleted through the API
+
+use super::{config, serve, services, TestDir};
+use crate::runner::mock::MockCommandRunner;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+/// Serve an outputs directory holding `files`, returning the base url
+fn serve_outputs(root: &TestDir, files: &[(&str, &str)]) -> String {
+    let config = config(root.path());
+    std::fs::create_dir_all(&config.outputs_dir).unwrap();
+    for (name, content) in files {
+        std::fs::write(config.outputs_dir.join(name), content).unwrap();
+    }
+    serve(services(config, Arc::new(MockCommandRunner::llama_cpp())))
+}
+
+#[tokio::test]
+async fn lists_the_quants_converted_from_a_model() {
+    let root = TestDir::new();
+    let url = serve_outputs(
+        &root,
+        &[
+            ("tiny-q4_0.gguf", "quantized"),
+            ("tiny-q4_0.gguf.sha256", "abc123"),
+            ("tiny-ggml-q8_0.bin", "quantized!"),
+            // another model sharing the prefix
+            ("tiny-chat-q4_0.gguf", "quantized"),
+        ],
+    );
+
+    let quants: Value = reqwest::get(format!("{url}/models/acme%2Ftiny/quants"))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        quants,
+        json!([
+            {
+                "quant": "q4_0",
+                "format": "Gguf",
+                "filename": "tiny-q4_0.gguf",
+                "size_bytes": 9,
+                "sha256": "abc123",
+            },
+            {
+                "quant": "q8_0",
+                "format": "Ggml",
+                "filename": "tiny-ggml-q8_0.bin",
+                "size_bytes": 10,
+            },
+        ])
+    );
+}
+
+#[tokio::test]
+async fn deletes_an_output_with_its_digest() {
+    let root = TestDir::new();
+    let url = serve_outputs(
+        &root,
+        &[
+            ("tiny-q4_0.gguf", "quantized"),
+            ("tiny-q4_0.gguf.sha256", "abc123"),
+            ("tiny-q8_0.gguf", "quantized"),
+        ],
+    );
+    let client = reqwest::Client::new();
+    let delete = |filename: &str| client.delete(format!("{url}/outputs/{filename}")).send();
+
+    let response = delete("tiny-q4_0.gguf").await.unwrap();
+
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.json::<Value>().await.unwrap(),
+        json!({"filename": "tiny-q4_0.gguf", "freed_bytes": 15})
+    );
+    let outputs = root.join("outputs");
+    assert!(!outputs.join("tiny-q4_0.gguf").exists());
+    assert!(!outputs.join("tiny-q4_0.gguf.sha256").exists());
+    assert!(outputs.join("tiny-q8_0.gguf").exists());
+    assert_eq!(delete("tiny-q4_0.gguf").await.unwrap().status(), 404);
+    assert_eq!(delete("..%2Fsecret").await.unwrap().status(), 400);
+}
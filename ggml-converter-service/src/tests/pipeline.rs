@@ -0,0 +1,508 @@
+//! The stages of the pipeline, each run against a `MockCommandRunner`
+
+use super::{
+    config, eventually, job_context, llama_cpp_checkout, llama_model, local_model, model_info,
+    TestDir,
+};
+use crate::{
+    build::BuildOptions,
+    convert_to_ggml, download_and_build_llama_cpp, download_llama2_models,
+    error::SubprocessError,
+    job::JobEvent,
+    quantize_ggml,
+    runner::{
+        mock::{exited, simulate, MockCommandRunner},
+        Stage,
+    },
+    Converter, JobStore, OutputFormat, QuantInfo, CODE_BASE, LLAMA_CPP_REPO, MODELS,
+};
+use serde_json::json;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+use tokio::sync::Semaphore;
+
+#[tokio::test]
+async fn clones_checks_out_and_builds_a_missing_checkout() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    let runner = MockCommandRunner::llama_cpp();
+
+    let checkout = download_and_build_llama_cpp(&config, CODE_BASE, &runner, &job_context())
+        .await
+        .unwrap();
+
+    assert_eq!(checkout, config.llama_cpp_checkout(CODE_BASE));
+    let calls = runner.calls();
+    let commands: Vec<String> = calls
+        .iter()
+        .map(|(command, _)| command.to_string())
+        .collect();
+    assert_eq!(
+        commands,
+        [
+            format!("git clone {LLAMA_CPP_REPO} {}", checkout.display()),
+            format!("git checkout {CODE_BASE}"),
+            "make -j2".to_string(),
+            format!("{} --help", checkout.join("quantize").display()),
+        ]
+    );
+    assert_eq!(
+        runner.stages(),
+        [Stage::Clone, Stage::Clone, Stage::Build, Stage::Build]
+    );
+    // every command runs where it has to, never in the service's own directory
+    assert_eq!(calls[0].0.current_dir, config.llama_cpp_dir);
+    for (command, _) in &calls[1..] {
+        assert_eq!(command.current_dir, checkout);
+    }
+}
+
+#[tokio::test]
+async fn builds_with_the_configured_jobs_and_flags() {
+    let root = TestDir::new();
+    let mut config = config(root.path());
+    config.build = BuildOptions {
+        jobs: 8,
+        flags: vec!["LLAMA_CUBLAS=1".to_string()],
+    };
+    let runner = MockCommandRunner::llama_cpp();
+
+    download_and_build_llama_cpp(&config, CODE_BASE, &runner, &job_context())
+        .await
+        .unwrap();
+
+    assert_eq!(runner.commands(Stage::Build)[0], "make -j8 LLAMA_CUBLAS=1");
+}
+
+#[tokio::test]
+async fn skips_the_clone_and_the_build_of_a_built_checkout() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    llama_cpp_checkout(&config);
+    let runner = MockCommandRunner::llama_cpp();
+
+    download_and_build_llama_cpp(&config, CODE_BASE, &runner, &job_context())
+        .await
+        .unwrap();
+
+    assert!(runner.calls().is_empty());
+}
+
+#[tokio::test]
+async fn clones_llama_cpp_with_git_and_checks_out_the_requested_ref() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    let runner = MockCommandRunner::llama_cpp();
+
+    let checkout = download_and_build_llama_cpp(&config, "b1500", &runner, &job_context())
+        .await
+        .unwrap();
+
+    assert_eq!(checkout, config.llama_cpp_checkout("b1500"));
+    let git: Vec<Vec<String>> = runner
+        .calls()
+        .into_iter()
+        .filter(|(command, _)| command.program == "git")
+        .map(|(command, _)| {
+            command
+                .args
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect()
+        })
+        .collect();
+    assert_eq!(
+        git,
+        [
+            vec![
+                "clone".to_string(),
+                LLAMA_CPP_REPO.to_string(),
+                checkout.display().to_string()
+            ],
+            vec!["checkout".to_string(), "b1500".to_string()],
+        ]
+    );
+    // nothing is fetched as an archive anymore
+    assert!(runner
+        .calls()
+        .iter()
+        .all(|(command, _)| !matches!(command.program.to_str(), Some("wget" | "tar"))));
+}
+
+#[tokio::test]
+async fn builds_two_refs_requested_at_once_each_in_its_own_checkout() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    let gate = Arc::new(Semaphore::new(0));
+    let runner = MockCommandRunner::llama_cpp().hold(Stage::Build, gate.clone());
+    let build = |llama_cpp_ref| {
+        let (config, runner) = (&config, &runner);
+        async move {
+            download_and_build_llama_cpp(config, llama_cpp_ref, runner, &job_context())
+                .await
+                .unwrap()
+        }
+    };
+    let builds = || runner.commands(Stage::Build).len();
+
+    let ((first, second), ()) = tokio::join!(
+        async { tokio::join!(build("b1500"), build("b1600")) },
+        async {
+            eventually("a build to start", || builds() == 1).await;
+            // the other one waits for the first to be done
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            assert_eq!(builds(), 1);
+            // make, then quantize --help
+            gate.add_permits(2);
+            eventually("the other build to start", || builds() == 3).await;
+            gate.add_permits(2);
+        }
+    );
+
+    assert_ne!(first, second);
+    for checkout in [&first, &second] {
+        let commands: Vec<_> = runner
+            .calls()
+            .into_iter()
+            .filter(|(command, stage)| *stage == Stage::Build && command.current_dir == *checkout)
+            .map(|(command, _)| command.program)
+            .collect();
+        assert_eq!(
+            commands,
+            ["make".into(), checkout.join("quantize").into_os_string()]
+        );
+        assert!(checkout.join("quantize").is_file());
+    }
+}
+
+#[tokio::test]
+async fn removes_the_checkout_of_a_ref_that_fails_to_check_out() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    let runner = MockCommandRunner::new(|command, stage| match command.args[0].to_str() {
+        Some("checkout") => Ok(exited(1, "error: pathspec 'd2a4366' did not match")),
+        _ => simulate(command, stage),
+    });
+
+    let err = download_and_build_llama_cpp(&config, CODE_BASE, &runner, &job_context())
+        .await
+        .unwrap_err();
+
+    assert_eq!(
+        err.to_string(),
+        format!("Failed to check out llama.cpp '{CODE_BASE}'")
+    );
+    assert!(!config.llama_cpp_checkout(CODE_BASE).exists());
+    assert_eq!(runner.stages(), [Stage::Clone, Stage::Clone]);
+}
+
+#[tokio::test]
+async fn converts_with_the_script_of_the_format() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    let checkout = llama_cpp_checkout(&config);
+    let model_dir = local_model(&config, "tiny");
+    let outfile = root.join("tiny.gguf");
+    let runner = MockCommandRunner::llama_cpp();
+    let args = ["--pad-vocab".to_string()];
+    let converter = Converter {
+        format: OutputFormat::Gguf,
+        script: config.converter_script(OutputFormat::Gguf),
+        args: &args,
+        env: &config.converter_env,
+    };
+
+    convert_to_ggml(
+        &runner,
+        &checkout,
+        &model_dir,
+        converter,
+        &outfile,
+        &JobStore::default(),
+        &job_context(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        runner.commands(Stage::Convert),
+        [format!(
+            "python3 {} --pad-vocab {} --outfile {}",
+            checkout.join("convert-hf-to-gguf.py").display(),
+            model_dir.display(),
+            outfile.display()
+        )]
+    );
+    assert!(outfile.is_file());
+}
+
+#[tokio::test]
+async fn surfaces_the_stderr_of_a_failed_conversion() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    let checkout = llama_cpp_checkout(&config);
+    let model_dir = local_model(&config, "tiny");
+    let runner = MockCommandRunner::new(|_, _| Ok(exited(1, "KeyError: 'model.embed_tokens'")));
+    let converter = Converter {
+        format: OutputFormat::Ggml,
+        script: config.converter_script(OutputFormat::Ggml),
+        args: &[],
+        env: &config.converter_env,
+    };
+
+    let err = convert_to_ggml(
+        &runner,
+        &checkout,
+        &model_dir,
+        converter,
+        &root.join("tiny-ggml.bin"),
+        &JobStore::default(),
+        &job_context(),
+    )
+    .await
+    .unwrap_err();
+
+    let err = err.downcast::<SubprocessError>().unwrap();
+    assert_eq!(err.stage, "Conversion");
+    assert_eq!(err.stderr, "KeyError: 'model.embed_tokens'");
+}
+
+#[tokio::test]
+async fn quantizes_into_a_partial_file_renamed_once_complete() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    let checkout = llama_cpp_checkout(&config);
+    let model = root.join("tiny.gguf");
+    let outfile = root.join("tiny-q4_K_M.gguf");
+    let runner = MockCommandRunner::llama_cpp();
+
+    quantize_ggml(
+        &runner,
+        &checkout,
+        &model,
+        QuantInfo::Q4_K_M,
+        None,
+        &outfile,
+        &job_context(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        runner.commands(Stage::Quantize),
+        [format!(
+            "{} {} {}.tmp q4_K_M",
+            checkout.join("quantize").display(),
+            model.display(),
+            outfile.display()
+        )]
+    );
+    assert_eq!(std::fs::read_to_string(&outfile).unwrap(), "quantized");
+    assert!(!root.join("tiny-q4_K_M.gguf.tmp").exists());
+}
+
+#[tokio::test]
+async fn leaves_no_output_behind_a_failed_quantization() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    let checkout = llama_cpp_checkout(&config);
+    let outfile = root.join("tiny-q4_0.gguf");
+    let runner = MockCommandRunner::new(|command, stage| {
+        simulate(command, stage)?;
+        Ok(exited(1, "invalid model file"))
+    });
+
+    let err = quantize_ggml(
+        &runner,
+        &checkout,
+        &root.join("tiny.gguf"),
+        QuantInfo::Q4,
+        None,
+        &outfile,
+        &job_context(),
+    )
+    .await
+    .unwrap_err();
+
+    assert_eq!(err.to_string(), "Quantization failed (exit status: 1)");
+    assert!(!outfile.exists());
+    assert!(!root.join("tiny-q4_0.gguf.tmp").exists());
+}
+
+#[tokio::test]
+async fn passes_the_llama_cpp_name_of_each_quant_to_quantize() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    let checkout = llama_cpp_checkout(&config);
+    let runner = MockCommandRunner::llama_cpp();
+    let names = [
+        "q4_0", "q8_0", "f16", "f32", "q2_K", "q3_K_M", "q4_K_M", "q5_K_M", "q6_K",
+    ];
+
+    for (quant, name) in QuantInfo::ALL.into_iter().zip(names) {
+        assert_eq!(quant.to_string(), name);
+        let outfile = root.join(format!("tiny-{name}.gguf"));
+        quantize_ggml(
+            &runner,
+            &checkout,
+            &root.join("tiny.gguf"),
+            quant,
+            None,
+            &outfile,
+            &job_context(),
+        )
+        .await
+        .unwrap();
+    }
+
+    let types: Vec<String> = runner
+        .calls()
+        .into_iter()
+        .map(|(command, _)| command.args.last().unwrap().to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(types, names);
+}
+
+/// A runner whose first `failures` clones of a model fail, the next ones bringing a Llama
+/// model along
+fn flaky_clones(failures: u32) -> MockCommandRunner {
+    let attempts = AtomicU32::new(0);
+    MockCommandRunner::new(move |command, stage| {
+        if command.args[0] != "clone" {
+            return simulate(command, stage);
+        }
+        if attempts.fetch_add(1, Ordering::SeqCst) < failures {
+            return Ok(exited(128, "fatal: the remote end hung up unexpectedly"));
+        }
+        llama_model(std::path::Path::new(command.args.last().unwrap()));
+        Ok(exited(0, ""))
+    })
+}
+
+/// Register the repo outside Hugging Face, so it is cloned rather than downloaded over HTTP
+fn registered(name: &str) -> crate::ModelInfo {
+    MODELS
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), format!("https://git.example.com/{name}"));
+    model_info(json!({"name": name, "quant_info": "Q4"}))
+}
+
+#[tokio::test(start_paused = true)]
+async fn retries_a_failed_clone_backing_off() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    let model_info = registered("acme/flaky-clone");
+    let runner = flaky_clones(2);
+    let ctx = job_context();
+    let started = tokio::time::Instant::now();
+
+    let model_dir =
+        download_llama2_models(&config, &model_info, &runner, &JobStore::default(), &ctx)
+            .await
+            .unwrap();
+
+    assert_eq!(runner.commands(Stage::Clone).len(), 3);
+    assert!(model_dir.join("config.json").is_file());
+    // 2s then 4s, each with up to 50% of jitter
+    let waited = started.elapsed().as_secs_f64();
+    assert!((6.0..=9.0).contains(&waited), "{waited}");
+    let (events, _) = ctx.events.subscribe();
+    let retries = events
+        .iter()
+        .filter(|event| matches!(event, JobEvent::Log(line) if line.starts_with("Retrying git clone in")))
+        .count();
+    assert_eq!(retries, 2);
+}
+
+#[tokio::test(start_paused = true)]
+async fn gives_up_once_every_clone_failed() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    let model_info = registered("acme/unreachable-clone");
+    let runner = flaky_clones(u32::MAX);
+
+    let err = download_llama2_models(
+        &config,
+        &model_info,
+        &runner,
+        &JobStore::default(),
+        &job_context(),
+    )
+    .await
+    .unwrap_err();
+
+    assert_eq!(
+        err.to_string(),
+        "Downloading 'acme/unreachable-clone' failed after 3 git clone attempts: fatal: the remote end hung up unexpectedly"
+    );
+    assert_eq!(runner.commands(Stage::Clone).len(), 3);
+    assert!(!config
+        .models_dir
+        .join(model_info.model_dir_name().unwrap())
+        .exists());
+}
+
+#[tokio::test]
+async fn rejects_a_clone_holding_lfs_pointers() {
+    let root = TestDir::new();
+    let config = config(root.path());
+    let model_info = registered("acme/lfs-pointers");
+    let runner = MockCommandRunner::new(|command, _| {
+        // cloned without git LFS, the weights are left as pointers
+        let dir = std::path::Path::new(command.args.last().unwrap());
+        llama_model(dir);
+        std::fs::write(
+            dir.join("model.safetensors"),
+            "version https://git-lfs.github.com/spec/v1\noid sha256:4d7a\nsize 13476839424\n",
+        )?;
+        Ok(exited(0, ""))
+    });
+
+    let err = download_llama2_models(
+        &config,
+        &model_info,
+        &runner,
+        &JobStore::default(),
+        &job_context(),
+    )
+    .await
+    .unwrap_err();
+
+    let err = err.to_string();
+    assert!(
+        err.starts_with("Downloading 'acme/lfs-pointers' failed: git LFS pointers were checked out instead of the files' content (1 of them, e.g. "),
+        "{err}"
+    );
+    assert!(err.ends_with("model.safetensors)"), "{err}");
+    // cloning again wouldn't fetch the weights either
+    assert_eq!(runner.commands(Stage::Clone).len(), 1);
+    assert!(!config
+        .models_dir
+        .join(model_info.model_dir_name().unwrap())
+        .exists());
+}
+
+#[test]
+fn reads_the_progress_of_convert_py() {
+    // as printed by convert.py converting a 7B model
+    let output = "\
+Loading model file models/Llama-2-7b-hf/pytorch_model-00001-of-00002.bin
+params = Params(n_vocab=32000, n_embd=4096, n_layer=32, n_ctx=4096)
+Writing models/Llama-2-7b-hf/ggml-model-f16.gguf, format 1
+[  1/291] Writing tensor token_embd.weight                      | size  32000 x   4096  | type F16  | T+   0
+[ 73/291] Writing tensor blk.7.attn_q.weight                    | size   4096 x   4096  | type F16  | T+  12
+[291/291] Writing tensor output_norm.weight                     | size   4096           | type F32  | T+  48
+Wrote models/Llama-2-7b-hf/ggml-model-f16.gguf";
+
+    let progress: Vec<Option<u8>> = output.lines().map(crate::convert_progress).collect();
+
+    assert_eq!(
+        progress,
+        [None, None, None, Some(0), Some(25), Some(100), None]
+    );
+    assert_eq!(crate::convert_progress("[0/0] Writing"), None);
+    assert_eq!(crate::convert_progress("[5/4] Writing"), None);
+}
@@ -0,0 +1,108 @@
+//! Parsing and validation of the conversion requests
+
+use super::model_info;
+use crate::{prefers_plain_text, runner::CommandSpec, with_git_token, OutputFormat, QuantInfo};
+use serde_json::json;
+
+#[test]
+fn parses_both_names_of_a_quant() {
+    for quant in QuantInfo::ALL.into_iter().chain([QuantInfo::NoQuant]) {
+        let rust_name = format!("{quant:?}");
+        let llama_cpp_name = quant.to_string();
+
+        assert_eq!(rust_name.parse::<QuantInfo>(), Ok(quant.clone()));
+        assert_eq!(llama_cpp_name.parse::<QuantInfo>(), Ok(quant.clone()));
+    }
+    let request = model_info(json!({"name": "acme/tiny", "quant_info": ["Q4_K_M", "q5_K_M"]}));
+    assert_eq!(
+        request.quant_info.quants(),
+        [QuantInfo::Q4_K_M, QuantInfo::Q5_K_M]
+    );
+}
+
+#[test]
+fn lists_the_known_quants_for_an_unknown_one() {
+    let err = "q4_K_S".parse::<QuantInfo>().unwrap_err();
+
+    assert!(err.starts_with("Unknown quant 'q4_K_S': expected one of Q4 (q4_0), Q8 (q8_0)"));
+    assert!(err.ends_with("NoQuant (unquantized)"), "{err}");
+}
+
+#[test]
+fn picks_the_converter_and_the_extension_of_the_format() {
+    let ggml = model_info(json!({"name": "acme/tiny", "quant_info": "Q4", "format": "Ggml"}));
+    let gguf = model_info(json!({"name": "acme/tiny", "quant_info": "Q4"}));
+
+    assert_eq!(ggml.format, OutputFormat::Ggml);
+    assert_eq!(ggml.format.converter_script(), "convert.py");
+    assert_eq!(ggml.quantized_filenames(), ["tiny-ggml-q4_0.bin"]);
+    // GGUF unless asked otherwise
+    assert_eq!(gguf.format, OutputFormat::Gguf);
+    assert_eq!(gguf.format.converter_script(), "convert-hf-to-gguf.py");
+    assert_eq!(gguf.quantized_filenames(), ["tiny-q4_0.gguf"]);
+}
+
+#[test]
+fn keeps_the_hugging_face_token_out_of_the_logs() {
+    let request = model_info(json!({
+        "name": "meta-llama/Llama-2-7b-hf",
+        "quant_info": "Q4",
+        "hf_token": "hf_secret",
+    }));
+
+    assert_eq!(request.hf_token().as_deref(), Some("hf_secret"));
+    let logged = format!("{request:?}");
+    assert!(!logged.contains("hf_secret"), "{logged}");
+    assert!(logged.contains("HfToken(<redacted>)"));
+    let serialized = serde_json::to_string(&request).unwrap();
+    assert!(!serialized.contains("hf_secret"), "{serialized}");
+}
+
+#[test]
+fn gives_the_token_to_git_through_its_environment() {
+    let clone = CommandSpec::new("git", "/data/models")
+        .arg("clone")
+        .arg("https://huggingface.co/meta-llama/Llama-2-7b-hf");
+
+    let clone = with_git_token(clone, "hf_secret");
+
+    assert!(clone.envs.contains(&(
+        "GIT_CONFIG_VALUE_0".into(),
+        "Authorization: Bearer hf_secret".into()
+    )));
+    assert!(clone
+        .args
+        .iter()
+        .all(|arg| !arg.to_string_lossy().contains("hf_secret")));
+    assert!(!clone.to_string().contains("hf_secret"));
+}
+
+#[test]
+fn ranks_plain_text_and_json_by_the_accept_header() {
+    let prefers_plain_text = |accept: &[&str]| {
+        let mut headers = axum::http::HeaderMap::new();
+        for accept in accept {
+            headers.append(axum::http::header::ACCEPT, accept.parse().unwrap());
+        }
+        prefers_plain_text(&headers)
+    };
+
+    for accept in [
+        &["text/plain"][..],
+        &["text/*, application/json;q=0.5"],
+        &["application/json;q=0.1", "text/plain"],
+        &["*/*;q=0.2, TEXT/PLAIN"],
+    ] {
+        assert!(prefers_plain_text(accept), "{accept:?}");
+    }
+    for accept in [
+        &[][..],
+        &["*/*"],
+        &["application/json"],
+        &["text/plain, application/json"],
+        &["text/plain;q=0.5, application/*"],
+        &["text/html"],
+    ] {
+        assert!(!prefers_plain_text(accept), "{accept:?}");
+    }
+}
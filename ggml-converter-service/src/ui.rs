@@ -0,0 +1,147 @@
+//! `GET /`: a page to start a conversion and follow it from a browser, built on the JSON API
+//! and the events of the job. Self-contained, with no assets to serve or build.
+
+use crate::{OutputFormat, QuantInfo};
+use axum::response::Html;
+
+pub async fn index() -> Html<String> {
+    let option = |value: String| format!(r#"<option value="{value}">{value}</option>"#);
+    let quants: String = QuantInfo::ALL
+        .iter()
+        .chain([&QuantInfo::NoQuant])
+        .map(|quant| option(quant.to_string()))
+        .collect();
+    let formats: String = [OutputFormat::Gguf, OutputFormat::Ggml]
+        .iter()
+        .map(|format| option(format!("{format:?}")))
+        .collect();
+    Html(
+        PAGE.replace("{quants}", &quants)
+            .replace("{formats}", &formats),
+    )
+}
+
+const PAGE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <title>ggml-converter-service</title>
+  <style>
+    body { font-family: sans-serif; max-width: 52rem; margin: 2rem auto; padding: 0 1rem; }
+    label { display: block; margin: .5rem 0; }
+    input[type=text] { width: 24rem; }
+    #status { font-weight: bold; margin: 1rem 0; }
+    #error { color: #b00; white-space: pre-wrap; }
+    #log { background: #f4f4f4; padding: .5rem; height: 20rem; overflow-y: auto; white-space: pre-wrap; font-size: .85rem; }
+  </style>
+</head>
+<body>
+  <h1>ggml-converter-service</h1>
+  <p><a href="/swagger-ui">API documentation</a></p>
+
+  <form id="convert">
+    <label>Model
+      <input type="text" name="name" list="models" placeholder="meta-llama/Llama-2-7b-hf" required>
+    </label>
+    <datalist id="models"></datalist>
+    <label>Quant <select name="quant_info">{quants}</select></label>
+    <label>Format <select name="format">{formats}</select></label>
+    <button type="submit">Convert</button>
+  </form>
+
+  <div id="error"></div>
+  <div id="status"></div>
+  <ul id="outputs"></ul>
+  <div id="log" hidden></div>
+
+  <script>
+    const form = document.getElementById("convert");
+    const error = document.getElementById("error");
+    const status = document.getElementById("status");
+    const outputs = document.getElementById("outputs");
+    const log = document.getElementById("log");
+    let events = null;
+
+    // the registered models, any Hugging Face repo may be typed in as well
+    fetch("/models").then(response => response.json()).then(models => {
+      const list = document.getElementById("models");
+      for (const name of Object.keys(models).sort()) {
+        const option = document.createElement("option");
+        option.value = name;
+        list.appendChild(option);
+      }
+    });
+
+    // the state of the job on a single line, read again at most once a second
+    let refreshing = null;
+    function refresh(id) {
+      if (refreshing) return;
+      refreshing = setTimeout(async () => {
+        refreshing = null;
+        const response = await fetch(`/jobs/${id}`, { headers: { Accept: "text/plain" } });
+        if (response.ok) status.textContent = await response.text();
+      }, 1000);
+    }
+
+    function append(line) {
+      const follow = log.scrollTop + log.clientHeight >= log.scrollHeight - 4;
+      log.textContent += line + "\n";
+      if (follow) log.scrollTop = log.scrollHeight;
+    }
+
+    function watch(id) {
+      if (events) events.close();
+      log.hidden = false;
+      log.textContent = "";
+      outputs.textContent = "";
+      status.textContent = "Queued";
+      events = new EventSource(`/jobs/${id}/events`);
+      events.addEventListener("log", event => {
+        append(event.data);
+        refresh(id);
+      });
+      events.addEventListener("done", event => {
+        events.close();
+        status.textContent = "Done";
+        for (const url of event.data.split("\n").filter(url => url)) {
+          const item = document.createElement("li");
+          const link = document.createElement("a");
+          link.href = url;
+          link.textContent = url;
+          item.appendChild(link);
+          outputs.appendChild(item);
+        }
+      });
+      // also fired by the browser when the connection drops, without data
+      events.addEventListener("error", event => {
+        if (event.data === undefined) return;
+        events.close();
+        status.textContent = "Failed";
+        error.textContent = event.data;
+      });
+    }
+
+    form.addEventListener("submit", async event => {
+      event.preventDefault();
+      error.textContent = "";
+      const data = new FormData(form);
+      const response = await fetch("/ggml", {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({
+          name: data.get("name").trim(),
+          quant_info: data.get("quant_info"),
+          format: data.get("format"),
+        }),
+      });
+      const body = await response.json().catch(() => ({}));
+      if (!response.ok) {
+        const problems = (body.details && body.details.problems) || [];
+        error.textContent = [body.message || response.statusText, ...problems].join("\n");
+        return;
+      }
+      watch(body.job_id);
+    });
+  </script>
+</body>
+</html>"##;
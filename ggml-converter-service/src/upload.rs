@@ -0,0 +1,190 @@
+//! `POST /upload/{repo_id}`: models provided by the caller as a tarball of their directory,
+//! extracted under the models directory so that conversions of the repo skip the download.
+//!
+//! A large archive may be sent in several requests, each with a `Content-Range` appending to
+//! what the previous ones stored, so an interrupted upload resumes where it stopped. Parts
+//! are kept under `<models dir>/.uploads/` until the archive is complete.
+
+use std::{
+    collections::HashSet,
+    io::Read,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// Default largest archive taken, in MB
+pub const DEFAULT_MAX_UPLOAD_MB: u64 = 64 * 1024;
+
+const MB: u64 = 1024 * 1024;
+
+/// Extensions of the weight files a conversion loads
+const WEIGHT_EXTENSIONS: &[&str] = &["safetensors", "bin", "pt", "pth"];
+
+/// Largest archive taken, and largest total size of its files, read from `GGML_MAX_UPLOAD_MB`
+pub fn max_upload_size() -> u64 {
+    std::env::var("GGML_MAX_UPLOAD_MB")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_MAX_UPLOAD_MB)
+        * MB
+}
+
+/// Directory holding the archives being received and their extraction
+pub fn uploads_dir(models_dir: &Path) -> PathBuf {
+    models_dir.join(".uploads")
+}
+
+/// The `Content-Range` of a part of the archive, `bytes <start>-<end>/<total>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentRange {
+    pub start: u64,
+    /// Last byte of the part, inclusive
+    pub end: u64,
+    pub total: u64,
+}
+
+impl ContentRange {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid =
+            || format!("Invalid Content-Range '{value}', expected 'bytes <start>-<end>/<total>'");
+        let (range, total) = value
+            .trim()
+            .strip_prefix("bytes ")
+            .and_then(|range| range.split_once('/'))
+            .ok_or_else(invalid)?;
+        let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+        let number = |value: &str| value.trim().parse::<u64>().map_err(|_| invalid());
+        let range = ContentRange {
+            start: number(start)?,
+            end: number(end)?,
+            total: number(total)?,
+        };
+        match range.start <= range.end && range.end < range.total {
+            true => Ok(range),
+            false => Err(invalid()),
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+}
+
+/// Repos being uploaded, each is received by a single request at a time
+#[derive(Debug, Clone, Default)]
+pub struct Uploads {
+    active: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Uploads {
+    /// Mark the repo as being uploaded, `None` if it already is
+    pub fn begin(&self, repo_name: &str) -> Option<UploadGuard> {
+        match self.active.lock().unwrap().insert(repo_name.to_string()) {
+            true => Some(UploadGuard {
+                uploads: self.clone(),
+                repo_name: repo_name.to_string(),
+            }),
+            false => None,
+        }
+    }
+}
+
+/// The upload of a repo, which ends when dropped
+#[derive(Debug)]
+pub struct UploadGuard {
+    uploads: Uploads,
+    repo_name: String,
+}
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        self.uploads.active.lock().unwrap().remove(&self.repo_name);
+    }
+}
+
+/// Extract the archive, gzipped or not, into `dest`.
+///
+/// Only files and directories are extracted, and only inside `dest`: links and paths that are
+/// absolute or go up are rejected, as is an archive whose files add up to more than `max_size`.
+pub fn extract(archive: &Path, dest: &Path, max_size: u64) -> Result<(), String> {
+    let open = || std::fs::File::open(archive).map_err(|err| format!("Reading the archive: {err}"));
+    let mut magic = [0u8; 2];
+    let gzipped = open()?.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b];
+    let reader: Box<dyn Read> = match gzipped {
+        true => Box::new(flate2::read::GzDecoder::new(open()?)),
+        false => Box::new(open()?),
+    };
+
+    std::fs::create_dir_all(dest).map_err(|err| format!("Creating {}: {err}", dest.display()))?;
+    let invalid = |err: std::io::Error| format!("Invalid archive: {err}");
+    let mut archive = tar::Archive::new(reader);
+    let mut size = 0u64;
+    for entry in archive.entries().map_err(invalid)? {
+        let mut entry = entry.map_err(invalid)?;
+        let path = entry.path().map_err(invalid)?.into_owned();
+        match entry.header().entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Directory => {}
+            // metadata of the archive itself
+            tar::EntryType::XGlobalHeader => continue,
+            kind => {
+                return Err(format!(
+                    "Unexpected {kind:?} entry '{}' in the archive, only files and directories are taken",
+                    path.display()
+                ))
+            }
+        }
+        let inside = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !inside {
+            return Err(format!(
+                "Unexpected path '{}' in the archive, paths must be relative and stay inside it",
+                path.display()
+            ));
+        }
+        size = size.saturating_add(entry.size());
+        if size > max_size {
+            return Err(format!(
+                "The files of the archive exceed the {} MB upload limit",
+                max_size / MB
+            ));
+        }
+        entry
+            .unpack_in(dest)
+            .map_err(|err| format!("Extracting '{}': {err}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// The model's directory within the extracted archive: its root, or its only directory for
+/// archives made of the directory itself, like `tar -czf model.tar.gz model/`
+pub fn model_root(dest: &Path) -> std::io::Result<PathBuf> {
+    let entries = std::fs::read_dir(dest)?.collect::<Result<Vec<_>, _>>()?;
+    match entries.as_slice() {
+        [entry] if entry.file_type()?.is_dir() => Ok(entry.path()),
+        _ => Ok(dest.to_path_buf()),
+    }
+}
+
+/// Check that the extracted model has what a conversion reads: a config, every shard its index
+/// lists, and weights
+pub fn verify(dir: &Path) -> Result<(), String> {
+    crate::download::verify_model_dir(dir)
+        .map_err(|err| err.replace(&dir.display().to_string(), "The archive"))?;
+    let entries =
+        std::fs::read_dir(dir).map_err(|err| format!("Reading {}: {err}", dir.display()))?;
+    let has_weights = entries.flatten().any(|entry| {
+        entry
+            .path()
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| WEIGHT_EXTENSIONS.contains(&extension))
+    });
+    match has_weights {
+        true => Ok(()),
+        false => Err(format!(
+            "The archive has no weights, expected .{} files",
+            WEIGHT_EXTENSIONS.join(", .")
+        )),
+    }
+}
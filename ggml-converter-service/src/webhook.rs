@@ -0,0 +1,197 @@
+//! Notifications POSTed to the `callback_url` of a conversion once it is over
+
+use crate::job::{Job, JobId, JobState, QuantStatus};
+use serde::Serialize;
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+/// Attempts to deliver a notification before giving up on it
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Body of the notification
+#[derive(Debug, Serialize)]
+pub struct Callback {
+    pub job_id: JobId,
+    pub state: JobState,
+    /// Set when a single quantization was requested
+    pub download_url: Option<String>,
+    pub download_urls: Vec<String>,
+    /// Commit of the repo the outputs were converted from
+    pub commit: Option<String>,
+    pub error: Option<String>,
+    /// Outcome of each target, when done
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub quant_statuses: Vec<QuantStatus>,
+}
+
+impl From<&Job> for Callback {
+    fn from(job: &Job) -> Self {
+        Callback {
+            job_id: job.id,
+            state: job.state,
+            download_url: match job.download_urls.as_slice() {
+                [download_url] => Some(download_url.clone()),
+                _ => None,
+            },
+            download_urls: job.download_urls.clone(),
+            commit: job.commit.clone(),
+            error: job.error.clone(),
+            quant_statuses: job.quant_statuses.clone(),
+        }
+    }
+}
+
+/// Whether callbacks may target loopback, link-local and private addresses, which
+/// `GGML_ALLOW_PRIVATE_CALLBACKS=true` allows for deployments calling back their own network
+fn allow_private() -> bool {
+    std::env::var("GGML_ALLOW_PRIVATE_CALLBACKS").is_ok_and(|value| value == "true")
+}
+
+/// Check that the callback is an http(s) url, and unless allowed, not one pointing the service
+/// at itself or its network. Only literal addresses and `localhost` are caught, host names
+/// are not resolved.
+pub fn validate_url(url: &str) -> Result<(), String> {
+    let invalid = || format!("Invalid callback_url '{url}'");
+    let parsed = reqwest::Url::parse(url).map_err(|_| invalid())?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(invalid());
+    }
+    let host = parsed.host_str().ok_or_else(invalid)?;
+    // IPv6 hosts come bracketed
+    let private = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => is_private(ip),
+        Err(_) => {
+            let domain = host.trim_end_matches('.').to_ascii_lowercase();
+            domain == "localhost" || domain.ends_with(".localhost")
+        }
+    };
+    match private && !allow_private() {
+        true => Err(format!(
+            "callback_url '{url}' points at a private address, set GGML_ALLOW_PRIVATE_CALLBACKS=true to allow it"
+        )),
+        false => Ok(()),
+    }
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private_v4(ip),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    // unique local fc00::/7 and link-local fe80::/10
+                    || (ip.segments()[0] & 0xfe00) == 0xfc00
+                    || (ip.segments()[0] & 0xffc0) == 0xfe80
+            }
+        },
+    }
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+}
+
+/// POST the notification, retrying with backoff on errors and non-2xx answers
+pub async fn notify(url: &str, callback: &Callback) {
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+    {
+        Ok(client) => client,
+        Err(err) => {
+            warn!("Failed to notify {url}: {err}");
+            return;
+        }
+    };
+
+    let mut attempt = 1;
+    loop {
+        let err = match client.post(url).json(callback).send().await {
+            Ok(response) if response.status().is_success() => {
+                info!("Notified {url} of job {}", callback.job_id);
+                return;
+            }
+            Ok(response) => format!("answered {}", response.status()),
+            Err(err) => err.to_string(),
+        };
+        if attempt >= MAX_ATTEMPTS {
+            warn!("Failed to notify {url} after {attempt} attempts: {err}");
+            return;
+        }
+        warn!("({attempt}) Failed to notify {url}: {err}");
+        tokio::time::sleep(Duration::from_secs(2u64.pow(attempt))).await;
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{model_info, serve_router};
+    use axum::{extract::Extension, routing::post, Json, Router};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn posts_the_outcome_of_the_job() {
+        let received: Arc<Mutex<Vec<Value>>> = Arc::default();
+        let receiver = serve_router(
+            Router::new()
+                .route(
+                    "/hooks/ggml",
+                    post(
+                        |Extension(received): Extension<Arc<Mutex<Vec<Value>>>>,
+                         Json(body): Json<Value>| async move {
+                            received.lock().unwrap().push(body);
+                        },
+                    ),
+                )
+                .layer(Extension(received.clone())),
+        );
+        let mut job = Job::new(model_info(json!({"name": "acme/tiny", "quant_info": "Q4"})));
+        job.state = JobState::Done;
+        job.download_urls = vec!["/download/tiny-q4_0.gguf".to_string()];
+        job.commit = Some("abc123".to_string());
+
+        notify(&format!("{receiver}/hooks/ggml"), &Callback::from(&job)).await;
+
+        assert_eq!(
+            *received.lock().unwrap(),
+            [json!({
+                "job_id": job.id,
+                "state": "Done",
+                "download_url": "/download/tiny-q4_0.gguf",
+                "download_urls": ["/download/tiny-q4_0.gguf"],
+                "commit": "abc123",
+                "error": null,
+            })]
+        );
+    }
+
+    #[test]
+    fn rejects_a_callback_to_a_private_address() {
+        assert!(validate_url("https://hooks.example.com/ggml").is_ok());
+        assert!(validate_url("ftp://hooks.example.com/ggml").is_err());
+        for url in [
+            "http://127.0.0.1:8080/",
+            "http://10.0.0.5/",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/",
+            "http://[::ffff:192.168.1.1]/",
+            "http://api.localhost/",
+        ] {
+            let err = validate_url(url).unwrap_err();
+
+            assert!(err.contains("points at a private address"), "{url}: {err}");
+        }
+    }
+}
@@ -0,0 +1,380 @@
+//! WebSockets (RFC 6455) on top of hyper's connection upgrades, as much of the protocol as
+//! `GET /jobs/{id}/ws` needs: the handshake, text messages both ways, pings and the closing
+//! handshake. No extensions nor subprotocols are negotiated.
+
+use crate::error::AppError;
+use axum::{
+    body,
+    http::{header, Request},
+    response::Response,
+};
+use http::StatusCode;
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+
+/// Appended to the key of the client before hashing it, fixed by the RFC
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message taken from a client, which only ever sends short commands
+const MAX_MESSAGE_LEN: usize = 64 * 1024;
+
+/// Status code of a close frame ending the connection normally
+pub const CLOSE_NORMAL: u16 = 1000;
+/// Status code of a close frame answering a frame that breaks the protocol
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// Check the opening handshake of the request. The response switches protocols, the socket
+/// is the output of the returned future once it has been sent.
+pub fn handshake<B>(req: &mut Request<B>) -> Result<(Response, OnUpgrade), AppError> {
+    let headers = req.headers();
+    let has_token = |name: header::HeaderName, token: &str| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    if !has_token(header::CONNECTION, "upgrade") || !has_token(header::UPGRADE, "websocket") {
+        return Err(AppError::BadRequest(
+            "Expected a WebSocket upgrade request".to_string(),
+        ));
+    }
+    if headers
+        .get(header::SEC_WEBSOCKET_VERSION)
+        .is_none_or(|version| version != "13")
+    {
+        return Err(AppError::BadRequest(
+            "Unsupported WebSocket version, expected 13".to_string(),
+        ));
+    }
+    let key = headers
+        .get(header::SEC_WEBSOCKET_KEY)
+        .ok_or_else(|| AppError::BadRequest("Missing Sec-WebSocket-Key".to_string()))?;
+    let mut hashed = key.as_bytes().to_vec();
+    hashed.extend_from_slice(ACCEPT_GUID.as_bytes());
+    let accept = openssl::base64::encode_block(&openssl::sha::sha1(&hashed));
+
+    let response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_ACCEPT, accept)
+        .body(body::boxed(body::Empty::new()))
+        .map_err(|err| AppError::Internal(err.to_string()))?;
+    Ok((response, hyper::upgrade::on(req)))
+}
+
+/// The reading and the writing halves of the socket, each usable from its own task
+pub fn split(socket: Upgraded) -> (Reader<ReadHalf<Upgraded>>, Writer<WriteHalf<Upgraded>>) {
+    let (reader, writer) = tokio::io::split(socket);
+    (Reader { inner: reader }, Writer { inner: writer })
+}
+
+/// A message of the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// To be answered with a pong of the same payload
+    Ping(Vec<u8>),
+    /// The client began the closing handshake
+    Close,
+}
+
+pub struct Reader<R> {
+    inner: R,
+}
+
+impl<R: AsyncRead + Unpin> Reader<R> {
+    /// The next message of the client, fragmented ones put back together. Pongs are skipped.
+    ///
+    /// Not cancel safe, a frame read half way is lost.
+    pub async fn recv(&mut self) -> std::io::Result<Message> {
+        let mut message: Option<(u8, Vec<u8>)> = None;
+        loop {
+            let (fin, opcode, payload) = self.read_frame().await?;
+            match opcode {
+                OPCODE_CLOSE => return Ok(Message::Close),
+                OPCODE_PING => return Ok(Message::Ping(payload)),
+                OPCODE_PONG => continue,
+                OPCODE_TEXT | OPCODE_BINARY if message.is_none() => {
+                    message = Some((opcode, payload))
+                }
+                OPCODE_CONTINUATION if message.is_some() => {
+                    if let Some((_, data)) = &mut message {
+                        data.extend_from_slice(&payload);
+                    }
+                }
+                _ => return Err(protocol_error(format!("unexpected opcode {opcode:#x}"))),
+            }
+            if message
+                .as_ref()
+                .is_some_and(|(_, data)| data.len() > MAX_MESSAGE_LEN)
+            {
+                return Err(protocol_error("message too long".to_string()));
+            }
+            if fin {
+                return match message.take() {
+                    Some((OPCODE_TEXT, data)) => String::from_utf8(data)
+                        .map(Message::Text)
+                        .map_err(|_| protocol_error("text message isn't UTF-8".to_string())),
+                    Some((_, data)) => Ok(Message::Binary(data)),
+                    None => Err(protocol_error("continuation without a message".to_string())),
+                };
+            }
+        }
+    }
+
+    async fn read_frame(&mut self) -> std::io::Result<(bool, u8, Vec<u8>)> {
+        let mut head = [0u8; 2];
+        self.inner.read_exact(&mut head).await?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0F;
+        if head[0] & 0x70 != 0 {
+            return Err(protocol_error("reserved bits set".to_string()));
+        }
+        // clients must mask what they send
+        if head[1] & 0x80 == 0 {
+            return Err(protocol_error("unmasked frame".to_string()));
+        }
+        let len = match head[1] & 0x7F {
+            126 => u64::from(self.inner.read_u16().await?),
+            127 => self.inner.read_u64().await?,
+            len => u64::from(len),
+        };
+        let control = opcode & 0x8 != 0;
+        if control && (!fin || len > 125) {
+            return Err(protocol_error(
+                "fragmented or long control frame".to_string(),
+            ));
+        }
+        if len > MAX_MESSAGE_LEN as u64 {
+            return Err(protocol_error("message too long".to_string()));
+        }
+        let mut mask = [0u8; 4];
+        self.inner.read_exact(&mut mask).await?;
+        let mut payload = vec![0u8; len as usize];
+        self.inner.read_exact(&mut payload).await?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok((fin, opcode, payload))
+    }
+}
+
+pub struct Writer<W> {
+    inner: W,
+}
+
+impl<W: AsyncWrite + Unpin> Writer<W> {
+    pub async fn send_text(&mut self, text: &str) -> std::io::Result<()> {
+        self.write_frame(OPCODE_TEXT, text.as_bytes()).await
+    }
+
+    pub async fn pong(&mut self, payload: &[u8]) -> std::io::Result<()> {
+        self.write_frame(OPCODE_PONG, payload).await
+    }
+
+    /// Begin, or answer, the closing handshake
+    pub async fn close(&mut self, code: u16) -> std::io::Result<()> {
+        self.write_frame(OPCODE_CLOSE, &code.to_be_bytes()).await
+    }
+
+    async fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+        // servers never mask, and send every message as a single frame
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        self.inner.write_all(&frame).await?;
+        self.inner.flush().await
+    }
+}
+
+fn protocol_error(msg: String) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("WebSocket protocol error: {msg}"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    /// A frame as a client sends it, its length in the shortest form unless `long_len` asks
+    /// for the 8 byte one
+    fn frame(fin: bool, opcode: u8, payload: &[u8], masked: bool, long_len: bool) -> Vec<u8> {
+        let mut frame = vec![u8::from(fin) << 7 | opcode];
+        let mask_bit = u8::from(masked) << 7;
+        match payload.len() {
+            len @ 0..=125 if !long_len => frame.push(mask_bit | len as u8),
+            len @ 126..=0xFFFF if !long_len => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        if !masked {
+            frame.extend_from_slice(payload);
+            return frame;
+        }
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        frame
+    }
+
+    fn masked(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        frame(fin, opcode, payload, true, false)
+    }
+
+    /// A reader of the frames, as sent by a client
+    async fn reader(frames: &[Vec<u8>]) -> Reader<DuplexStream> {
+        let (mut client, server) = duplex(1 << 20);
+        client.write_all(&frames.concat()).await.unwrap();
+        Reader { inner: server }
+    }
+
+    fn assert_protocol_error(result: std::io::Result<Message>, expected: &str) {
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            format!("WebSocket protocol error: {expected}")
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_masked_messages() {
+        let mut reader = reader(&[
+            masked(true, OPCODE_TEXT, b"cancel"),
+            masked(true, OPCODE_PING, b"are you there"),
+            masked(true, OPCODE_PONG, b""),
+            masked(true, OPCODE_BINARY, &[0, 1, 2]),
+            masked(true, OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes()),
+        ])
+        .await;
+
+        assert_eq!(
+            reader.recv().await.unwrap(),
+            Message::Text("cancel".to_string())
+        );
+        assert_eq!(
+            reader.recv().await.unwrap(),
+            Message::Ping(b"are you there".to_vec())
+        );
+        // the pong is skipped
+        assert_eq!(reader.recv().await.unwrap(), Message::Binary(vec![0, 1, 2]));
+        assert_eq!(reader.recv().await.unwrap(), Message::Close);
+    }
+
+    #[tokio::test]
+    async fn rejects_an_unmasked_frame() {
+        let mut reader = reader(&[frame(true, OPCODE_TEXT, b"cancel", false, false)]).await;
+
+        assert_protocol_error(reader.recv().await, "unmasked frame");
+    }
+
+    #[tokio::test]
+    async fn rejects_a_control_frame_over_125_bytes() {
+        let mut reader = reader(&[masked(true, OPCODE_PING, &[b'p'; 126])]).await;
+
+        assert_protocol_error(reader.recv().await, "fragmented or long control frame");
+    }
+
+    #[tokio::test]
+    async fn reads_the_8_byte_length_of_a_frame() {
+        let text = "a".repeat(300);
+        let mut reader = reader(&[
+            frame(true, OPCODE_TEXT, text.as_bytes(), true, true),
+            frame(
+                true,
+                OPCODE_TEXT,
+                &vec![b'a'; MAX_MESSAGE_LEN + 1],
+                true,
+                true,
+            ),
+        ])
+        .await;
+
+        assert_eq!(reader.recv().await.unwrap(), Message::Text(text));
+        assert_protocol_error(reader.recv().await, "message too long");
+    }
+
+    #[tokio::test]
+    async fn puts_a_fragmented_text_message_back_together() {
+        let mut reader = reader(&[
+            masked(false, OPCODE_TEXT, b"can"),
+            masked(false, OPCODE_CONTINUATION, b"c"),
+            masked(true, OPCODE_CONTINUATION, b"el"),
+            masked(true, OPCODE_CONTINUATION, b"orphan"),
+        ])
+        .await;
+
+        assert_eq!(
+            reader.recv().await.unwrap(),
+            Message::Text("cancel".to_string())
+        );
+        assert_protocol_error(reader.recv().await, "unexpected opcode 0x0");
+    }
+
+    #[tokio::test]
+    async fn writes_unmasked_frames_with_the_shortest_length() {
+        let (server, mut client) = duplex(1 << 20);
+        let mut writer = Writer { inner: server };
+        let long = "b".repeat(70_000);
+
+        writer.send_text("done").await.unwrap();
+        writer.send_text(&"a".repeat(200)).await.unwrap();
+        writer.send_text(&long).await.unwrap();
+        writer.close(CLOSE_NORMAL).await.unwrap();
+        drop(writer);
+
+        let mut sent = Vec::new();
+        client.read_to_end(&mut sent).await.unwrap();
+        let expected = [
+            frame(true, OPCODE_TEXT, b"done", false, false),
+            frame(true, OPCODE_TEXT, "a".repeat(200).as_bytes(), false, false),
+            frame(true, OPCODE_TEXT, long.as_bytes(), false, false),
+            frame(
+                true,
+                OPCODE_CLOSE,
+                &CLOSE_NORMAL.to_be_bytes(),
+                false,
+                false,
+            ),
+        ]
+        .concat();
+        assert_eq!(sent, expected);
+        assert_eq!(sent[6..8], [0x81, 126]);
+        assert_eq!(sent[210..212], [0x81, 127]);
+    }
+}
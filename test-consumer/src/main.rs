@@ -5,6 +5,11 @@ use serde::{Deserialize, Serialize};
 struct ModelInfo {
     name: ModelType,
     quant_info: QuantInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    llama_cpp_ref: Option<String>,
+    /// Needed for gated repos such as Llama 2 unless the service has its own `HF_TOKEN`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hf_token: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -17,6 +22,8 @@ enum ModelType {
     Llama2Chinese7b,
 }
 
+// variant names follow llama.cpp's quant type names
+#[allow(non_camel_case_types)]
 #[derive(Debug, Deserialize, Serialize)]
 enum QuantInfo {
     /// q4_0
@@ -27,11 +34,49 @@ enum QuantInfo {
     F16,
     /// f32
     F32,
+    /// q2_K
+    Q2_K,
+    /// q3_K_M
+    Q3_K_M,
+    /// q4_K_M
+    Q4_K_M,
+    /// q5_K_M
+    Q5_K_M,
+    /// q6_K
+    Q6_K,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
-struct ConversionResult {
-    download_url: String,
+struct JobCreated {
+    job_id: String,
+}
+
+/// Body of the error responses, `code` is stable where `message` isn't
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    code: String,
+    message: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+enum JobState {
+    Queued,
+    Downloading,
+    Converting,
+    Quantizing,
+    Done,
+    Failed,
+    Cancelled,
+    /// Stopped by the service shutting down, it has to be requested again
+    Interrupted,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct JobStatus {
+    state: JobState,
+    started_at: u64,
+    updated_at: u64,
+    download_url: Option<String>,
 }
 
 #[tokio::main]
@@ -39,6 +84,8 @@ async fn main() -> Result<(), Error> {
     let model_info = ModelInfo {
         name: ModelType::Llama2_7b, // "meta-llama/Llama-2-7b-hf".to_string(),
         quant_info: QuantInfo::Q4,  // "q4_0".to_string(),
+        llama_cpp_ref: None,
+        hf_token: std::env::var("HF_TOKEN").ok(),
     };
 
     let client = reqwest::Client::new();
@@ -51,8 +98,50 @@ async fn main() -> Result<(), Error> {
 
     println!("{:?}", response);
 
-    let conversion_result = response.json::<ConversionResult>().await?;
-    println!("download url: {}", conversion_result.download_url);
+    if !response.status().is_success() {
+        let error = response.json::<ApiError>().await?;
+        match error.code.as_str() {
+            "RATE_LIMITED" => println!("too many conversions requested, try again later"),
+            "MODEL_NOT_FOUND" => println!("no such model on Hugging Face"),
+            _ => println!("conversion refused ({}): {}", error.code, error.message),
+        }
+        return Ok(());
+    }
+    let job = response.json::<JobCreated>().await?;
+    println!("job id: {}", job.job_id);
+
+    // poll the job until it finishes
+    loop {
+        let status = client
+            .get(format!("http://localhost:3000/jobs/{}", job.job_id))
+            .send()
+            .await?
+            .json::<JobStatus>()
+            .await?;
+        println!("state: {:?}", status.state);
+
+        match status.state {
+            JobState::Done => {
+                if let Some(download_url) = status.download_url {
+                    println!("download url: {}", download_url);
+                }
+                break;
+            }
+            JobState::Failed => {
+                println!("conversion failed");
+                break;
+            }
+            JobState::Cancelled => {
+                println!("conversion cancelled");
+                break;
+            }
+            JobState::Interrupted => {
+                println!("conversion interrupted by the service shutting down");
+                break;
+            }
+            _ => tokio::time::sleep(std::time::Duration::from_secs(5)).await,
+        }
+    }
 
     Ok(())
 }